@@ -3,8 +3,8 @@
 qemu_path="../../qemu_iommu/build/qemu-system-riscv64"
 
 function help() {
-    echo "create [host/guest/all]: create host or guest dts from qemu dtb"
-    echo "build [host/guest/all]: build host or guest dtb from guest.dts"
+    echo "create host: create host dts from qemu dtb"
+    echo "build host: build host dtb from host.dts"
 }
 
 function create_host() {
@@ -25,29 +25,12 @@ function create_host() {
     rm -f qemu.dtb
 }
 
-function create_guest() {
-    $qemu_path -S -gdb tcp::10000 \
-        -machine virt \
-        -bios none  \
-        -m 256M \
-        -initrd ../vmlinux_debug \
-        -drive file=../rootfs.ext2,format=raw,id=hd0,if=none \
-        -device virtio-blk-device,drive=hd0 \
-        -netdev user,id=n1 \
-        -device virtio-net-pci,netdev=n1 \
-        -append "root=/dev/vda rw console=ttyS0" \
-        -kernel ../target/riscv64imac-unknown-none-elf/debug/hikami \
-        -machine dumpdtb=qemu.dtb
-    dtc -I dtb -O dts -o guest.dts qemu.dtb
-    rm -f qemu.dtb
-}
-
 if [ "$#" -eq 0 ]; then
     help
 fi
 
 if [ "$#" -eq 1 ]; then
-    echo "specify target: host or guest or all"
+    echo "specify target: host"
     help
 fi
 
@@ -58,15 +41,8 @@ if [ "$#" -eq 2 ]; then
                 "host")
                     create_host
                     ;;
-                "guest")
-                    create_guest
-                    ;;
-                "all")
-                    create_host
-                    create_guest
-                    ;;
                 *)
-                    echo "specify target: host or guest or all"
+                    echo "specify target: host"
                     help
                     ;;
             esac
@@ -76,15 +52,8 @@ if [ "$#" -eq 2 ]; then
                 "host")
                     dtc -I dts -O dtb -o ../host.dtb host.dts
                     ;;
-                "guest")
-                    dtc -I dts -O dtb -o ../guest.dtb guest.dts
-                    ;;
-                "all")
-                    dtc -I dts -O dtb -o ../host.dtb host.dts
-                    dtc -I dts -O dtb -o ../guest.dtb guest.dts
-                    ;;
                 *)
-                    echo "specify target: host or guest or all"
+                    echo "specify target: host"
                     help
                     ;;
             esac
@@ -0,0 +1,83 @@
+//! Host-side unit test harness of hikami.
+//!
+//! hikami is `no_std` and `no_main` for RISC-V, so `cargo test` can not be run on the crate itself.
+//! This crate compiles hardware-independent modules of hikami for the host with `std` by `#[path]`,
+//! and replaces the modules they depend on (CSRs and items of crate root) with mocks.
+//! Unit tests are written in `#[cfg(test)] mod tests` of each module as usual.
+//!
+//! ```sh
+//! $ cargo test --manifest-path host_test/Cargo.toml --target x86_64-unknown-linux-gnu
+//! ```
+
+extern crate alloc;
+
+mod mock;
+
+/// Modules of hikami. (`src/`)
+///
+/// Only the modules that can be compiled for the host are declared.
+/// Their items are public here, so lints for public API that do not fire in hikami are allowed.
+#[path = "../../src"]
+#[allow(clippy::new_without_default)]
+mod hikami {
+    pub mod memmap;
+
+    pub mod device {
+        //! Devices data
+
+        mod mmio;
+        #[allow(clippy::module_name_repetitions)]
+        pub use mmio::{DeviceEmulateError, MmioDevice, PTE_FLAGS_FOR_DEVICE};
+
+        pub use crate::mock::EmulatedMmioDevice;
+        pub mod mmio_registry;
+
+        pub mod pci;
+
+        pub mod aplic {
+            //! APLIC: Advanced Platform-Level Interrupt Controller
+
+            pub mod state;
+        }
+
+        pub mod iommu {
+            //! IOMMU: I/O memory management unit.
+
+            pub mod register_map;
+        }
+
+        pub mod plic {
+            //! PLIC: Platform-Level Interrupt Controller
+
+            pub mod state;
+        }
+
+        pub mod uart {
+            //! UART: Universal Asynchronous Receiver-Transmitter
+
+            pub mod register;
+            pub mod virtual_uart;
+        }
+    }
+
+    pub mod trap {
+        //! Trap handlers
+
+        pub mod hypervisor_supervisor {
+            //! HS-mode trap handler
+
+            pub mod exception {
+                //! HS-mode exception handler
+
+                pub mod sbi_handler {
+                    //! Handle VS-mode Ecall exception
+
+                    pub mod fwft;
+                }
+            }
+        }
+    }
+}
+
+pub use hikami::{device, memmap};
+pub use mock::{guest_access, h_extension, PageBlock, FRAME_ALLOCATOR};
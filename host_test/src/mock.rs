@@ -0,0 +1,244 @@
+//! Mocks of hardware and the items of hikami that are not compiled for the host.
+
+use crate::memmap::{
+    frame_allocator::FrameAllocator, page_table::constants::PAGE_SIZE, HostPhysicalAddress,
+};
+
+use spin::Mutex;
+use std::alloc::{alloc_zeroed, Layout};
+use std::fmt::Debug;
+use std::sync::LazyLock;
+
+/// Size of mock physical memory.
+const MOCK_MEMORY_SIZE: usize = 16 * 1024 * 1024;
+/// Alignment of mock physical memory. (2 MiB)
+const MOCK_MEMORY_ALIGN: usize = 0x20_0000;
+
+/// Physical frame allocator that manages mock physical memory.
+///
+/// Mock physical memory is allocated from the heap of the host and never freed.
+/// Host virtual address of it is used as host physical address, so page tables can be walked as is.
+pub static FRAME_ALLOCATOR: LazyLock<Mutex<FrameAllocator>> = LazyLock::new(|| {
+    let layout = Layout::from_size_align(MOCK_MEMORY_SIZE, MOCK_MEMORY_ALIGN).unwrap();
+    let start = HostPhysicalAddress(unsafe { alloc_zeroed(layout) } as usize);
+    assert!(start.raw() != 0, "failed to allocate mock physical memory");
+
+    let mut frame_allocator = FrameAllocator::new();
+    frame_allocator.add_region(start..start + MOCK_MEMORY_SIZE);
+    Mutex::new(frame_allocator)
+});
+
+/// Aligned page size memory block
+pub struct PageBlock;
+
+impl PageBlock {
+    /// Return aligned address of page size memory block.
+    ///
+    /// The block is allocated from mock physical memory and not initialized.
+    ///
+    /// # Panics
+    /// It will be panic if mock physical memory is exhausted.
+    pub fn alloc() -> HostPhysicalAddress {
+        FRAME_ALLOCATOR
+            .lock()
+            .alloc(1, PAGE_SIZE)
+            .expect("mock physical memory is exhausted")
+    }
+}
+
+/// Device model that is emulated by hikami.
+///
+/// `MmioRegistry` only stores device models, so the methods that depend on the other parts of hikami are omitted.
+pub trait EmulatedMmioDevice: Debug {}
+
+pub mod guest_access {
+    //! Mock of guest memory access by hypervisor virtual-machine load/store instructions.
+    //!
+    //! Guest memory of the tests is mapped to the same host physical address,
+    //! so guest physical address that is mapped by G-stage page table of `hgatp` is read as is.
+
+    use super::h_extension::csrs::hgatp;
+    use crate::memmap::{
+        page_table::g_stage, GuestPhysicalAddress, GuestVirtualAddress, HostPhysicalAddress,
+    };
+
+    /// Load guest-page fault
+    const LOAD_GUEST_PAGE_FAULT: usize = 21;
+
+    /// Fault of guest memory access.
+    #[derive(Debug, Copy, Clone)]
+    pub struct GuestAccessFault {
+        /// Exception code of the fault.
+        pub cause: usize,
+        /// Faulting address.
+        pub addr: GuestVirtualAddress,
+    }
+
+    /// Read a value from guest physical address.
+    pub fn read_guest_physical<T: Copy>(gpa: GuestPhysicalAddress) -> Result<T, GuestAccessFault> {
+        let root = HostPhysicalAddress(hgatp::read().ppn() << 12);
+        if g_stage::is_mapped(root, gpa) {
+            Ok(unsafe { (gpa.raw() as *const T).read_volatile() })
+        } else {
+            Err(GuestAccessFault {
+                cause: LOAD_GUEST_PAGE_FAULT,
+                addr: GuestVirtualAddress(gpa.raw()),
+            })
+        }
+    }
+}
+
+pub mod h_extension {
+    //! Mock of H extension.
+
+    pub mod csrs {
+        //! CSRs are emulated by thread local variables, so that tests can be run in parallel.
+
+        pub mod hgatp {
+            //! Hypervisor guest address translation and protection.
+
+            use std::cell::Cell;
+
+            std::thread_local! {
+                /// Value of hgatp.
+                static HGATP: Cell<usize> = const { Cell::new(0) };
+            }
+
+            /// Hypervisor guest address translation and protection.
+            pub struct Hgatp(usize);
+
+            impl Hgatp {
+                /// Return ppn.
+                pub fn ppn(&self) -> usize {
+                    self.0 & 0xfff_ffff_ffff // 44 bit
+                }
+
+                /// Return translation mode.
+                pub fn mode(&self) -> Mode {
+                    match (self.0 >> 60) & 0b1111 {
+                        0 => Mode::Bare,
+                        8 => Mode::Sv39x4,
+                        9 => Mode::Sv48x4,
+                        10 => Mode::Sv57x4,
+                        _ => unreachable!(),
+                    }
+                }
+
+                /// Return raw value.
+                pub fn bits(&self) -> usize {
+                    self.0
+                }
+            }
+
+            /// Translation mode in G-stage.
+            #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+            pub enum Mode {
+                Bare = 0,
+                Sv39x4 = 8,
+                Sv48x4 = 9,
+                Sv57x4 = 10,
+            }
+
+            /// Set Hgatp fields.
+            pub fn set(mode: Mode, vmid: usize, ppn: usize) {
+                HGATP.set(
+                    (0xF & (mode as usize)) << 60 | (0x3FFF & vmid) << 44 | 0x0FFF_FFFF_FFFF & ppn,
+                );
+            }
+
+            /// Read hgatp.
+            pub fn read() -> Hgatp {
+                Hgatp(HGATP.get())
+            }
+
+            /// All modes are supported as QEMU.
+            pub fn is_mode_supported(_mode: Mode) -> bool {
+                true
+            }
+        }
+
+        pub mod vsatp {
+            //! Virtual supervisor address translation and protection.
+
+            use std::cell::Cell;
+
+            std::thread_local! {
+                /// Value of vsatp.
+                static VSATP: Cell<usize> = const { Cell::new(0) };
+            }
+
+            /// Virtual supervisor address translation and protection.
+            pub struct Vsatp(usize);
+
+            impl Vsatp {
+                /// Return translation mode.
+                pub fn mode(&self) -> Mode {
+                    match (self.0 >> 60) & 0b1111 {
+                        0 => Mode::Bare,
+                        8 => Mode::Sv39,
+                        9 => Mode::Sv48,
+                        10 => Mode::Sv57,
+                        11 => Mode::Sv64,
+                        _ => unreachable!(),
+                    }
+                }
+
+                /// Return ppn.
+                pub fn ppn(&self) -> usize {
+                    self.0 & 0xfff_ffff_ffff // 44 bit
+                }
+            }
+
+            /// Translation mode in VS-stage.
+            #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+            pub enum Mode {
+                Bare = 0,
+                Sv39 = 8,
+                Sv48 = 9,
+                Sv57 = 10,
+                Sv64 = 11,
+            }
+
+            /// Read vsatp.
+            pub fn read() -> Vsatp {
+                Vsatp(VSATP.get())
+            }
+
+            /// Write vsatp.
+            pub fn write(bits: usize) {
+                VSATP.set(bits);
+            }
+        }
+
+        pub mod vsstatus {
+            //! Virtual supervisor status register.
+
+            use std::cell::Cell;
+
+            std::thread_local! {
+                /// Value of vsstatus.
+                static VSSTATUS: Cell<usize> = const { Cell::new(0) };
+            }
+
+            /// Virtual supervisor status register.
+            pub struct Vsstatus(usize);
+
+            impl Vsstatus {
+                /// Return raw value.
+                pub fn bits(&self) -> usize {
+                    self.0
+                }
+            }
+
+            /// Read vsstatus.
+            pub fn read() -> Vsstatus {
+                Vsstatus(VSSTATUS.get())
+            }
+
+            /// Write vsstatus.
+            pub fn write(bits: usize) {
+                VSSTATUS.set(bits);
+            }
+        }
+    }
+}
@@ -21,9 +21,19 @@ REGION_ALIAS("REGION_HEAP", RAM);
 REGION_ALIAS("REGION_STACK", L2_LIM);
 
 _stack_start = ORIGIN(L2_LIM) + LENGTH(L2_LIM);
+/* stack for each hart (= STACK_SIZE_PER_HART) */
+_hart_stack_size = 0x10000;
+/* = MAX_HART_NUM - 1 */
+_max_hart_id = 7;
 _hv_heap_size = 0x20000000;
 _m_stack_size = 0x200000;
 
+/* text, rodata and initial values of data are placed in FLASH */
+_start_image = ORIGIN(FLASH);
+_end_image = ORIGIN(FLASH) + LENGTH(FLASH);
+/* data and bss are placed from the origin of RAM (followed by heap) */
+_start_data = ORIGIN(RAM);
+
 /* defined section in hikami */
 SECTIONS
 {
@@ -39,18 +49,6 @@ SECTIONS
         . = ALIGN(4K);
     } > REGION_DATA
 
-    .guest_dtb : ALIGN(4K)
-    {
-        *(.guest_dtb);
-        . = ALIGN(4K);
-    } > REGION_DATA
-
-    .root_page_table : ALIGN(16K)
-    {
-        *(.root_page_table);
-        . = ALIGN(4K);
-    } > REGION_DATA
-
     .hv_heap (NOLOAD) : ALIGN(1024K) 
     {
         _start_heap = .;
@@ -1,32 +1,187 @@
 //! Devices data
 
+pub mod aplic;
 pub mod clint;
+pub mod imsic;
 mod initrd;
 pub mod iommu;
+mod mmio;
+mod mmio_registry;
 mod pci;
 pub mod plic;
 mod rtc;
+mod test_finisher;
 pub mod uart;
 mod virtio;
 
-use crate::memmap::page_table::PteFlag;
-use crate::memmap::{page_table, HostPhysicalAddress, MemoryMap};
+use crate::guest::device_tree::FdtBuilder;
+use crate::memmap::{page_table, GuestPhysicalAddress, HostPhysicalAddress, MemoryMap};
+use mmio::PTE_FLAGS_FOR_DEVICE;
+#[allow(clippy::module_name_repetitions)]
+pub use mmio::{DeviceEmulateError, MmioDevice};
+use mmio_registry::{EmulatedDevice, MmioRegistry};
+
+use alloc::boxed::Box;
 use alloc::vec::Vec;
+use core::fmt::Debug;
+use core::ops::Range;
 use fdt::Fdt;
 
-/// Page table for device
-const PTE_FLAGS_FOR_DEVICE: [PteFlag; 4] =
-    [PteFlag::Write, PteFlag::Read, PteFlag::User, PteFlag::Valid];
+/// Kind of devices that can be assigned to guest.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[allow(clippy::module_name_repetitions)]
+pub enum DeviceKind {
+    /// UART
+    Uart,
+    /// All virtio mmio devices
+    VirtIo,
+    /// initrd
+    Initrd,
+    /// PLIC
+    Plic,
+    /// CLINT
+    Clint,
+    /// RTC
+    Rtc,
+    /// PCI (including memory regions of PCI devices)
+    Pci,
+    /// APLIC (supervisor-level domain)
+    Aplic,
+    /// IMSIC (guest interrupt files)
+    Imsic,
+    /// Test finisher of QEMU
+    TestFinisher,
+}
+
+impl DeviceKind {
+    /// All kinds of devices.
+    pub const ALL: [DeviceKind; 10] = [
+        DeviceKind::Uart,
+        DeviceKind::VirtIo,
+        DeviceKind::Initrd,
+        DeviceKind::Plic,
+        DeviceKind::Clint,
+        DeviceKind::Rtc,
+        DeviceKind::Pci,
+        DeviceKind::Aplic,
+        DeviceKind::Imsic,
+        DeviceKind::TestFinisher,
+    ];
+
+    /// Convert device name in device tree to `DeviceKind`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "uart" => Some(DeviceKind::Uart),
+            "virtio" => Some(DeviceKind::VirtIo),
+            "initrd" => Some(DeviceKind::Initrd),
+            "plic" => Some(DeviceKind::Plic),
+            "clint" => Some(DeviceKind::Clint),
+            "rtc" => Some(DeviceKind::Rtc),
+            "pci" => Some(DeviceKind::Pci),
+            "aplic" => Some(DeviceKind::Aplic),
+            "imsic" => Some(DeviceKind::Imsic),
+            "test" => Some(DeviceKind::TestFinisher),
+            _ => None,
+        }
+    }
+
+    /// Whether the device can be assigned to only one guest.
+    ///
+    /// They are passed through to the guest by identity map, or emulated only for the guest. (test finisher)
+    /// The others are emulated for each guest (UART, PLIC and APLIC) or assigned for each vCPU (IMSIC) by hikami.
+    pub fn is_exclusive(self) -> bool {
+        !matches!(
+            self,
+            DeviceKind::Uart | DeviceKind::Plic | DeviceKind::Aplic | DeviceKind::Imsic
+        )
+    }
+}
+
+/// Width of MMIO access.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AccessWidth {
+    /// 8 bit
+    Byte,
+    /// 16 bit
+    Half,
+    /// 32 bit
+    Word,
+    /// 64 bit
+    Double,
+}
+
+impl AccessWidth {
+    /// Return width in bytes.
+    pub fn bytes(self) -> usize {
+        match self {
+            AccessWidth::Byte => 1,
+            AccessWidth::Half => 2,
+            AccessWidth::Word => 4,
+            AccessWidth::Double => 8,
+        }
+    }
+
+    /// Clear the bits above the width.
+    pub fn zero_extend(self, value: u64) -> u64 {
+        match self {
+            AccessWidth::Double => value,
+            _ => value & ((1 << (self.bytes() * 8)) - 1),
+        }
+    }
 
-/// Device emulation error.
+    /// Fill the bits above the width with the sign bit.
+    #[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+    pub fn sign_extend(self, value: u64) -> u64 {
+        let shift = 64 - self.bytes() * 8;
+        (((value << shift) as i64) >> shift) as u64
+    }
+}
+
+/// Device model that is emulated by hikami.
+///
+/// It claims guest physical address ranges by `Devices::register_emulated_device`
+/// and receives decoded loads/stores to the ranges.
 #[allow(clippy::module_name_repetitions)]
-pub enum DeviceEmulateError {
-    /// Invalid plic address.
-    InvalidAddress,
-    /// Context ID is out of range.
-    InvalidContextId,
-    /// Accessed register is reserved.
-    ReservedRegister,
+pub trait EmulatedMmioDevice: Debug {
+    /// Emulate reading register.
+    /// * `guest_id` - guest that accesses the device
+    /// * `offset` - offset from the start of the claimed range
+    /// * `width` - access width
+    ///
+    /// Return value is zero-extended. Loads are sign-extended by the caller if needed.
+    fn read(
+        &mut self,
+        guest_id: usize,
+        offset: usize,
+        width: AccessWidth,
+    ) -> Result<u64, DeviceEmulateError>;
+
+    /// Emulate writing register.
+    /// * `guest_id` - guest that accesses the device
+    /// * `offset` - offset from the start of the claimed range
+    /// * `width` - access width
+    /// * `value` - written value. The bits above the width are cleared.
+    fn write(
+        &mut self,
+        guest_id: usize,
+        offset: usize,
+        width: AccessWidth,
+        value: u64,
+    ) -> Result<(), DeviceEmulateError>;
+
+    /// Write the node of the device to guest device tree.
+    /// * `guest_id` - guest whose device tree is generated
+    /// * `builder` - builder that is in `/soc` node
+    /// * `range` - guest physical address range claimed by the device
+    ///
+    /// Nothing is written by default. (the device is not discoverable by the guest)
+    fn write_device_tree_node(
+        &self,
+        _guest_id: usize,
+        _builder: &mut FdtBuilder,
+        _range: &Range<GuestPhysicalAddress>,
+    ) {
+    }
 }
 
 /// Pci device.
@@ -46,23 +201,6 @@ pub trait PciDevice {
     fn init(&self, pci: &pci::Pci);
 }
 
-/// Memory mapped I/O device.
-///
-/// A struct that implement this trait **must** has `base_addr` and size member.
-#[allow(clippy::module_name_repetitions)]
-pub trait MmioDevice {
-    /// Create self instance.
-    /// * `device_tree` - struct Fdt
-    /// * `node_path` - node path in fdt
-    fn new(device_tree: &Fdt, node_path: &str) -> Self;
-    /// Return size of memory region.
-    fn size(&self) -> usize;
-    /// Return address of physical memory
-    fn paddr(&self) -> HostPhysicalAddress;
-    /// Return memory map between physical to physical (identity map) for crate page table.
-    fn memmap(&self) -> MemoryMap;
-}
-
 /// Manage devices sush as uart, plic, etc...
 ///
 /// `memory_map` has memory region data of each devices.  
@@ -80,7 +218,13 @@ pub struct Devices {
     pub initrd: initrd::Initrd,
 
     /// PLIC: Platform-Level Interrupt Controller  
-    pub plic: plic::Plic,
+    pub plic: Option<plic::Plic>,
+
+    /// APLIC: Advanced Platform-Level Interrupt Controller
+    pub aplic: Option<aplic::Aplic>,
+
+    /// IMSIC: Incoming Message-Signaled Interrupt Controller
+    pub imsic: Option<imsic::Imsic>,
 
     /// clint: Core Local INTerrupt
     pub clint: clint::Clint,
@@ -93,56 +237,257 @@ pub struct Devices {
 
     /// IOMMU: I/O memory management unit.
     pub iommu: Option<iommu::IoMmu>,
+
+    /// Test finisher (QEMU only)
+    pub test_finisher: Option<test_finisher::TestFinisher>,
+
+    /// Guest physical address ranges of emulated devices.
+    mmio_registry: MmioRegistry,
 }
 
 impl Devices {
     /// Constructor for `Devices`.
     pub fn new(device_tree: Fdt) -> Self {
-        Devices {
+        let mut devices = Devices {
             uart: uart::Uart::new(&device_tree, "/soc/serial"),
             virtio_list: virtio::VirtIoList::new(&device_tree, "/soc/virtio_mmio"),
             initrd: initrd::Initrd::new(&device_tree, "/chosen"),
-            plic: plic::Plic::new(&device_tree, "/soc/plic"),
+            plic: device_tree
+                .find_node("/soc/plic")
+                .map(|_| plic::Plic::new(&device_tree, "/soc/plic")),
+            aplic: aplic::supervisor_domain_path(&device_tree)
+                .map(|path| aplic::Aplic::new(&device_tree, &path)),
+            imsic: imsic::supervisor_level_path(&device_tree)
+                .map(|path| imsic::Imsic::new(&device_tree, &path)),
             clint: clint::Clint::new(&device_tree, "/soc/clint"),
             rtc: rtc::Rtc::new(&device_tree, "/soc/rtc"),
             pci: pci::Pci::new(&device_tree, "/soc/pci"),
             iommu: iommu::IoMmu::new(&device_tree, "/soc/pci/iommu"),
+            test_finisher: device_tree
+                .find_node("/soc/test")
+                .map(|_| test_finisher::TestFinisher::new(&device_tree, "/soc/test")),
+            mmio_registry: MmioRegistry::new(),
+        };
+
+        // all registers of them are emulated at the same address as the physical device.
+        let uart_range = identity_range(&devices.uart);
+        devices
+            .mmio_registry
+            .register(uart_range, EmulatedDevice::Uart);
+        if let Some(range) = devices.plic.as_ref().map(identity_range) {
+            devices.mmio_registry.register(range, EmulatedDevice::Plic);
+        }
+        if let Some(range) = devices.aplic.as_ref().map(identity_range) {
+            devices.mmio_registry.register(range, EmulatedDevice::Aplic);
+        }
+
+        devices
+    }
+
+    /// Register the device model that handles accesses to the guest physical address range.
+    ///
+    /// # Panics
+    /// It will be panic if the range overlaps with other emulated devices.
+    pub fn register_emulated_device(
+        &mut self,
+        range: Range<GuestPhysicalAddress>,
+        device: Box<dyn EmulatedMmioDevice>,
+    ) {
+        self.mmio_registry
+            .register(range, EmulatedDevice::Model(device));
+    }
+
+    /// Emulate the test finisher for the guest.
+    ///
+    /// # Panics
+    /// It will be panic if the test finisher is already registered for another guest.
+    pub fn register_test_finisher(&mut self, guest_id: usize) {
+        if let Some(test_finisher) = &self.test_finisher {
+            let range = identity_range(test_finisher);
+            self.register_emulated_device(range, Box::new(test_finisher.virtual_device(guest_id)));
+        }
+    }
+
+    /// Write nodes of the device models registered by `register_emulated_device` to guest device tree.
+    ///
+    /// Devices that are passed through to the guest (UART, PLIC, APLIC) are copied from host device tree instead.
+    pub fn write_emulated_device_nodes(&self, guest_id: usize, builder: &mut FdtBuilder) {
+        for (range, device) in self.mmio_registry.models() {
+            device.write_device_tree_node(guest_id, builder, range);
         }
     }
 
     /// Initialization of IOMMU.
-    pub fn init_iommu(&mut self) {
-        if let Some(iommu) = &self.iommu {
+    ///
+    /// MSIs from PCI devices are delivered to the guest interrupt files of the guest if IMSIC exists.
+    pub fn init_iommu(&mut self, guest_id: usize) {
+        if let Some(iommu) = &mut self.iommu {
+            if let Some(imsic) = &self.imsic {
+                iommu.set_msi_page_table(imsic, guest_id);
+            }
             iommu.init(&self.pci);
         }
     }
 
-    /// Identity map for devices.
-    pub fn device_mapping_g_stage(&self, page_table_start: HostPhysicalAddress) {
-        let memory_map = self.create_device_map();
-        page_table::sv39x4::generate_page_table(page_table_start, &memory_map);
+    /// Initialization of console.
+    ///
+    /// hikami handles the receive interrupt of UART on the hart.
+    pub fn init_console(&mut self, hart_id: usize) {
+        self.uart.init();
+        if let (Some(plic), Some(irq)) = (&mut self.plic, self.uart.irq()) {
+            plic.register_hypervisor_irq(irq, hart_id);
+        }
+    }
+
+    /// Create emulated UART for the guest.
+    pub fn register_console(&mut self, guest_id: usize) {
+        self.uart.register_guest(guest_id);
+        if let (Some(plic), Some(irq)) = (&mut self.plic, self.uart.irq()) {
+            plic.register_virtual_irq(guest_id, irq);
+        }
+    }
+
+    /// Handle physical external interrupt of the hart.
+    ///
+    /// IRQs of guests are made pending on their virtual PLIC, and the others are handled by hikami.
+    pub fn handle_external_interrupt(&mut self, hart_id: usize) {
+        let Some(plic) = &mut self.plic else {
+            return;
+        };
+        let Some(irq) = plic.handle_external_interrupt(hart_id) else {
+            return;
+        };
+
+        if Some(irq) == self.uart.irq() {
+            if let Some(guest_id) = self.uart.handle_input() {
+                plic.set_virtual_irq_line(guest_id, irq, self.uart.interrupt_line(guest_id));
+            }
+        }
+        plic.complete_hypervisor_irq(irq, hart_id);
+    }
+
+    /// Update external interrupt of the guest running on the hart.
+    ///
+    /// It must be called after the state of emulated devices or virtual PLIC is changed.
+    pub fn update_external_interrupt(&mut self, guest_id: usize, hart_id: usize) {
+        if let Some(plic) = &mut self.plic {
+            if let Some(irq) = self.uart.irq() {
+                plic.set_virtual_irq_line(guest_id, irq, self.uart.interrupt_line(guest_id));
+            }
+            plic.update_vs_external_interrupt(guest_id, hart_id);
+        }
+    }
+
+    /// Return the emulated device that claims the address and offset in it.
+    fn emulated_device(
+        &mut self,
+        addr: GuestPhysicalAddress,
+    ) -> Result<(&mut dyn EmulatedMmioDevice, usize), DeviceEmulateError> {
+        let (device, offset) = self
+            .mmio_registry
+            .find_mut(addr)
+            .ok_or(DeviceEmulateError::InvalidAddress)?;
+        let device: &mut dyn EmulatedMmioDevice = match device {
+            EmulatedDevice::Uart => &mut self.uart,
+            EmulatedDevice::Plic => self
+                .plic
+                .as_mut()
+                .ok_or(DeviceEmulateError::InvalidAddress)?,
+            EmulatedDevice::Aplic => self
+                .aplic
+                .as_mut()
+                .ok_or(DeviceEmulateError::InvalidAddress)?,
+            EmulatedDevice::Model(model) => model.as_mut(),
+        };
+
+        Ok((device, offset))
+    }
+
+    /// Emulate reading register of devices that are emulated by hikami.
+    ///
+    /// Return value is zero-extended.
+    pub fn emulate_read(
+        &mut self,
+        guest_id: usize,
+        hart_id: usize,
+        addr: GuestPhysicalAddress,
+        width: AccessWidth,
+    ) -> Result<u64, DeviceEmulateError> {
+        let (device, offset) = self.emulated_device(addr)?;
+        let value = width.zero_extend(device.read(guest_id, offset, width)?);
+
+        // e.g. reading RBR or claiming may change the external interrupt.
+        self.update_external_interrupt(guest_id, hart_id);
+        Ok(value)
+    }
+
+    /// Emulate writing register of devices that are emulated by hikami.
+    pub fn emulate_write(
+        &mut self,
+        guest_id: usize,
+        hart_id: usize,
+        addr: GuestPhysicalAddress,
+        width: AccessWidth,
+        value: u64,
+    ) -> Result<(), DeviceEmulateError> {
+        let (device, offset) = self.emulated_device(addr)?;
+        device.write(guest_id, offset, width, width.zero_extend(value))?;
+
+        // e.g. enable, threshold or completion may change the external interrupt.
+        self.update_external_interrupt(guest_id, hart_id);
+        Ok(())
+    }
+
+    /// Initialization of APLIC.
+    pub fn init_aplic(&self) {
+        if let (Some(aplic), Some(imsic)) = (&self.aplic, &self.imsic) {
+            aplic.init(imsic);
+        }
+    }
+
+    /// Identity map for devices that are assigned to the guest.
+    pub fn device_mapping_g_stage(
+        &self,
+        page_table_start: HostPhysicalAddress,
+        assigned_devices: &[DeviceKind],
+    ) {
+        let memory_map = self.create_device_map(assigned_devices);
+        page_table::g_stage::generate_page_table(page_table_start, &memory_map);
     }
 
     /// Return devices range to crate identity map.  
-    /// It does not return `Plic` address to emulate it.
-    fn create_device_map(&self) -> Vec<MemoryMap> {
-        let mut device_mapping: Vec<MemoryMap> = self
-            .virtio_list
-            .iter()
-            .flat_map(|virt| [virt.memmap()])
-            .collect();
-
-        device_mapping.extend_from_slice(&[
-            self.uart.memmap(),
-            self.initrd.memmap(),
-            self.plic.memmap(),
-            self.clint.memmap(),
-            self.pci.memmap(),
-            self.rtc.memmap(),
-        ]);
-
-        device_mapping.extend_from_slice(self.pci.pci_memory_maps());
+    /// It does not return `Uart`, `Plic`, `Aplic` and `TestFinisher` address to emulate them.
+    fn create_device_map(&self, assigned_devices: &[DeviceKind]) -> Vec<MemoryMap> {
+        let mut device_mapping: Vec<MemoryMap> = Vec::new();
+
+        for device in assigned_devices {
+            match device {
+                DeviceKind::VirtIo => {
+                    device_mapping.extend(self.virtio_list.iter().map(MmioDevice::memmap));
+                }
+                DeviceKind::Initrd => device_mapping.push(self.initrd.memmap()),
+                // all registers of UART, PLIC, APLIC and test finisher are emulated.
+                // guest interrupt files of IMSIC are mapped for each vCPU. (see `Imsic::guest_file_memmap`)
+                DeviceKind::Uart
+                | DeviceKind::Plic
+                | DeviceKind::Aplic
+                | DeviceKind::Imsic
+                | DeviceKind::TestFinisher => (),
+                DeviceKind::Clint => device_mapping.push(self.clint.memmap()),
+                DeviceKind::Rtc => device_mapping.push(self.rtc.memmap()),
+                DeviceKind::Pci => {
+                    device_mapping.push(self.pci.memmap());
+                    device_mapping.extend_from_slice(self.pci.pci_memory_maps());
+                }
+            }
+        }
 
         device_mapping
     }
 }
+
+/// Guest physical address range that is the same as the physical device.
+fn identity_range<T: MmioDevice>(device: &T) -> Range<GuestPhysicalAddress> {
+    let start = GuestPhysicalAddress(device.paddr().raw());
+    start..start + device.size()
+}
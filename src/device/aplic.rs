@@ -0,0 +1,281 @@
+//! APLIC: Advanced Platform-Level Interrupt Controller
+//! Ref: [https://github.com/riscv/riscv-aia/releases/download/1.0/riscv-interrupts-1.0.pdf](https://github.com/riscv/riscv-aia/releases/download/1.0/riscv-interrupts-1.0.pdf)
+//!
+//! hikami uses APLIC in MSI delivery mode, so wired interrupts are forwarded to IMSIC as MSIs.
+//!
+//! - Machine-level domain delegates all interrupt sources to supervisor-level domain. (`Aplic::init`)
+//! - Supervisor-level domain is virtualized for each guest. (`AplicState`)
+//!   Each interrupt source is assigned to at most one guest and the guest can not see the other sources.
+//! - Registers of assigned sources are passed through to physical APLIC, except that the guest index of
+//!   `target` is replaced with the guest interrupt file of the guest on the target hart.
+//!   Thus MSIs are delivered to the guest interrupt file directly without hikami.
+//! - `domaincfg.IE` is emulated only in virtual APLIC. Delivery mode is always MSI. (`domaincfg.DM` is read-only)
+
+mod state;
+
+use super::imsic::{GuestInterruptFile, Imsic};
+use super::{
+    AccessWidth, DeviceEmulateError, EmulatedMmioDevice, MmioDevice, PTE_FLAGS_FOR_DEVICE,
+};
+use crate::memmap::{GuestPhysicalAddress, HostPhysicalAddress, MemoryMap};
+use state::{
+    AplicState, PhysicalWrite, RegisterRead, DOMAINCFG, DOMAINCFG_DM, DOMAINCFG_IE, MAX_SOURCE_NUM,
+    MMSIADDRCFG, MMSIADDRCFGH, SMSIADDRCFG, SMSIADDRCFGH, SOURCECFG_BASE, SOURCECFG_D,
+};
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use fdt::{node::FdtNode, Fdt};
+
+/// Guest Index field of `target`.
+const TARGET_GUEST_INDEX_SHIFT: u32 = 12;
+
+/// Return the node of supervisor-level APLIC domain.
+///
+/// Machine-level domain has `riscv,children` property.
+pub fn find_supervisor_domain<'b, 'a>(device_tree: &'b Fdt<'a>) -> Option<FdtNode<'b, 'a>> {
+    device_tree
+        .all_nodes()
+        .find(|node| is_aplic(*node) && node.property("riscv,children").is_none())
+}
+
+/// Return node path of supervisor-level APLIC domain.
+pub fn supervisor_domain_path(device_tree: &Fdt) -> Option<String> {
+    find_supervisor_domain(device_tree).map(|node| format!("/soc/{}", node.name))
+}
+
+/// Whether the node is APLIC domain.
+fn is_aplic(node: FdtNode) -> bool {
+    node.compatible()
+        .is_some_and(|compatible| compatible.all().any(|c| c == "riscv,aplic"))
+}
+
+/// APLIC: Advanced Platform-Level Interrupt Controller
+#[derive(Debug)]
+pub struct Aplic {
+    /// Base address of supervisor-level domain.
+    base_addr: HostPhysicalAddress,
+    /// Memory map size.
+    size: usize,
+    /// Base address of machine-level domain.
+    machine_base_addr: Option<HostPhysicalAddress>,
+    /// Number of interrupt sources. (`riscv,num-sources`)
+    source_num: usize,
+    /// State of virtual APLICs.
+    state: AplicState,
+    /// Guest interrupt files of all guests.
+    guest_files: Vec<GuestInterruptFile>,
+}
+
+impl Aplic {
+    /// Configure physical APLIC domains to forward all interrupts to IMSIC.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn init(&self, imsic: &Imsic) {
+        /// Low Hart Index Shift field of `*msiaddrcfgh`.
+        const LHXS_SHIFT: usize = 20;
+        /// Low Hart Index Width field of `mmsiaddrcfgh`.
+        const LHXW_SHIFT: usize = 12;
+
+        let supervisor_file_ppn = imsic.paddr().raw() >> 12;
+
+        if let Some(machine_base_addr) = self.machine_base_addr {
+            let write = |offset: usize, value: usize| unsafe {
+                physical_reg(machine_base_addr, offset).write_volatile(value as u32);
+            };
+
+            // delegate all sources to supervisor-level domain. (child index 0)
+            for source in 1..=self.source_num {
+                write(SOURCECFG_BASE + (source - 1) * 4, SOURCECFG_D as usize);
+            }
+            // machine-level interrupt files are not used. (all sources are delegated)
+            write(MMSIADDRCFG, 0);
+            write(MMSIADDRCFGH, imsic.hart_index_bits() << LHXW_SHIFT);
+            write(SMSIADDRCFG, supervisor_file_ppn & 0xffff_ffff);
+            write(
+                SMSIADDRCFGH,
+                imsic.guest_index_bits() << LHXS_SHIFT | supervisor_file_ppn >> 32 & 0xfff,
+            );
+            write(DOMAINCFG, (DOMAINCFG_IE | DOMAINCFG_DM) as usize);
+        }
+
+        unsafe {
+            self.physical_reg(DOMAINCFG)
+                .write_volatile(DOMAINCFG_IE | DOMAINCFG_DM);
+        }
+    }
+
+    /// Create virtual APLIC domain for the guest with assigned sources.
+    ///
+    /// # Panics
+    /// It will be panic if the source is invalid or assigned to other guest.
+    pub fn register_guest(
+        &mut self,
+        guest_id: usize,
+        sources: &[usize],
+        guest_files: &[GuestInterruptFile],
+    ) {
+        for source in sources {
+            assert!(
+                (1..=self.source_num).contains(source),
+                "invalid interrupt source: {source}"
+            );
+        }
+
+        self.state.register_guest(guest_id, sources);
+        self.guest_files.extend_from_slice(guest_files);
+    }
+
+    /// Return pointer to the physical register of supervisor-level domain.
+    fn physical_reg(&self, offset: usize) -> *mut u32 {
+        physical_reg(self.base_addr, offset)
+    }
+
+    /// Return guest interrupt file of the guest on the hart.
+    fn guest_file(&self, guest_id: usize, hart_id: usize) -> Option<&GuestInterruptFile> {
+        self.guest_files
+            .iter()
+            .find(|file| file.guest_id == guest_id && file.hart_id == hart_id)
+    }
+
+    /// Emulate reading APLIC register.
+    fn read_register(&mut self, guest_id: usize, offset: usize) -> Result<u32, DeviceEmulateError> {
+        match self.state.read(guest_id, offset)? {
+            RegisterRead::Virtual(value) => Ok(value),
+            RegisterRead::Physical { offset, mask } => {
+                Ok(unsafe { self.physical_reg(offset).read_volatile() } & mask)
+            }
+        }
+    }
+
+    /// Emulate writing APLIC register.
+    fn write_register(
+        &mut self,
+        guest_id: usize,
+        offset: usize,
+        value: u32,
+    ) -> Result<(), DeviceEmulateError> {
+        match self.state.write(guest_id, offset, value)? {
+            None => (),
+            Some(PhysicalWrite::Register { offset, value }) => unsafe {
+                self.physical_reg(offset).write_volatile(value);
+            },
+            Some(PhysicalWrite::Target {
+                offset,
+                target,
+                hart_id,
+            }) => {
+                // the interrupt is not delivered if the guest does not run on the hart.
+                if let Some(file) = self.guest_file(guest_id, hart_id) {
+                    #[allow(clippy::cast_possible_truncation)]
+                    let target = target | (file.vgein as u32) << TARGET_GUEST_INDEX_SHIFT;
+                    unsafe {
+                        self.physical_reg(offset).write_volatile(target);
+                    }
+                }
+            }
+            Some(PhysicalWrite::Msi { hart_id, eiid }) => {
+                // send MSI to `seteipnum_le` of the guest interrupt file.
+                if let Some(file) = self.guest_file(guest_id, hart_id) {
+                    unsafe {
+                        physical_reg(file.addr, 0).write_volatile(eiid);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// All registers are 32 bit wide and must be accessed by naturally aligned 32 bit accesses.
+impl EmulatedMmioDevice for Aplic {
+    fn read(
+        &mut self,
+        guest_id: usize,
+        offset: usize,
+        width: AccessWidth,
+    ) -> Result<u64, DeviceEmulateError> {
+        if width != AccessWidth::Word || offset % 4 != 0 {
+            return Err(DeviceEmulateError::InvalidAccessWidth);
+        }
+        self.read_register(guest_id, offset).map(u64::from)
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn write(
+        &mut self,
+        guest_id: usize,
+        offset: usize,
+        width: AccessWidth,
+        value: u64,
+    ) -> Result<(), DeviceEmulateError> {
+        if width != AccessWidth::Word || offset % 4 != 0 {
+            return Err(DeviceEmulateError::InvalidAccessWidth);
+        }
+        self.write_register(guest_id, offset, value as u32)
+    }
+}
+
+/// Return pointer to the physical APLIC register.
+#[allow(clippy::cast_ptr_alignment)]
+fn physical_reg(base_addr: HostPhysicalAddress, offset: usize) -> *mut u32 {
+    (base_addr + offset).raw() as *mut u32
+}
+
+impl MmioDevice for Aplic {
+    fn new(device_tree: &Fdt, node_path: &str) -> Self {
+        let node = device_tree.find_node(node_path).unwrap();
+        let region = node.reg().unwrap().next().unwrap();
+        let source_num = node
+            .property("riscv,num-sources")
+            .and_then(fdt::node::NodeProperty::as_usize)
+            .unwrap();
+        assert!(source_num < MAX_SOURCE_NUM);
+
+        // machine-level domain has supervisor-level domain as its child.
+        let machine_base_addr = device_tree
+            .all_nodes()
+            .filter(|parent| is_aplic(*parent))
+            .find(|parent| {
+                parent.property("riscv,children").is_some_and(|children| {
+                    children.value.chunks(4).any(|phandle| {
+                        device_tree
+                            .find_phandle(u32::from_be_bytes(phandle.try_into().unwrap()))
+                            .is_some_and(|child| child.name == node.name)
+                    })
+                })
+            })
+            .and_then(|parent| parent.reg()?.next())
+            .map(|region| HostPhysicalAddress(region.starting_address as usize));
+
+        Aplic {
+            base_addr: HostPhysicalAddress(region.starting_address as usize),
+            size: region.size.unwrap(),
+            machine_base_addr,
+            source_num,
+            state: AplicState::new(),
+            guest_files: Vec::new(),
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn paddr(&self) -> HostPhysicalAddress {
+        self.base_addr
+    }
+
+    /// Whole region of supervisor-level domain.
+    ///
+    /// It is not mapped to guests because all registers are emulated.
+    fn memmap(&self) -> MemoryMap {
+        let vaddr = GuestPhysicalAddress(self.paddr().raw());
+        MemoryMap::new(
+            vaddr..vaddr + self.size(),
+            self.paddr()..self.paddr() + self.size(),
+            &PTE_FLAGS_FOR_DEVICE,
+        )
+    }
+}
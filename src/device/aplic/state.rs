@@ -0,0 +1,518 @@
+//! Hardware-independent register emulation of virtual APLIC domains.
+//!
+//! It takes register offsets from the base of supervisor-level domain and returns
+//! how to read the value and the accesses that must be reflected to physical APLIC or IMSIC.
+//! It does not touch any hardware, so it can be tested on host.
+
+use crate::device::DeviceEmulateError;
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Max number of interrupt sources. (including source 0)
+pub const MAX_SOURCE_NUM: usize = 1024;
+/// Number of 32 bit words for bitmap of interrupt sources.
+const SOURCE_WORDS: usize = MAX_SOURCE_NUM / 32;
+
+/// Domain configuration register.
+pub const DOMAINCFG: usize = 0x0;
+/// Base offset of source configuration registers. (for source 1)
+pub const SOURCECFG_BASE: usize = 0x4;
+/// End of source configuration registers. (exclusive)
+const SOURCECFG_END: usize = 0x1000;
+/// MSI address configuration registers of machine-level domain.
+pub const MMSIADDRCFG: usize = 0x1bc0;
+/// MSI address configuration registers of machine-level domain. (upper half)
+pub const MMSIADDRCFGH: usize = 0x1bc4;
+/// MSI address configuration registers of supervisor-level domain.
+pub const SMSIADDRCFG: usize = 0x1bc8;
+/// MSI address configuration registers of supervisor-level domain. (upper half)
+pub const SMSIADDRCFGH: usize = 0x1bcc;
+/// Base offset of set interrupt-pending bits.
+const SETIP_BASE: usize = 0x1c00;
+/// Set interrupt-pending bit by number.
+const SETIPNUM: usize = 0x1cdc;
+/// Base offset of rectified inputs / clear interrupt-pending bits.
+const IN_CLRIP_BASE: usize = 0x1d00;
+/// Clear interrupt-pending bit by number.
+const CLRIPNUM: usize = 0x1ddc;
+/// Base offset of set interrupt-enable bits.
+const SETIE_BASE: usize = 0x1e00;
+/// Set interrupt-enable bit by number.
+const SETIENUM: usize = 0x1edc;
+/// Base offset of clear interrupt-enable bits.
+const CLRIE_BASE: usize = 0x1f00;
+/// Clear interrupt-enable bit by number.
+const CLRIENUM: usize = 0x1fdc;
+/// Set interrupt-pending bit by number, little-endian.
+const SETIPNUM_LE: usize = 0x2000;
+/// Set interrupt-pending bit by number, big-endian.
+const SETIPNUM_BE: usize = 0x2004;
+/// Generate MSI.
+const GENMSI: usize = 0x3000;
+/// Base offset of interrupt targets. (for source 1)
+const TARGET_BASE: usize = 0x3004;
+/// End of interrupt targets. (exclusive)
+const TARGET_END: usize = 0x4000;
+/// Size of bitmap registers. (`setip`, `in_clrip`, `setie` and `clrie`)
+const BITMAP_SIZE: usize = SOURCE_WORDS * 4;
+
+/// `domaincfg` bits that are read as 0x80.
+const DOMAINCFG_RESERVED: u32 = 0x80 << 24;
+/// Interrupt Enable field of `domaincfg`.
+pub const DOMAINCFG_IE: u32 = 1 << 8;
+/// Delivery Mode field of `domaincfg`. (1: MSI delivery mode)
+pub const DOMAINCFG_DM: u32 = 1 << 2;
+/// Delegate field of `sourcecfg`.
+pub const SOURCECFG_D: u32 = 1 << 10;
+/// Source Mode field of `sourcecfg`.
+const SOURCECFG_SM_MASK: u32 = 0b111;
+/// Hart Index field of `target` and `genmsi`.
+const TARGET_HART_INDEX_MASK: u32 = 0x3fff << 18;
+/// Shift of Hart Index field of `target` and `genmsi`.
+const TARGET_HART_INDEX_SHIFT: u32 = 18;
+/// External Interrupt Identity field of `target` and `genmsi`.
+const TARGET_EIID_MASK: u32 = 0x7ff;
+
+/// Return whether the bit of `source` is set in bitmap.
+fn test_bit(bitmap: &[u32; SOURCE_WORDS], source: usize) -> bool {
+    source < MAX_SOURCE_NUM && bitmap[source / 32] >> (source % 32) & 1 == 1
+}
+
+/// Return hart index of `target` or `genmsi`.
+fn hart_index(value: u32) -> usize {
+    ((value & TARGET_HART_INDEX_MASK) >> TARGET_HART_INDEX_SHIFT) as usize
+}
+
+/// How to read the register.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RegisterRead {
+    /// Value that is emulated by virtual APLIC.
+    Virtual(u32),
+    /// Value of the physical register of supervisor-level domain with the mask.
+    Physical {
+        /// Offset from the base of supervisor-level domain.
+        offset: usize,
+        /// Bits that are visible to the guest.
+        mask: u32,
+    },
+}
+
+/// Access to physical APLIC or IMSIC that is caused by register write.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PhysicalWrite {
+    /// Write the value to the register of supervisor-level domain.
+    Register {
+        /// Offset from the base of supervisor-level domain.
+        offset: usize,
+        /// Written value.
+        value: u32,
+    },
+    /// Write `target` register with guest index of the guest interrupt file on the hart.
+    ///
+    /// It must be skipped if the guest does not have the interrupt file on the hart.
+    Target {
+        /// Offset from the base of supervisor-level domain.
+        offset: usize,
+        /// Hart Index and EIID fields. (guest index is 0)
+        target: u32,
+        /// Hart ID of Hart Index field.
+        hart_id: usize,
+    },
+    /// Send MSI to the guest interrupt file of the guest on the hart.
+    Msi {
+        /// Destination hart.
+        hart_id: usize,
+        /// External interrupt identity.
+        eiid: u32,
+    },
+}
+
+/// APLIC domain state of a guest.
+#[derive(Debug)]
+struct VirtualAplic {
+    /// Guest ID of the owner.
+    guest_id: usize,
+    /// Sources that are assigned to the guest.
+    assigned: [u32; SOURCE_WORDS],
+    /// `domaincfg.IE`
+    interrupt_enable: bool,
+    /// `target` registers seen by the guest. (guest index is always 0)
+    target: Vec<u32>,
+    /// `genmsi` register seen by the guest.
+    genmsi: u32,
+}
+
+impl VirtualAplic {
+    /// Return whether the source is assigned to the guest.
+    fn is_assigned(&self, source: usize) -> bool {
+        source != 0 && test_bit(&self.assigned, source)
+    }
+}
+
+/// State of virtual APLIC domains of all guests.
+#[derive(Debug)]
+#[allow(clippy::module_name_repetitions)]
+pub struct AplicState {
+    /// Virtual APLIC of each guest.
+    virtual_aplics: Vec<VirtualAplic>,
+}
+
+impl AplicState {
+    /// Constructor for `AplicState`.
+    pub const fn new() -> Self {
+        AplicState {
+            virtual_aplics: Vec::new(),
+        }
+    }
+
+    /// Create virtual APLIC domain for the guest with assigned sources.
+    ///
+    /// # Panics
+    /// It will be panic if the guest is already registered or the source is assigned to other guest.
+    pub fn register_guest(&mut self, guest_id: usize, sources: &[usize]) {
+        assert!(
+            self.virtual_aplics.iter().all(|v| v.guest_id != guest_id),
+            "virtual APLIC of guest {guest_id} is already registered"
+        );
+
+        let mut assigned = [0; SOURCE_WORDS];
+        for source in sources {
+            assert!(
+                self.virtual_aplics
+                    .iter()
+                    .all(|other| !other.is_assigned(*source)),
+                "interrupt source {source} is assigned to multiple guests"
+            );
+            assigned[source / 32] |= 1 << (source % 32);
+        }
+
+        self.virtual_aplics.push(VirtualAplic {
+            guest_id,
+            assigned,
+            interrupt_enable: false,
+            target: vec![0; MAX_SOURCE_NUM],
+            genmsi: 0,
+        });
+    }
+
+    /// Return virtual APLIC of the guest.
+    fn virtual_aplic(&mut self, guest_id: usize) -> Result<&mut VirtualAplic, DeviceEmulateError> {
+        self.virtual_aplics
+            .iter_mut()
+            .find(|v| v.guest_id == guest_id)
+            .ok_or(DeviceEmulateError::InvalidAddress)
+    }
+
+    /// Emulate reading register.
+    pub fn read(
+        &mut self,
+        guest_id: usize,
+        offset: usize,
+    ) -> Result<RegisterRead, DeviceEmulateError> {
+        let vaplic = self.virtual_aplic(guest_id)?;
+
+        match offset {
+            DOMAINCFG => Ok(RegisterRead::Virtual(
+                DOMAINCFG_RESERVED
+                    | DOMAINCFG_DM
+                    | if vaplic.interrupt_enable {
+                        DOMAINCFG_IE
+                    } else {
+                        0
+                    },
+            )),
+            SOURCECFG_BASE..SOURCECFG_END => {
+                let source = (offset - SOURCECFG_BASE) / 4 + 1;
+                if vaplic.is_assigned(source) {
+                    Ok(RegisterRead::Physical {
+                        offset,
+                        mask: u32::MAX,
+                    })
+                } else {
+                    // inactive source.
+                    Ok(RegisterRead::Virtual(0))
+                }
+            }
+            SETIP_BASE..SETIPNUM | IN_CLRIP_BASE..CLRIPNUM | SETIE_BASE..SETIENUM
+                if offset % 0x100 < BITMAP_SIZE =>
+            {
+                let word = offset % 0x100 / 4;
+                Ok(RegisterRead::Physical {
+                    offset,
+                    mask: vaplic.assigned[word],
+                })
+            }
+            CLRIE_BASE..CLRIENUM if offset - CLRIE_BASE < BITMAP_SIZE => {
+                Ok(RegisterRead::Virtual(0))
+            }
+            SETIPNUM | CLRIPNUM | SETIENUM | CLRIENUM | SETIPNUM_LE | SETIPNUM_BE => {
+                Ok(RegisterRead::Virtual(0))
+            }
+            GENMSI => Ok(RegisterRead::Virtual(vaplic.genmsi)),
+            TARGET_BASE..TARGET_END => {
+                let source = (offset - TARGET_BASE) / 4 + 1;
+                if vaplic.is_assigned(source) {
+                    Ok(RegisterRead::Virtual(vaplic.target[source]))
+                } else {
+                    Ok(RegisterRead::Virtual(0))
+                }
+            }
+            // `*msiaddrcfg*` are implemented only in machine-level domain and IDCs are not used in MSI mode.
+            _ => Err(DeviceEmulateError::ReservedRegister),
+        }
+    }
+
+    /// Emulate writing register.
+    ///
+    /// Return the access that must be reflected to physical APLIC or IMSIC.
+    pub fn write(
+        &mut self,
+        guest_id: usize,
+        offset: usize,
+        value: u32,
+    ) -> Result<Option<PhysicalWrite>, DeviceEmulateError> {
+        let vaplic = self.virtual_aplic(guest_id)?;
+        let register = |value: u32| Some(PhysicalWrite::Register { offset, value });
+
+        match offset {
+            DOMAINCFG => {
+                vaplic.interrupt_enable = value & DOMAINCFG_IE != 0;
+                Ok(None)
+            }
+            SOURCECFG_BASE..SOURCECFG_END => {
+                let source = (offset - SOURCECFG_BASE) / 4 + 1;
+                if !vaplic.is_assigned(source) {
+                    Ok(None)
+                } else if value & SOURCECFG_D == 0 {
+                    Ok(register(value & SOURCECFG_SM_MASK))
+                } else {
+                    // the guest domain has no child domain.
+                    Ok(register(0))
+                }
+            }
+            SETIP_BASE..SETIPNUM
+            | IN_CLRIP_BASE..CLRIPNUM
+            | SETIE_BASE..SETIENUM
+            | CLRIE_BASE..CLRIENUM
+                if offset % 0x100 < BITMAP_SIZE =>
+            {
+                let word = offset % 0x100 / 4;
+                Ok(register(value & vaplic.assigned[word]))
+            }
+            SETIPNUM | CLRIPNUM | SETIENUM | CLRIENUM | SETIPNUM_LE => {
+                Ok(if vaplic.is_assigned(value as usize) {
+                    register(value)
+                } else {
+                    None
+                })
+            }
+            SETIPNUM_BE => Ok(if vaplic.is_assigned(value.swap_bytes() as usize) {
+                register(value)
+            } else {
+                None
+            }),
+            GENMSI => {
+                vaplic.genmsi = value & (TARGET_HART_INDEX_MASK | TARGET_EIID_MASK);
+                // sent to `seteipnum_le` of the guest interrupt file.
+                Ok(Some(PhysicalWrite::Msi {
+                    hart_id: hart_index(value),
+                    eiid: value & TARGET_EIID_MASK,
+                }))
+            }
+            TARGET_BASE..TARGET_END => {
+                let source = (offset - TARGET_BASE) / 4 + 1;
+                if !vaplic.is_assigned(source) {
+                    return Ok(None);
+                }
+                let target = value & (TARGET_HART_INDEX_MASK | TARGET_EIID_MASK);
+                vaplic.target[source] = target;
+                Ok(Some(PhysicalWrite::Target {
+                    offset,
+                    target,
+                    hart_id: hart_index(target),
+                }))
+            }
+            _ => Err(DeviceEmulateError::ReservedRegister),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Guest ID for tests.
+    const GUEST: usize = 1;
+    /// Other guest ID for tests.
+    const OTHER_GUEST: usize = 2;
+
+    /// Offset of `sourcecfg` of the source.
+    fn sourcecfg(source: usize) -> usize {
+        SOURCECFG_BASE + (source - 1) * 4
+    }
+
+    /// Offset of `target` of the source.
+    fn target(source: usize) -> usize {
+        TARGET_BASE + (source - 1) * 4
+    }
+
+    /// Guest 1 owns source 1-10 and 33, guest 2 owns source 11.
+    fn setup() -> AplicState {
+        let mut state = AplicState::new();
+        state.register_guest(GUEST, &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 33]);
+        state.register_guest(OTHER_GUEST, &[11]);
+        state
+    }
+
+    /// `domaincfg.IE` is emulated and DM is always set.
+    #[test]
+    fn domaincfg() {
+        let mut state = setup();
+        assert_eq!(
+            state.read(GUEST, DOMAINCFG),
+            Ok(RegisterRead::Virtual(DOMAINCFG_RESERVED | DOMAINCFG_DM))
+        );
+        assert_eq!(state.write(GUEST, DOMAINCFG, DOMAINCFG_IE), Ok(None));
+        assert_eq!(
+            state.read(GUEST, DOMAINCFG),
+            Ok(RegisterRead::Virtual(
+                DOMAINCFG_RESERVED | DOMAINCFG_DM | DOMAINCFG_IE
+            ))
+        );
+        // other guest is not affected.
+        assert_eq!(
+            state.read(OTHER_GUEST, DOMAINCFG),
+            Ok(RegisterRead::Virtual(DOMAINCFG_RESERVED | DOMAINCFG_DM))
+        );
+    }
+
+    /// `sourcecfg` of assigned sources are passed through without delegation.
+    #[test]
+    fn sourcecfg_of_assigned_source() {
+        let mut state = setup();
+        assert_eq!(
+            state.read(GUEST, sourcecfg(3)),
+            Ok(RegisterRead::Physical {
+                offset: sourcecfg(3),
+                mask: u32::MAX
+            })
+        );
+        assert_eq!(
+            state.write(GUEST, sourcecfg(3), 0x6),
+            Ok(Some(PhysicalWrite::Register {
+                offset: sourcecfg(3),
+                value: 0x6
+            }))
+        );
+        // delegation is ignored.
+        assert_eq!(
+            state.write(GUEST, sourcecfg(3), SOURCECFG_D | 1),
+            Ok(Some(PhysicalWrite::Register {
+                offset: sourcecfg(3),
+                value: 0
+            }))
+        );
+    }
+
+    /// Sources of other guests are inactive.
+    #[test]
+    fn sourcecfg_of_other_source() {
+        let mut state = setup();
+        assert_eq!(
+            state.read(GUEST, sourcecfg(11)),
+            Ok(RegisterRead::Virtual(0))
+        );
+        assert_eq!(state.write(GUEST, sourcecfg(11), 0x6), Ok(None));
+        assert_eq!(state.write(GUEST, target(11), 0x1), Ok(None));
+        assert_eq!(state.write(GUEST, SETIENUM, 11), Ok(None));
+        assert_eq!(
+            state.write(GUEST, SETIPNUM_BE, 11u32.swap_bytes()),
+            Ok(None)
+        );
+    }
+
+    /// Bitmap registers are masked by assigned sources.
+    #[test]
+    fn bitmap_is_masked() {
+        let mut state = setup();
+        assert_eq!(
+            state.read(GUEST, SETIE_BASE),
+            Ok(RegisterRead::Physical {
+                offset: SETIE_BASE,
+                mask: 0x7fe
+            })
+        );
+        assert_eq!(
+            state.write(GUEST, SETIP_BASE + 4, u32::MAX),
+            Ok(Some(PhysicalWrite::Register {
+                offset: SETIP_BASE + 4,
+                value: 1 << 1
+            }))
+        );
+        assert_eq!(
+            state.write(OTHER_GUEST, CLRIE_BASE, u32::MAX),
+            Ok(Some(PhysicalWrite::Register {
+                offset: CLRIE_BASE,
+                value: 1 << 11
+            }))
+        );
+        assert_eq!(state.read(GUEST, CLRIE_BASE), Ok(RegisterRead::Virtual(0)));
+    }
+
+    /// `target` keeps the value seen by the guest and is written with guest index.
+    #[test]
+    fn target_and_genmsi() {
+        let mut state = setup();
+        let value = 2 << TARGET_HART_INDEX_SHIFT | 0x3f << 12 | 5;
+        assert_eq!(
+            state.write(GUEST, target(33), value),
+            Ok(Some(PhysicalWrite::Target {
+                offset: target(33),
+                target: 2 << TARGET_HART_INDEX_SHIFT | 5,
+                hart_id: 2
+            }))
+        );
+        assert_eq!(
+            state.read(GUEST, target(33)),
+            Ok(RegisterRead::Virtual(2 << TARGET_HART_INDEX_SHIFT | 5))
+        );
+
+        assert_eq!(
+            state.write(GUEST, GENMSI, 1 << TARGET_HART_INDEX_SHIFT | 7),
+            Ok(Some(PhysicalWrite::Msi {
+                hart_id: 1,
+                eiid: 7
+            }))
+        );
+        assert_eq!(
+            state.read(GUEST, GENMSI),
+            Ok(RegisterRead::Virtual(1 << TARGET_HART_INDEX_SHIFT | 7))
+        );
+    }
+
+    /// MSI address configuration and unregistered guests are rejected.
+    #[test]
+    fn invalid_access() {
+        let mut state = setup();
+        assert_eq!(
+            state.read(GUEST, SMSIADDRCFG),
+            Err(DeviceEmulateError::ReservedRegister)
+        );
+        assert_eq!(
+            state.write(GUEST, MMSIADDRCFG, 0),
+            Err(DeviceEmulateError::ReservedRegister)
+        );
+        assert_eq!(
+            state.read(3, DOMAINCFG),
+            Err(DeviceEmulateError::InvalidAddress)
+        );
+    }
+
+    /// A source can not be assigned to two guests.
+    #[test]
+    #[should_panic(expected = "assigned to multiple guests")]
+    fn source_assigned_twice() {
+        setup().register_guest(3, &[11]);
+    }
+}
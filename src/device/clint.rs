@@ -74,8 +74,8 @@ impl rustsbi::Timer for Clint {
     fn set_timer(&self, stime_value: u64) {
         unsafe {
             let hart_id = riscv::register::mhartid::read();
-            assert_eq!(hart_id, 0);
-            let mtimecmp_ptr = (self.base_addr.raw() + register::MTIMECMP_OFFSET) as *mut u64;
+            let mtimecmp_ptr =
+                (self.base_addr.raw() + register::MTIMECMP_OFFSET + hart_id * 8) as *mut u64;
             mtimecmp_ptr.write_volatile(stime_value);
         }
     }
@@ -87,10 +87,9 @@ impl rustsbi::Ipi for Clint {
         for i in 0..constant::MAX_HART_NUM {
             // TODO check hsm wheter allow_ipi enabled.
             if hart_mask.has_bit(i) {
-                let msip_ptr = (self.base_addr.raw() + register::MSIP_OFFSET) as *mut u64;
+                let msip_ptr = (self.base_addr.raw() + register::MSIP_OFFSET + i * 4) as *mut u32;
                 unsafe {
-                    let msip_value = msip_ptr.read_volatile();
-                    msip_ptr.write_volatile(msip_value | i as u64);
+                    msip_ptr.write_volatile(1);
                 }
             }
         }
@@ -0,0 +1,189 @@
+//! IMSIC: Incoming Message-Signaled Interrupt Controller
+//! Ref: [https://github.com/riscv/riscv-aia/releases/download/1.0/riscv-interrupts-1.0.pdf](https://github.com/riscv/riscv-aia/releases/download/1.0/riscv-interrupts-1.0.pdf)
+//!
+//! Each hart has a supervisor-level interrupt file and `GEILEN` guest interrupt files.
+//! The interrupt files of a hart are placed in consecutive pages:
+//! `base + ((hart index << guest index bits) | guest index) * PAGE_SIZE` (guest index 0 is the supervisor-level file)
+//!
+//! hikami assigns a guest interrupt file to each vCPU and selects it by `hstatus.VGEIN` while the vCPU runs.
+//! The guest interrupt file is mapped to the address of the supervisor-level interrupt file in G-stage,
+//! so that the guest uses it as its own supervisor-level interrupt file without emulation.
+
+use super::{MmioDevice, PTE_FLAGS_FOR_DEVICE};
+use crate::h_extension::csrs::hgeie;
+use crate::memmap::{
+    constant::MAX_HART_NUM, page_table::constants::PAGE_SIZE, GuestPhysicalAddress,
+    HostPhysicalAddress, MemoryMap,
+};
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use fdt::{node::FdtNode, Fdt};
+
+/// Supervisor external interrupt number in `interrupts-extended`.
+const IRQ_S_EXT: u32 = 9;
+
+/// Return the node of supervisor-level IMSIC.
+///
+/// It is distinguished from machine-level one by the interrupt number of `interrupts-extended`.
+pub fn find_supervisor_level<'b, 'a>(device_tree: &'b Fdt<'a>) -> Option<FdtNode<'b, 'a>> {
+    device_tree.all_nodes().find(|node| {
+        node.compatible()
+            .is_some_and(|compatible| compatible.all().any(|c| c == "riscv,imsics"))
+            && node
+                .property("interrupts-extended")
+                .is_some_and(|interrupts| {
+                    // <phandle interrupt number> for each hart.
+                    interrupts.value.chunks(8).all(|cells| {
+                        u32::from_be_bytes(cells[4..8].try_into().unwrap()) == IRQ_S_EXT
+                    })
+                })
+    })
+}
+
+/// Return node path of supervisor-level IMSIC.
+pub fn supervisor_level_path(device_tree: &Fdt) -> Option<String> {
+    find_supervisor_level(device_tree).map(|node| format!("/soc/{}", node.name))
+}
+
+/// Guest interrupt file that is assigned to a vCPU.
+#[derive(Debug, Copy, Clone)]
+pub struct GuestInterruptFile {
+    /// Guest ID of the owner.
+    pub guest_id: usize,
+    /// HART id of the vCPU.
+    pub hart_id: usize,
+    /// Guest interrupt number. (= guest index of the interrupt file)
+    pub vgein: usize,
+    /// Address of the interrupt file.
+    pub addr: HostPhysicalAddress,
+}
+
+/// IMSIC: Incoming Message-Signaled Interrupt Controller
+#[derive(Debug)]
+pub struct Imsic {
+    /// Base address of supervisor-level interrupt files.
+    base_addr: HostPhysicalAddress,
+    /// Memory map size.
+    size: usize,
+    /// Number of bits of guest index. (`riscv,guest-index-bits`)
+    guest_index_bits: usize,
+    /// Number of bits of hart index. (`riscv,hart-index-bits`)
+    hart_index_bits: usize,
+    /// Number of guest interrupt files per hart that can be used.
+    guest_file_num: usize,
+    /// Guest interrupt files that are assigned to vCPUs.
+    guest_files: Vec<GuestInterruptFile>,
+}
+
+impl Imsic {
+    /// Return address of the interrupt file.
+    pub fn interrupt_file_addr(&self, hart_id: usize, guest_index: usize) -> HostPhysicalAddress {
+        self.base_addr + ((hart_id << self.guest_index_bits) | guest_index) * PAGE_SIZE
+    }
+
+    /// Return number of bits of guest index.
+    pub fn guest_index_bits(&self) -> usize {
+        self.guest_index_bits
+    }
+
+    /// Return number of bits of hart index.
+    pub fn hart_index_bits(&self) -> usize {
+        self.hart_index_bits
+    }
+
+    /// Assign a free guest interrupt file of the hart to the vCPU of the guest.
+    ///
+    /// # Panics
+    /// It will be panic if all guest interrupt files of the hart are already assigned.
+    pub fn assign_guest_file(&mut self, guest_id: usize, hart_id: usize) -> GuestInterruptFile {
+        let vgein = self
+            .guest_files
+            .iter()
+            .filter(|file| file.hart_id == hart_id)
+            .count()
+            + 1;
+        assert!(
+            vgein <= self.guest_file_num,
+            "no guest interrupt file is left on hart {hart_id}"
+        );
+
+        let file = GuestInterruptFile {
+            guest_id,
+            hart_id,
+            vgein,
+            addr: self.interrupt_file_addr(hart_id, vgein),
+        };
+        self.guest_files.push(file);
+        file
+    }
+
+    /// Return guest interrupt files that are assigned to the guest.
+    pub fn guest_files(&self, guest_id: usize) -> impl Iterator<Item = &GuestInterruptFile> {
+        self.guest_files
+            .iter()
+            .filter(move |file| file.guest_id == guest_id)
+    }
+
+    /// Memory map from the supervisor-level interrupt file in guest to the guest interrupt file.
+    pub fn guest_file_memmap(&self, file: &GuestInterruptFile) -> MemoryMap {
+        let vaddr = GuestPhysicalAddress(self.interrupt_file_addr(file.hart_id, 0).raw());
+        MemoryMap::new(
+            vaddr..vaddr + PAGE_SIZE,
+            file.addr..file.addr + PAGE_SIZE,
+            &PTE_FLAGS_FOR_DEVICE,
+        )
+    }
+}
+
+impl MmioDevice for Imsic {
+    fn new(device_tree: &Fdt, node_path: &str) -> Self {
+        let node = device_tree.find_node(node_path).unwrap();
+        let region = node.reg().unwrap().next().unwrap();
+        let read_u32_prop = |name: &str| {
+            node.property(name)
+                .map(|prop| u32::from_be_bytes(prop.value.try_into().unwrap()) as usize)
+        };
+
+        assert_eq!(
+            read_u32_prop("riscv,group-index-bits").unwrap_or(0),
+            0,
+            "multiple IMSIC groups are not supported"
+        );
+        let hart_num = node.property("interrupts-extended").unwrap().value.len() / 8;
+        assert!(hart_num <= MAX_HART_NUM);
+        let guest_index_bits = read_u32_prop("riscv,guest-index-bits").unwrap_or(0);
+        let hart_index_bits = read_u32_prop("riscv,hart-index-bits")
+            .unwrap_or(hart_num.next_power_of_two().trailing_zeros() as usize);
+
+        Imsic {
+            base_addr: HostPhysicalAddress(region.starting_address as usize),
+            size: region.size.unwrap(),
+            guest_index_bits,
+            hart_index_bits,
+            guest_file_num: hgeie::geilen().min((1 << guest_index_bits) - 1),
+            guest_files: Vec::new(),
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn paddr(&self) -> HostPhysicalAddress {
+        self.base_addr
+    }
+
+    /// Whole region of supervisor-level interrupt files.
+    ///
+    /// It is not mapped to guests. (see `guest_file_memmap`)
+    fn memmap(&self) -> MemoryMap {
+        let vaddr = GuestPhysicalAddress(self.paddr().raw());
+        MemoryMap::new(
+            vaddr..vaddr + self.size(),
+            self.paddr()..self.paddr() + self.size(),
+            &PTE_FLAGS_FOR_DEVICE,
+        )
+    }
+}
@@ -4,8 +4,9 @@
 mod register_map;
 
 use super::{
+    imsic::Imsic,
     pci::{ConfigSpaceRegister, Pci},
-    PciDevice,
+    MmioDevice, PciDevice,
 };
 use crate::h_extension::csrs::hgatp;
 use crate::memmap::{page_table::constants::PAGE_SIZE, HostPhysicalAddress};
@@ -23,13 +24,72 @@ pub struct IoMmu {
     device: u32,
     /// PCI Function number
     function: u32,
+    /// MSI page table that redirects MSIs to guest interrupt files.
+    msi_page_table: Option<MsiPageTable>,
+}
+
+/// MSI page table in flat mode.
+///
+/// The guest physical address that matches `pattern` except for the bits of `mask` is
+/// an address of interrupt file, and the bits of `mask` are the index of the table.
+#[derive(Debug)]
+struct MsiPageTable {
+    /// Address of the table.
+    addr: HostPhysicalAddress,
+    /// `msi_addr_mask` (PPN bits that select interrupt file number)
+    mask: u64,
+    /// `msi_addr_pattern` (PPN of interrupt files)
+    pattern: u64,
 }
 
 impl IoMmu {
+    /// Create MSI page table that redirects MSIs to guest interrupt files of the guest.
+    ///
+    /// The guest accesses the guest interrupt file at the address of supervisor-level interrupt file,
+    /// so the interrupt file number is the hart index.
+    pub fn set_msi_page_table(&mut self, imsic: &Imsic, guest_id: usize) {
+        /// Size of MSI page table entry [byte].
+        const MSI_PTE_SIZE: usize = 16;
+        /// V field of MSI PTE.
+        const MSI_PTE_V: u64 = 1;
+        /// M field of MSI PTE. (basic translate mode)
+        const MSI_PTE_M_BASIC: u64 = 0b11 << 1;
+
+        assert!((1 << imsic.hart_index_bits()) * MSI_PTE_SIZE <= PAGE_SIZE);
+
+        let table_addr = PageBlock::alloc();
+        unsafe {
+            core::ptr::write_bytes(table_addr.raw() as *mut u8, 0u8, PAGE_SIZE);
+        }
+        for file in imsic.guest_files(guest_id) {
+            let pte_addr = table_addr + file.hart_id * MSI_PTE_SIZE;
+            unsafe {
+                core::ptr::write_volatile(
+                    pte_addr.raw() as *mut u64,
+                    (file.addr.raw() as u64 >> 12) << 10 | MSI_PTE_M_BASIC | MSI_PTE_V,
+                );
+            }
+        }
+
+        self.msi_page_table = Some(MsiPageTable {
+            addr: table_addr,
+            mask: ((1 << imsic.hart_index_bits()) - 1) << imsic.guest_index_bits(),
+            pattern: imsic.paddr().raw() as u64 >> 12,
+        });
+    }
+
     /// Set page table in IOMMU.
-    fn init_page_table(ddt_addr: HostPhysicalAddress) {
+    fn init_page_table(&self, ddt_addr: HostPhysicalAddress) {
         /// Offset of `iohgatp` register [byte].
         const OFFSET_IOHGATP: usize = 8;
+        /// Offset of `msiptp` register [byte].
+        const OFFSET_MSIPTP: usize = 32;
+        /// Offset of `msi_addr_mask` register [byte].
+        const OFFSET_MSI_ADDR_MASK: usize = 40;
+        /// Offset of `msi_addr_pattern` register [byte].
+        const OFFSET_MSI_ADDR_PATTERN: usize = 48;
+        /// Flat mode of `msiptp`.
+        const MSIPTP_MODE_FLAT: u64 = 1 << 60;
         /// Size of leaf ddt entry [byte].
         const LEAF_DDT_ENTRY_SIZE: usize = 64; // 512 / 8 = 64 [byte]
         /// V field in TC regsiter.
@@ -44,6 +104,23 @@ impl IoMmu {
                 core::ptr::write_volatile(tc_addr.0 as *mut u64, TC_V);
                 core::ptr::write_volatile(iohgatp_addr.0 as *mut u64, hgatp::read().bits() as u64);
             }
+
+            if let Some(msi_page_table) = &self.msi_page_table {
+                unsafe {
+                    core::ptr::write_volatile(
+                        (ddt_addr + offset + OFFSET_MSIPTP).0 as *mut u64,
+                        MSIPTP_MODE_FLAT | msi_page_table.addr.raw() as u64 >> 12,
+                    );
+                    core::ptr::write_volatile(
+                        (ddt_addr + offset + OFFSET_MSI_ADDR_MASK).0 as *mut u64,
+                        msi_page_table.mask,
+                    );
+                    core::ptr::write_volatile(
+                        (ddt_addr + offset + OFFSET_MSI_ADDR_PATTERN).0 as *mut u64,
+                        msi_page_table.pattern,
+                    );
+                }
+            }
         }
     }
 }
@@ -67,6 +144,7 @@ impl PciDevice for IoMmu {
             bus: pci_first_reg >> 16 & 0b1111_1111, // 8 bit
             device: pci_first_reg >> 11 & 0b1_1111, // 5 bit
             function: pci_first_reg >> 8 & 0b111,   // 3 bit
+            msi_page_table: None,
         })
     }
 
@@ -103,7 +181,10 @@ impl PciDevice for IoMmu {
         // 2. Stop and report failure if capabilities.version is not supported.
         let (major, _minor) = registers.capabilities.version();
         assert!(major >= 1);
-        assert!(registers.capabilities.is_sv39x4_supported());
+        // iohgatp shares the G-stage page table with hgatp.
+        assert!(registers
+            .capabilities
+            .is_g_stage_mode_supported(hgatp::read().mode()));
         assert!(!registers.capabilities.is_base_format());
 
         // 3. Read the feature control register (fctl).
@@ -171,7 +252,7 @@ impl PciDevice for IoMmu {
         unsafe {
             core::ptr::write_bytes(ddt_ptr, 0u8, PAGE_SIZE);
         }
-        Self::init_page_table(ddt_addr);
+        self.init_page_table(ddt_addr);
         registers.ddtp.set(IoMmuMode::Lv1, ddt_addr);
     }
 }
@@ -1,5 +1,6 @@
 //! Register map for IOMMU.
 
+use crate::h_extension::csrs::hgatp;
 use crate::memmap::HostPhysicalAddress;
 
 /// IOMMU register map
@@ -60,12 +61,22 @@ impl Capabilities {
         (self.0 >> 22) & 0x1 == 0
     }
 
-    /// Is sv39x4 supported?
-    pub fn is_sv39x4_supported(&self) -> bool {
+    /// Is the G-stage translation mode supported?
+    pub fn is_g_stage_mode_supported(&self, mode: hgatp::Mode) -> bool {
         /// Field `Sv39x4` of `capabilities` register.
         const FIELD_CAPABILITIES_SV39X4: usize = 17;
+        /// Field `Sv48x4` of `capabilities` register.
+        const FIELD_CAPABILITIES_SV48X4: usize = 18;
+        /// Field `Sv57x4` of `capabilities` register.
+        const FIELD_CAPABILITIES_SV57X4: usize = 19;
 
-        self.0 >> FIELD_CAPABILITIES_SV39X4 & 0x1 == 1
+        let field = match mode {
+            hgatp::Mode::Bare => return true,
+            hgatp::Mode::Sv39x4 => FIELD_CAPABILITIES_SV39X4,
+            hgatp::Mode::Sv48x4 => FIELD_CAPABILITIES_SV48X4,
+            hgatp::Mode::Sv57x4 => FIELD_CAPABILITIES_SV57X4,
+        };
+        self.0 >> field & 0x1 == 1
     }
 }
 
@@ -214,3 +225,94 @@ impl Ddtp {
         self.0 = (ddt_addr.0 as u64 >> 12) << FIELD_DDTP_PPN | mode as u64;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::offset_of;
+
+    /// Offsets of registers are the same as the spec.
+    #[test]
+    fn register_offsets() {
+        assert_eq!(offset_of!(IoMmuRegisters, capabilities), 0x0);
+        assert_eq!(offset_of!(IoMmuRegisters, ddtp), 0x10);
+        assert_eq!(offset_of!(IoMmuRegisters, cqb), 0x18);
+        assert_eq!(offset_of!(IoMmuRegisters, cqt), 0x24);
+        assert_eq!(offset_of!(IoMmuRegisters, fqb), 0x28);
+        assert_eq!(offset_of!(IoMmuRegisters, fqt), 0x34);
+        assert_eq!(offset_of!(IoMmuRegisters, pqb), 0x38);
+        assert_eq!(offset_of!(IoMmuRegisters, pqt), 0x44);
+        assert_eq!(offset_of!(IoMmuRegisters, cqcsr), 0x48);
+        assert_eq!(offset_of!(IoMmuRegisters, fqcsr), 0x4c);
+        assert_eq!(offset_of!(IoMmuRegisters, pqcsr), 0x50);
+    }
+
+    /// Fields of `capabilities` register.
+    #[test]
+    fn capabilities() {
+        // version 1.0, Sv39x4 and Sv48x4, base format
+        let capabilities = Capabilities(0x10 | 1 << 17 | 1 << 18);
+        assert_eq!(capabilities.version(), (1, 0));
+        assert!(capabilities.is_base_format());
+        assert!(capabilities.is_g_stage_mode_supported(hgatp::Mode::Bare));
+        assert!(capabilities.is_g_stage_mode_supported(hgatp::Mode::Sv39x4));
+        assert!(capabilities.is_g_stage_mode_supported(hgatp::Mode::Sv48x4));
+        assert!(!capabilities.is_g_stage_mode_supported(hgatp::Mode::Sv57x4));
+
+        // extended format
+        assert!(!Capabilities(1 << 22).is_base_format());
+    }
+
+    /// Queue base registers have PPN and `LOG2SZ-1`.
+    #[test]
+    fn queue_base() {
+        let mut cqb = Cqb(0);
+        cqb.set(HostPhysicalAddress(0x8020_3000), 1024);
+        assert_eq!(cqb.0, 0x80203 << 10 | 9);
+
+        let mut fqb = Fqb(0);
+        fqb.set(HostPhysicalAddress(0x8020_4000), 2);
+        assert_eq!(fqb.0, 0x80204 << 10);
+
+        let mut pqb = Pqb(0);
+        pqb.set(HostPhysicalAddress(0x8020_5000), 4096);
+        assert_eq!(pqb.0, 0x80205 << 10 | 11);
+    }
+
+    /// Queue base must be page aligned.
+    #[test]
+    #[should_panic]
+    fn misaligned_queue_base() {
+        Cqb(0).set(HostPhysicalAddress(0x8020_3800), 1024);
+    }
+
+    /// Enable bits and `on` bits of queue CSRs.
+    #[test]
+    fn queue_csr() {
+        let mut cqcsr = CqCsr(0);
+        cqcsr.set_cqen();
+        assert_eq!(cqcsr.0, 1);
+        assert!(!cqcsr.cqon());
+        assert!(CqCsr(1 << 16).cqon());
+
+        let mut fqcsr = FqCsr(0);
+        fqcsr.set_fqen();
+        assert_eq!(fqcsr.0, 1);
+        assert!(FqCsr(1 << 16).fqon());
+
+        let mut pqcsr = PqCsr(0);
+        pqcsr.set_pqen();
+        assert_eq!(pqcsr.0, 1);
+        assert!(PqCsr(1 << 16).pqon());
+    }
+
+    /// `ddtp` has PPN and `iommu_mode`.
+    #[test]
+    fn ddtp() {
+        let mut ddtp = Ddtp(0);
+        ddtp.set(IoMmuMode::Lv1, HostPhysicalAddress(0x8030_0000));
+        assert_eq!(ddtp.0, 0x80300 << 10 | 2);
+        ddtp.set(IoMmuMode::Off, HostPhysicalAddress(0));
+        assert_eq!(ddtp.0, 0);
+    }
+}
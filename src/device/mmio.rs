@@ -0,0 +1,42 @@
+//! Items shared by memory mapped I/O devices.
+//!
+//! They do not depend on the other parts of `device` module, so that the devices can be compiled for `host_test`.
+
+use crate::memmap::page_table::PteFlag;
+use crate::memmap::{HostPhysicalAddress, MemoryMap};
+
+use fdt::Fdt;
+
+/// Page table for device
+pub const PTE_FLAGS_FOR_DEVICE: [PteFlag; 4] =
+    [PteFlag::Write, PteFlag::Read, PteFlag::User, PteFlag::Valid];
+
+/// Device emulation error.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DeviceEmulateError {
+    /// Invalid plic address.
+    InvalidAddress,
+    /// Context ID is out of range.
+    InvalidContextId,
+    /// Accessed register is reserved.
+    ReservedRegister,
+    /// Access width is not supported by the register.
+    InvalidAccessWidth,
+}
+
+/// Memory mapped I/O device.
+///
+/// A struct that implement this trait **must** has `base_addr` and size member.
+#[allow(clippy::module_name_repetitions)]
+pub trait MmioDevice {
+    /// Create self instance.
+    /// * `device_tree` - struct Fdt
+    /// * `node_path` - node path in fdt
+    fn new(device_tree: &Fdt, node_path: &str) -> Self;
+    /// Return size of memory region.
+    fn size(&self) -> usize;
+    /// Return address of physical memory
+    fn paddr(&self) -> HostPhysicalAddress;
+    /// Return memory map between physical to physical (identity map) for crate page table.
+    fn memmap(&self) -> MemoryMap;
+}
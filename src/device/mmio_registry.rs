@@ -0,0 +1,197 @@
+//! Registry of emulated MMIO devices.
+//!
+//! Each emulated device claims guest physical address ranges.
+//! Guest page faults in the ranges are dispatched to the device with offset from the start of the range.
+
+use super::EmulatedMmioDevice;
+use crate::memmap::GuestPhysicalAddress;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+/// Device that handles accesses to the registered range.
+///
+/// Devices owned by `Devices` are referred by kind, and the others are owned by the registry.
+#[derive(Debug)]
+pub enum EmulatedDevice {
+    /// `Devices::uart`
+    Uart,
+    /// `Devices::plic`
+    Plic,
+    /// `Devices::aplic`
+    Aplic,
+    /// Other device models.
+    Model(Box<dyn EmulatedMmioDevice>),
+}
+
+/// Registered range.
+#[derive(Debug)]
+struct Entry {
+    /// Guest physical address range claimed by the device.
+    range: Range<GuestPhysicalAddress>,
+    /// Device that handles the range.
+    device: EmulatedDevice,
+}
+
+/// Registry of emulated MMIO devices.
+///
+/// Entries are sorted by start address and never overlap.
+#[derive(Debug)]
+pub struct MmioRegistry {
+    /// Registered ranges.
+    entries: Vec<Entry>,
+}
+
+impl MmioRegistry {
+    /// Constructor for `MmioRegistry`.
+    pub const fn new() -> Self {
+        MmioRegistry {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Claim the guest physical address range for the device.
+    ///
+    /// # Panics
+    /// It will be panic if the range is empty or overlaps with a registered range.
+    pub fn register(&mut self, range: Range<GuestPhysicalAddress>, device: EmulatedDevice) {
+        assert!(range.start < range.end, "empty MMIO range: {range:x?}");
+
+        let index = self
+            .entries
+            .partition_point(|entry| entry.range.start < range.start);
+        let overlaps_prev = index > 0 && self.entries[index - 1].range.end > range.start;
+        let overlaps_next = self
+            .entries
+            .get(index)
+            .is_some_and(|entry| entry.range.start < range.end);
+        assert!(
+            !overlaps_prev && !overlaps_next,
+            "MMIO range {range:x?} overlaps with registered range"
+        );
+
+        self.entries.insert(index, Entry { range, device });
+    }
+
+    /// Return device models and their ranges in order of address.
+    pub fn models(
+        &self,
+    ) -> impl Iterator<Item = (&Range<GuestPhysicalAddress>, &dyn EmulatedMmioDevice)> {
+        self.entries.iter().filter_map(|entry| match &entry.device {
+            EmulatedDevice::Model(model) => Some((&entry.range, model.as_ref())),
+            _ => None,
+        })
+    }
+
+    /// Return the device that claims the address and offset from the start of its range.
+    pub fn find_mut(&mut self, addr: GuestPhysicalAddress) -> Option<(&mut EmulatedDevice, usize)> {
+        let index = self
+            .entries
+            .partition_point(|entry| entry.range.start <= addr)
+            .checked_sub(1)?;
+        let entry = &mut self.entries[index];
+        entry
+            .range
+            .contains(&addr)
+            .then(|| (&mut entry.device, addr.raw() - entry.range.start.raw()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Return the range of `size` bytes from `start`.
+    fn range(start: usize, size: usize) -> Range<GuestPhysicalAddress> {
+        GuestPhysicalAddress(start)..GuestPhysicalAddress(start + size)
+    }
+
+    /// Ranges of UART, PLIC and APLIC are registered out of address order.
+    fn setup() -> MmioRegistry {
+        let mut registry = MmioRegistry::new();
+        registry.register(range(0x3000, 0x1000), EmulatedDevice::Aplic);
+        registry.register(range(0x1000, 0x100), EmulatedDevice::Uart);
+        // adjacent to UART
+        registry.register(range(0x1100, 0x100), EmulatedDevice::Plic);
+        registry
+    }
+
+    /// Entries are sorted regardless of the order of registration.
+    #[test]
+    fn unsorted_register() {
+        let registry = setup();
+        let starts: Vec<_> = registry
+            .entries
+            .iter()
+            .map(|entry| entry.range.start)
+            .collect();
+        assert_eq!(
+            starts,
+            [
+                GuestPhysicalAddress(0x1000),
+                GuestPhysicalAddress(0x1100),
+                GuestPhysicalAddress(0x3000)
+            ]
+        );
+    }
+
+    /// The start is included and the end is excluded.
+    #[test]
+    fn find_at_boundaries() {
+        let mut registry = setup();
+        let mut find = |addr| {
+            registry
+                .find_mut(GuestPhysicalAddress(addr))
+                .map(|(device, offset)| (format!("{device:?}"), offset))
+        };
+
+        assert_eq!(find(0), None);
+        assert_eq!(find(0xfff), None);
+        assert_eq!(find(0x1000), Some(("Uart".into(), 0)));
+        assert_eq!(find(0x10ff), Some(("Uart".into(), 0xff)));
+        assert_eq!(find(0x1100), Some(("Plic".into(), 0)));
+        assert_eq!(find(0x11ff), Some(("Plic".into(), 0xff)));
+        assert_eq!(find(0x1200), None);
+        assert_eq!(find(0x2fff), None);
+        assert_eq!(find(0x3000), Some(("Aplic".into(), 0)));
+        assert_eq!(find(0x3fff), Some(("Aplic".into(), 0xfff)));
+        assert_eq!(find(0x4000), None);
+        assert_eq!(find(usize::MAX), None);
+    }
+
+    /// Range that overlaps with the end of the previous range is rejected.
+    #[test]
+    #[should_panic(expected = "overlaps")]
+    fn overlap_with_prev() {
+        setup().register(range(0x10ff, 1), EmulatedDevice::Uart);
+    }
+
+    /// Range that overlaps with the start of the next range is rejected.
+    #[test]
+    #[should_panic(expected = "overlaps")]
+    fn overlap_with_next() {
+        setup().register(range(0x2000, 0x1001), EmulatedDevice::Uart);
+    }
+
+    /// Range that contains a registered range is rejected.
+    #[test]
+    #[should_panic(expected = "overlaps")]
+    fn overlap_containing() {
+        setup().register(range(0x800, 0x1000), EmulatedDevice::Uart);
+    }
+
+    /// Range that has the same start is rejected.
+    #[test]
+    #[should_panic(expected = "overlaps")]
+    fn overlap_same_start() {
+        setup().register(range(0x3000, 0x10), EmulatedDevice::Uart);
+    }
+
+    /// Empty range is rejected.
+    #[test]
+    #[should_panic(expected = "empty")]
+    fn empty_range() {
+        MmioRegistry::new().register(range(0x1000, 0), EmulatedDevice::Uart);
+    }
+}
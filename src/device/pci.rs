@@ -106,14 +106,49 @@ impl Pci {
     }
 }
 
+/// Parse `ranges` property of Generic PCI host controller into identity memory maps.
+///
+/// I/O space is ignored because it is not mapped to guests.
+fn parse_ranges(ranges: &[u8]) -> Vec<MemoryMap> {
+    /// Bytes size of u32.
+    const BYTES_U32: usize = 4;
+    /// Number of bytes in each range chunks.
+    /// `BUS_ADDRESS(3)` - `CPU_PHYSICAL(2)` - `SIZE(2)`
+    const RANGE_NUM: usize = 7;
+
+    assert!(ranges.len() % 4 == 0);
+    assert!((ranges.len() / 4) % 7 == 0);
+
+    let get_u32 = |range: &[u8], four_bytes_index: usize| {
+        let index = four_bytes_index * 4;
+        u32::from(range[index]) << 24
+            | u32::from(range[index + 1]) << 16
+            | u32::from(range[index + 2]) << 8
+            | u32::from(range[index + 3])
+    };
+    let mut memory_maps = Vec::new();
+    for range in ranges.chunks(RANGE_NUM * BYTES_U32) {
+        let bus_address = get_u32(range, 0);
+
+        // ignore I/O space map
+        // https://elinux.org/Device_Tree_Usage#PCI_Address_Translation
+        if (bus_address >> 24) & 0b11 != 0b01 {
+            let address = (get_u32(range, 3) as usize) << 32 | get_u32(range, 4) as usize;
+            let size = (get_u32(range, 5) as usize) << 32 | get_u32(range, 6) as usize;
+
+            memory_maps.push(MemoryMap::new(
+                GuestPhysicalAddress(address)..GuestPhysicalAddress(address) + size,
+                HostPhysicalAddress(address)..HostPhysicalAddress(address) + size,
+                &PTE_FLAGS_FOR_DEVICE,
+            ));
+        }
+    }
+
+    memory_maps
+}
+
 impl MmioDevice for Pci {
     fn new(device_tree: &Fdt, node_path: &str) -> Self {
-        /// Bytes size of u32.
-        const BYTES_U32: usize = 4;
-        /// Number of bytes in each range chunks.
-        /// `BUS_ADDRESS(3)` - `CPU_PHYSICAL(2)` - `SIZE(2)`
-        const RANGE_NUM: usize = 7;
-
         let region = device_tree
             .find_node(node_path)
             .unwrap()
@@ -127,34 +162,7 @@ impl MmioDevice for Pci {
             .property("ranges")
             .unwrap()
             .value;
-
-        assert!(ranges.len() % 4 == 0);
-        assert!((ranges.len() / 4) % 7 == 0);
-
-        let get_u32 = |range: &[u8], four_bytes_index: usize| {
-            let index = four_bytes_index * 4;
-            u32::from(range[index]) << 24
-                | u32::from(range[index + 1]) << 16
-                | u32::from(range[index + 2]) << 8
-                | u32::from(range[index + 3])
-        };
-        let mut memory_maps = Vec::new();
-        for range in ranges.chunks(RANGE_NUM * BYTES_U32) {
-            let bus_address = get_u32(range, 0);
-
-            // ignore I/O space map
-            // https://elinux.org/Device_Tree_Usage#PCI_Address_Translation
-            if (bus_address >> 24) & 0b11 != 0b01 {
-                let address = (get_u32(range, 3) as usize) << 32 | get_u32(range, 4) as usize;
-                let size = (get_u32(range, 5) as usize) << 32 | get_u32(range, 6) as usize;
-
-                memory_maps.push(MemoryMap::new(
-                    GuestPhysicalAddress(address)..GuestPhysicalAddress(address) + size,
-                    HostPhysicalAddress(address)..HostPhysicalAddress(address) + size,
-                    &PTE_FLAGS_FOR_DEVICE,
-                ));
-            }
-        }
+        let memory_maps = parse_ranges(ranges);
 
         Pci {
             base_addr: HostPhysicalAddress(region.starting_address as usize),
@@ -180,3 +188,67 @@ impl MmioDevice for Pci {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode a range of `ranges` property. (3 cells of bus address, 2 cells of CPU address, 2 cells of size)
+    fn range(space: u32, address: u64, size: u64) -> Vec<u8> {
+        let address_hi = (address >> 32) as u32;
+        let address_lo = address as u32;
+        [
+            space << 24,
+            address_hi,
+            address_lo,
+            address_hi,
+            address_lo,
+            (size >> 32) as u32,
+            size as u32,
+        ]
+        .iter()
+        .flat_map(|cell| cell.to_be_bytes())
+        .collect()
+    }
+
+    /// Ranges of QEMU virt machine: I/O space, 32 bit memory space and 64 bit memory space.
+    #[test]
+    fn parse_qemu_virt_ranges() {
+        let ranges = [
+            range(0b01, 0x300_0000, 0x1_0000),
+            range(0b10, 0x4000_0000, 0x4000_0000),
+            range(0b11, 0x4_0000_0000, 0x4_0000_0000),
+        ]
+        .concat();
+
+        let memory_maps = parse_ranges(&ranges);
+        assert_eq!(memory_maps.len(), 2);
+        assert_eq!(
+            memory_maps[0].phys,
+            HostPhysicalAddress(0x4000_0000)..HostPhysicalAddress(0x8000_0000)
+        );
+        assert_eq!(
+            memory_maps[1].phys,
+            HostPhysicalAddress(0x4_0000_0000)..HostPhysicalAddress(0x8_0000_0000)
+        );
+    }
+
+    /// Configuration space (space code 0b00) is mapped as well as memory space.
+    #[test]
+    fn parse_config_space_range() {
+        let memory_maps = parse_ranges(&range(0b00, 0x3000_0000, 0x1000_0000));
+        assert_eq!(memory_maps.len(), 1);
+        assert_eq!(
+            memory_maps[0].phys,
+            HostPhysicalAddress(0x3000_0000)..HostPhysicalAddress(0x4000_0000)
+        );
+    }
+
+    /// `ranges` must consist of 7 cells entries.
+    #[test]
+    #[should_panic]
+    fn parse_truncated_ranges() {
+        let ranges = range(0b10, 0x4000_0000, 0x4000_0000);
+        parse_ranges(&ranges[..6 * 4]);
+    }
+}
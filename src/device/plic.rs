@@ -1,42 +1,39 @@
-//! PLIC: Platform-Level Interrupt Controller  
+//! PLIC: Platform-Level Interrupt Controller
 //! ref: [https://github.com/riscv/riscv-plic-spec/releases/download/1.0.0/riscv-plic-1.0.0.pdf](https://github.com/riscv/riscv-plic-spec/releases/download/1.0.0/riscv-plic-1.0.0.pdf)
-
-use super::{DeviceEmulateError, MmioDevice, PTE_FLAGS_FOR_DEVICE};
+//!
+//! PLIC is fully virtualized for each guest.
+//!
+//! - Each guest has its own priority, pending, enable, threshold and claim state. (`VirtualPlic`)
+//! - Each interrupt source (IRQ) is assigned to at most one guest.
+//!   A guest can not see or change the IRQs that are not assigned to it.
+//! - Physical external interrupts are handled by hikami.
+//!   hikami claims the IRQ on the physical PLIC, makes it pending on the owner's virtual PLIC and injects
+//!   it to the guest via `hvip.VSEIP`. The physical IRQ is completed when the guest completes it.
+//!
+//! The context ID of virtual PLIC is the same as physical one. (`2 * hart_id + 1` for supervisor)
+//! Priorities and enable bits of assigned IRQs are reflected to the physical PLIC,
+//! so that the physical interrupt is raised on the hart that the guest expects.
+//! Threshold is emulated only in virtual PLIC.
+//!
+//! Register emulation is implemented in hardware-independent `state::PlicState`,
+//! and `Plic` reflects its results to the physical PLIC and `hvip`.
+//!
+//! Some IRQs are not assigned to guests.
+//! - Hypervisor IRQ: physical IRQ that is handled by hikami. (e.g. console input)
+//! - Virtual IRQ: IRQ of the device emulated by hikami. It is raised by `set_virtual_irq_line`.
+
+mod state;
+
+use super::{
+    AccessWidth, DeviceEmulateError, EmulatedMmioDevice, MmioDevice, PTE_FLAGS_FOR_DEVICE,
+};
 use crate::h_extension::csrs::{hvip, VsInterruptKind};
-use crate::memmap::constant::MAX_HART_NUM;
 use crate::memmap::{GuestPhysicalAddress, HostPhysicalAddress, MemoryMap};
-use fdt::Fdt;
-
-/// Max number of PLIC context.
-pub const MAX_CONTEXT_NUM: usize = MAX_HART_NUM * 2;
-
-/// Base offset of context.
-const CONTEXT_BASE: usize = 0x20_0000;
-/// Context registers region size.
-const CONTEXT_REGS_SIZE: usize = 0x1000;
-/// Claim/complete register offset from `CONTEXT_BASE` + `CONTEXT_REGS_SIZE` * `CONTEXT_REGS_SIZE`.
-const CONTEXT_CLAIM: usize = 0x4;
-/// End of context registers region.
-const CONTEXT_END: usize = CONTEXT_BASE * CONTEXT_REGS_SIZE * MAX_CONTEXT_NUM;
-
-/// PLIC context ID.
-pub struct ContextId(usize);
+use state::{ContextId, PhysicalWrite, PlicState, CONTEXT_BASE, CONTEXT_CLAIM, CONTEXT_REGS_SIZE};
 
-impl ContextId {
-    /// Create new `ContextId` from hart id.
-    ///
-    /// Each hart has two id for machine and supervisor.
-    pub fn new(hart_id: usize, is_supervisor: bool) -> Self {
-        ContextId(2 * hart_id + usize::from(is_supervisor))
-    }
-
-    /// Return raw usize value.
-    pub fn raw(&self) -> usize {
-        self.0
-    }
-}
+use fdt::Fdt;
 
-/// PLIC: Platform-Level Interrupt Controller  
+/// PLIC: Platform-Level Interrupt Controller
 /// Interrupt controller for global interrupts.
 #[derive(Debug)]
 pub struct Plic {
@@ -44,86 +41,127 @@ pub struct Plic {
     base_addr: HostPhysicalAddress,
     /// Memory map size.
     size: usize,
-    /// Claim complete flags for external interrupts emulation.
-    ///
-    /// Each bit indicates whether interrupts are claimed in context.
-    claim_complete: [u32; MAX_CONTEXT_NUM],
+    /// State of virtual PLICs.
+    state: PlicState,
 }
 
 impl Plic {
-    /// Read plic claim/update register and reflect to `claim_complete`.
-    pub fn update_claim_complete(&mut self, context_id: &ContextId) {
-        let claim_complete_addr =
-            self.base_addr + CONTEXT_BASE + CONTEXT_REGS_SIZE * context_id.raw() + CONTEXT_CLAIM;
-        let irq = unsafe { core::ptr::read_volatile(claim_complete_addr.raw() as *const u32) };
-        self.claim_complete[context_id.raw()] = irq;
+    /// Create virtual PLIC for the guest with assigned IRQs.
+    ///
+    /// # Panics
+    /// It will be panic if the IRQ is invalid or assigned to other guest.
+    pub fn register_guest(&mut self, guest_id: usize, irqs: &[usize]) {
+        self.state.register_guest(guest_id, irqs);
     }
 
-    /// Emulate reading plic context register
-    fn context_read(&self, offset: usize) -> Result<u32, DeviceEmulateError> {
-        let context_id = (offset - CONTEXT_BASE) / CONTEXT_REGS_SIZE;
-        if context_id > MAX_CONTEXT_NUM {
-            Err(DeviceEmulateError::InvalidContextId)
-        } else {
-            Ok(self.claim_complete[context_id])
+    /// Handle the physical IRQ by hikami on the supervisor context of the hart.
+    ///
+    /// # Panics
+    /// It will be panic if the IRQ is invalid or assigned to a guest.
+    pub fn register_hypervisor_irq(&mut self, irq: usize, hart_id: usize) {
+        let context_id = ContextId::new(hart_id, true);
+        for write in self.state.register_hypervisor_irq(irq, context_id.raw()) {
+            self.physical_write(&write);
         }
     }
 
-    /// Emulate reading plic register.
-    pub fn emulate_read(&self, dst_addr: HostPhysicalAddress) -> Result<u32, DeviceEmulateError> {
-        let offset = dst_addr.raw() - self.base_addr.raw();
-        match offset {
-            CONTEXT_BASE..=CONTEXT_END => self.context_read(offset),
-            _ => Err(DeviceEmulateError::InvalidAddress),
+    /// Complete the physical IRQ that is handled by hikami.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn complete_hypervisor_irq(&self, irq: usize, hart_id: usize) {
+        let context_id = ContextId::new(hart_id, true);
+        self.physical_write(&PhysicalWrite {
+            offset: CONTEXT_BASE + CONTEXT_REGS_SIZE * context_id.raw() + CONTEXT_CLAIM,
+            value: irq as u32,
+        });
+    }
+
+    /// Assign the IRQ of the device emulated by hikami to the guest.
+    pub fn register_virtual_irq(&mut self, guest_id: usize, irq: usize) {
+        self.state.register_virtual_irq(guest_id, irq);
+    }
+
+    /// Set level of the interrupt line of the virtual IRQ.
+    pub fn set_virtual_irq_line(&mut self, guest_id: usize, irq: usize, level: bool) {
+        self.state.set_virtual_irq_line(guest_id, irq, level);
+    }
+
+    /// Return pointer to the physical PLIC register.
+    #[allow(clippy::cast_ptr_alignment)]
+    fn physical_reg(&self, offset: usize) -> *mut u32 {
+        (self.base_addr + offset).raw() as *mut u32
+    }
+
+    /// Write to the physical PLIC register.
+    fn physical_write(&self, write: &PhysicalWrite) {
+        unsafe {
+            self.physical_reg(write.offset).write_volatile(write.value);
+        }
+    }
+
+    /// Handle physical external interrupt of the hart.
+    ///
+    /// Claim the IRQ from physical PLIC and make it pending on the owner's virtual PLIC.
+    /// Return the IRQ if it is handled by hikami. It must be completed by `complete_hypervisor_irq`.
+    pub fn handle_external_interrupt(&mut self, hart_id: usize) -> Option<usize> {
+        let context_id = ContextId::new(hart_id, true);
+        let irq = unsafe {
+            self.physical_reg(CONTEXT_BASE + CONTEXT_REGS_SIZE * context_id.raw() + CONTEXT_CLAIM)
+                .read_volatile()
+        };
+
+        let irq = irq as usize;
+        if self.state.is_hypervisor_irq(irq) {
+            return Some(irq);
+        }
+
+        if let Some(write) = self.state.physical_claimed(context_id.raw(), irq) {
+            // no guest handles the IRQ.
+            self.physical_write(&write);
         }
+        None
     }
 
-    /// Emulate writing plic context register.
-    fn context_write(
+    /// Update `hvip.VSEIP` of current hart according to the guest's virtual PLIC.
+    pub fn update_vs_external_interrupt(&self, guest_id: usize, hart_id: usize) {
+        let context_id = ContextId::new(hart_id, true);
+        if self.state.interrupt_line(guest_id, context_id.raw()) {
+            hvip::set(VsInterruptKind::External);
+        } else {
+            hvip::clear(VsInterruptKind::External);
+        }
+    }
+}
+
+/// All registers are 32 bit wide and must be accessed by naturally aligned 32 bit accesses.
+impl EmulatedMmioDevice for Plic {
+    fn read(
         &mut self,
-        dst_addr: HostPhysicalAddress,
-        value: u32,
-    ) -> Result<(), DeviceEmulateError> {
-        let offset = dst_addr.raw() - self.base_addr.raw();
-        let context_id = (offset - CONTEXT_BASE) / CONTEXT_REGS_SIZE;
-        let offset_per_context = offset % CONTEXT_REGS_SIZE;
-        match offset_per_context {
-            // threshold
-            0 => {
-                let dst_ptr = dst_addr.raw() as *mut u32;
-                unsafe {
-                    dst_ptr.write_volatile(value);
-                }
-
-                Ok(())
-            }
-            // claim/complete
-            4 => {
-                let dst_ptr = dst_addr.raw() as *mut u32;
-                unsafe {
-                    dst_ptr.write_volatile(value);
-                }
-                self.claim_complete[context_id] = 0;
-                hvip::clear(VsInterruptKind::External);
-
-                Ok(())
-            }
-            8 => Err(DeviceEmulateError::ReservedRegister),
-            _ => Err(DeviceEmulateError::InvalidAddress),
+        guest_id: usize,
+        offset: usize,
+        width: AccessWidth,
+    ) -> Result<u64, DeviceEmulateError> {
+        if width != AccessWidth::Word || offset % 4 != 0 {
+            return Err(DeviceEmulateError::InvalidAccessWidth);
         }
+        self.state.read(guest_id, offset).map(u64::from)
     }
 
-    /// Emulate writing plic register.
-    pub fn emulate_write(
+    #[allow(clippy::cast_possible_truncation)]
+    fn write(
         &mut self,
-        dst_addr: HostPhysicalAddress,
-        value: u32,
+        guest_id: usize,
+        offset: usize,
+        width: AccessWidth,
+        value: u64,
     ) -> Result<(), DeviceEmulateError> {
-        let offset = dst_addr.raw() - self.base_addr.raw();
-        match offset {
-            CONTEXT_BASE..=CONTEXT_END => self.context_write(dst_addr, value),
-            _ => Err(DeviceEmulateError::InvalidAddress),
+        if width != AccessWidth::Word || offset % 4 != 0 {
+            return Err(DeviceEmulateError::InvalidAccessWidth);
+        }
+        if let Some(write) = self.state.write(guest_id, offset, value as u32)? {
+            self.physical_write(&write);
         }
+
+        Ok(())
     }
 }
 
@@ -141,7 +179,7 @@ impl MmioDevice for Plic {
         Plic {
             base_addr: HostPhysicalAddress(region.starting_address as usize),
             size: region.size.unwrap(),
-            claim_complete: [0u32; MAX_CONTEXT_NUM],
+            state: PlicState::new(),
         }
     }
 
@@ -153,13 +191,14 @@ impl MmioDevice for Plic {
         self.base_addr
     }
 
+    /// Whole region of PLIC.
+    ///
+    /// It is not mapped to guests because all registers are emulated.
     fn memmap(&self) -> MemoryMap {
-        // Pass through 0x0 - 0x20_0000.
-        // Disallow 0x20_0000 - for emulation.
         let vaddr = GuestPhysicalAddress(self.paddr().raw());
         MemoryMap::new(
-            vaddr..vaddr + CONTEXT_BASE,
-            self.paddr()..self.paddr() + CONTEXT_BASE,
+            vaddr..vaddr + self.size(),
+            self.paddr()..self.paddr() + self.size(),
             &PTE_FLAGS_FOR_DEVICE,
         )
     }
@@ -0,0 +1,780 @@
+//! Hardware-independent state machine of virtual PLIC.
+//!
+//! It takes register offsets from the base of PLIC and returns read values and
+//! the register writes that must be reflected to physical PLIC.
+//! It does not touch any hardware, so it can be tested on host.
+
+use crate::device::DeviceEmulateError;
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Max number of PLIC context.
+pub const MAX_CONTEXT_NUM: usize = crate::memmap::constant::MAX_HART_NUM * 2;
+/// Max number of interrupt sources. (including IRQ 0)
+pub const MAX_IRQ_NUM: usize = 1024;
+/// Number of 32 bit words for bitmap of interrupt sources.
+const IRQ_WORDS: usize = MAX_IRQ_NUM / 32;
+
+/// Base offset of priority registers.
+const PRIORITY_BASE: usize = 0x0;
+/// Base offset of pending bits.
+const PENDING_BASE: usize = 0x1000;
+/// End of pending bits. (exclusive)
+const PENDING_END: usize = PENDING_BASE + IRQ_WORDS * 4;
+/// Base offset of enable bits.
+const ENABLE_BASE: usize = 0x2000;
+/// Enable bits region size per context.
+const ENABLE_REGS_SIZE: usize = 0x80;
+/// End of enable bits of supported contexts. (exclusive)
+const ENABLE_END: usize = ENABLE_BASE + ENABLE_REGS_SIZE * MAX_CONTEXT_NUM;
+/// Base offset of context.
+pub const CONTEXT_BASE: usize = 0x20_0000;
+/// Context registers region size.
+pub const CONTEXT_REGS_SIZE: usize = 0x1000;
+/// Threshold register offset from `CONTEXT_BASE` + `CONTEXT_REGS_SIZE` * context id.
+const CONTEXT_THRESHOLD: usize = 0x0;
+/// Claim/complete register offset from `CONTEXT_BASE` + `CONTEXT_REGS_SIZE` * context id.
+pub const CONTEXT_CLAIM: usize = 0x4;
+/// End of context registers of supported contexts. (exclusive)
+const CONTEXT_END: usize = CONTEXT_BASE + CONTEXT_REGS_SIZE * MAX_CONTEXT_NUM;
+
+/// PLIC context ID.
+pub struct ContextId(usize);
+
+impl ContextId {
+    /// Create new `ContextId` from hart id.
+    ///
+    /// Each hart has two id for machine and supervisor.
+    pub fn new(hart_id: usize, is_supervisor: bool) -> Self {
+        ContextId(2 * hart_id + usize::from(is_supervisor))
+    }
+
+    /// Return raw usize value.
+    pub fn raw(&self) -> usize {
+        self.0
+    }
+}
+
+/// Register write to physical PLIC.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PhysicalWrite {
+    /// Offset from the base of PLIC.
+    pub offset: usize,
+    /// Written value.
+    pub value: u32,
+}
+
+/// Return whether the bit of `irq` is set in bitmap.
+fn test_bit(bitmap: &[u32; IRQ_WORDS], irq: usize) -> bool {
+    bitmap[irq / 32] >> (irq % 32) & 1 == 1
+}
+
+/// Set or clear the bit of `irq` in bitmap.
+fn assign_bit(bitmap: &mut [u32; IRQ_WORDS], irq: usize, value: bool) {
+    if value {
+        bitmap[irq / 32] |= 1 << (irq % 32);
+    } else {
+        bitmap[irq / 32] &= !(1 << (irq % 32));
+    }
+}
+
+/// PLIC state of a guest.
+#[derive(Debug)]
+struct VirtualPlic {
+    /// Guest ID of the owner.
+    guest_id: usize,
+    /// IRQs that are assigned to the guest.
+    assigned: [u32; IRQ_WORDS],
+    /// Assigned IRQs that are raised by devices emulated in hikami. (not connected to physical IRQ)
+    virtual_irqs: [u32; IRQ_WORDS],
+    /// Priority of each IRQ.
+    priority: Vec<u32>,
+    /// Pending bits.
+    pending: [u32; IRQ_WORDS],
+    /// IRQs that are claimed and not completed yet.
+    claimed: [u32; IRQ_WORDS],
+    /// Enable bits of each context.
+    enable: [[u32; IRQ_WORDS]; MAX_CONTEXT_NUM],
+    /// Priority threshold of each context.
+    threshold: [u32; MAX_CONTEXT_NUM],
+}
+
+impl VirtualPlic {
+    /// Constructor for `VirtualPlic`.
+    fn new(guest_id: usize, irqs: &[usize]) -> Self {
+        let mut assigned = [0; IRQ_WORDS];
+        for irq in irqs {
+            assign_bit(&mut assigned, *irq, true);
+        }
+
+        VirtualPlic {
+            guest_id,
+            assigned,
+            virtual_irqs: [0; IRQ_WORDS],
+            priority: vec![0; MAX_IRQ_NUM],
+            pending: [0; IRQ_WORDS],
+            claimed: [0; IRQ_WORDS],
+            enable: [[0; IRQ_WORDS]; MAX_CONTEXT_NUM],
+            threshold: [0; MAX_CONTEXT_NUM],
+        }
+    }
+
+    /// Return whether the IRQ is assigned to the guest.
+    fn is_assigned(&self, irq: usize) -> bool {
+        irq != 0 && irq < MAX_IRQ_NUM && test_bit(&self.assigned, irq)
+    }
+
+    /// Return whether the IRQ is assigned to the guest and connected to physical IRQ.
+    fn is_physical(&self, irq: usize) -> bool {
+        self.is_assigned(irq) && !test_bit(&self.virtual_irqs, irq)
+    }
+
+    /// Return the pending IRQ that has the highest priority for the context.
+    ///
+    /// The IRQ with smaller ID is chosen if priorities are same.
+    fn highest_pending(&self, context_id: usize) -> Option<usize> {
+        (1..MAX_IRQ_NUM)
+            .filter(|irq| {
+                test_bit(&self.pending, *irq)
+                    && test_bit(&self.enable[context_id], *irq)
+                    && self.priority[*irq] > self.threshold[context_id]
+            })
+            // `max_by_key` returns the last element, so reverse to take the smallest IRQ.
+            .rev()
+            .max_by_key(|irq| self.priority[*irq])
+    }
+
+    /// Claim the highest priority pending IRQ. (0 if there is no IRQ)
+    #[allow(clippy::cast_possible_truncation)]
+    fn claim(&mut self, context_id: usize) -> u32 {
+        match self.highest_pending(context_id) {
+            Some(irq) => {
+                assign_bit(&mut self.pending, irq, false);
+                assign_bit(&mut self.claimed, irq, true);
+                irq as u32
+            }
+            None => 0,
+        }
+    }
+
+    /// Complete the IRQ.
+    ///
+    /// Return `false` if the completion is ignored. (the IRQ is not claimed or not enabled for the context)
+    fn complete(&mut self, context_id: usize, irq: usize) -> bool {
+        if !self.is_assigned(irq)
+            || !test_bit(&self.claimed, irq)
+            || !test_bit(&self.enable[context_id], irq)
+        {
+            return false;
+        }
+
+        assign_bit(&mut self.claimed, irq, false);
+        true
+    }
+}
+
+/// State of virtual PLICs of all guests.
+#[derive(Debug)]
+#[allow(clippy::module_name_repetitions)]
+pub struct PlicState {
+    /// Virtual PLIC of each guest.
+    virtual_plics: Vec<VirtualPlic>,
+    /// Physical context that claimed each IRQ. (used for completion)
+    claimed_context: Vec<Option<usize>>,
+    /// IRQs that are handled by hikami itself.
+    hypervisor_irqs: [u32; IRQ_WORDS],
+    /// Enable bits of IRQs that are handled by hikami for each context.
+    hypervisor_enable: [[u32; IRQ_WORDS]; MAX_CONTEXT_NUM],
+}
+
+impl PlicState {
+    /// Constructor for `PlicState`.
+    pub fn new() -> Self {
+        PlicState {
+            virtual_plics: Vec::new(),
+            claimed_context: vec![None; MAX_IRQ_NUM],
+            hypervisor_irqs: [0; IRQ_WORDS],
+            hypervisor_enable: [[0; IRQ_WORDS]; MAX_CONTEXT_NUM],
+        }
+    }
+
+    /// Handle the IRQ by hikami on the context instead of guests.
+    ///
+    /// Return the register writes to enable the IRQ on physical PLIC.
+    ///
+    /// # Panics
+    /// It will be panic if the IRQ is invalid or assigned to a guest.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn register_hypervisor_irq(&mut self, irq: usize, context_id: usize) -> [PhysicalWrite; 2] {
+        assert!((1..MAX_IRQ_NUM).contains(&irq), "invalid IRQ {irq}");
+        assert!(context_id < MAX_CONTEXT_NUM);
+        assert!(
+            self.owner(irq).is_none(),
+            "IRQ {irq} is assigned to a guest"
+        );
+
+        assign_bit(&mut self.hypervisor_irqs, irq, true);
+        assign_bit(&mut self.hypervisor_enable[context_id], irq, true);
+        [
+            PhysicalWrite {
+                offset: PRIORITY_BASE + irq * 4,
+                value: 1,
+            },
+            self.physical_enable(context_id, irq / 32),
+        ]
+    }
+
+    /// Return whether the IRQ is handled by hikami.
+    pub fn is_hypervisor_irq(&self, irq: usize) -> bool {
+        irq < MAX_IRQ_NUM && test_bit(&self.hypervisor_irqs, irq)
+    }
+
+    /// Assign the IRQ that is raised by device emulated in hikami to the guest.
+    ///
+    /// The IRQ number may be same as other guest's one or physical one, because it is not connected to physical IRQ.
+    /// It is ignored if the guest does not have virtual PLIC.
+    pub fn register_virtual_irq(&mut self, guest_id: usize, irq: usize) {
+        assert!((1..MAX_IRQ_NUM).contains(&irq), "invalid IRQ {irq}");
+        let Ok(vplic) = self.virtual_plic_mut(guest_id) else {
+            return;
+        };
+        assert!(!vplic.is_assigned(irq), "IRQ {irq} is already assigned");
+
+        assign_bit(&mut vplic.assigned, irq, true);
+        assign_bit(&mut vplic.virtual_irqs, irq, true);
+    }
+
+    /// Set level of the interrupt line of the virtual IRQ.
+    ///
+    /// The IRQ becomes pending if the line is asserted and not claimed, as the gateway of PLIC does.
+    pub fn set_virtual_irq_line(&mut self, guest_id: usize, irq: usize, level: bool) {
+        let Ok(vplic) = self.virtual_plic_mut(guest_id) else {
+            return;
+        };
+        if !vplic.is_assigned(irq) || vplic.is_physical(irq) {
+            return;
+        }
+
+        let pending = level && !test_bit(&vplic.claimed, irq);
+        assign_bit(&mut vplic.pending, irq, pending);
+    }
+
+    /// Return the register write of physical enable bits.
+    ///
+    /// Physical enable bits are union of all guests and hikami. (assigned IRQs are disjoint)
+    fn physical_enable(&self, context_id: usize, word: usize) -> PhysicalWrite {
+        let enable = self
+            .virtual_plics
+            .iter()
+            .fold(self.hypervisor_enable[context_id][word], |bits, vplic| {
+                bits | vplic.enable[context_id][word] & !vplic.virtual_irqs[word]
+            });
+        PhysicalWrite {
+            offset: ENABLE_BASE + ENABLE_REGS_SIZE * context_id + word * 4,
+            value: enable,
+        }
+    }
+
+    /// Create virtual PLIC for the guest with assigned IRQs.
+    ///
+    /// # Panics
+    /// It will be panic if the IRQ is invalid or assigned to other guest.
+    pub fn register_guest(&mut self, guest_id: usize, irqs: &[usize]) {
+        for irq in irqs {
+            assert!(
+                (1..MAX_IRQ_NUM).contains(irq),
+                "invalid IRQ {irq} for guest {guest_id}"
+            );
+            assert!(
+                self.owner(*irq).is_none(),
+                "IRQ {irq} is assigned to multiple guests"
+            );
+            assert!(
+                !self.is_hypervisor_irq(*irq),
+                "IRQ {irq} is handled by hikami"
+            );
+        }
+
+        self.virtual_plics.push(VirtualPlic::new(guest_id, irqs));
+    }
+
+    /// Return virtual PLIC of the guest.
+    fn virtual_plic(&self, guest_id: usize) -> Result<&VirtualPlic, DeviceEmulateError> {
+        self.virtual_plics
+            .iter()
+            .find(|vplic| vplic.guest_id == guest_id)
+            .ok_or(DeviceEmulateError::InvalidAddress)
+    }
+
+    /// Return mutable virtual PLIC of the guest.
+    fn virtual_plic_mut(
+        &mut self,
+        guest_id: usize,
+    ) -> Result<&mut VirtualPlic, DeviceEmulateError> {
+        self.virtual_plics
+            .iter_mut()
+            .find(|vplic| vplic.guest_id == guest_id)
+            .ok_or(DeviceEmulateError::InvalidAddress)
+    }
+
+    /// Return virtual PLIC of the guest that the physical IRQ is assigned to.
+    fn owner(&self, irq: usize) -> Option<&VirtualPlic> {
+        self.virtual_plics
+            .iter()
+            .find(|vplic| vplic.is_physical(irq))
+    }
+
+    /// Make the IRQ that is claimed from physical PLIC pending on the owner's virtual PLIC.
+    ///
+    /// Return the completion of physical IRQ if no guest handles it.
+    /// IRQs handled by hikami are completed after handling by the caller.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn physical_claimed(&mut self, context_id: usize, irq: usize) -> Option<PhysicalWrite> {
+        if irq == 0 || self.is_hypervisor_irq(irq) {
+            return None;
+        }
+
+        let Some(owner_id) = self.owner(irq).map(|vplic| vplic.guest_id) else {
+            return Some(PhysicalWrite {
+                offset: CONTEXT_BASE + CONTEXT_REGS_SIZE * context_id + CONTEXT_CLAIM,
+                value: irq as u32,
+            });
+        };
+
+        self.claimed_context[irq] = Some(context_id);
+        let vplic = self.virtual_plic_mut(owner_id).unwrap();
+        assign_bit(&mut vplic.pending, irq, true);
+        None
+    }
+
+    /// Return whether the external interrupt line of the guest's context is asserted.
+    pub fn interrupt_line(&self, guest_id: usize, context_id: usize) -> bool {
+        context_id < MAX_CONTEXT_NUM
+            && self
+                .virtual_plic(guest_id)
+                .is_ok_and(|vplic| vplic.highest_pending(context_id).is_some())
+    }
+
+    /// Emulate reading register.
+    pub fn read(&mut self, guest_id: usize, offset: usize) -> Result<u32, DeviceEmulateError> {
+        match offset {
+            PRIORITY_BASE..PENDING_BASE => {
+                let vplic = self.virtual_plic(guest_id)?;
+                let irq = (offset - PRIORITY_BASE) / 4;
+                Ok(if vplic.is_assigned(irq) {
+                    vplic.priority[irq]
+                } else {
+                    0
+                })
+            }
+            PENDING_BASE..PENDING_END => {
+                let vplic = self.virtual_plic(guest_id)?;
+                Ok(vplic.pending[(offset - PENDING_BASE) / 4])
+            }
+            PENDING_END..ENABLE_BASE => Err(DeviceEmulateError::ReservedRegister),
+            ENABLE_BASE..ENABLE_END => {
+                let vplic = self.virtual_plic(guest_id)?;
+                let context_id = (offset - ENABLE_BASE) / ENABLE_REGS_SIZE;
+                let word = (offset % ENABLE_REGS_SIZE) / 4;
+                Ok(vplic.enable[context_id][word])
+            }
+            CONTEXT_BASE..CONTEXT_END => {
+                let vplic = self.virtual_plic_mut(guest_id)?;
+                let context_id = (offset - CONTEXT_BASE) / CONTEXT_REGS_SIZE;
+                match offset % CONTEXT_REGS_SIZE {
+                    CONTEXT_THRESHOLD => Ok(vplic.threshold[context_id]),
+                    CONTEXT_CLAIM => Ok(vplic.claim(context_id)),
+                    _ => Err(DeviceEmulateError::ReservedRegister),
+                }
+            }
+            // contexts that are not supported.
+            ENABLE_END..CONTEXT_BASE | CONTEXT_END.. => Err(DeviceEmulateError::InvalidContextId),
+        }
+    }
+
+    /// Emulate writing register.
+    ///
+    /// Return the register write that must be reflected to physical PLIC.
+    pub fn write(
+        &mut self,
+        guest_id: usize,
+        offset: usize,
+        value: u32,
+    ) -> Result<Option<PhysicalWrite>, DeviceEmulateError> {
+        match offset {
+            PRIORITY_BASE..PENDING_BASE => {
+                let vplic = self.virtual_plic_mut(guest_id)?;
+                let irq = (offset - PRIORITY_BASE) / 4;
+                // writing to the IRQ that is not assigned is ignored.
+                if !vplic.is_assigned(irq) {
+                    return Ok(None);
+                }
+
+                vplic.priority[irq] = value;
+                if !vplic.is_physical(irq) {
+                    return Ok(None);
+                }
+                Ok(Some(PhysicalWrite { offset, value }))
+            }
+            // pending bits are read only.
+            PENDING_BASE..PENDING_END => Ok(None),
+            PENDING_END..ENABLE_BASE => Err(DeviceEmulateError::ReservedRegister),
+            ENABLE_BASE..ENABLE_END => {
+                let vplic = self.virtual_plic_mut(guest_id)?;
+                let context_id = (offset - ENABLE_BASE) / ENABLE_REGS_SIZE;
+                let word = (offset % ENABLE_REGS_SIZE) / 4;
+                vplic.enable[context_id][word] = value & vplic.assigned[word];
+                Ok(Some(self.physical_enable(context_id, word)))
+            }
+            CONTEXT_BASE..CONTEXT_END => {
+                let context_id = (offset - CONTEXT_BASE) / CONTEXT_REGS_SIZE;
+                match offset % CONTEXT_REGS_SIZE {
+                    CONTEXT_THRESHOLD => {
+                        self.virtual_plic_mut(guest_id)?.threshold[context_id] = value;
+                        Ok(None)
+                    }
+                    CONTEXT_CLAIM => {
+                        let irq = value as usize;
+                        if !self.virtual_plic_mut(guest_id)?.complete(context_id, irq) {
+                            return Ok(None);
+                        }
+
+                        // complete physical IRQ on the context that claimed it.
+                        Ok(self.claimed_context[irq]
+                            .take()
+                            .map(|claimed_context| PhysicalWrite {
+                                offset: CONTEXT_BASE
+                                    + CONTEXT_REGS_SIZE * claimed_context
+                                    + CONTEXT_CLAIM,
+                                value,
+                            }))
+                    }
+                    _ => Err(DeviceEmulateError::ReservedRegister),
+                }
+            }
+            // contexts that are not supported.
+            ENABLE_END..CONTEXT_BASE | CONTEXT_END.. => Err(DeviceEmulateError::InvalidContextId),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Guest ID for tests.
+    const GUEST: usize = 1;
+    /// Other guest ID for tests.
+    const OTHER_GUEST: usize = 2;
+    /// Supervisor context of hart 0.
+    const CONTEXT: usize = 1;
+
+    /// Offset of priority register.
+    fn priority(irq: usize) -> usize {
+        PRIORITY_BASE + irq * 4
+    }
+
+    /// Offset of enable bits.
+    fn enable(context_id: usize, irq: usize) -> usize {
+        ENABLE_BASE + ENABLE_REGS_SIZE * context_id + irq / 32 * 4
+    }
+
+    /// Offset of threshold register.
+    fn threshold(context_id: usize) -> usize {
+        CONTEXT_BASE + CONTEXT_REGS_SIZE * context_id + CONTEXT_THRESHOLD
+    }
+
+    /// Offset of claim/complete register.
+    fn claim(context_id: usize) -> usize {
+        CONTEXT_BASE + CONTEXT_REGS_SIZE * context_id + CONTEXT_CLAIM
+    }
+
+    /// Guest 1 owns IRQ 1-10, guest 2 owns IRQ 11.
+    /// IRQ 1-10 are enabled with priority 1 on `CONTEXT`.
+    fn setup() -> PlicState {
+        let mut state = PlicState::new();
+        state.register_guest(GUEST, &(1..=10).collect::<Vec<_>>());
+        state.register_guest(OTHER_GUEST, &[11]);
+        for irq in 1..=10 {
+            state.write(GUEST, priority(irq), 1).unwrap();
+        }
+        state.write(GUEST, enable(CONTEXT, 0), 0x7fe).unwrap();
+        state
+    }
+
+    /// Claimed IRQ is delivered to the guest and completed on physical PLIC.
+    #[test]
+    fn claim_and_complete() {
+        let mut state = setup();
+        assert_eq!(state.read(GUEST, claim(CONTEXT)), Ok(0));
+        assert!(!state.interrupt_line(GUEST, CONTEXT));
+
+        assert_eq!(state.physical_claimed(CONTEXT, 10), None);
+        assert!(state.interrupt_line(GUEST, CONTEXT));
+        assert_eq!(state.read(GUEST, PENDING_BASE), Ok(1 << 10));
+
+        assert_eq!(state.read(GUEST, claim(CONTEXT)), Ok(10));
+        assert!(!state.interrupt_line(GUEST, CONTEXT));
+        assert_eq!(state.read(GUEST, PENDING_BASE), Ok(0));
+
+        assert_eq!(
+            state.write(GUEST, claim(CONTEXT), 10),
+            Ok(Some(PhysicalWrite {
+                offset: claim(CONTEXT),
+                value: 10
+            }))
+        );
+    }
+
+    /// IRQs are claimed in order of priority.
+    #[test]
+    fn claim_highest_priority() {
+        let mut state = setup();
+        state.write(GUEST, priority(5), 3).unwrap();
+        state.write(GUEST, priority(7), 3).unwrap();
+        for irq in [2, 5, 7] {
+            state.physical_claimed(CONTEXT, irq);
+        }
+
+        // same priority: smaller IRQ first
+        assert_eq!(state.read(GUEST, claim(CONTEXT)), Ok(5));
+        assert_eq!(state.read(GUEST, claim(CONTEXT)), Ok(7));
+        assert_eq!(state.read(GUEST, claim(CONTEXT)), Ok(2));
+        assert_eq!(state.read(GUEST, claim(CONTEXT)), Ok(0));
+    }
+
+    /// Completion of the IRQ that is not claimed is ignored.
+    #[test]
+    fn complete_without_claim_is_ignored() {
+        let mut state = setup();
+        assert_eq!(state.write(GUEST, claim(CONTEXT), 3), Ok(None));
+
+        state.physical_claimed(CONTEXT, 3);
+        assert_eq!(state.read(GUEST, claim(CONTEXT)), Ok(3));
+        assert!(state.write(GUEST, claim(CONTEXT), 3).unwrap().is_some());
+        // second completion
+        assert_eq!(state.write(GUEST, claim(CONTEXT), 3), Ok(None));
+    }
+
+    /// IRQs whose priority is not greater than threshold are masked.
+    #[test]
+    fn threshold_masks_interrupt() {
+        let mut state = setup();
+        state.write(GUEST, priority(4), 2).unwrap();
+        state.physical_claimed(CONTEXT, 4);
+
+        state.write(GUEST, threshold(CONTEXT), 2).unwrap();
+        assert_eq!(state.read(GUEST, threshold(CONTEXT)), Ok(2));
+        assert!(!state.interrupt_line(GUEST, CONTEXT));
+        assert_eq!(state.read(GUEST, claim(CONTEXT)), Ok(0));
+
+        state.write(GUEST, threshold(CONTEXT), 1).unwrap();
+        assert!(state.interrupt_line(GUEST, CONTEXT));
+        assert_eq!(state.read(GUEST, claim(CONTEXT)), Ok(4));
+    }
+
+    /// Priority 0 means "never interrupt".
+    #[test]
+    fn priority_zero_never_interrupts() {
+        let mut state = setup();
+        state.write(GUEST, priority(6), 0).unwrap();
+        state.physical_claimed(CONTEXT, 6);
+        assert!(!state.interrupt_line(GUEST, CONTEXT));
+    }
+
+    /// Guests can not access IRQs of other guests.
+    #[test]
+    fn unassigned_irq_is_isolated() {
+        let mut state = setup();
+
+        // guest 1 can not see or change IRQ 11.
+        assert_eq!(state.write(GUEST, priority(11), 7), Ok(None));
+        assert_eq!(state.read(OTHER_GUEST, priority(11)), Ok(0));
+        assert_eq!(
+            state.write(GUEST, enable(CONTEXT, 11), 1 << 11),
+            Ok(Some(PhysicalWrite {
+                offset: enable(CONTEXT, 11),
+                value: 0,
+            }))
+        );
+        assert_eq!(state.read(GUEST, enable(CONTEXT, 11)), Ok(0));
+
+        // physical enable bits include IRQs of other guests.
+        assert_eq!(
+            state.write(OTHER_GUEST, enable(CONTEXT, 11), 1 << 11),
+            Ok(Some(PhysicalWrite {
+                offset: enable(CONTEXT, 11),
+                value: 1 << 11,
+            }))
+        );
+        assert_eq!(
+            state.write(GUEST, enable(CONTEXT, 1), 1 << 1),
+            Ok(Some(PhysicalWrite {
+                offset: enable(CONTEXT, 1),
+                value: 1 << 1 | 1 << 11,
+            }))
+        );
+
+        // pending IRQ of guest 2 does not interrupt guest 1.
+        state.write(OTHER_GUEST, priority(11), 1).unwrap();
+        state.physical_claimed(CONTEXT, 11);
+        assert!(!state.interrupt_line(GUEST, CONTEXT));
+        assert!(state.interrupt_line(OTHER_GUEST, CONTEXT));
+        assert_eq!(state.read(GUEST, claim(CONTEXT)), Ok(0));
+        assert_eq!(state.write(GUEST, claim(CONTEXT), 11), Ok(None));
+    }
+
+    /// IRQ that is not assigned to any guest is completed immediately.
+    #[test]
+    fn unowned_irq_is_completed() {
+        let mut state = setup();
+        assert_eq!(
+            state.physical_claimed(CONTEXT, 20),
+            Some(PhysicalWrite {
+                offset: claim(CONTEXT),
+                value: 20
+            })
+        );
+    }
+
+    /// Guest that has no virtual PLIC can not access it.
+    #[test]
+    fn unregistered_guest() {
+        let mut state = setup();
+        assert_eq!(
+            state.read(3, claim(CONTEXT)),
+            Err(DeviceEmulateError::InvalidAddress)
+        );
+        assert_eq!(
+            state.write(3, threshold(CONTEXT), 0),
+            Err(DeviceEmulateError::InvalidAddress)
+        );
+    }
+
+    /// Contexts after `MAX_CONTEXT_NUM` are rejected.
+    #[test]
+    fn out_of_range_context() {
+        let mut state = setup();
+        let last = MAX_CONTEXT_NUM - 1;
+        assert_eq!(state.read(GUEST, threshold(last)), Ok(0));
+        assert_eq!(state.write(GUEST, threshold(last), 1), Ok(None));
+        assert_eq!(state.read(GUEST, enable(last, 0)), Ok(0));
+
+        for context_id in [MAX_CONTEXT_NUM, MAX_CONTEXT_NUM + 1, 0x3e00] {
+            assert_eq!(
+                state.read(GUEST, threshold(context_id)),
+                Err(DeviceEmulateError::InvalidContextId)
+            );
+            assert_eq!(
+                state.read(GUEST, claim(context_id)),
+                Err(DeviceEmulateError::InvalidContextId)
+            );
+            assert_eq!(
+                state.write(GUEST, claim(context_id), 1),
+                Err(DeviceEmulateError::InvalidContextId)
+            );
+        }
+        assert_eq!(
+            state.read(GUEST, enable(MAX_CONTEXT_NUM, 0)),
+            Err(DeviceEmulateError::InvalidContextId)
+        );
+        assert_eq!(
+            state.write(GUEST, enable(MAX_CONTEXT_NUM, 0), 1),
+            Err(DeviceEmulateError::InvalidContextId)
+        );
+        assert!(!state.interrupt_line(GUEST, MAX_CONTEXT_NUM));
+    }
+
+    /// Access to reserved registers.
+    #[test]
+    fn reserved_registers() {
+        let mut state = setup();
+        assert_eq!(
+            state.read(GUEST, CONTEXT_BASE + 0x8),
+            Err(DeviceEmulateError::ReservedRegister)
+        );
+        assert_eq!(
+            state.write(GUEST, PENDING_END, 0),
+            Err(DeviceEmulateError::ReservedRegister)
+        );
+        // pending bits are read only.
+        assert_eq!(state.write(GUEST, PENDING_BASE, u32::MAX), Ok(None));
+        assert_eq!(state.read(GUEST, PENDING_BASE), Ok(0));
+    }
+
+    /// IRQ handled by hikami is not delivered to guests and stays enabled on physical PLIC.
+    #[test]
+    fn hypervisor_irq() {
+        let mut state = setup();
+        assert_eq!(
+            state.register_hypervisor_irq(12, CONTEXT),
+            [
+                PhysicalWrite {
+                    offset: priority(12),
+                    value: 1
+                },
+                PhysicalWrite {
+                    offset: enable(CONTEXT, 12),
+                    value: 0x17fe
+                }
+            ]
+        );
+        assert!(state.is_hypervisor_irq(12));
+
+        // completed by hikami, not by guests.
+        assert_eq!(state.physical_claimed(CONTEXT, 12), None);
+        assert!(!state.interrupt_line(GUEST, CONTEXT));
+
+        // guests do not clear the enable bit of hikami.
+        assert_eq!(
+            state.write(GUEST, enable(CONTEXT, 0), 0),
+            Ok(Some(PhysicalWrite {
+                offset: enable(CONTEXT, 0),
+                value: 1 << 12
+            }))
+        );
+    }
+
+    /// Virtual IRQ follows the interrupt line and is not reflected to physical PLIC.
+    #[test]
+    fn virtual_irq() {
+        let mut state = setup();
+        state.register_hypervisor_irq(12, CONTEXT);
+        state.register_virtual_irq(GUEST, 12);
+        state.register_virtual_irq(OTHER_GUEST, 12);
+
+        assert_eq!(state.write(GUEST, priority(12), 1), Ok(None));
+        assert_eq!(
+            state.write(GUEST, enable(CONTEXT, 0), 0x17fe),
+            Ok(Some(PhysicalWrite {
+                offset: enable(CONTEXT, 0),
+                value: 0x17fe
+            }))
+        );
+
+        state.set_virtual_irq_line(GUEST, 12, true);
+        assert!(state.interrupt_line(GUEST, CONTEXT));
+        assert!(!state.interrupt_line(OTHER_GUEST, CONTEXT));
+        assert_eq!(state.read(GUEST, claim(CONTEXT)), Ok(12));
+
+        // the line is still asserted, but claimed IRQ does not become pending until completion.
+        state.set_virtual_irq_line(GUEST, 12, true);
+        assert!(!state.interrupt_line(GUEST, CONTEXT));
+        assert_eq!(state.write(GUEST, claim(CONTEXT), 12), Ok(None));
+        state.set_virtual_irq_line(GUEST, 12, true);
+        assert!(state.interrupt_line(GUEST, CONTEXT));
+
+        state.set_virtual_irq_line(GUEST, 12, false);
+        assert!(!state.interrupt_line(GUEST, CONTEXT));
+    }
+
+    /// Each hart has machine context (even) and supervisor context (odd).
+    #[test]
+    fn context_id() {
+        assert_eq!(ContextId::new(0, false).raw(), 0);
+        assert_eq!(ContextId::new(0, true).raw(), 1);
+        assert_eq!(ContextId::new(3, false).raw(), 6);
+        assert_eq!(ContextId::new(3, true).raw(), CONTEXT + 6);
+    }
+}
@@ -0,0 +1,114 @@
+//! Test finisher: `sifive,test0` compatible device of QEMU virt machine.
+//!
+//! Writing to it terminates QEMU with the status.
+//! It is emulated for the guest that it is assigned to, so that test guests can report the results. (See `xtask`)
+
+use super::{
+    AccessWidth, DeviceEmulateError, EmulatedMmioDevice, MmioDevice, PTE_FLAGS_FOR_DEVICE,
+};
+use crate::memmap::{GuestPhysicalAddress, HostPhysicalAddress, MemoryMap};
+use fdt::Fdt;
+
+/// Offset of the register that terminates QEMU.
+const FINISHER_OFFSET: usize = 0;
+
+/// Test finisher.
+#[derive(Debug)]
+pub struct TestFinisher {
+    /// Base address of memory map.
+    base_addr: HostPhysicalAddress,
+    /// Memory map size.
+    size: usize,
+}
+
+impl TestFinisher {
+    /// Create emulated test finisher for the guest.
+    pub fn virtual_device(&self, guest_id: usize) -> VirtualTestFinisher {
+        VirtualTestFinisher {
+            base_addr: self.base_addr,
+            guest_id,
+        }
+    }
+}
+
+impl MmioDevice for TestFinisher {
+    fn new(device_tree: &Fdt, node_path: &str) -> Self {
+        let region = device_tree
+            .find_node(node_path)
+            .unwrap()
+            .reg()
+            .unwrap()
+            .next()
+            .unwrap();
+
+        TestFinisher {
+            base_addr: HostPhysicalAddress(region.starting_address as usize),
+            size: region.size.unwrap(),
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn paddr(&self) -> HostPhysicalAddress {
+        self.base_addr
+    }
+
+    fn memmap(&self) -> MemoryMap {
+        let vaddr = GuestPhysicalAddress(self.paddr().raw());
+        MemoryMap::new(
+            vaddr..vaddr + self.size(),
+            self.paddr()..self.paddr() + self.size(),
+            &PTE_FLAGS_FOR_DEVICE,
+        )
+    }
+}
+
+/// Test finisher that is emulated for a guest.
+///
+/// 32 bit writes of the guest are forwarded to the physical device. Other guests can not access it.
+#[derive(Debug)]
+#[allow(clippy::module_name_repetitions)]
+pub struct VirtualTestFinisher {
+    /// Base address of the physical device.
+    base_addr: HostPhysicalAddress,
+    /// Guest that the test finisher is assigned to.
+    guest_id: usize,
+}
+
+impl EmulatedMmioDevice for VirtualTestFinisher {
+    /// The register is write only and reads as zero.
+    fn read(
+        &mut self,
+        guest_id: usize,
+        offset: usize,
+        _width: AccessWidth,
+    ) -> Result<u64, DeviceEmulateError> {
+        if guest_id != self.guest_id || offset != FINISHER_OFFSET {
+            return Err(DeviceEmulateError::InvalidAddress);
+        }
+        Ok(0)
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn write(
+        &mut self,
+        guest_id: usize,
+        offset: usize,
+        width: AccessWidth,
+        value: u64,
+    ) -> Result<(), DeviceEmulateError> {
+        if guest_id != self.guest_id || offset != FINISHER_OFFSET {
+            return Err(DeviceEmulateError::InvalidAddress);
+        }
+        if width != AccessWidth::Word {
+            return Err(DeviceEmulateError::InvalidAccessWidth);
+        }
+
+        unsafe {
+            ((self.base_addr + FINISHER_OFFSET).raw() as *mut u32).write_volatile(value as u32);
+        }
+        Ok(())
+    }
+}
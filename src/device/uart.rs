@@ -1,20 +1,32 @@
 //! UART: Universal Asynchronous Receiver-Transmitter
+//!
+//! hikami owns the physical UART and each guest has an emulated 16550. (`VirtualUart`)
+//!
+//! - Output of all guests and hikami is written to the physical UART.
+//! - Input is delivered to one guest at a time.
+//!   `Ctrl-A n` switches the input to guest `n`, and `Ctrl-A Ctrl-A` sends `Ctrl-A` itself.
+//! - Receive interrupt of the physical UART is handled by hikami, and the interrupt of the emulated UART
+//!   is injected to the guest via virtual PLIC with the same IRQ number.
+//!   (Interrupts are not supported with APLIC yet, so the console input requires PLIC.)
 
-use super::{MmioDevice, PTE_FLAGS_FOR_DEVICE};
+mod register;
+mod virtual_uart;
+
+use super::{
+    AccessWidth, DeviceEmulateError, EmulatedMmioDevice, MmioDevice, PTE_FLAGS_FOR_DEVICE,
+};
 use crate::memmap::{GuestPhysicalAddress, HostPhysicalAddress, MemoryMap};
+use virtual_uart::VirtualUart;
 
+use alloc::vec::Vec;
 use core::cell::OnceCell;
 use core::fmt::{self, Write};
 use fdt::Fdt;
 use rustsbi::{Physical, SbiRet};
 use spin::Mutex;
 
-mod register {
-    //! Ref: [http://byterunner.com/16550.html](http://byterunner.com/16550.html)
-
-    /// LSR register offset.
-    pub const LSR_OFFSET: usize = 3;
-}
+/// Escape character of console multiplexer. (Ctrl-A)
+const ESCAPE_CHAR: u8 = 0x01;
 
 /// Print to standard output.
 #[macro_export]
@@ -25,8 +37,8 @@ macro_rules! print {
 /// Print with linebreak to standard output.
 #[macro_export]
 macro_rules! println {
-    ($fmt:expr) => (print!(concat!($fmt, "\n")));
-    ($fmt:expr, $($arg:tt)*) => (print!(concat!($fmt, "\n"), $($arg)*));
+    ($fmt:expr) => ($crate::print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::print!(concat!($fmt, "\n"), $($arg)*));
 }
 
 /// Print function calling from print macro
@@ -43,19 +55,38 @@ struct UartWriter;
 
 impl Write for UartWriter {
     /// Write string to tty via UART.
-    #[allow(clippy::cast_possible_wrap)]
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        let uart_addr = UART_ADDR.lock().get().unwrap().raw() as *mut u32;
+        let uart_addr = *UART_ADDR.lock().get().unwrap();
         for c in s.bytes() {
-            unsafe {
-                while (uart_addr.read_volatile() as i32) < 0 {}
-                uart_addr.write_volatile(u32::from(c));
-            }
+            put_byte(uart_addr, c);
         }
         Ok(())
     }
 }
 
+/// Write a byte to the physical UART.
+fn put_byte(base_addr: HostPhysicalAddress, byte: u8) {
+    let thr_ptr = (base_addr + register::THR_OFFSET).raw() as *mut u8;
+    let lsr_ptr = (base_addr + register::LSR_OFFSET).raw() as *const u8;
+    unsafe {
+        while lsr_ptr.read_volatile() & register::LSR_THRE == 0 {}
+        thr_ptr.write_volatile(byte);
+    }
+}
+
+/// Read a byte from the physical UART if received.
+fn get_byte(base_addr: HostPhysicalAddress) -> Option<u8> {
+    let rbr_ptr = (base_addr + register::RBR_OFFSET).raw() as *const u8;
+    let lsr_ptr = (base_addr + register::LSR_OFFSET).raw() as *const u8;
+    unsafe {
+        if lsr_ptr.read_volatile() & register::LSR_DR == 0 {
+            None
+        } else {
+            Some(rbr_ptr.read_volatile())
+        }
+    }
+}
+
 /// UART: Universal asynchronous receiver-transmitter
 #[derive(Debug)]
 pub struct Uart {
@@ -63,24 +94,156 @@ pub struct Uart {
     base_addr: HostPhysicalAddress,
     /// Memory map size.
     size: usize,
+    /// IRQ number of the UART.
+    irq: Option<usize>,
+    /// Emulated UART of each guest. (guest id, UART)
+    virtual_uarts: Vec<(usize, VirtualUart)>,
+    /// Guest ID that receives console input.
+    input_focus: Option<usize>,
+    /// Escape character has been received.
+    escaped: bool,
 }
 
 impl Uart {
-    /// Return address of LSR register.
-    pub fn lsr_addr(&self) -> HostPhysicalAddress {
-        self.base_addr + register::LSR_OFFSET
+    /// Return IRQ number of the UART.
+    pub fn irq(&self) -> Option<usize> {
+        self.irq
+    }
+
+    /// Read a byte from the physical UART if received.
+    pub fn get_char(&self) -> Option<u8> {
+        get_byte(self.base_addr)
+    }
+
+    /// Enable receive interrupt of the physical UART.
+    pub fn init(&self) {
+        /// IER: Enable Received Data Available Interrupt
+        const IER_ERBFI: u8 = 1;
+        /// FCR: enable and reset FIFOs.
+        const FCR_ENABLE_AND_RESET: u8 = 0b111;
+
+        unsafe {
+            ((self.base_addr + register::FCR_OFFSET).raw() as *mut u8)
+                .write_volatile(FCR_ENABLE_AND_RESET);
+            ((self.base_addr + register::IER_OFFSET).raw() as *mut u8).write_volatile(IER_ERBFI);
+        }
+    }
+
+    /// Create emulated UART for the guest.
+    ///
+    /// The first guest receives console input.
+    pub fn register_guest(&mut self, guest_id: usize) {
+        self.virtual_uarts.push((guest_id, VirtualUart::new()));
+        self.input_focus.get_or_insert(guest_id);
+    }
+
+    /// Return emulated UART of the guest.
+    fn virtual_uart(&mut self, guest_id: usize) -> Result<&mut VirtualUart, DeviceEmulateError> {
+        self.virtual_uarts
+            .iter_mut()
+            .find(|(id, _)| *id == guest_id)
+            .map(|(_, vuart)| vuart)
+            .ok_or(DeviceEmulateError::InvalidAddress)
+    }
+
+    /// Write a byte of the guest's debug console (SBI DBCN) to the physical UART.
+    pub fn console_write(&self, byte: u8) {
+        put_byte(self.base_addr, byte);
+    }
+
+    /// Read a byte of console input that is delivered to the guest. (SBI DBCN)
+    ///
+    /// It is taken from the receive buffer of the guest's emulated UART.
+    pub fn console_read(&mut self, guest_id: usize) -> Option<u8> {
+        let vuart = self.virtual_uart(guest_id).ok()?;
+        if vuart.read(register::LSR_OFFSET).ok()? & register::LSR_DR == 0 {
+            None
+        } else {
+            vuart.read(register::RBR_OFFSET).ok()
+        }
+    }
+
+    /// Return whether the interrupt line of the guest's emulated UART is asserted.
+    pub fn interrupt_line(&self, guest_id: usize) -> bool {
+        self.virtual_uarts
+            .iter()
+            .any(|(id, vuart)| *id == guest_id && vuart.interrupt_line())
+    }
+
+    /// Receive all input from the physical UART and deliver it to the focused guest.
+    ///
+    /// Return the guest ID that received input.
+    pub fn handle_input(&mut self) -> Option<usize> {
+        let mut received = None;
+        while let Some(byte) = get_byte(self.base_addr) {
+            if self.escaped {
+                self.escaped = false;
+                if byte != ESCAPE_CHAR {
+                    self.switch_input_focus(byte);
+                    continue;
+                }
+            } else if byte == ESCAPE_CHAR {
+                self.escaped = true;
+                continue;
+            }
+
+            if let Some(guest_id) = self.input_focus {
+                if let Ok(vuart) = self.virtual_uart(guest_id) {
+                    vuart.push_input(byte);
+                    received = Some(guest_id);
+                }
+            }
+        }
+
+        received
+    }
+
+    /// Switch console input to the guest that is selected by digit character.
+    fn switch_input_focus(&mut self, selector: u8) {
+        if !selector.is_ascii_digit() {
+            return;
+        }
+
+        let guest_id = usize::from(selector - b'0');
+        if self.virtual_uarts.iter().any(|(id, _)| *id == guest_id) {
+            self.input_focus = Some(guest_id);
+            crate::println!("\r\n[hikami] console input: guest {}\r", guest_id);
+        }
+    }
+}
+
+/// Registers are 8 bit wide. Wider accesses use the lowest byte.
+impl EmulatedMmioDevice for Uart {
+    fn read(
+        &mut self,
+        guest_id: usize,
+        offset: usize,
+        _width: AccessWidth,
+    ) -> Result<u64, DeviceEmulateError> {
+        self.virtual_uart(guest_id)?.read(offset).map(u64::from)
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn write(
+        &mut self,
+        guest_id: usize,
+        offset: usize,
+        _width: AccessWidth,
+        value: u64,
+    ) -> Result<(), DeviceEmulateError> {
+        let base_addr = self.base_addr;
+        if let Some(byte) = self.virtual_uart(guest_id)?.write(offset, value as u8)? {
+            put_byte(base_addr, byte);
+        }
+
+        Ok(())
     }
 }
 
 impl MmioDevice for Uart {
     fn new(device_tree: &Fdt, node_path: &str) -> Self {
-        let region = device_tree
-            .find_node(node_path)
-            .unwrap()
-            .reg()
-            .unwrap()
-            .next()
-            .unwrap();
+        let node = device_tree.find_node(node_path).unwrap();
+        let region = node.reg().unwrap().next().unwrap();
 
         UART_ADDR
             .lock()
@@ -89,6 +252,12 @@ impl MmioDevice for Uart {
         Uart {
             base_addr: HostPhysicalAddress(region.starting_address as usize),
             size: region.size.unwrap(),
+            irq: node
+                .property("interrupts")
+                .and_then(fdt::node::NodeProperty::as_usize),
+            virtual_uarts: Vec::new(),
+            input_focus: None,
+            escaped: false,
         }
     }
 
@@ -100,6 +269,9 @@ impl MmioDevice for Uart {
         self.base_addr
     }
 
+    /// Whole region of UART.
+    ///
+    /// It is not mapped to guests because all registers are emulated.
     fn memmap(&self) -> MemoryMap {
         let vaddr = GuestPhysicalAddress(self.paddr().raw());
         MemoryMap::new(
@@ -117,38 +289,28 @@ impl MmioDevice for Uart {
 impl rustsbi::Console for Uart {
     /// Write bytes to the debug console from input memory.
     fn write(&self, bytes: Physical<&[u8]>) -> SbiRet {
-        let uart_ptr = self.base_addr.raw() as *mut u32;
-        let uart_lsr_ptr = self.lsr_addr().raw() as *mut u32;
         let byte_data = unsafe {
             core::slice::from_raw_parts(bytes.phys_addr_lo() as *const u8, bytes.num_bytes())
         };
         for c in byte_data {
-            unsafe {
-                while (uart_lsr_ptr.read_volatile() >> 5 & 0x1) == 1 {}
-                uart_ptr.write_volatile(u32::from(*c));
-            }
+            put_byte(self.base_addr, *c);
         }
         SbiRet::success(0)
     }
 
     /// Read bytes from the debug console into an output memory.
-    #[allow(clippy::cast_possible_truncation)]
     fn read(&self, bytes: Physical<&mut [u8]>) -> SbiRet {
-        let uart_ptr = self.base_addr.raw() as *mut u32;
-        let uart_lsr_ptr = self.lsr_addr().raw() as *mut u32;
         let buffer = unsafe {
             core::slice::from_raw_parts_mut(bytes.phys_addr_lo() as *mut u8, bytes.num_bytes())
         };
 
         let mut count = 0usize;
-        unsafe {
-            for c in buffer {
-                if uart_lsr_ptr.read_volatile() & 0x1 == 1 {
-                    *c = uart_ptr.read_volatile() as u8;
-                    count += 1;
-                } else {
-                    break;
-                }
+        for c in buffer {
+            if let Some(byte) = get_byte(self.base_addr) {
+                *c = byte;
+                count += 1;
+            } else {
+                break;
             }
         }
         SbiRet::success(count)
@@ -156,12 +318,7 @@ impl rustsbi::Console for Uart {
 
     /// Write a single byte to the debug console.
     fn write_byte(&self, byte: u8) -> SbiRet {
-        let uart_ptr = self.base_addr.raw() as *mut u32;
-        let uart_lsr_ptr = self.lsr_addr().raw() as *mut u32;
-        unsafe {
-            while (uart_lsr_ptr.read_volatile() >> 5 & 0x1) == 1 {}
-            uart_ptr.write_volatile(u32::from(byte));
-        }
+        put_byte(self.base_addr, byte);
         SbiRet::success(0)
     }
 }
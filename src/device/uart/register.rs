@@ -0,0 +1,33 @@
+//! Registers of 16550 UART.
+//!
+//! Ref: [http://byterunner.com/16550.html](http://byterunner.com/16550.html)
+
+/// RBR register offset. (read, DLAB = 0)
+pub const RBR_OFFSET: usize = 0;
+/// THR register offset. (write, DLAB = 0)
+pub const THR_OFFSET: usize = 0;
+/// DLL register offset. (DLAB = 1)
+pub const DLL_OFFSET: usize = 0;
+/// IER register offset. (DLAB = 0)
+pub const IER_OFFSET: usize = 1;
+/// DLM register offset. (DLAB = 1)
+pub const DLM_OFFSET: usize = 1;
+/// IIR register offset. (read)
+pub const IIR_OFFSET: usize = 2;
+/// FCR register offset. (write)
+pub const FCR_OFFSET: usize = 2;
+/// LCR register offset.
+pub const LCR_OFFSET: usize = 3;
+/// MCR register offset.
+pub const MCR_OFFSET: usize = 4;
+/// LSR register offset.
+pub const LSR_OFFSET: usize = 5;
+/// MSR register offset.
+pub const MSR_OFFSET: usize = 6;
+/// SCR register offset.
+pub const SCR_OFFSET: usize = 7;
+
+/// LSR: Data Ready
+pub const LSR_DR: u8 = 1;
+/// LSR: Transmitter Holding Register Empty
+pub const LSR_THRE: u8 = 1 << 5;
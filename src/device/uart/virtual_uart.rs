@@ -0,0 +1,278 @@
+//! Hardware-independent emulation of 16550 UART.
+//!
+//! It takes register offsets and returns read values and the bytes that must be transmitted.
+//! Transmission completes immediately, so THR is always empty.
+//! In loopback mode (`MCR.LOOP`), transmitted bytes are received by the UART itself instead.
+
+use super::register::{
+    DLL_OFFSET, DLM_OFFSET, FCR_OFFSET, IER_OFFSET, IIR_OFFSET, LCR_OFFSET, LSR_OFFSET, MCR_OFFSET,
+    MSR_OFFSET, RBR_OFFSET, SCR_OFFSET, THR_OFFSET,
+};
+use crate::device::DeviceEmulateError;
+
+use alloc::collections::VecDeque;
+
+/// Size of receive buffer. Input is discarded if it is full.
+const RX_BUFFER_SIZE: usize = 256;
+
+/// IER: Enable Received Data Available Interrupt
+const IER_ERBFI: u8 = 1;
+/// IER: Enable Transmitter Holding Register Empty Interrupt
+const IER_ETBEI: u8 = 1 << 1;
+/// IER: writable bits.
+const IER_MASK: u8 = 0x0f;
+/// IIR: no interrupt is pending.
+const IIR_NO_INTERRUPT: u8 = 0x01;
+/// IIR: transmitter holding register empty.
+const IIR_THR_EMPTY: u8 = 0x02;
+/// IIR: received data available.
+const IIR_RX_DATA: u8 = 0x04;
+/// IIR: FIFOs are enabled.
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+/// FCR: FIFO Enable
+const FCR_FIFO_ENABLE: u8 = 1;
+/// FCR: Receiver FIFO Reset
+const FCR_RX_RESET: u8 = 1 << 1;
+/// LCR: Divisor Latch Access Bit
+const LCR_DLAB: u8 = 1 << 7;
+/// MCR: Loopback mode
+const MCR_LOOP: u8 = 1 << 4;
+/// LSR: Data Ready
+const LSR_DR: u8 = 1;
+/// LSR: Transmitter Holding Register Empty
+const LSR_THRE: u8 = 1 << 5;
+/// LSR: Transmitter Empty
+const LSR_TEMT: u8 = 1 << 6;
+/// MSR: CTS, DSR and DCD are always asserted.
+const MSR_CONNECTED: u8 = 0xb0;
+
+/// Emulated 16550 UART of a guest.
+#[derive(Debug)]
+pub struct VirtualUart {
+    /// Interrupt Enable Register
+    ier: u8,
+    /// Line Control Register
+    lcr: u8,
+    /// Modem Control Register
+    mcr: u8,
+    /// Scratch Register
+    scr: u8,
+    /// Divisor Latch (low byte, high byte)
+    divisor: [u8; 2],
+    /// Whether FIFOs are enabled by FCR.
+    fifo_enabled: bool,
+    /// Received bytes that are not read by the guest.
+    rx_buffer: VecDeque<u8>,
+    /// THR empty interrupt is pending.
+    thr_empty_pending: bool,
+}
+
+impl VirtualUart {
+    /// Constructor for `VirtualUart`.
+    pub fn new() -> Self {
+        VirtualUart {
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            divisor: [0; 2],
+            fifo_enabled: false,
+            rx_buffer: VecDeque::new(),
+            thr_empty_pending: false,
+        }
+    }
+
+    /// Receive a byte from console.
+    pub fn push_input(&mut self, byte: u8) {
+        if self.rx_buffer.len() < RX_BUFFER_SIZE {
+            self.rx_buffer.push_back(byte);
+        }
+    }
+
+    /// Return the pending interrupt of the highest priority. (value of IIR without FIFO bits)
+    fn pending_interrupt(&self) -> u8 {
+        if self.ier & IER_ERBFI != 0 && !self.rx_buffer.is_empty() {
+            IIR_RX_DATA
+        } else if self.ier & IER_ETBEI != 0 && self.thr_empty_pending {
+            IIR_THR_EMPTY
+        } else {
+            IIR_NO_INTERRUPT
+        }
+    }
+
+    /// Return whether the interrupt line is asserted.
+    pub fn interrupt_line(&self) -> bool {
+        self.pending_interrupt() != IIR_NO_INTERRUPT
+    }
+
+    /// Return whether divisor latch is selected.
+    fn is_dlab(&self) -> bool {
+        self.lcr & LCR_DLAB != 0
+    }
+
+    /// Emulate reading register.
+    pub fn read(&mut self, offset: usize) -> Result<u8, DeviceEmulateError> {
+        match offset {
+            DLL_OFFSET if self.is_dlab() => Ok(self.divisor[0]),
+            DLM_OFFSET if self.is_dlab() => Ok(self.divisor[1]),
+            RBR_OFFSET => Ok(self.rx_buffer.pop_front().unwrap_or(0)),
+            IER_OFFSET => Ok(self.ier),
+            IIR_OFFSET => {
+                let interrupt = self.pending_interrupt();
+                // reading IIR clears THR empty interrupt.
+                if interrupt == IIR_THR_EMPTY {
+                    self.thr_empty_pending = false;
+                }
+                Ok(interrupt
+                    | if self.fifo_enabled {
+                        IIR_FIFO_ENABLED
+                    } else {
+                        0
+                    })
+            }
+            LCR_OFFSET => Ok(self.lcr),
+            MCR_OFFSET => Ok(self.mcr),
+            LSR_OFFSET => {
+                Ok(LSR_THRE | LSR_TEMT | if self.rx_buffer.is_empty() { 0 } else { LSR_DR })
+            }
+            MSR_OFFSET => {
+                if self.mcr & MCR_LOOP == 0 {
+                    Ok(MSR_CONNECTED)
+                } else {
+                    // DTR -> DSR, RTS -> CTS, OUT1 -> RI, OUT2 -> DCD
+                    let mcr = self.mcr;
+                    Ok((mcr & 0b01) << 5 | (mcr & 0b10) << 3 | (mcr & 0b1100) << 4)
+                }
+            }
+            SCR_OFFSET => Ok(self.scr),
+            _ => Err(DeviceEmulateError::InvalidAddress),
+        }
+    }
+
+    /// Emulate writing register.
+    ///
+    /// Return the byte that is transmitted. (`None` in loopback mode)
+    pub fn write(&mut self, offset: usize, value: u8) -> Result<Option<u8>, DeviceEmulateError> {
+        match offset {
+            DLL_OFFSET if self.is_dlab() => self.divisor[0] = value,
+            DLM_OFFSET if self.is_dlab() => self.divisor[1] = value,
+            THR_OFFSET => {
+                // transmission completes immediately.
+                self.thr_empty_pending = true;
+                if self.mcr & MCR_LOOP != 0 {
+                    self.push_input(value);
+                    return Ok(None);
+                }
+                return Ok(Some(value));
+            }
+            IER_OFFSET => {
+                // enabling THR empty interrupt raises it because THR is always empty.
+                if self.ier & IER_ETBEI == 0 && value & IER_ETBEI != 0 {
+                    self.thr_empty_pending = true;
+                }
+                self.ier = value & IER_MASK;
+            }
+            FCR_OFFSET => {
+                self.fifo_enabled = value & FCR_FIFO_ENABLE != 0;
+                if value & FCR_RX_RESET != 0 {
+                    self.rx_buffer.clear();
+                }
+            }
+            LCR_OFFSET => self.lcr = value,
+            MCR_OFFSET => self.mcr = value & 0x1f,
+            // LSR and MSR are read only.
+            LSR_OFFSET | MSR_OFFSET => (),
+            SCR_OFFSET => self.scr = value,
+            _ => return Err(DeviceEmulateError::InvalidAddress),
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes written to THR are received in loopback mode instead of transmitted.
+    #[test]
+    fn loopback() {
+        let mut vuart = VirtualUart::new();
+        assert_eq!(vuart.write(THR_OFFSET, b'a'), Ok(Some(b'a')));
+        assert_eq!(vuart.read(LSR_OFFSET), Ok(LSR_THRE | LSR_TEMT));
+
+        vuart.write(MCR_OFFSET, MCR_LOOP).unwrap();
+        assert_eq!(vuart.write(THR_OFFSET, b'b'), Ok(None));
+        assert_eq!(vuart.read(LSR_OFFSET), Ok(LSR_THRE | LSR_TEMT | LSR_DR));
+        assert_eq!(vuart.read(RBR_OFFSET), Ok(b'b'));
+        assert_eq!(vuart.read(LSR_OFFSET), Ok(LSR_THRE | LSR_TEMT));
+    }
+
+    /// DLAB switches offset 0 and 1 between RBR/THR/IER and divisor latch.
+    #[test]
+    fn divisor_latch() {
+        let mut vuart = VirtualUart::new();
+        vuart.write(IER_OFFSET, IER_ERBFI).unwrap();
+
+        vuart.write(LCR_OFFSET, LCR_DLAB | 0x03).unwrap();
+        assert_eq!(vuart.write(DLL_OFFSET, 0x12), Ok(None));
+        vuart.write(DLM_OFFSET, 0x34).unwrap();
+        assert_eq!(vuart.read(DLL_OFFSET), Ok(0x12));
+        assert_eq!(vuart.read(DLM_OFFSET), Ok(0x34));
+
+        vuart.write(LCR_OFFSET, 0x03).unwrap();
+        assert_eq!(vuart.read(IER_OFFSET), Ok(IER_ERBFI));
+        vuart.push_input(b'x');
+        assert_eq!(vuart.read(RBR_OFFSET), Ok(b'x'));
+
+        vuart.write(LCR_OFFSET, LCR_DLAB).unwrap();
+        assert_eq!(vuart.read(DLL_OFFSET), Ok(0x12));
+        assert_eq!(vuart.read(DLM_OFFSET), Ok(0x34));
+    }
+
+    /// Received data interrupt has priority over THR empty, which is cleared by reading IIR.
+    #[test]
+    fn interrupt_priority() {
+        let mut vuart = VirtualUart::new();
+        assert_eq!(vuart.read(IIR_OFFSET), Ok(IIR_NO_INTERRUPT));
+
+        vuart.write(IER_OFFSET, IER_ERBFI | IER_ETBEI).unwrap();
+        vuart.push_input(b'x');
+        assert!(vuart.interrupt_line());
+        assert_eq!(vuart.read(IIR_OFFSET), Ok(IIR_RX_DATA));
+        // reading IIR does not clear received data interrupt.
+        assert_eq!(vuart.read(IIR_OFFSET), Ok(IIR_RX_DATA));
+
+        assert_eq!(vuart.read(RBR_OFFSET), Ok(b'x'));
+        assert_eq!(vuart.read(IIR_OFFSET), Ok(IIR_THR_EMPTY));
+        assert_eq!(vuart.read(IIR_OFFSET), Ok(IIR_NO_INTERRUPT));
+        assert!(!vuart.interrupt_line());
+
+        // transmission raises THR empty interrupt again.
+        vuart.write(THR_OFFSET, b'y').unwrap();
+        assert!(vuart.interrupt_line());
+        assert_eq!(vuart.read(IIR_OFFSET), Ok(IIR_THR_EMPTY));
+    }
+
+    /// FCR enables FIFOs and resets receive buffer.
+    #[test]
+    fn fifo_control() {
+        let mut vuart = VirtualUart::new();
+        vuart.write(FCR_OFFSET, FCR_FIFO_ENABLE).unwrap();
+        assert_eq!(
+            vuart.read(IIR_OFFSET),
+            Ok(IIR_FIFO_ENABLED | IIR_NO_INTERRUPT)
+        );
+
+        vuart.push_input(b'x');
+        vuart.push_input(b'y');
+        vuart
+            .write(FCR_OFFSET, FCR_FIFO_ENABLE | FCR_RX_RESET)
+            .unwrap();
+        assert_eq!(vuart.read(LSR_OFFSET), Ok(LSR_THRE | LSR_TEMT));
+        assert_eq!(vuart.read(RBR_OFFSET), Ok(0));
+
+        vuart.write(FCR_OFFSET, 0).unwrap();
+        assert_eq!(vuart.read(IIR_OFFSET), Ok(IIR_NO_INTERRUPT));
+    }
+}
@@ -2,31 +2,42 @@
 //! Ref: [https://github.com/riscv/riscv-cfi/releases/download/v1.0/riscv-cfi.pdf](https://github.com/riscv/riscv-cfi/releases/download/v1.0/riscv-cfi.pdf)
 
 use super::{pseudo_vs_exception, EmulateExtension, EmulatedCsr};
+use crate::guest::context::Context;
 use crate::memmap::{
-    page_table::{g_stage_trans_addr, vs_stage_trans_addr},
-    GuestVirtualAddress,
+    guest_access::{read_guest_physical, write_guest_physical, GuestAccessFault},
+    page_table::{
+        vs_stage::{AccessType, Privilege, TranslationError},
+        vs_stage_trans_addr,
+    },
+    GuestPhysicalAddress, GuestVirtualAddress,
 };
 use crate::HYPERVISOR_DATA;
 
-use core::cell::OnceCell;
 use raki::{Instruction, OpcodeKind, ZicfissOpcode, ZicsrOpcode};
-use spin::Mutex;
-
-/// Singleton for Zicfiss.
-/// TODO: change `OnceCell` to `LazyCell` when stable `LazyCell::force_mut`.
-pub static mut ZICFISS_DATA: Mutex<OnceCell<Zicfiss>> = Mutex::new(OnceCell::new());
 
 /// Software-check exception. (cause value)
 const SOFTWARE_CHECK_EXCEPTION: usize = 18;
-/// Store/AMO page fault
-const STORE_AMO_PAGE_FAULT: usize = 15;
 /// Shadow stack fault. (tval value)
 const SHADOW_STACK_FAULT: usize = 3;
 
-/// Singleton for Zicfiss extension
+/// Back the page if it is demand-paged guest memory.
+///
+/// Return `true` if the page is backed.
+fn back_demand_page(gpa: GuestPhysicalAddress) -> bool {
+    unsafe { HYPERVISOR_DATA.lock() }
+        .get()
+        .unwrap()
+        .guest()
+        .back_demand_page(gpa)
+}
+
+/// Emulated Zicfiss extension of a vCPU.
+///
+/// It is kept in `Guest::zicfiss`, so that each vCPU has its own shadow stack enables
+/// and they are switched with the vCPU.
+/// Shadow stack pointer (`ssp`) is a part of the guest context. (`Context::ssp`)
+#[derive(Debug, Default, Copy, Clone)]
 pub struct Zicfiss {
-    /// Shadow stack pointer
-    pub ssp: EmulatedCsr,
     /// Shadow Stack Enable in henvcfg (for VS-mode)
     pub henv_sse: bool,
     /// Shadow Stack Enable in senvcfg (for VU-mode)
@@ -34,56 +45,86 @@ pub struct Zicfiss {
 }
 
 impl Zicfiss {
-    /// Constructor for `Zicfiss`.
+    /// Constructor for `Zicfiss`. (shadow stack is disabled)
     pub fn new() -> Self {
         Zicfiss {
-            ssp: EmulatedCsr(0),
             henv_sse: false,
             senv_sse: false,
         }
     }
 
-    /// Return host physical shadow stack pointer as `*mut usize`.
-    #[allow(clippy::similar_names, clippy::cast_possible_truncation)]
-    fn ssp_hp_ptr(&self) -> *mut usize {
-        if let Ok(gpa) = vs_stage_trans_addr(GuestVirtualAddress(self.ssp.0 as usize)) {
-            let hpa = g_stage_trans_addr(gpa);
-            hpa.0 as *mut usize
+    /// Return guest physical address of shadow stack access.
+    ///
+    /// Shadow stack pages (`xwr = 010`) are reserved encoding for hardware without Zicfiss,
+    /// so VS-stage page table is walked by software instead of `hlv`/`hsv`.
+    /// Translation error is raised to the guest as page fault or access fault.
+    /// Demand-paged VS-stage page table is backed before the walk.
+    fn ssp_gpa(context: Context, ssp: usize) -> GuestPhysicalAddress {
+        let privilege = if context.sstatus() >> 8 & 0x1 == 1 {
+            Privilege::Supervisor
         } else {
-            unsafe {
-                HYPERVISOR_DATA.force_unlock();
-                ZICFISS_DATA.force_unlock();
+            Privilege::User
+        };
+
+        loop {
+            match vs_stage_trans_addr(GuestVirtualAddress(ssp), privilege, AccessType::ShadowStack)
+            {
+                Ok(gpa) => return gpa,
+                // demand-paged guest memory is backed on the first access. (retry the translation)
+                Err(TranslationError::GuestPageFault(gpa)) if back_demand_page(gpa) => {}
+                Err(err) => {
+                    unsafe {
+                        HYPERVISOR_DATA.force_unlock();
+                    }
+                    pseudo_vs_exception(err.exception_code(AccessType::ShadowStack), ssp);
+                }
             }
-            pseudo_vs_exception(STORE_AMO_PAGE_FAULT, self.ssp.0 as usize);
         }
     }
 
-    /// Push value to shadow stack
-    pub fn ss_push(&mut self, value: usize) {
+    /// Handle the fault of shadow stack access to guest physical address.
+    ///
+    /// Demand-paged shadow stack is backed so that the access can be retried.
+    /// Otherwise, store/AMO access fault is raised to the guest.
+    fn access_fault(fault: GuestAccessFault, ssp: usize) {
+        if fault.is_guest_page_fault() && back_demand_page(GuestPhysicalAddress(fault.addr.0)) {
+            return;
+        }
         unsafe {
-            self.ssp = EmulatedCsr(
-                (self.ssp.0 as *const usize).byte_sub(core::mem::size_of::<usize>()) as u64,
-            );
-            self.ssp_hp_ptr().write_volatile(value);
+            HYPERVISOR_DATA.force_unlock();
+        }
+        pseudo_vs_exception(AccessType::ShadowStack.access_fault_code(), ssp);
+    }
+
+    /// Push value to shadow stack
+    pub fn ss_push(context: &mut Context, value: usize) {
+        let ssp = context.ssp() - core::mem::size_of::<usize>();
+        let gpa = Self::ssp_gpa(*context, ssp);
+        while let Err(fault) = write_guest_physical(gpa, value) {
+            Self::access_fault(fault, ssp);
         }
+        context.set_ssp(ssp);
     }
 
     /// Pop value from shadow stack
-    pub fn ss_pop(&mut self) -> usize {
-        unsafe {
-            let pop_value = self.ssp_hp_ptr().read_volatile();
-            self.ssp = EmulatedCsr(
-                (self.ssp.0 as *const usize).byte_add(core::mem::size_of::<usize>()) as u64,
-            );
+    pub fn ss_pop(context: &mut Context) -> usize {
+        let ssp = context.ssp();
+        let gpa = Self::ssp_gpa(*context, ssp);
+        let pop_value = loop {
+            match read_guest_physical(gpa) {
+                Ok(value) => break value,
+                Err(fault) => Self::access_fault(fault, ssp),
+            }
+        };
+        context.set_ssp(ssp + core::mem::size_of::<usize>());
 
-            pop_value
-        }
+        pop_value
     }
 
     /// Is shadow stack enabled?
     ///
     /// Chack corresponding `SSE` bit of xenvcfg.
-    fn is_ss_enable(&self, sstatus: usize) -> bool {
+    fn is_ss_enable(self, sstatus: usize) -> bool {
         let spp = sstatus >> 8 & 0x1;
         if spp == 0 {
             self.senv_sse
@@ -108,23 +149,22 @@ impl EmulateExtension for Zicfiss {
             OpcodeKind::Zicfiss(ZicfissOpcode::SSPUSH) => {
                 if self.is_ss_enable(sstatus) {
                     let push_value = context.xreg(inst.rs2.unwrap());
-                    self.ss_push(push_value as usize);
+                    Self::ss_push(&mut context, push_value as usize);
                 }
             }
             OpcodeKind::Zicfiss(ZicfissOpcode::C_SSPUSH) => {
                 if self.is_ss_enable(sstatus) {
                     let push_value = context.xreg(inst.rd.unwrap());
-                    self.ss_push(push_value as usize);
+                    Self::ss_push(&mut context, push_value as usize);
                 }
             }
             OpcodeKind::Zicfiss(ZicfissOpcode::SSPOPCHK) => {
                 if self.is_ss_enable(sstatus) {
-                    let pop_value = self.ss_pop();
+                    let pop_value = Self::ss_pop(&mut context);
                     let expected_value = context.xreg(inst.rs1.unwrap()) as usize;
                     if pop_value != expected_value {
                         unsafe {
                             HYPERVISOR_DATA.force_unlock();
-                            ZICFISS_DATA.force_unlock();
                         }
                         pseudo_vs_exception(SOFTWARE_CHECK_EXCEPTION, SHADOW_STACK_FAULT)
                     }
@@ -132,12 +172,11 @@ impl EmulateExtension for Zicfiss {
             }
             OpcodeKind::Zicfiss(ZicfissOpcode::C_SSPOPCHK) => {
                 if self.is_ss_enable(sstatus) {
-                    let pop_value = self.ss_pop();
+                    let pop_value = Self::ss_pop(&mut context);
                     let expected_value = context.xreg(inst.rd.unwrap()) as usize;
                     if pop_value != expected_value {
                         unsafe {
                             HYPERVISOR_DATA.force_unlock();
-                            ZICFISS_DATA.force_unlock();
                         }
                         pseudo_vs_exception(SOFTWARE_CHECK_EXCEPTION, SHADOW_STACK_FAULT)
                     }
@@ -145,7 +184,7 @@ impl EmulateExtension for Zicfiss {
             }
             OpcodeKind::Zicfiss(ZicfissOpcode::SSRDP) => {
                 if self.is_ss_enable(sstatus) {
-                    context.set_xreg(inst.rd.unwrap(), self.ssp.0);
+                    context.set_xreg(inst.rd.unwrap(), context.ssp() as u64);
                 } else {
                     context.set_xreg(inst.rd.unwrap(), 0);
                 }
@@ -156,6 +195,7 @@ impl EmulateExtension for Zicfiss {
     }
 
     /// Emulate Zicfiss CSRs access.
+    #[allow(clippy::cast_possible_truncation)]
     fn csr(&mut self, inst: &Instruction) {
         /// Register number of `Shadow Stack Pointer`.
         const CSR_SSP: usize = 0x11;
@@ -165,36 +205,40 @@ impl EmulateExtension for Zicfiss {
 
         let csr_num = inst.rs2.unwrap();
         match csr_num {
-            CSR_SSP => match inst.opc {
-                OpcodeKind::Zicsr(ZicsrOpcode::CSRRW) => {
-                    let rs1 = context.xreg(inst.rs1.unwrap());
-                    context.set_xreg(inst.rd.unwrap(), self.ssp.bits());
-                    self.ssp.write(rs1);
-                }
-                OpcodeKind::Zicsr(ZicsrOpcode::CSRRS) => {
-                    let rs1 = context.xreg(inst.rs1.unwrap());
-                    context.set_xreg(inst.rd.unwrap(), self.ssp.bits());
-                    self.ssp.set(rs1);
-                }
-                OpcodeKind::Zicsr(ZicsrOpcode::CSRRC) => {
-                    let rs1 = context.xreg(inst.rs1.unwrap());
-                    context.set_xreg(inst.rd.unwrap(), self.ssp.bits());
-                    self.ssp.clear(rs1);
-                }
-                OpcodeKind::Zicsr(ZicsrOpcode::CSRRWI) => {
-                    context.set_xreg(inst.rd.unwrap(), self.ssp.bits());
-                    self.ssp.write(inst.rs1.unwrap() as u64);
-                }
-                OpcodeKind::Zicsr(ZicsrOpcode::CSRRSI) => {
-                    context.set_xreg(inst.rd.unwrap(), self.ssp.bits());
-                    self.ssp.set(inst.rs1.unwrap() as u64);
-                }
-                OpcodeKind::Zicsr(ZicsrOpcode::CSRRCI) => {
-                    context.set_xreg(inst.rd.unwrap(), self.ssp.bits());
-                    self.ssp.clear(inst.rs1.unwrap() as u64);
+            CSR_SSP => {
+                let mut ssp = EmulatedCsr(context.ssp() as u64);
+                match inst.opc {
+                    OpcodeKind::Zicsr(ZicsrOpcode::CSRRW) => {
+                        let rs1 = context.xreg(inst.rs1.unwrap());
+                        context.set_xreg(inst.rd.unwrap(), ssp.bits());
+                        ssp.write(rs1);
+                    }
+                    OpcodeKind::Zicsr(ZicsrOpcode::CSRRS) => {
+                        let rs1 = context.xreg(inst.rs1.unwrap());
+                        context.set_xreg(inst.rd.unwrap(), ssp.bits());
+                        ssp.set(rs1);
+                    }
+                    OpcodeKind::Zicsr(ZicsrOpcode::CSRRC) => {
+                        let rs1 = context.xreg(inst.rs1.unwrap());
+                        context.set_xreg(inst.rd.unwrap(), ssp.bits());
+                        ssp.clear(rs1);
+                    }
+                    OpcodeKind::Zicsr(ZicsrOpcode::CSRRWI) => {
+                        context.set_xreg(inst.rd.unwrap(), ssp.bits());
+                        ssp.write(inst.rs1.unwrap() as u64);
+                    }
+                    OpcodeKind::Zicsr(ZicsrOpcode::CSRRSI) => {
+                        context.set_xreg(inst.rd.unwrap(), ssp.bits());
+                        ssp.set(inst.rs1.unwrap() as u64);
+                    }
+                    OpcodeKind::Zicsr(ZicsrOpcode::CSRRCI) => {
+                        context.set_xreg(inst.rd.unwrap(), ssp.bits());
+                        ssp.clear(inst.rs1.unwrap() as u64);
+                    }
+                    _ => unreachable!(),
                 }
-                _ => unreachable!(),
-            },
+                context.set_ssp(ssp.bits() as usize);
+            }
             unsupported_csr_num => {
                 unimplemented!("unsupported CSRs: {unsupported_csr_num:#x}")
             }
@@ -1,24 +1,96 @@
 //! Guest data of each HARTs.
 
+pub mod config;
 pub mod context;
-
-use crate::memmap::page_table::sv39x4::FIRST_LV_PAGE_TABLE_LEN;
+pub mod device_tree;
+pub mod image;
+pub mod lazy_context;
+pub mod scheduler;
+pub mod vcpu;
+
+use crate::emulate_extension::zicfiss::Zicfiss;
+use crate::h_extension::csrs::{hgatp, hstatus};
+use crate::h_extension::instruction::{hfence_gvma, hfence_gvma_all, hfence_vvma_all};
 use crate::memmap::{
     constant::guest_memory,
     page_table,
-    page_table::{constants::PAGE_SIZE, PageTableEntry, PteFlag},
+    page_table::{constants::PAGE_SIZE, PteFlag},
     GuestPhysicalAddress, HostPhysicalAddress, MemoryMap,
 };
-use crate::PageBlock;
+use crate::{hs_stack_top, PageBlock, FRAME_ALLOCATOR};
 use context::{Context, ContextData};
+use image::GuestImageFormat;
+use lazy_context::LazyContext;
 
+use core::cell::OnceCell;
 use core::ops::Range;
 use elf::{endian::AnyEndian, ElfBytes};
+use spin::Mutex;
+
+/// Whether each guest has its own VMID. (set by `init_vmid`)
+///
+/// If VMIDLEN is too short for the guest ids, all guests share VMID 0.
+static VMID_ENABLED: Mutex<OnceCell<bool>> = Mutex::new(OnceCell::new());
+
+/// Decide whether guest ids can be used as VMID.
+///
+/// Guest id is used as VMID if all ids fit in VMIDLEN.
+/// Otherwise (e.g. VMIDLEN = 0), all guests use VMID 0 and G-stage TLB is flushed on each guest switch.
+/// It overwrites hgatp, so it must be called before enabling G-stage translation.
+pub fn init_vmid(max_guest_id: usize) {
+    let vmid_len = hgatp::vmid_len();
+    let enabled = max_guest_id < 1 << vmid_len;
+    if !enabled {
+        crate::println!(
+            "[hikami] VMIDLEN ({} bits) is too short for guest id {}: VMID is not used\r",
+            vmid_len,
+            max_guest_id
+        );
+    }
+    VMID_ENABLED.lock().get_or_init(|| enabled);
+}
+
+/// Whether each guest has its own VMID.
+fn vmid_enabled() -> bool {
+    *VMID_ENABLED.lock().get().expect("VMID is not initialized")
+}
+
+/// Hart state of guest for SBI HSM (Hart State Management) extension.
+///
+/// Ref: [https://github.com/riscv-non-isa/riscv-sbi-doc/releases/download/v2.0/riscv-sbi.pdf](https://github.com/riscv-non-isa/riscv-sbi-doc/releases/download/v2.0/riscv-sbi.pdf) p.24
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum HartState {
+    /// The hart is running guest.
+    Started,
+    /// The hart is waiting for `sbi_hart_start` from guest.
+    Stopped,
+    /// `sbi_hart_start` has been requested but the hart has not entered guest yet.
+    StartPending {
+        /// Address where the hart starts executing. (GPA)
+        start_addr: GuestPhysicalAddress,
+        /// Value passed to `a1` register.
+        opaque: usize,
+    },
+}
+
+impl HartState {
+    /// Return state id defined in SBI specification.
+    pub fn state_id(self) -> usize {
+        use sbi_spec::hsm::hart_state::{STARTED, START_PENDING, STOPPED};
+        match self {
+            HartState::Started => STARTED,
+            HartState::Stopped => STOPPED,
+            HartState::StartPending { .. } => START_PENDING,
+        }
+    }
+}
 
 /// Guest Information
 #[derive(Debug)]
 pub struct Guest {
-    /// Guest ID
+    /// HART id that the guest runs on.
+    hart_id: usize,
+    /// Guest ID (= VMID)
     #[allow(clippy::struct_field_names)]
     guest_id: usize,
     /// Page table that is passed to guest address
@@ -29,88 +101,222 @@ pub struct Guest {
     stack_top_addr: HostPhysicalAddress,
     /// Allocated memory region
     memory_region: Range<GuestPhysicalAddress>,
+    /// Whether the memory region is backed on the first access. (demand paging)
+    demand_paging: bool,
     /// Guest context data
     pub context: Context,
+    /// Floating-point and vector context.
+    pub lazy_context: LazyContext,
+    /// Emulated shadow stack extension. (enabled by FWFT and `senvcfg`)
+    pub zicfiss: Zicfiss,
+    /// Hart state for HSM extension.
+    hart_state: HartState,
+    /// Time of the timer interrupt set by SBI `set_timer`. (`usize::MAX`: not set)
+    timer: usize,
+    /// Scheduling priority of the vCPU. (higher value runs first)
+    priority: usize,
+    /// Guest interrupt file of IMSIC that is assigned to the vCPU. (0: not assigned)
+    vgein: usize,
 }
 
 impl Guest {
     /// Initialize `Guest`.
     ///
+    /// - Allocate root page table.
     /// - Zero filling root page table.
     /// - Map guest dtb to guest memory space.
     pub fn new(
         hart_id: usize,
-        root_page_table: &'static [PageTableEntry; FIRST_LV_PAGE_TABLE_LEN],
-        guest_dtb: &'static [u8; include_bytes!("../guest.dtb").len()],
+        guest_id: usize,
+        guest_dtb: &[u8],
         memory_region: Range<GuestPhysicalAddress>,
     ) -> Self {
-        let stack_top_addr =
-            unsafe { HostPhysicalAddress(core::ptr::addr_of!(crate::_stack_start) as usize) };
-        let page_table_addr = HostPhysicalAddress(root_page_table.as_ptr() as usize);
+        let stack_top_addr = hs_stack_top(hart_id);
+        let page_table_addr = page_table::g_stage::allocate_root_page_table();
 
-        page_table::sv39x4::initialize_page_table(page_table_addr);
+        page_table::g_stage::initialize_page_table(page_table_addr);
 
         let dtb_addr = Self::map_guest_dtb(hart_id, page_table_addr, guest_dtb);
 
         Guest {
-            guest_id: hart_id,
-            page_table_addr: HostPhysicalAddress(root_page_table.as_ptr() as usize),
+            hart_id,
+            guest_id,
+            page_table_addr,
             dtb_addr,
             stack_top_addr,
             memory_region,
+            demand_paging: false,
             context: Context::new(stack_top_addr - core::mem::size_of::<ContextData>()),
+            lazy_context: LazyContext::new(),
+            zicfiss: Zicfiss::new(),
+            hart_state: HartState::Started,
+            timer: usize::MAX,
+            priority: 0,
+            vgein: 0,
+        }
+    }
+
+    /// Initialize `Guest` for secondary hart.
+    ///
+    /// It shares page table, device tree and memory region with the guest of boot hart.
+    /// The hart waits for `sbi_hart_start` from the guest (`HartState::Stopped`).
+    pub fn new_secondary(hart_id: usize, boot_hart_guest: &Guest) -> Self {
+        let stack_top_addr = hs_stack_top(hart_id);
+
+        Guest {
+            hart_id,
+            guest_id: boot_hart_guest.guest_id,
+            page_table_addr: boot_hart_guest.page_table_addr,
+            dtb_addr: boot_hart_guest.dtb_addr,
+            stack_top_addr,
+            memory_region: boot_hart_guest.memory_region.clone(),
+            demand_paging: boot_hart_guest.demand_paging,
+            context: Context::new(stack_top_addr - core::mem::size_of::<ContextData>()),
+            lazy_context: LazyContext::new(),
+            zicfiss: Zicfiss::new(),
+            hart_state: HartState::Stopped,
+            timer: usize::MAX,
+            priority: boot_hart_guest.priority,
+            vgein: 0,
         }
     }
 
     /// Map guest device tree region
+    ///
+    /// The device tree generated by `device_tree::generate` is copied to physical frames.
     fn map_guest_dtb(
         hart_id: usize,
         page_table_addr: HostPhysicalAddress,
-        guest_dtb: &'static [u8; include_bytes!("../guest.dtb").len()],
+        guest_dtb: &[u8],
     ) -> GuestPhysicalAddress {
         use PteFlag::{Accessed, Dirty, Read, User, Valid, Write};
 
-        assert!(guest_dtb.len() < guest_memory::GUEST_DTB_SIZE_PER_HART);
+        assert!(
+            guest_dtb.len() <= guest_memory::GUEST_DTB_SIZE_PER_HART,
+            "guest device tree is too large"
+        );
 
         let guest_dtb_gpa =
             guest_memory::DRAM_BASE + hart_id * guest_memory::GUEST_DTB_SIZE_PER_HART;
         let aligned_dtb_size = guest_dtb.len().div_ceil(PAGE_SIZE) * PAGE_SIZE;
 
-        for offset in (0..aligned_dtb_size).step_by(PAGE_SIZE) {
-            let guest_physical_addr = guest_dtb_gpa + offset;
-
-            // allocate memory from heap
-            let aligned_page_size_block_addr = PageBlock::alloc();
-
-            // copy elf segment to new heap block
-            unsafe {
-                core::ptr::copy(
-                    guest_dtb.as_ptr().byte_add(offset),
-                    aligned_page_size_block_addr.raw() as *mut u8,
-                    PAGE_SIZE,
-                );
-            }
-
-            // create memory mapping
-            page_table::sv39x4::generate_page_table(
-                page_table_addr,
-                &[MemoryMap::new(
-                    guest_physical_addr..guest_physical_addr + PAGE_SIZE,
-                    aligned_page_size_block_addr..aligned_page_size_block_addr + PAGE_SIZE,
-                    // allow writing data to dtb to modify device tree on guest OS.
-                    &[Dirty, Accessed, Write, Read, User, Valid],
-                )],
-            );
-        }
+        // allocate memory from physical frames
+        let dtb_block_addr = FRAME_ALLOCATOR
+            .lock()
+            .alloc(aligned_dtb_size / PAGE_SIZE, PAGE_SIZE)
+            .expect("failed to allocate guest device tree");
+
+        // copy device tree to new block
+        // the rest of the last page is also mapped, so the whole block is zeroed first.
+        let dtb_copy = unsafe {
+            core::slice::from_raw_parts_mut(dtb_block_addr.raw() as *mut u8, aligned_dtb_size)
+        };
+        dtb_copy.fill(0);
+        dtb_copy[..guest_dtb.len()].copy_from_slice(guest_dtb);
+
+        // create memory mapping
+        page_table::g_stage::generate_page_table(
+            page_table_addr,
+            &[MemoryMap::new(
+                guest_dtb_gpa..guest_dtb_gpa + aligned_dtb_size,
+                dtb_block_addr..dtb_block_addr + aligned_dtb_size,
+                // allow writing data to dtb to modify device tree on guest OS.
+                &[Dirty, Accessed, Write, Read, User, Valid],
+            )],
+        );
 
         guest_dtb_gpa
     }
 
     /// Return HART(HARdware Thread) id.
     pub fn hart_id(&self) -> usize {
+        self.hart_id
+    }
+
+    /// Return guest id.
+    pub fn guest_id(&self) -> usize {
         self.guest_id
     }
 
+    /// Return VMID for G-stage address translation.
+    ///
+    /// It is guest id, or 0 if VMID is not used. (See `init_vmid`)
+    pub fn vmid(&self) -> usize {
+        if vmid_enabled() {
+            self.guest_id
+        } else {
+            0
+        }
+    }
+
+    /// Switch G-stage address translation to this guest.
+    ///
+    /// Set root page table and VMID of the guest to `hgatp`.
+    /// If VMID is not used, TLB entries of the previous guest (G-stage and VS-stage) are flushed.
+    pub fn activate_g_stage(&self) {
+        hgatp::set(
+            page_table::g_stage::mode(),
+            self.vmid(),
+            self.page_table_addr.raw() >> 12,
+        );
+        if !vmid_enabled() {
+            hfence_gvma_all();
+            hfence_vvma_all();
+        }
+    }
+
+    /// Select the guest interrupt file of the vCPU by `hstatus.VGEIN`.
+    pub fn activate_interrupt_file(&self) {
+        hstatus::set_vgein(self.vgein);
+    }
+
+    /// Set guest interrupt file of IMSIC.
+    pub fn set_vgein(&mut self, vgein: usize) {
+        self.vgein = vgein;
+    }
+
+    /// Return hart state for HSM extension.
+    pub fn hart_state(&self) -> HartState {
+        self.hart_state
+    }
+
+    /// Set hart state for HSM extension.
+    pub fn set_hart_state(&mut self, state: HartState) {
+        self.hart_state = state;
+    }
+
+    /// Return time of the timer interrupt set by SBI `set_timer`. (`usize::MAX`: not set)
+    pub fn timer(&self) -> usize {
+        self.timer
+    }
+
+    /// Set time of the timer interrupt. (`usize::MAX`: not set)
+    pub fn set_timer(&mut self, time: usize) {
+        self.timer = time;
+    }
+
+    /// Return scheduling priority.
+    pub fn priority(&self) -> usize {
+        self.priority
+    }
+
+    /// Set scheduling priority.
+    pub fn set_priority(&mut self, priority: usize) {
+        self.priority = priority;
+    }
+
+    /// Enable demand paging of the memory region.
+    ///
+    /// The memory region is reserved but not backed until the guest accesses it.
+    pub fn enable_demand_paging(&mut self) {
+        self.demand_paging = true;
+    }
+
+    /// Return address of root page table in G-stage.
+    pub fn page_table_addr(&self) -> HostPhysicalAddress {
+        self.page_table_addr
+    }
+
     /// Return Stack top (end of memory region)
     pub fn stack_top(&self) -> HostPhysicalAddress {
         self.stack_top_addr
@@ -121,11 +327,111 @@ impl Guest {
         self.dtb_addr
     }
 
+    /// Return guest memory region. (GPA)
+    pub fn memory_region(&self) -> &Range<GuestPhysicalAddress> {
+        &self.memory_region
+    }
+
     /// Return guest dram space start
     fn dram_base(&self) -> GuestPhysicalAddress {
         self.memory_region.start
     }
 
+    /// Load the guest image to new allocated guest memory pages.
+    ///
+    /// The format is detected by the header of the image. (See `guest::image`)
+    ///
+    /// # Return
+    /// - Entry point address in Guest memory space.
+    /// - Loaded region in Guest memory space. (for filling remind memory space)
+    pub fn load_guest_image(
+        &self,
+        image: &[u8],
+    ) -> (GuestPhysicalAddress, Range<GuestPhysicalAddress>) {
+        match GuestImageFormat::detect(image) {
+            GuestImageFormat::Elf(guest_elf) => {
+                let (entry_point, elf_end) =
+                    self.load_guest_elf(&guest_elf, image.as_ptr().cast_mut());
+                (entry_point, self.dram_base()..elf_end)
+            }
+            GuestImageFormat::LinuxImage(header) => {
+                let load_addr = self.dram_base() + header.text_offset;
+                let loaded_end =
+                    self.load_raw_image(image, load_addr, header.image_size.max(image.len()));
+                (load_addr, load_addr..loaded_end)
+            }
+            GuestImageFormat::Flat => {
+                let loaded_end = self.load_raw_image(image, self.dram_base(), image.len());
+                (self.dram_base(), self.dram_base()..loaded_end)
+            }
+        }
+    }
+
+    /// Copy the guest initramfs to new allocated guest memory pages from `load_addr`.
+    ///
+    /// Return the mapped region.
+    pub fn load_guest_initrd(
+        &self,
+        initrd: &[u8],
+        load_addr: GuestPhysicalAddress,
+    ) -> Range<GuestPhysicalAddress> {
+        load_addr..self.load_raw_image(initrd, load_addr, initrd.len())
+    }
+
+    /// Copy the raw image to new allocated guest memory pages from `load_addr`.
+    ///
+    /// `memory_size` bytes are mapped with all permissions and the part after the image is zero filled. (e.g. bss)
+    /// Return end address of the mapped region.
+    fn load_raw_image(
+        &self,
+        image: &[u8],
+        load_addr: GuestPhysicalAddress,
+        memory_size: usize,
+    ) -> GuestPhysicalAddress {
+        use PteFlag::{Accessed, Dirty, Exec, Read, User, Valid, Write};
+
+        assert!(
+            load_addr % PAGE_SIZE == 0,
+            "guest image must be page aligned"
+        );
+        let loaded_end = load_addr + memory_size.next_multiple_of(PAGE_SIZE);
+        assert!(
+            self.memory_region.start <= load_addr && loaded_end <= self.memory_region.end,
+            "guest image does not fit in guest memory"
+        );
+
+        for offset in (0..memory_size).step_by(PAGE_SIZE) {
+            // allocate memory from heap
+            let aligned_page_size_block_addr = PageBlock::alloc();
+
+            // copy image to new heap block
+            let page = unsafe {
+                core::slice::from_raw_parts_mut(
+                    aligned_page_size_block_addr.raw() as *mut u8,
+                    PAGE_SIZE,
+                )
+            };
+            let src = image.get(offset..).unwrap_or_default();
+            let copy_size = src.len().min(PAGE_SIZE);
+            page[..copy_size].copy_from_slice(&src[..copy_size]);
+            page[copy_size..].fill(0);
+
+            // create memory mapping
+            let guest_physical_addr = load_addr + offset;
+            page_table::g_stage::generate_page_table(
+                self.page_table_addr,
+                &[MemoryMap::new(
+                    guest_physical_addr..guest_physical_addr + PAGE_SIZE,
+                    aligned_page_size_block_addr..aligned_page_size_block_addr + PAGE_SIZE,
+                    // the kernel patches its text and data is not distinguished.
+                    &[Dirty, Accessed, Exec, Write, Read, User, Valid],
+                )],
+            );
+        }
+
+        loaded_end
+    }
+
     /// Load an elf to new allocated guest memory page.
     ///
     /// It only load `PT_LOAD` type segments.
@@ -183,7 +489,7 @@ impl Guest {
                     }
 
                     // create memory mapping
-                    page_table::sv39x4::generate_page_table(
+                    page_table::g_stage::generate_page_table(
                         self.page_table_addr,
                         &[MemoryMap::new(
                             guest_physical_addr..guest_physical_addr + PAGE_SIZE,
@@ -209,26 +515,90 @@ impl Guest {
         (self.dram_base(), elf_end)
     }
 
-    /// Allocate guest memory space from heap and create corresponding page table.
-    pub fn filling_memory_region(&self, region: Range<GuestPhysicalAddress>) {
+    /// Back the page of the guest physical address if it is in the demand-paged memory region.
+    ///
+    /// The page is allocated and zero filled on the first access.
+    /// Return `true` if the page is backed, so that the faulting instruction can be retried.
+    pub fn back_demand_page(&self, gpa: GuestPhysicalAddress) -> bool {
         use PteFlag::{Accessed, Dirty, Exec, Read, User, Valid, Write};
 
-        let all_pte_flags_are_set = &[Dirty, Accessed, Exec, Write, Read, User, Valid];
-        for guest_physical_addr in (region.start.raw()..region.end.raw()).step_by(PAGE_SIZE) {
-            let guest_physical_addr = GuestPhysicalAddress(guest_physical_addr);
+        if !self.demand_paging || !self.memory_region.contains(&gpa) {
+            return false;
+        }
 
-            // allocate memory from heap
-            let aligned_page_size_block_addr = PageBlock::alloc();
+        let page_addr = GuestPhysicalAddress(gpa.raw() / PAGE_SIZE * PAGE_SIZE);
+        // the page may have been backed by another vCPU of the guest.
+        if !page_table::g_stage::is_mapped(self.page_table_addr, page_addr) {
+            let page_block_addr = PageBlock::alloc();
+            unsafe {
+                core::ptr::write_bytes(page_block_addr.raw() as *mut u8, 0, PAGE_SIZE);
+            }
+
+            page_table::g_stage::generate_page_table(
+                self.page_table_addr,
+                &[MemoryMap::new(
+                    page_addr..page_addr + PAGE_SIZE,
+                    page_block_addr..page_block_addr + PAGE_SIZE,
+                    &[Dirty, Accessed, Exec, Write, Read, User, Valid],
+                )],
+            );
+        }
+
+        // invalid PTE may be cached.
+        hfence_gvma(page_addr, self.vmid());
+        true
+    }
+
+    /// Allocate guest memory space from physical frames and create corresponding page table.
+    ///
+    /// The region is allocated chunk by chunk. Each chunk is the largest of 1 GiB, 2 MiB and 4 KiB
+    /// that is aligned as the guest address, fits in the rest of the region and can be allocated,
+    /// so that it is mapped by a superpage.
+    /// Thus the guest memory is backed even if free frames are fragmented.
+    pub fn filling_memory_region(&self, region: Range<GuestPhysicalAddress>) {
+        use PteFlag::{Accessed, Dirty, Exec, Read, User, Valid, Write};
+
+        /// Size of 2 MiB superpage.
+        const SUPERPAGE_2MB: usize = 0x20_0000;
+        /// Size of 1 GiB superpage.
+        const SUPERPAGE_1GB: usize = 0x4000_0000;
+
+        let mut guest_physical_addr = region.start;
+        while guest_physical_addr < region.end {
+            let rest = region.end.raw() - guest_physical_addr.raw();
+            let (chunk, chunk_size) = [SUPERPAGE_1GB, SUPERPAGE_2MB, PAGE_SIZE]
+                .into_iter()
+                .filter(|size| guest_physical_addr.raw() % size == 0 && rest >= *size)
+                .find_map(|size| {
+                    FRAME_ALLOCATOR
+                        .lock()
+                        .alloc(size / PAGE_SIZE, size)
+                        .map(|chunk| (chunk, size))
+                })
+                .unwrap_or_else(|| {
+                    let stats = FRAME_ALLOCATOR.lock().stats();
+                    panic!(
+                        "failed to allocate guest memory at {guest_physical_addr:#x?} ({} KiB free / {} KiB)",
+                        stats.free_frames * PAGE_SIZE / 1024,
+                        stats.total_frames * PAGE_SIZE / 1024,
+                    )
+                });
+
+            // frames may have data of hikami or the other guests.
+            unsafe {
+                core::ptr::write_bytes(chunk.raw() as *mut u8, 0, chunk_size);
+            }
 
             // create memory mapping
-            page_table::sv39x4::generate_page_table(
+            page_table::g_stage::generate_page_table(
                 self.page_table_addr,
                 &[MemoryMap::new(
-                    guest_physical_addr..guest_physical_addr + PAGE_SIZE,
-                    aligned_page_size_block_addr..aligned_page_size_block_addr + PAGE_SIZE,
-                    all_pte_flags_are_set,
+                    guest_physical_addr..guest_physical_addr + chunk_size,
+                    chunk..chunk + chunk_size,
+                    &[Dirty, Accessed, Exec, Write, Read, User, Valid],
                 )],
             );
+            guest_physical_addr = guest_physical_addr + chunk_size;
         }
     }
 }
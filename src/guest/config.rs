@@ -0,0 +1,457 @@
+//! Guest configuration.
+//!
+//! The boot hart creates all guests from this configuration.
+//!
+//! - Guest 1 (primary guest) is loaded from initrd.
+//!   It owns hart 0, and the harts and devices that are not assigned to other guests.
+//! - Additional guests are described by `/chosen/guest@<guest id>` nodes in host device tree.
+//!
+//! Devices are assigned by `hikami,devices`.
+//! UART, PLIC, APLIC and IMSIC are emulated for each guest, and the others are passed through.
+//! (the test finisher is emulated for the guest that it is assigned to)
+//! The passed-through devices and the test finisher can be assigned to only one guest.
+//! CLINT can not be passed through to guests other than the primary guest,
+//! because it has MSIP and `mtimecmp` of all harts.
+//!
+//! Interrupt sources of PLIC (or APLIC) are assigned by `hikami,irqs`.
+//! The primary guest owns the interrupt sources that are not assigned to other guests.
+//! The interrupt source of UART is handled by hikami and can not be assigned to guests.
+//!
+//! Guest memory is placed at `hikami,memory-base` with `hikami,memory-size` in guest physical address.
+//! (default: `DRAM_BASE + guest_id * 256 MiB` and 256 MiB)
+//! `/memory` node of guest device tree is generated from it. (See `guest::device_tree`)
+//!
+//! Guest memory is allocated at boot by default.
+//! If `hikami,demand-paging` is present, it is backed on the first access instead,
+//! so that the memory sizes of guests can exceed the free physical memory in total.
+//!
+//! Memory properties of the primary guest are read from `/chosen` node.
+//!
+//! Harts can be shared by multiple guests. The vCPUs on the shared hart are time-sliced by
+//! the scheduler in order of `hikami,priority` (default: 0).
+//!
+//! Guest initramfs can be given by `hikami,initrd-start` and `hikami,initrd-end` separately from the guest image.
+//! It is copied to the end of guest memory and passed by `linux,initrd-start` and `linux,initrd-end` of guest device tree.
+//!
+//! Kernel command line of the guest is given by `hikami,guest-bootargs`. (default: `bootargs` of host `/chosen`)
+//!
+//! Guest device tree is generated from host device tree,
+//! or a prebuilt one can be given by `hikami,dtb-start` and `hikami,dtb-end`.
+//! `/memory` and `/chosen` of the prebuilt one are rewritten in the same way,
+//! so `bootargs` of it is kept only if `hikami,guest-bootargs` is not given.
+//!
+//! ```dts
+//! chosen {
+//!     guest@2 {
+//!         hikami,image-start = <0x00 0xa0000000>;
+//!         hikami,image-end = <0x00 0xa0100000>;
+//!         hikami,initrd-start = <0x00 0xa0200000>;
+//!         hikami,initrd-end = <0x00 0xa0400000>;
+//!         hikami,harts = <0x02 0x03>;
+//!         hikami,memory-base = <0x00 0xa0000000>;
+//!         hikami,memory-size = <0x00 0x1000000>;
+//!         hikami,devices = "uart", "plic", "rtc";
+//!         hikami,irqs = <0x0a>;
+//!         hikami,priority = <0x01>;
+//!         hikami,demand-paging;
+//!         hikami,guest-bootargs = "console=ttyS0 rdinit=/sbin/init";
+//!     };
+//! };
+//! ```
+//!
+//! # Boot configuration blob
+//! Instead of `/chosen`, guests can be described by a separate flattened device tree
+//! whose address is given by `/chosen/hikami,config`.
+//! It is useful to change the guests without rebuilding host device tree (e.g. loaded by `-device loader` of QEMU).
+//! The root node of the blob has `guest@<guest id>` nodes with the same properties as above.
+//! `guest@1` configures the primary guest, and `hikami,image-*`, `hikami,harts`, `hikami,devices` and `hikami,irqs`
+//! default to initrd and the remaining harts, devices and interrupt sources.
+//!
+//! ```dts
+//! // host device tree
+//! chosen {
+//!     hikami,config = <0x00 0x88000000>;
+//! };
+//!
+//! // boot configuration blob
+//! / {
+//!     guest@1 {
+//!         hikami,memory-size = <0x00 0x8000000>;
+//!     };
+//!     guest@2 {
+//!         hikami,image-start = <0x00 0xa0000000>;
+//!         hikami,image-end = <0x00 0xa0100000>;
+//!         hikami,harts = <0x01>;
+//!         hikami,devices = "uart";
+//!     };
+//! };
+//! ```
+
+use crate::device::{aplic, DeviceKind};
+use crate::memmap::{
+    constant::{guest_memory, MAX_HART_NUM},
+    page_table::constants::PAGE_SIZE,
+    GuestPhysicalAddress, HostPhysicalAddress,
+};
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::Range;
+use fdt::{node::FdtNode, Fdt};
+
+/// Guest ID of primary guest.
+pub const PRIMARY_GUEST_ID: usize = 1;
+
+/// Where the guest image is placed.
+#[derive(Debug)]
+pub enum GuestImage {
+    /// initrd that is passed by host device tree.
+    Initrd,
+    /// Memory region in host physical address.
+    Region(Range<HostPhysicalAddress>),
+}
+
+/// Configuration of a guest.
+#[derive(Debug)]
+#[allow(clippy::module_name_repetitions)]
+pub struct GuestConfig {
+    /// Guest ID. It is also used as VMID.
+    pub guest_id: usize,
+    /// Harts assigned to the guest. The first one is the boot hart.
+    pub harts: Vec<usize>,
+    /// Guest image. (ELF, Linux `Image` or flat binary)
+    pub image: GuestImage,
+    /// Guest memory region.
+    pub memory_region: Range<GuestPhysicalAddress>,
+    /// Whether guest memory is backed on the first access.
+    pub demand_paging: bool,
+    /// Devices that are mapped to the guest.
+    pub devices: Vec<DeviceKind>,
+    /// Scheduling priority of vCPUs.
+    pub priority: usize,
+    /// Interrupt sources of PLIC (or APLIC) that are assigned to the guest.
+    pub irqs: Vec<usize>,
+    /// Prebuilt guest device tree. (generated from host device tree if `None`)
+    pub dtb: Option<Range<HostPhysicalAddress>>,
+    /// Guest initramfs in host physical address.
+    pub initrd: Option<Range<HostPhysicalAddress>>,
+    /// Kernel command line of the guest. (`bootargs` of host or prebuilt device tree is used if `None`)
+    pub bootargs: Option<String>,
+}
+
+impl GuestConfig {
+    /// Return boot hart id of the guest.
+    pub fn boot_hart_id(&self) -> usize {
+        self.harts[0]
+    }
+
+    /// Return the region where guest initramfs is copied in guest memory.
+    ///
+    /// It is placed at the page aligned end of guest memory.
+    ///
+    /// # Panics
+    /// It will be panic if the initramfs is larger than guest memory.
+    pub fn initrd_region(&self) -> Option<Range<GuestPhysicalAddress>> {
+        self.initrd.as_ref().map(|initrd| {
+            let size = initrd.end.raw() - initrd.start.raw();
+            let memory_size = self.memory_region.end.raw() - self.memory_region.start.raw();
+            assert!(
+                size.next_multiple_of(PAGE_SIZE) <= memory_size,
+                "initrd of guest {} is larger than guest memory",
+                self.guest_id
+            );
+            let start =
+                GuestPhysicalAddress((self.memory_region.end.raw() - size) / PAGE_SIZE * PAGE_SIZE);
+            start..start + size
+        })
+    }
+}
+
+/// Read big-endian `u64` property.
+fn read_u64_prop(value: &[u8]) -> usize {
+    usize::try_from(u64::from_be_bytes(value.try_into().unwrap())).unwrap()
+}
+
+/// Read big-endian `u32` list property.
+fn read_u32_list_prop(value: &[u8]) -> Vec<usize> {
+    value
+        .chunks(4)
+        .map(|cell| u32::from_be_bytes(cell.try_into().unwrap()) as usize)
+        .collect()
+}
+
+/// Return guest memory region from `hikami,memory-base` and `hikami,memory-size` of the node.
+///
+/// # Panics
+/// It will be panic if the region is not page aligned or overlaps with guest device tree.
+fn memory_region(node: FdtNode, guest_id: usize) -> Range<GuestPhysicalAddress> {
+    let base = node.property("hikami,memory-base").map_or(
+        guest_memory::DRAM_BASE + guest_id * guest_memory::DEFAULT_DRAM_SIZE,
+        |base| GuestPhysicalAddress(read_u64_prop(base.value)),
+    );
+    let size = node
+        .property("hikami,memory-size")
+        .map_or(guest_memory::DEFAULT_DRAM_SIZE, |size| {
+            read_u64_prop(size.value)
+        });
+
+    assert!(
+        base % PAGE_SIZE == 0 && size % PAGE_SIZE == 0 && size > 0,
+        "memory of guest {guest_id} must be page aligned"
+    );
+    let dtb_window_end =
+        guest_memory::DRAM_BASE + MAX_HART_NUM * guest_memory::GUEST_DTB_SIZE_PER_HART;
+    assert!(
+        base + size <= guest_memory::DRAM_BASE || dtb_window_end <= base,
+        "memory of guest {guest_id} overlaps with guest device tree"
+    );
+
+    base..base + size
+}
+
+/// Return interrupt sources of the primary guest.
+///
+/// The primary guest owns the remaining interrupt sources except for console. (handled by hikami)
+fn primary_irqs(device_tree: &Fdt, configs: &[GuestConfig]) -> Vec<usize> {
+    let irq_num = device_tree
+        .find_node("/soc/plic")
+        .and_then(|plic| plic.property("riscv,ndev"))
+        .or_else(|| {
+            aplic::find_supervisor_domain(device_tree)
+                .and_then(|aplic| aplic.property("riscv,num-sources"))
+        })
+        .and_then(fdt::node::NodeProperty::as_usize)
+        .unwrap_or(0);
+    let console_irq = device_tree
+        .find_node("/soc/serial")
+        .and_then(|serial| serial.property("interrupts"))
+        .and_then(fdt::node::NodeProperty::as_usize);
+
+    (1..=irq_num)
+        .filter(|irq| Some(*irq) != console_irq)
+        .filter(|irq| !configs.iter().any(|config| config.irqs.contains(irq)))
+        .collect()
+}
+
+/// Return the address of boot configuration blob given by `/chosen/hikami,config`.
+pub fn boot_config_blob_addr(device_tree: &Fdt) -> Option<HostPhysicalAddress> {
+    device_tree
+        .find_node("/chosen")
+        .and_then(|chosen| chosen.property("hikami,config"))
+        .map(|config| HostPhysicalAddress(read_u64_prop(config.value)))
+}
+
+/// Return guest id from unit address of the node.
+fn guest_id(node: FdtNode) -> usize {
+    node.name
+        .split_once('@')
+        .and_then(|(_, unit_addr)| usize::from_str_radix(unit_addr, 16).ok())
+        .expect("guest node must have guest id as unit address")
+}
+
+/// Return host physical address range from `<prefix>-start` and `<prefix>-end` of the node.
+fn region_prop(node: FdtNode, prefix: &str) -> Option<Range<HostPhysicalAddress>> {
+    let start = node.property(&format!("{prefix}-start"))?;
+    let end = node
+        .property(&format!("{prefix}-end"))
+        .unwrap_or_else(|| panic!("{prefix}-end is not found in {}", node.name));
+    Some(
+        HostPhysicalAddress(read_u64_prop(start.value))
+            ..HostPhysicalAddress(read_u64_prop(end.value)),
+    )
+}
+
+/// Return devices of `hikami,devices`.
+fn devices_prop(node: FdtNode) -> Option<Vec<DeviceKind>> {
+    node.property("hikami,devices").map(|devices| {
+        devices
+            .value
+            .split(|c| *c == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                let name = core::str::from_utf8(name).unwrap();
+                DeviceKind::from_name(name).unwrap_or_else(|| panic!("unknown device: {name}"))
+            })
+            .collect()
+    })
+}
+
+/// Return kernel command line of `hikami,guest-bootargs`.
+fn bootargs_prop(node: FdtNode) -> Option<String> {
+    node.property("hikami,guest-bootargs").map(|bootargs| {
+        bootargs
+            .as_str()
+            .expect("hikami,guest-bootargs must be a string")
+            .into()
+    })
+}
+
+/// Check that each exclusive device is assigned to at most one guest.
+///
+/// # Panics
+/// It will be panic if two guests claim the same device.
+fn check_exclusive_devices(configs: &[GuestConfig]) {
+    for (index, config) in configs.iter().enumerate() {
+        for other in &configs[index + 1..] {
+            if let Some(device) = config
+                .devices
+                .iter()
+                .find(|device| device.is_exclusive() && other.devices.contains(device))
+            {
+                panic!(
+                    "{device:?} is assigned to both guest {} and guest {}",
+                    config.guest_id, other.guest_id
+                );
+            }
+        }
+    }
+}
+
+/// Parse configuration of the guest that is not primary guest.
+///
+/// # Panics
+/// It will be panic if CLINT is assigned to the guest.
+fn parse_guest_node(node: FdtNode, guest_id: usize) -> GuestConfig {
+    assert!(
+        guest_id > PRIMARY_GUEST_ID,
+        "guest id must be greater than 1"
+    );
+
+    let image = region_prop(node, "hikami,image")
+        .unwrap_or_else(|| panic!("guest {guest_id} has no image"));
+    let harts = read_u32_list_prop(node.property("hikami,harts").unwrap().value);
+    let priority = node.property("hikami,priority").map_or(0, |priority| {
+        u32::from_be_bytes(priority.value.try_into().unwrap()) as usize
+    });
+    let irqs = node
+        .property("hikami,irqs")
+        .map(|irqs| read_u32_list_prop(irqs.value))
+        .unwrap_or_default();
+    let devices = devices_prop(node).unwrap_or_default();
+
+    assert!(!harts.is_empty(), "guest {guest_id} has no hart");
+    // MSIP and mtimecmp of the other harts would be exposed.
+    assert!(
+        !devices.contains(&DeviceKind::Clint),
+        "CLINT can not be passed through to guest {guest_id}"
+    );
+
+    GuestConfig {
+        guest_id,
+        harts,
+        image: GuestImage::Region(image),
+        memory_region: memory_region(node, guest_id),
+        demand_paging: node.property("hikami,demand-paging").is_some(),
+        devices,
+        priority,
+        irqs,
+        dtb: region_prop(node, "hikami,dtb"),
+        initrd: region_prop(node, "hikami,initrd"),
+        bootargs: bootargs_prop(node),
+    }
+}
+
+/// Parse guest configurations from host device tree.
+///
+/// If `/chosen/hikami,config` is present, guests are described by the boot configuration blob instead.
+///
+/// # Panics
+/// It will be panic if the configuration is invalid.
+pub fn parse_guest_configs(device_tree: &Fdt) -> Vec<GuestConfig> {
+    let config_blob = boot_config_blob_addr(device_tree).map(|addr| unsafe {
+        Fdt::from_ptr(addr.raw() as *const u8)
+            .unwrap_or_else(|e| panic!("failed to parse boot configuration blob: {e}"))
+    });
+    let (guest_nodes, primary_node): (Vec<FdtNode>, FdtNode) = match &config_blob {
+        Some(config_blob) => {
+            let (primary, others) = config_blob
+                .find_all_nodes("/guest")
+                .partition::<Vec<FdtNode>, _>(|node| guest_id(*node) == PRIMARY_GUEST_ID);
+            let primary = primary
+                .first()
+                .copied()
+                .or_else(|| config_blob.find_node("/"))
+                .unwrap();
+            (others, primary)
+        }
+        // configuration of primary guest is written in `/chosen`.
+        None => (
+            device_tree.find_all_nodes("/chosen/guest").collect(),
+            device_tree
+                .find_node("/chosen")
+                .expect("chosen node is not found"),
+        ),
+    };
+
+    let hart_num = device_tree.cpus().count();
+    let mut configs: Vec<GuestConfig> = guest_nodes
+        .into_iter()
+        .map(|node| parse_guest_node(node, guest_id(node)))
+        .collect();
+
+    for (index, config) in configs.iter().enumerate() {
+        assert!(
+            config.harts.iter().all(|hart_id| *hart_id < hart_num),
+            "guest {} has nonexistent hart",
+            config.guest_id
+        );
+        for other in &configs[index + 1..] {
+            assert_ne!(config.guest_id, other.guest_id, "duplicated guest id");
+        }
+    }
+
+    // primary guest uses hart 0 and the remaining harts by default.
+    let primary_harts: Vec<usize> = primary_node.property("hikami,harts").map_or_else(
+        || {
+            (0..hart_num)
+                .filter(|hart_id| {
+                    *hart_id == 0 || !configs.iter().any(|config| config.harts.contains(hart_id))
+                })
+                .collect()
+        },
+        |harts| read_u32_list_prop(harts.value),
+    );
+    assert!(
+        primary_harts.first() == Some(&0)
+            && primary_harts.iter().all(|hart_id| *hart_id < hart_num),
+        "primary guest must boot on hart 0 and have existent harts"
+    );
+
+    let primary_irqs = primary_node.property("hikami,irqs").map_or_else(
+        || primary_irqs(device_tree, &configs),
+        |irqs| read_u32_list_prop(irqs.value),
+    );
+
+    // primary guest uses the devices that are not exclusively assigned to other guests by default.
+    let primary_devices = devices_prop(primary_node).unwrap_or_else(|| {
+        DeviceKind::ALL
+            .into_iter()
+            .filter(|device| {
+                !device.is_exclusive()
+                    || !configs.iter().any(|config| config.devices.contains(device))
+            })
+            .collect()
+    });
+
+    configs.insert(
+        0,
+        GuestConfig {
+            guest_id: PRIMARY_GUEST_ID,
+            harts: primary_harts,
+            image: region_prop(primary_node, "hikami,image")
+                .map_or(GuestImage::Initrd, GuestImage::Region),
+            memory_region: memory_region(primary_node, PRIMARY_GUEST_ID),
+            demand_paging: primary_node.property("hikami,demand-paging").is_some(),
+            devices: primary_devices,
+            priority: 0,
+            irqs: primary_irqs,
+            dtb: region_prop(primary_node, "hikami,dtb"),
+            initrd: region_prop(primary_node, "hikami,initrd"),
+            bootargs: bootargs_prop(primary_node),
+        },
+    );
+    check_exclusive_devices(&configs);
+
+    configs
+}
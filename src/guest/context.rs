@@ -1,13 +1,44 @@
 //! Guest context.
+//!
+//! # Layout of `ContextData`
+//! The trap vector (`hstrap_vector`, `hstrap_exit` and `hart_entry`) accesses `ContextData` by fixed offsets.
+//! Assembly can not refer to Rust constants yet (`asm_const`), so the layout is checked at compile time.
+//!
+//! | offset       | field                    | saved by                     |
+//! |--------------|--------------------------|------------------------------|
+//! | `0*8..32*8`  | `xreg`                   | trap vector                  |
+//! | `32*8`       | `sstatus`                | trap vector                  |
+//! | `33*8`       | `sepc`                   | trap vector                  |
+//! | `34*8..45*8` | VS-level CSRs            | `Context::save_vs_csrs`      |
+//! | `45*8`       | `ssp` (emulated Zicfiss) | `emulate_extension::zicfiss` |
+//!
+//! VS-level CSRs stay in the hardware while the vCPU is running on a hart.
+//! They are captured into the context only when the vCPU is switched out (or for debugging).
 
+use crate::h_extension::csrs::{
+    henvcfg, hvip, vsatp, vscause, vsepc, vsie, vsscratch, vsstatus, vstimecmp, vstval, vstvec,
+};
 use crate::memmap::HostPhysicalAddress;
 
+use core::mem::{offset_of, size_of};
 use raki::Instruction;
 
+/// Check the layout that the trap vector assembly depends on.
+const _: () = {
+    assert!(size_of::<ContextData>() == 46 * 8);
+    // stack pointer must be 16 bytes aligned.
+    assert!(size_of::<ContextData>() % 16 == 0);
+    assert!(offset_of!(ContextData, sstatus) == 32 * 8);
+    assert!(offset_of!(ContextData, sepc) == 33 * 8);
+    assert!(offset_of!(ContextData, vsstatus) == 34 * 8);
+    assert!(offset_of!(ContextData, ssp) == 45 * 8);
+};
+
 /// Guest context on memory
 ///
 /// It place to hypervisor stack top.
 #[repr(C)]
+#[derive(Debug, Copy, Clone)]
 #[allow(dead_code)]
 #[allow(clippy::module_name_repetitions)]
 pub struct ContextData {
@@ -17,6 +48,54 @@ pub struct ContextData {
     pub sstatus: usize,
     /// Program counter
     pub sepc: usize,
+    /// Virtual supervisor status register.
+    pub vsstatus: usize,
+    /// Virtual supervisor interrupt-enable register.
+    pub vsie: usize,
+    /// Virtual supervisor trap handler base address.
+    pub vstvec: usize,
+    /// Virtual supervisor scratch register.
+    pub vsscratch: usize,
+    /// Virtual supervisor exception program counter.
+    pub vsepc: usize,
+    /// Virtual supervisor cause register.
+    pub vscause: usize,
+    /// Virtual supervisor trap value register.
+    pub vstval: usize,
+    /// Virtual supervisor address translation and protection.
+    pub vsatp: usize,
+    /// Hypervisor virtual interrupt pending. (pending interrupts of the vCPU)
+    pub hvip: usize,
+    /// Hypervisor environment configuration register.
+    pub henvcfg: usize,
+    /// Virtual supervisor timer compare register.
+    pub vstimecmp: usize,
+    /// Shadow stack pointer. (emulated Zicfiss)
+    pub ssp: usize,
+}
+
+impl Default for ContextData {
+    /// Context of the vCPU that has not run yet.
+    fn default() -> Self {
+        ContextData {
+            xreg: [0; 32],
+            sstatus: 0,
+            sepc: 0,
+            vsstatus: 0,
+            vsie: 0,
+            vstvec: 0,
+            vsscratch: 0,
+            vsepc: 0,
+            vscause: 0,
+            vstval: 0,
+            vsatp: 0,
+            hvip: 0,
+            henvcfg: 0,
+            // no timer interrupt
+            vstimecmp: usize::MAX,
+            ssp: 0,
+        }
+    }
 }
 
 /// Guest context
@@ -44,6 +123,16 @@ impl Context {
         }
     }
 
+    /// Return copy of whole context data.
+    pub fn data(self) -> ContextData {
+        *self.get_context()
+    }
+
+    /// Overwrite whole context data.
+    pub fn load(&mut self, data: &ContextData) {
+        *self.get_context() = *data;
+    }
+
     /// Return regular register value.
     pub fn xreg(self, index: usize) -> u64 {
         self.get_context().xreg[index]
@@ -84,4 +173,46 @@ impl Context {
     pub fn set_sstatus(&mut self, value: usize) {
         self.get_context().sstatus = value;
     }
+
+    /// Return shadow stack pointer. (emulated Zicfiss)
+    pub fn ssp(self) -> usize {
+        self.get_context().ssp
+    }
+
+    /// Set shadow stack pointer. (emulated Zicfiss)
+    pub fn set_ssp(&mut self, value: usize) {
+        self.get_context().ssp = value;
+    }
+
+    /// Capture VS-level CSRs of current hart to the context.
+    pub fn save_vs_csrs(&mut self) {
+        let context = self.get_context();
+        context.vsstatus = vsstatus::read().bits();
+        context.vsie = vsie::read().bits();
+        context.vstvec = vstvec::read().bits();
+        context.vsscratch = vsscratch::read().bits();
+        context.vsepc = vsepc::read().bits();
+        context.vscause = vscause::read().bits();
+        context.vstval = vstval::read().bits();
+        context.vsatp = vsatp::read().bits();
+        context.hvip = hvip::read().bits();
+        context.henvcfg = henvcfg::read().bits();
+        context.vstimecmp = vstimecmp::read().bits();
+    }
+
+    /// Restore VS-level CSRs of current hart from the context.
+    pub fn restore_vs_csrs(self) {
+        let context = self.get_context();
+        vsstatus::write(context.vsstatus);
+        vsie::write(context.vsie);
+        vstvec::write(context.vstvec);
+        vsscratch::write(context.vsscratch);
+        vsepc::write(context.vsepc);
+        vscause::write(context.vscause);
+        vstval::write(context.vstval);
+        vsatp::write(context.vsatp);
+        hvip::write(context.hvip);
+        henvcfg::write(context.henvcfg);
+        vstimecmp::write(context.vstimecmp);
+    }
 }
@@ -0,0 +1,493 @@
+//! Generate the flattened device tree of guest from the host device tree.
+//! (or patch the prebuilt one that is given by `hikami,dtb`)
+//!
+//! - CPU nodes are copied only for the harts that are assigned to the guest.
+//! - Devices under `/soc` are copied only if they are assigned to the guest.
+//!   IOMMU is hidden because it is used by hikami.
+//! - `/memory` is rewritten with the guest memory region.
+//! - `/chosen` is rewritten for the guest. (bootargs, guest initramfs, stdout-path)
+//! - Nodes of device models emulated by hikami are inserted under `/soc`.
+//!
+//! Prebuilt device tree is copied as it is except for `/memory` and `/chosen`.
+//!
+//! ref: [Devicetree Specification v0.4](https://github.com/devicetree-org/devicetree-specification/releases/download/v0.4/devicetree-specification-v0.4.pdf) p.50
+
+use super::config::GuestConfig;
+use crate::device::{aplic, imsic, DeviceKind, Devices};
+
+use alloc::format;
+use alloc::vec::Vec;
+use fdt::{node::FdtNode, Fdt};
+
+/// Magic number of FDT header.
+const FDT_MAGIC: u32 = 0xd00d_feed;
+/// Size of FDT header.
+const FDT_HEADER_SIZE: usize = 40;
+/// Version of generated FDT.
+const FDT_VERSION: u32 = 17;
+/// The lowest version that is backwards compatible with `FDT_VERSION`.
+const FDT_LAST_COMP_VERSION: u32 = 16;
+
+/// Token: beginning of node
+const FDT_BEGIN_NODE: u32 = 1;
+/// Token: end of node
+const FDT_END_NODE: u32 = 2;
+/// Token: property
+const FDT_PROP: u32 = 3;
+/// Token: end of structure block
+const FDT_END: u32 = 9;
+
+/// Builder of flattened device tree.
+#[derive(Debug)]
+pub struct FdtBuilder {
+    /// Structure block.
+    structure: Vec<u8>,
+    /// Strings block.
+    strings: Vec<u8>,
+    /// Memory reservations. (address, size)
+    reservations: Vec<(u64, u64)>,
+}
+
+impl FdtBuilder {
+    /// Constructor for `FdtBuilder`.
+    pub fn new() -> Self {
+        FdtBuilder {
+            structure: Vec::new(),
+            strings: Vec::new(),
+            reservations: Vec::new(),
+        }
+    }
+
+    /// Append big-endian `u32` to structure block.
+    fn push_u32(&mut self, value: u32) {
+        self.structure.extend_from_slice(&value.to_be_bytes());
+    }
+
+    /// Pad structure block to 4 bytes alignment.
+    fn align(&mut self) {
+        self.structure
+            .resize(self.structure.len().next_multiple_of(4), 0);
+    }
+
+    /// Return offset of the name in strings block.
+    #[allow(clippy::cast_possible_truncation)]
+    fn string_offset(&mut self, name: &str) -> u32 {
+        let mut offset = 0;
+        for string in self.strings.split(|c| *c == 0) {
+            if string == name.as_bytes() {
+                return offset as u32;
+            }
+            offset += string.len() + 1;
+        }
+
+        let offset = self.strings.len();
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        offset as u32
+    }
+
+    /// Begin a node. (empty name for root node)
+    pub fn begin_node(&mut self, name: &str) {
+        self.push_u32(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+    }
+
+    /// End the current node.
+    pub fn end_node(&mut self) {
+        self.push_u32(FDT_END_NODE);
+    }
+
+    /// Add a property to the current node.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let name_offset = self.string_offset(name);
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(name_offset);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    /// Add a string property to the current node.
+    pub fn property_str(&mut self, name: &str, value: &str) {
+        let mut bytes = Vec::from(value.as_bytes());
+        bytes.push(0);
+        self.property(name, &bytes);
+    }
+
+    /// Add a property of `u64` cells to the current node.
+    pub fn property_u64_cells(&mut self, name: &str, cells: &[u64]) {
+        let bytes: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.property(name, &bytes);
+    }
+
+    /// Add a memory reservation.
+    pub fn reserve_memory(&mut self, address: u64, size: u64) {
+        self.reservations.push((address, size));
+    }
+
+    /// Finish building and return the blob.
+    ///
+    /// # Panics
+    /// It will be panic if nodes are not closed.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn finish(mut self, boot_cpuid: usize) -> Vec<u8> {
+        self.push_u32(FDT_END);
+
+        // memory reservation block is terminated by a zero entry.
+        self.reservations.push((0, 0));
+        let off_mem_rsvmap = FDT_HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + self.reservations.len() * 16;
+        let off_dt_strings = off_dt_struct + self.structure.len();
+        let total_size = off_dt_strings + self.strings.len();
+
+        let header = [
+            FDT_MAGIC,
+            total_size as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            boot_cpuid as u32,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+
+        let mut blob = Vec::with_capacity(total_size);
+        blob.extend(header.iter().flat_map(|field| field.to_be_bytes()));
+        for (address, size) in &self.reservations {
+            blob.extend_from_slice(&address.to_be_bytes());
+            blob.extend_from_slice(&size.to_be_bytes());
+        }
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+impl Default for FdtBuilder {
+    /// Empty device tree.
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Return whether the node is compatible with one of `compatibles`.
+fn is_compatible(node: FdtNode, compatibles: &[&str]) -> bool {
+    node.compatible()
+        .is_some_and(|compatible| compatible.all().any(|c| compatibles.contains(&c)))
+}
+
+/// Return the name of node without unit address.
+fn base_name<'a>(node: FdtNode<'_, 'a>) -> &'a str {
+    node.name.split('@').next().unwrap_or(node.name)
+}
+
+/// Return root node of the device tree.
+///
+/// # Panics
+/// It will be panic if the device tree does not have root node,
+/// or the root node does not have two address cells and two size cells.
+fn root_node<'b, 'a>(device_tree: &'b Fdt<'a>) -> FdtNode<'b, 'a> {
+    let root = device_tree
+        .find_node("/")
+        .expect("root node is not found in device tree");
+    for cells in ["#address-cells", "#size-cells"] {
+        assert_eq!(
+            root.property(cells)
+                .and_then(fdt::node::NodeProperty::as_usize),
+            Some(2),
+            "{cells} of root node must be 2"
+        );
+    }
+    root
+}
+
+/// Copy the node and its descendants as they are.
+fn copy_subtree(builder: &mut FdtBuilder, node: FdtNode) {
+    builder.begin_node(node.name);
+    for property in node.properties() {
+        builder.property(property.name, property.value);
+    }
+    for child in node.children() {
+        copy_subtree(builder, child);
+    }
+    builder.end_node();
+}
+
+/// Generator of guest device tree.
+struct GuestTreeGenerator<'g, 'a> {
+    /// Host device tree.
+    host: &'g Fdt<'a>,
+    /// Configuration of the guest.
+    config: &'g GuestConfig,
+    /// Phandles of interrupt controllers of the harts that are not assigned to the guest.
+    removed_phandles: Vec<u32>,
+    /// Output.
+    builder: FdtBuilder,
+}
+
+impl GuestTreeGenerator<'_, '_> {
+    /// Return the kind of device of the node under `/soc`.
+    fn device_kind(&self, node: FdtNode) -> Option<DeviceKind> {
+        let path = format!("/soc/{}", node.name);
+        if aplic::supervisor_domain_path(self.host).is_some_and(|aplic| aplic == path) {
+            return Some(DeviceKind::Aplic);
+        }
+        if imsic::supervisor_level_path(self.host).is_some_and(|imsic| imsic == path) {
+            return Some(DeviceKind::Imsic);
+        }
+
+        match base_name(node) {
+            "serial" => Some(DeviceKind::Uart),
+            "virtio_mmio" => Some(DeviceKind::VirtIo),
+            "plic" => Some(DeviceKind::Plic),
+            "clint" => Some(DeviceKind::Clint),
+            "rtc" => Some(DeviceKind::Rtc),
+            "pci" => Some(DeviceKind::Pci),
+            "test" => Some(DeviceKind::TestFinisher),
+            _ => None,
+        }
+    }
+
+    /// Return whether the device is assigned to the guest.
+    fn is_assigned(&self, kind: DeviceKind) -> bool {
+        self.config.devices.contains(&kind)
+    }
+
+    /// Return `interrupts-extended` without the entries of removed harts.
+    ///
+    /// The value is returned as is if the number of cells can not be determined.
+    fn filter_interrupts_extended(&self, value: &[u8]) -> Vec<u8> {
+        let cells: Vec<u32> = value
+            .chunks_exact(4)
+            .map(|cell| u32::from_be_bytes(cell.try_into().unwrap()))
+            .collect();
+
+        let mut filtered = Vec::new();
+        let mut index = 0;
+        while index < cells.len() {
+            let phandle = cells[index];
+            let Some(interrupt_cells) = self
+                .host
+                .find_phandle(phandle)
+                .and_then(|parent| parent.property("#interrupt-cells"))
+                .and_then(fdt::node::NodeProperty::as_usize)
+            else {
+                return Vec::from(value);
+            };
+            let entry = &cells[index..(index + 1 + interrupt_cells).min(cells.len())];
+            if !self.removed_phandles.contains(&phandle) {
+                filtered.extend(entry.iter().flat_map(|cell| cell.to_be_bytes()));
+            }
+            index += entry.len();
+        }
+
+        filtered
+    }
+
+    /// Copy the node and its descendants.
+    ///
+    /// IOMMU is removed and interrupts to removed harts are filtered out.
+    fn copy_node(&mut self, node: FdtNode) {
+        if is_compatible(node, &["riscv,iommu", "riscv,pci-iommu"]) {
+            return;
+        }
+
+        self.builder.begin_node(node.name);
+        // interrupt files of IMSIC are indexed by the position in `interrupts-extended`.
+        let is_imsic = is_compatible(node, &["riscv,imsics"]);
+        for property in node.properties() {
+            match property.name {
+                "iommu-map" | "iommus" => (),
+                "interrupts-extended" if !is_imsic => {
+                    let filtered = self.filter_interrupts_extended(property.value);
+                    self.builder.property(property.name, &filtered);
+                }
+                _ => self.builder.property(property.name, property.value),
+            }
+        }
+        for child in node.children() {
+            self.copy_node(child);
+        }
+        self.builder.end_node();
+    }
+
+    /// Write `/cpus` with the harts that are assigned to the guest.
+    ///
+    /// `cpu-map` is removed because it refers to all harts.
+    fn write_cpus(&mut self, cpus: FdtNode) {
+        self.builder.begin_node(cpus.name);
+        for property in cpus.properties() {
+            self.builder.property(property.name, property.value);
+        }
+        for cpu in cpus.children() {
+            let hart_id = cpu
+                .property("reg")
+                .and_then(fdt::node::NodeProperty::as_usize);
+            if base_name(cpu) == "cpu" && hart_id.is_some_and(|id| self.config.harts.contains(&id))
+            {
+                self.copy_node(cpu);
+            }
+        }
+        self.builder.end_node();
+    }
+
+    /// Write `/soc` with the devices that are assigned to the guest.
+    fn write_soc(&mut self, soc: FdtNode, devices: &Devices) {
+        self.builder.begin_node(soc.name);
+        for property in soc.properties() {
+            self.builder.property(property.name, property.value);
+        }
+        for child in soc.children() {
+            if self
+                .device_kind(child)
+                .is_some_and(|kind| self.is_assigned(kind))
+            {
+                self.copy_node(child);
+            }
+        }
+        devices.write_emulated_device_nodes(self.config.guest_id, &mut self.builder);
+        self.builder.end_node();
+    }
+
+    /// Write `/memory` with the guest memory region.
+    fn write_memory(&mut self) {
+        let region = &self.config.memory_region;
+        let base = region.start.raw();
+        let size = region.end.raw() - base;
+
+        self.builder.begin_node(&format!("memory@{base:x}"));
+        self.builder.property_str("device_type", "memory");
+        self.builder
+            .property_u64_cells("reg", &[base as u64, size as u64]);
+        self.builder.end_node();
+    }
+
+    /// Write `/chosen` for the guest.
+    ///
+    /// Configurations of hikami are removed, and `bootargs` is replaced with the one of guest configuration.
+    fn write_chosen(&mut self, chosen: Option<FdtNode>) {
+        self.builder.begin_node("chosen");
+        for property in chosen.iter().flat_map(|chosen| chosen.properties()) {
+            let copied = match property.name {
+                name if name.starts_with("hikami,") => false,
+                "stdout-path" | "linux,stdout-path" => self.is_assigned(DeviceKind::Uart),
+                // overwritten by the guest configuration.
+                "bootargs" => self.config.bootargs.is_none(),
+                // host initrd is guest image, and guest initramfs is written below.
+                "linux,initrd-start" | "linux,initrd-end" => false,
+                _ => true,
+            };
+            if copied {
+                self.builder.property(property.name, property.value);
+            }
+        }
+        if let Some(bootargs) = &self.config.bootargs {
+            self.builder.property_str("bootargs", bootargs);
+        }
+        if let Some(initrd) = self.config.initrd_region() {
+            self.builder
+                .property_u64_cells("linux,initrd-start", &[initrd.start.raw() as u64]);
+            self.builder
+                .property_u64_cells("linux,initrd-end", &[initrd.end.raw() as u64]);
+        }
+        self.builder.end_node();
+    }
+}
+
+/// Generate the device tree of the guest from the host device tree.
+///
+/// # Panics
+/// It will be panic if the host device tree does not have root node,
+/// or the root node does not have two address cells and two size cells.
+pub fn generate(host: &Fdt, config: &GuestConfig, devices: &Devices) -> Vec<u8> {
+    let root = root_node(host);
+
+    // interrupt controllers of the harts that are not assigned to the guest.
+    let removed_phandles = host
+        .find_node("/cpus")
+        .into_iter()
+        .flat_map(FdtNode::children)
+        .filter(|cpu| {
+            cpu.property("reg")
+                .and_then(fdt::node::NodeProperty::as_usize)
+                .is_some_and(|hart_id| !config.harts.contains(&hart_id))
+        })
+        .flat_map(FdtNode::children)
+        .filter_map(|intc| intc.property("phandle"))
+        .filter_map(fdt::node::NodeProperty::as_usize)
+        .filter_map(|phandle| u32::try_from(phandle).ok())
+        .collect();
+
+    let mut generator = GuestTreeGenerator {
+        host,
+        config,
+        removed_phandles,
+        builder: FdtBuilder::new(),
+    };
+
+    generator.builder.begin_node("");
+    for property in root.properties() {
+        generator.builder.property(property.name, property.value);
+    }
+    for child in root.children() {
+        match base_name(child) {
+            "cpus" => generator.write_cpus(child),
+            "soc" => generator.write_soc(child, devices),
+            // `/memory` and `/chosen` are rewritten below, and `/reserved-memory` is for host only.
+            "memory" | "chosen" | "reserved-memory" => (),
+            // devices that are not managed by hikami (e.g. flash, syscon-poweroff) are not passed through.
+            _ if child.property("reg").is_some() || child.property("regmap").is_some() => (),
+            _ => generator.copy_node(child),
+        }
+    }
+    generator.write_memory();
+    generator.write_chosen(host.find_node("/chosen"));
+    generator.builder.end_node();
+
+    generator.builder.finish(config.boot_hart_id())
+}
+
+/// Patch the prebuilt device tree of the guest.
+///
+/// `/memory` and `/chosen` are rewritten as the generated device tree,
+/// and the other nodes and memory reservations are copied as they are.
+///
+/// # Panics
+/// It will be panic if the device tree does not have root node,
+/// or the root node does not have two address cells and two size cells.
+pub fn patch(prebuilt: &Fdt, config: &GuestConfig) -> Vec<u8> {
+    let root = root_node(prebuilt);
+    let mut generator = GuestTreeGenerator {
+        host: prebuilt,
+        config,
+        removed_phandles: Vec::new(),
+        builder: FdtBuilder::new(),
+    };
+
+    for reservation in prebuilt.memory_reservations() {
+        generator
+            .builder
+            .reserve_memory(reservation.address() as u64, reservation.size() as u64);
+    }
+
+    generator.builder.begin_node("");
+    for property in root.properties() {
+        generator.builder.property(property.name, property.value);
+    }
+    for child in root.children() {
+        match base_name(child) {
+            "memory" | "chosen" => (),
+            _ => copy_subtree(&mut generator.builder, child),
+        }
+    }
+    generator.write_memory();
+    generator.write_chosen(prebuilt.find_node("/chosen"));
+    generator.builder.end_node();
+
+    generator.builder.finish(config.boot_hart_id())
+}
@@ -0,0 +1,75 @@
+//! Guest image formats.
+//!
+//! - ELF (e.g. `vmlinux`): `PT_LOAD` segments are loaded at `p_paddr` relative to guest DRAM base.
+//! - RISC-V Linux `Image`: loaded at `text_offset` from guest DRAM base. (EFI stub images also have the header)
+//! - Flat binary: loaded at guest DRAM base and entered from the first byte.
+//!
+//! ref: [Boot image header in RISC-V Linux](https://docs.kernel.org/arch/riscv/boot-image-header.html)
+
+use elf::{endian::AnyEndian, ElfBytes};
+
+/// Magic number of ELF.
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+/// Size of RISC-V Linux image header.
+const LINUX_IMAGE_HEADER_SIZE: usize = 64;
+/// `magic2` of RISC-V Linux image header.
+const LINUX_IMAGE_MAGIC: &[u8; 4] = b"RSC\x05";
+
+/// Header of RISC-V Linux `Image`.
+#[derive(Debug, Copy, Clone)]
+pub struct LinuxImageHeader {
+    /// Image load offset from start of RAM.
+    pub text_offset: usize,
+    /// Effective image size including bss. (0 for old kernels)
+    pub image_size: usize,
+}
+
+impl LinuxImageHeader {
+    /// Parse the header at the start of the image.
+    ///
+    /// Return `None` if it does not have magic number.
+    fn parse(image: &[u8]) -> Option<Self> {
+        let header = image.get(..LINUX_IMAGE_HEADER_SIZE)?;
+        if &header[56..60] != LINUX_IMAGE_MAGIC {
+            return None;
+        }
+
+        let read_u64 = |offset: usize| {
+            usize::try_from(u64::from_le_bytes(
+                header[offset..offset + 8].try_into().unwrap(),
+            ))
+            .unwrap()
+        };
+        Some(LinuxImageHeader {
+            text_offset: read_u64(8),
+            image_size: read_u64(16),
+        })
+    }
+}
+
+/// Format of guest image.
+#[derive(Debug)]
+pub enum GuestImageFormat<'a> {
+    /// ELF
+    Elf(ElfBytes<'a, AnyEndian>),
+    /// RISC-V Linux `Image`
+    LinuxImage(LinuxImageHeader),
+    /// Flat binary
+    Flat,
+}
+
+impl<'a> GuestImageFormat<'a> {
+    /// Detect the format of the image by its header.
+    ///
+    /// # Panics
+    /// It will be panic if the image has ELF magic but can not be parsed.
+    pub fn detect(image: &'a [u8]) -> Self {
+        if image.starts_with(ELF_MAGIC) {
+            return GuestImageFormat::Elf(
+                ElfBytes::<AnyEndian>::minimal_parse(image).expect("failed to parse guest elf"),
+            );
+        }
+
+        LinuxImageHeader::parse(image).map_or(GuestImageFormat::Flat, GuestImageFormat::LinuxImage)
+    }
+}
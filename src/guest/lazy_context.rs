@@ -0,0 +1,407 @@
+//! Floating-point and vector context of guest.
+//!
+//! hikami itself does not use F/D/V extensions, so these registers keep the values of the running vCPU
+//! while handling traps, and the trap vector does not save them.
+//! They are saved and restored lazily on vCPU switching by `sstatus.FS`/`sstatus.VS`.
+//!
+//! - Switch out: save the registers only if the status is `Dirty`, and mark it `Clean`.
+//! - Switch in: set the status to `Off` instead of restoring the registers.
+//!   The first FP/vector instruction of the vCPU raises illegal instruction exception,
+//!   then the registers are restored and the status is set back.
+//!
+//! If the hart does not implement V extension (`has_vector_extension`), `sstatus.VS` of the guest is kept `Off`
+//! and vector context is never used.
+
+use super::context::Context;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::asm;
+
+/// Position of `sstatus.VS`.
+const SSTATUS_VS_SHIFT: usize = 9;
+/// Position of `sstatus.FS`.
+const SSTATUS_FS_SHIFT: usize = 13;
+
+/// Return whether the hart implements V extension.
+///
+/// `sstatus.VS` is read-only zero if V extension is not implemented, so set it and read back.
+pub fn has_vector_extension() -> bool {
+    /// sstatus.VS
+    const SSTATUS_VS: usize = 0b11 << SSTATUS_VS_SHIFT;
+    let (prev, probed): (usize, usize);
+    unsafe {
+        asm!("csrrs {prev}, sstatus, {vs}", prev = out(reg) prev, vs = in(reg) SSTATUS_VS);
+        asm!("csrr {probed}, sstatus", probed = out(reg) probed);
+        asm!("csrc sstatus, {bits}", bits = in(reg) SSTATUS_VS & !prev);
+    }
+    probed & SSTATUS_VS != 0
+}
+
+/// Status of extension context. (`sstatus.FS` and `sstatus.VS`)
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum ExtensionStatus {
+    /// Instructions of the extension raise illegal instruction exception.
+    Off = 0,
+    /// Registers are initial state.
+    Initial = 1,
+    /// Registers have not been changed since last save.
+    Clean = 2,
+    /// Registers have been changed since last save.
+    Dirty = 3,
+}
+
+impl ExtensionStatus {
+    /// Read the field at `shift` of sstatus value.
+    fn from_sstatus(sstatus: usize, shift: usize) -> Self {
+        match sstatus >> shift & 0b11 {
+            0 => ExtensionStatus::Off,
+            1 => ExtensionStatus::Initial,
+            2 => ExtensionStatus::Clean,
+            3 => ExtensionStatus::Dirty,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Return sstatus value that the field at `shift` is replaced with `self`.
+    fn set_to_sstatus(self, sstatus: usize, shift: usize) -> usize {
+        sstatus & !(0b11 << shift) | (self as usize) << shift
+    }
+}
+
+/// Floating-point registers.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct FloatRegisters {
+    /// f0 - f31
+    freg: [u64; 32],
+    /// Floating-point control and status register.
+    fcsr: usize,
+}
+
+impl FloatRegisters {
+    /// Save floating-point registers of current hart.
+    ///
+    /// `sstatus.FS` must not be `Off`.
+    fn save(&mut self) {
+        unsafe {
+            asm!(
+                ".option push
+                .option arch, +d
+                fsd f0, 0*8({regs})
+                fsd f1, 1*8({regs})
+                fsd f2, 2*8({regs})
+                fsd f3, 3*8({regs})
+                fsd f4, 4*8({regs})
+                fsd f5, 5*8({regs})
+                fsd f6, 6*8({regs})
+                fsd f7, 7*8({regs})
+                fsd f8, 8*8({regs})
+                fsd f9, 9*8({regs})
+                fsd f10, 10*8({regs})
+                fsd f11, 11*8({regs})
+                fsd f12, 12*8({regs})
+                fsd f13, 13*8({regs})
+                fsd f14, 14*8({regs})
+                fsd f15, 15*8({regs})
+                fsd f16, 16*8({regs})
+                fsd f17, 17*8({regs})
+                fsd f18, 18*8({regs})
+                fsd f19, 19*8({regs})
+                fsd f20, 20*8({regs})
+                fsd f21, 21*8({regs})
+                fsd f22, 22*8({regs})
+                fsd f23, 23*8({regs})
+                fsd f24, 24*8({regs})
+                fsd f25, 25*8({regs})
+                fsd f26, 26*8({regs})
+                fsd f27, 27*8({regs})
+                fsd f28, 28*8({regs})
+                fsd f29, 29*8({regs})
+                fsd f30, 30*8({regs})
+                fsd f31, 31*8({regs})
+                frcsr {fcsr}
+                .option pop",
+                regs = in(reg) self.freg.as_mut_ptr(),
+                fcsr = out(reg) self.fcsr,
+            );
+        }
+    }
+
+    /// Restore floating-point registers of current hart.
+    ///
+    /// `sstatus.FS` must not be `Off`.
+    fn restore(&self) {
+        unsafe {
+            asm!(
+                ".option push
+                .option arch, +d
+                fld f0, 0*8({regs})
+                fld f1, 1*8({regs})
+                fld f2, 2*8({regs})
+                fld f3, 3*8({regs})
+                fld f4, 4*8({regs})
+                fld f5, 5*8({regs})
+                fld f6, 6*8({regs})
+                fld f7, 7*8({regs})
+                fld f8, 8*8({regs})
+                fld f9, 9*8({regs})
+                fld f10, 10*8({regs})
+                fld f11, 11*8({regs})
+                fld f12, 12*8({regs})
+                fld f13, 13*8({regs})
+                fld f14, 14*8({regs})
+                fld f15, 15*8({regs})
+                fld f16, 16*8({regs})
+                fld f17, 17*8({regs})
+                fld f18, 18*8({regs})
+                fld f19, 19*8({regs})
+                fld f20, 20*8({regs})
+                fld f21, 21*8({regs})
+                fld f22, 22*8({regs})
+                fld f23, 23*8({regs})
+                fld f24, 24*8({regs})
+                fld f25, 25*8({regs})
+                fld f26, 26*8({regs})
+                fld f27, 27*8({regs})
+                fld f28, 28*8({regs})
+                fld f29, 29*8({regs})
+                fld f30, 30*8({regs})
+                fld f31, 31*8({regs})
+                fscsr {fcsr}
+                .option pop",
+                regs = in(reg) self.freg.as_ptr(),
+                fcsr = in(reg) self.fcsr,
+            );
+        }
+    }
+}
+
+/// Vector registers.
+#[derive(Debug, Clone)]
+struct VectorRegisters {
+    /// v0 - v31 (`32 * vlenb` bytes)
+    vreg: Vec<u8>,
+    /// Vector start position.
+    vstart: usize,
+    /// Vector length.
+    vl: usize,
+    /// Vector data type register.
+    vtype: usize,
+    /// Vector control and status register.
+    vcsr: usize,
+}
+
+impl VectorRegisters {
+    /// Allocate vector registers area according to `vlenb`.
+    ///
+    /// `sstatus.VS` must not be `Off`.
+    fn new() -> Self {
+        let vlenb: usize;
+        unsafe {
+            asm!(
+                ".option push
+                .option arch, +v
+                csrr {vlenb}, vlenb
+                .option pop",
+                vlenb = out(reg) vlenb,
+            );
+        }
+
+        VectorRegisters {
+            vreg: vec![0; 32 * vlenb],
+            vstart: 0,
+            vl: 0,
+            // vtype.vill = 1
+            vtype: 1 << 63,
+            vcsr: 0,
+        }
+    }
+
+    /// Save vector registers of current hart.
+    ///
+    /// `sstatus.VS` must not be `Off`.
+    fn save(&mut self) {
+        unsafe {
+            asm!(
+                ".option push
+                .option arch, +v
+                csrr {vstart}, vstart
+                csrr {vl}, vl
+                csrr {vtype}, vtype
+                csrr {vcsr}, vcsr
+                csrw vstart, zero
+
+                // size of 8 registers
+                csrr {stride}, vlenb
+                slli {stride}, {stride}, 3
+
+                vs8r.v v0, ({regs})
+                add {regs}, {regs}, {stride}
+                vs8r.v v8, ({regs})
+                add {regs}, {regs}, {stride}
+                vs8r.v v16, ({regs})
+                add {regs}, {regs}, {stride}
+                vs8r.v v24, ({regs})
+                .option pop",
+                regs = inout(reg) self.vreg.as_mut_ptr() => _,
+                stride = out(reg) _,
+                vstart = out(reg) self.vstart,
+                vl = out(reg) self.vl,
+                vtype = out(reg) self.vtype,
+                vcsr = out(reg) self.vcsr,
+            );
+        }
+    }
+
+    /// Restore vector registers of current hart.
+    ///
+    /// `sstatus.VS` must not be `Off`.
+    fn restore(&self) {
+        unsafe {
+            asm!(
+                ".option push
+                .option arch, +v
+                csrw vstart, zero
+
+                // size of 8 registers
+                csrr {stride}, vlenb
+                slli {stride}, {stride}, 3
+
+                vl8re8.v v0, ({regs})
+                add {regs}, {regs}, {stride}
+                vl8re8.v v8, ({regs})
+                add {regs}, {regs}, {stride}
+                vl8re8.v v16, ({regs})
+                add {regs}, {regs}, {stride}
+                vl8re8.v v24, ({regs})
+
+                vsetvl zero, {vl}, {vtype}
+                csrw vstart, {vstart}
+                csrw vcsr, {vcsr}
+                .option pop",
+                regs = inout(reg) self.vreg.as_ptr() => _,
+                stride = out(reg) _,
+                vstart = in(reg) self.vstart,
+                vl = in(reg) self.vl,
+                vtype = in(reg) self.vtype,
+                vcsr = in(reg) self.vcsr,
+            );
+        }
+    }
+}
+
+/// Floating-point and vector context of a vCPU.
+#[derive(Debug, Clone)]
+#[allow(clippy::module_name_repetitions)]
+pub struct LazyContext {
+    /// Saved floating-point registers.
+    float: FloatRegisters,
+    /// Saved vector registers. (allocated on first use)
+    vector: Option<VectorRegisters>,
+    /// `sstatus.FS` that is set after restoring floating-point registers.
+    /// `None` if the registers on the hart belong to the vCPU.
+    pending_fs: Option<ExtensionStatus>,
+    /// `sstatus.VS` that is set after restoring vector registers.
+    /// `None` if the registers on the hart belong to the vCPU.
+    pending_vs: Option<ExtensionStatus>,
+}
+
+impl Default for LazyContext {
+    /// All registers are zero.
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LazyContext {
+    /// Constructor for `LazyContext`. (all registers are zero)
+    pub const fn new() -> Self {
+        LazyContext {
+            float: FloatRegisters {
+                freg: [0; 32],
+                fcsr: 0,
+            },
+            vector: None,
+            pending_fs: None,
+            pending_vs: None,
+        }
+    }
+
+    /// Save floating-point and vector registers if they are dirty.
+    ///
+    /// It is called when the vCPU is switched out.
+    pub fn save(&mut self, context: &mut Context) {
+        let mut sstatus = context.sstatus();
+
+        if ExtensionStatus::from_sstatus(sstatus, SSTATUS_FS_SHIFT) == ExtensionStatus::Dirty {
+            self.float.save();
+            sstatus = ExtensionStatus::Clean.set_to_sstatus(sstatus, SSTATUS_FS_SHIFT);
+        }
+
+        if ExtensionStatus::from_sstatus(sstatus, SSTATUS_VS_SHIFT) == ExtensionStatus::Dirty {
+            self.vector.get_or_insert_with(VectorRegisters::new).save();
+            sstatus = ExtensionStatus::Clean.set_to_sstatus(sstatus, SSTATUS_VS_SHIFT);
+        }
+
+        context.set_sstatus(sstatus);
+    }
+
+    /// Defer restoring registers until the vCPU uses them.
+    ///
+    /// It is called when the vCPU is switched in, because the registers on the hart may belong to other vCPU.
+    pub fn defer_restore(&mut self, context: &mut Context) {
+        let mut sstatus = context.sstatus();
+
+        let fs = ExtensionStatus::from_sstatus(sstatus, SSTATUS_FS_SHIFT);
+        if fs != ExtensionStatus::Off {
+            self.pending_fs = Some(fs);
+            sstatus = ExtensionStatus::Off.set_to_sstatus(sstatus, SSTATUS_FS_SHIFT);
+        }
+
+        // vector registers can not be accessed without V extension.
+        let vs = ExtensionStatus::from_sstatus(sstatus, SSTATUS_VS_SHIFT);
+        if vs != ExtensionStatus::Off && has_vector_extension() {
+            self.pending_vs = Some(vs);
+            sstatus = ExtensionStatus::Off.set_to_sstatus(sstatus, SSTATUS_VS_SHIFT);
+        }
+
+        context.set_sstatus(sstatus);
+    }
+
+    /// Restore the registers whose restoring is deferred.
+    ///
+    /// It is called on illegal instruction exception.
+    /// Return `true` if the registers are restored, then the faulting instruction should be retried.
+    pub fn restore_pending(&mut self, context: &mut Context) -> bool {
+        if self.pending_fs.is_none() && self.pending_vs.is_none() {
+            return false;
+        }
+
+        let mut sstatus = context.sstatus();
+
+        if let Some(fs) = self.pending_fs.take() {
+            // enable FP instructions in HS-mode temporarily.
+            // (sstatus is overwritten by the context on `hstrap_exit`)
+            unsafe {
+                asm!("csrs sstatus, {bits}", bits = in(reg) 0b11 << SSTATUS_FS_SHIFT);
+            }
+            self.float.restore();
+            sstatus = fs.set_to_sstatus(sstatus, SSTATUS_FS_SHIFT);
+        }
+
+        if let Some(vs) = self.pending_vs.take() {
+            // enable vector instructions in HS-mode temporarily.
+            unsafe {
+                asm!("csrs sstatus, {bits}", bits = in(reg) 0b11 << SSTATUS_VS_SHIFT);
+            }
+            self.vector
+                .get_or_insert_with(VectorRegisters::new)
+                .restore();
+            sstatus = vs.set_to_sstatus(sstatus, SSTATUS_VS_SHIFT);
+        }
+
+        context.set_sstatus(sstatus);
+        true
+    }
+}
@@ -0,0 +1,138 @@
+//! vCPU scheduler.
+//!
+//! Each hart has its own scheduler that time-slices the vCPUs assigned to the hart.
+//! The scheduler is driven by HS-level timer (`stimecmp`).
+//! The timer that the running vCPU sets by SBI `set_timer` shares `stimecmp` with the scheduler. (See `arm_timer`)
+//!
+//! The vCPU with the highest priority runs first.
+//! vCPUs that have same priority are scheduled in round-robin.
+
+use super::vcpu::Vcpu;
+use super::Guest;
+use crate::h_extension::csrs::stimecmp;
+use crate::HypervisorData;
+
+use alloc::collections::VecDeque;
+use riscv::register::{sie, time};
+
+/// Time slice of each vCPU. (10 ms at 10 `MHz` timebase)
+const TIME_SLICE: usize = 100_000;
+
+/// vCPU scheduler of a hart.
+#[derive(Debug)]
+pub struct Scheduler {
+    /// vCPUs waiting on the hart.
+    run_queue: VecDeque<Vcpu>,
+    /// End of the time slice of the running vCPU. (`usize::MAX` if the hart is not shared)
+    slice_end: usize,
+}
+
+impl Scheduler {
+    /// Constructor for `Scheduler`.
+    pub const fn new() -> Self {
+        Scheduler {
+            run_queue: VecDeque::new(),
+            slice_end: usize::MAX,
+        }
+    }
+
+    /// Add vCPU to the tail of run queue.
+    pub fn push(&mut self, vcpu: Vcpu) {
+        self.run_queue.push_back(vcpu);
+    }
+
+    /// Whether the hart is shared with other vCPUs.
+    pub fn is_active(&self) -> bool {
+        !self.run_queue.is_empty()
+    }
+
+    /// Return iterator of waiting vCPUs.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Vcpu> {
+        self.run_queue.iter_mut()
+    }
+
+    /// Return iterator of waiting vCPUs.
+    pub fn iter(&self) -> impl Iterator<Item = &Vcpu> {
+        self.run_queue.iter()
+    }
+
+    /// Take out the next vCPU to run.
+    ///
+    /// The vCPU is chosen if its priority is greater than or equal to `current_priority`.
+    /// `None` of `current_priority` means the current vCPU can not run.
+    fn pick_next(&mut self, current_priority: Option<usize>) -> Option<Vcpu> {
+        let (index, priority) = self
+            .run_queue
+            .iter()
+            .enumerate()
+            .filter(|(_, vcpu)| vcpu.is_runnable())
+            .map(|(index, vcpu)| (index, vcpu.guest().priority()))
+            // `max_by_key` returns the last element, so reverse to take the first one.
+            .rev()
+            .max_by_key(|(_, priority)| *priority)?;
+
+        if current_priority.is_some_and(|current| priority < current) {
+            return None;
+        }
+
+        self.run_queue.remove(index)
+    }
+
+    /// Start new time slice of the running vCPU if the hart is shared.
+    pub fn start_slice(&mut self) {
+        self.slice_end = if self.is_active() {
+            time::read() + TIME_SLICE
+        } else {
+            usize::MAX
+        };
+    }
+
+    /// Whether the time slice of the running vCPU has expired.
+    pub fn is_slice_expired(&self) -> bool {
+        self.slice_end <= time::read()
+    }
+}
+
+/// Set HS-level timer to the earlier of the end of time slice and the timer of the running vCPU.
+pub fn arm_timer(hypervisor_data: &HypervisorData, hart_id: usize) {
+    let guest_timer = hypervisor_data.guests[hart_id]
+        .as_ref()
+        .map_or(usize::MAX, Guest::timer);
+    stimecmp::write(
+        hypervisor_data.schedulers[hart_id]
+            .slice_end
+            .min(guest_timer),
+    );
+    unsafe {
+        sie::set_stimer();
+    }
+}
+
+/// Switch the running vCPU of the hart to next vCPU.
+///
+/// It must be called in HS-mode trap handler, and `hstrap_exit` enters the new vCPU.
+/// Return `false` if there is no vCPU to switch.
+pub fn switch_vcpu(hypervisor_data: &mut HypervisorData, hart_id: usize) -> bool {
+    let current_priority = hypervisor_data.guests[hart_id]
+        .as_ref()
+        .filter(|guest| guest.hart_state() != super::HartState::Stopped)
+        .map(super::Guest::priority);
+
+    let Some(next_vcpu) = hypervisor_data.schedulers[hart_id].pick_next(current_priority) else {
+        return false;
+    };
+
+    let current_guest = hypervisor_data.guests[hart_id]
+        .take()
+        .expect("guest data not found");
+    hypervisor_data.schedulers[hart_id].push(Vcpu::park(current_guest));
+    let next_guest = next_vcpu.resume();
+    // inject external interrupts that arrived while the vCPU was waiting.
+    // (MSIs to the guest interrupt file are kept in the file while waiting.)
+    hypervisor_data
+        .devices
+        .update_external_interrupt(next_guest.guest_id(), hart_id);
+    hypervisor_data.guests[hart_id] = Some(next_guest);
+
+    true
+}
@@ -0,0 +1,95 @@
+//! vCPU: virtual CPU.
+//!
+//! A vCPU that is not running on a hart keeps its guest context (including VS-level CSRs) in `Vcpu`.
+//! The running vCPU of each hart uses the context area at the hart's stack top and the real CSRs.
+
+use super::context::ContextData;
+use super::lazy_context::LazyContext;
+use super::{Guest, HartState};
+use crate::emulate_extension::zicfiss::Zicfiss;
+use crate::h_extension::csrs::henvcfg;
+use crate::hypervisor_init::prepare_vsmode_entry;
+
+use riscv::register::sscratch;
+
+/// vCPU that is waiting on a hart.
+#[derive(Debug)]
+pub struct Vcpu {
+    /// Guest data of the vCPU.
+    guest: Guest,
+    /// Saved guest context.
+    context: ContextData,
+}
+
+impl Vcpu {
+    /// Create vCPU that has not run yet.
+    pub fn new(guest: Guest) -> Self {
+        Vcpu {
+            guest,
+            context: ContextData {
+                // same environment configuration as the hart.
+                henvcfg: henvcfg::read().bits(),
+                ..ContextData::default()
+            },
+        }
+    }
+
+    /// Save the state of the running vCPU on current hart.
+    ///
+    /// It must be called in HS-mode trap handler.
+    pub fn park(mut guest: Guest) -> Self {
+        guest.context.save_vs_csrs();
+        guest.lazy_context.save(&mut guest.context);
+        let mut context = guest.context.data();
+        // stack pointer of the guest is kept in sscratch while handling trap.
+        context.xreg[2] = sscratch::read() as u64;
+
+        Vcpu { guest, context }
+    }
+
+    /// Restore the state of the vCPU to current hart and return the guest data.
+    ///
+    /// The vCPU that has been requested to start enters the entry point with `a0 = hart_id`, `a1 = opaque`.
+    /// After that, call `hstrap_exit` to enter the vCPU.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn resume(self) -> Guest {
+        let Vcpu { mut guest, context } = self;
+
+        guest.context.load(&context);
+        guest.context.restore_vs_csrs();
+        guest.activate_g_stage();
+        guest.activate_interrupt_file();
+
+        if let HartState::StartPending { start_addr, opaque } = guest.hart_state() {
+            guest.set_hart_state(HartState::Started);
+            prepare_vsmode_entry(guest.context, start_addr);
+            guest.context.set_xreg(2, 0);
+            guest.context.set_xreg(10, guest.hart_id() as u64);
+            guest.context.set_xreg(11, opaque as u64);
+            guest.lazy_context = LazyContext::new();
+            guest.zicfiss = Zicfiss::new();
+            guest.set_timer(usize::MAX);
+        }
+
+        // floating-point and vector registers on the hart belong to other vCPU.
+        guest.lazy_context.defer_restore(&mut guest.context);
+
+        sscratch::write(guest.context.xreg(2) as usize);
+        guest
+    }
+
+    /// Return guest data of the vCPU.
+    pub fn guest(&self) -> &Guest {
+        &self.guest
+    }
+
+    /// Return mutable guest data of the vCPU.
+    pub fn guest_mut(&mut self) -> &mut Guest {
+        &mut self.guest
+    }
+
+    /// Whether the vCPU can run.
+    pub fn is_runnable(&self) -> bool {
+        self.guest.hart_state() != HartState::Stopped
+    }
+}
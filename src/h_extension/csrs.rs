@@ -93,6 +93,34 @@ pub enum VsInterruptKind {
     Software = 1 << 2,
 }
 
+pub mod vsstatus {
+    //! Virtual supervisor status register.
+    #![allow(dead_code)]
+
+    /// vsstatus register number.
+    const VSSTATUS: usize = 0x200;
+    /// Virtual supervisor status register.
+    pub struct Vsstatus(usize);
+
+    impl_bits!(Vsstatus);
+    read_csr_as!(Vsstatus, 0x200);
+    write_csr_as!(0x200);
+}
+
+pub mod vsie {
+    //! Virtual supervisor interrupt-enable register.
+    #![allow(dead_code)]
+
+    /// vsie register number.
+    const VSIE: usize = 0x204;
+    /// Virtual supervisor interrupt-enable register.
+    pub struct Vsie(usize);
+
+    impl_bits!(Vsie);
+    read_csr_as!(Vsie, 0x204);
+    write_csr_as!(0x204);
+}
+
 pub mod vstvec {
     //! Virtual supervisor trap handler base address.
     #![allow(dead_code)]
@@ -107,6 +135,62 @@ pub mod vstvec {
     write_csr_as!(0x205);
 }
 
+pub mod vsscratch {
+    //! Virtual supervisor scratch register.
+    #![allow(dead_code)]
+
+    /// vsscratch register number.
+    const VSSCRATCH: usize = 0x240;
+    /// Virtual supervisor scratch register.
+    pub struct Vsscratch(usize);
+
+    impl_bits!(Vsscratch);
+    read_csr_as!(Vsscratch, 0x240);
+    write_csr_as!(0x240);
+}
+
+pub mod vsepc {
+    //! Virtual supervisor exception program counter.
+    #![allow(dead_code)]
+
+    /// vsepc register number.
+    const VSEPC: usize = 0x241;
+    /// Virtual supervisor exception program counter.
+    pub struct Vsepc(usize);
+
+    impl_bits!(Vsepc);
+    read_csr_as!(Vsepc, 0x241);
+    write_csr_as!(0x241);
+}
+
+pub mod vscause {
+    //! Virtual supervisor cause register.
+    #![allow(dead_code)]
+
+    /// vscause register number.
+    const VSCAUSE: usize = 0x242;
+    /// Virtual supervisor cause register.
+    pub struct Vscause(usize);
+
+    impl_bits!(Vscause);
+    read_csr_as!(Vscause, 0x242);
+    write_csr_as!(0x242);
+}
+
+pub mod vstval {
+    //! Virtual supervisor trap value register.
+    #![allow(dead_code)]
+
+    /// vstval register number.
+    const VSTVAL: usize = 0x243;
+    /// Virtual supervisor trap value register.
+    pub struct Vstval(usize);
+
+    impl_bits!(Vstval);
+    read_csr_as!(Vstval, 0x243);
+    write_csr_as!(0x243);
+}
+
 pub mod vsip {
     //! Virtual supervisor interrupt pending.
     #![allow(dead_code)]
@@ -140,6 +224,35 @@ pub mod vsip {
     }
 }
 
+pub mod vstimecmp {
+    //! Virtual supervisor timer compare register. (Sstc extension)
+    #![allow(dead_code)]
+
+    /// vstimecmp register number.
+    const VSTIMECMP: usize = 0x24d;
+    /// Virtual supervisor timer compare register. (Sstc extension)
+    pub struct Vstimecmp(usize);
+
+    impl_bits!(Vstimecmp);
+    read_csr_as!(Vstimecmp, 0x24d);
+    write_csr_as!(0x24d);
+}
+
+pub mod stimecmp {
+    //! Supervisor timer compare register. (Sstc extension)
+    //! It is used as the scheduler timer of HS-mode.
+    #![allow(dead_code)]
+
+    /// stimecmp register number.
+    const STIMECMP: usize = 0x14d;
+    /// Supervisor timer compare register.
+    pub struct Stimecmp(usize);
+
+    impl_bits!(Stimecmp);
+    read_csr_as!(Stimecmp, 0x14d);
+    write_csr_as!(0x14d);
+}
+
 pub mod vsatp {
     //! Virtual supervisor address translation and protection.
     #![allow(dead_code)]
@@ -179,6 +292,7 @@ pub mod vsatp {
         Sv64 = 11,
     }
 
+    impl_bits!(Vsatp);
     read_csr_as!(Vsatp, 0x280);
     write_csr_as!(0x280);
 }
@@ -192,9 +306,20 @@ pub mod hstatus {
     /// hstatus util functions.
     pub struct Hstatus(usize);
 
+    impl_bits!(Hstatus);
     read_csr_as!(Hstatus, 0x600);
     write_csr_as!(0x600);
 
+    /// set VGEIN field (Virtual Guest External Interrupt Number, 17:12 bit)
+    ///
+    /// It selects the guest interrupt file of IMSIC that is used by VS-mode.
+    pub fn set_vgein(vgein: usize) {
+        /// Mask of VGEIN field.
+        const VGEIN_MASK: usize = 0b11_1111 << 12;
+
+        write(read().bits() & !VGEIN_MASK | (vgein << 12) & VGEIN_MASK);
+    }
+
     /// set spv bit (Supervisor Previous Virtualization mode, 7 bit)
     pub unsafe fn set_spv() {
         core::arch::asm!(
@@ -275,6 +400,32 @@ pub mod hcounteren {
     set_csr_as!(0x606);
 }
 
+pub mod hgeie {
+    //! Hypervisor guest external interrupt-enable register.
+    #![allow(dead_code)]
+
+    /// hgeie register number.
+    const HGEIE: usize = 0x607;
+    /// Hypervisor guest external interrupt-enable register.
+    pub struct Hgeie(usize);
+
+    impl_bits!(Hgeie);
+    read_csr_as!(Hgeie, 0x607);
+    write_csr_as!(0x607);
+
+    /// Return GEILEN (the number of guest external interrupts).
+    ///
+    /// Bits 1..=GEILEN of hgeie are writable, so it is probed by writing all ones.
+    pub fn geilen() -> usize {
+        let saved = read().bits();
+        write(usize::MAX);
+        let writable = read().bits();
+        write(saved);
+
+        writable.count_ones() as usize
+    }
+}
+
 pub mod henvcfg {
     //! Hypervisor environment configuration register.
     #![allow(dead_code)]
@@ -284,6 +435,10 @@ pub mod henvcfg {
     /// Hypervisor environment configuration register.
     pub struct Henvcfg(usize);
 
+    impl_bits!(Henvcfg);
+    read_csr_as!(Henvcfg, 0x60a);
+    write_csr_as!(0x60a);
+
     /// set STCE (63 bit)
     pub fn set_stce() {
         unsafe {
@@ -384,6 +539,7 @@ pub mod hvip {
     set_csr_from_enum!(VsInterruptKind, 0x645);
     clear_csr_from_enum!(VsInterruptKind, 0x645);
 
+    impl_bits!(Hvip);
     read_csr_as!(Hvip, 0x645);
     write_csr_as!(0x645);
 }
@@ -430,6 +586,7 @@ pub mod hgatp {
     }
 
     /// Translation mode in G-stage.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
     #[allow(clippy::module_name_repetitions)]
     pub enum Mode {
         Bare = 0,
@@ -443,6 +600,28 @@ pub mod hgatp {
         write((0xF & (mode as usize)) << 60 | (0x3FFF & vmid) << 44 | 0x0FFF_FFFF_FFFF & ppn);
     }
 
+    /// Return the number of implemented VMID bits (VMIDLEN).
+    ///
+    /// Write one to all bits of VMID field and read back. (p.144)  
+    /// It overwrites hgatp, so it must be called before enabling G-stage translation.
+    pub fn vmid_len() -> usize {
+        set(Mode::Bare, 0x3FFF, 0);
+        let vmid = (read().bits() >> 44) & 0x3FFF;
+        set(Mode::Bare, 0, 0);
+        vmid.count_ones() as usize
+    }
+
+    /// Return whether the translation mode is supported.
+    ///
+    /// Write the mode and read back because MODE field is WARL. (p.144)  
+    /// It overwrites hgatp, so it must be called before enabling G-stage translation.
+    pub fn is_mode_supported(mode: Mode) -> bool {
+        set(mode, 0, 0);
+        let supported = (read().bits() >> 60) & 0b1111 == mode as usize;
+        set(Mode::Bare, 0, 0);
+        supported
+    }
+
     impl_bits!(Hgatp);
     read_csr_as!(Hgatp, 0x680);
     write_csr_as!(0x680);
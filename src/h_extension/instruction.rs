@@ -1,5 +1,7 @@
 //! Utility for H extension instructions.
 
+use crate::memmap::GuestPhysicalAddress;
+
 use core::arch::asm;
 
 /// Hypervisor memory management fence for all virtual machines and guest physical addresses.
@@ -10,3 +12,22 @@ pub fn hfence_gvma_all() {
         asm!("hfence.gvma x0, x0");
     }
 }
+
+/// Hypervisor memory management fence for all guest virtual addresses of current VMID (`hgatp.VMID`).
+#[inline(always)]
+#[allow(clippy::inline_always)]
+pub fn hfence_vvma_all() {
+    unsafe {
+        asm!("hfence.vvma x0, x0");
+    }
+}
+
+/// Hypervisor memory management fence for the guest physical address of the virtual machine.
+#[inline(always)]
+#[allow(clippy::inline_always)]
+pub fn hfence_gvma(gpa: GuestPhysicalAddress, vmid: usize) {
+    // rs1 holds the guest physical address shifted right by 2 bits.
+    unsafe {
+        asm!("hfence.gvma {gpa}, {vmid}", gpa = in(reg) gpa.raw() >> 2, vmid = in(reg) vmid);
+    }
+}
@@ -1,30 +1,36 @@
 //! HS-mode level initialization.
 
-use crate::device::MmioDevice;
-use crate::emulate_extension;
-use crate::guest::Guest;
+use crate::device::{DeviceKind, MmioDevice};
+use crate::emulate_extension::zicfiss::Zicfiss;
+use crate::guest::{
+    config::{self, GuestConfig, GuestImage},
+    context::{Context, ContextData},
+    device_tree, init_vmid,
+    lazy_context::{has_vector_extension, LazyContext},
+    scheduler, Guest, HartState,
+};
 use crate::h_extension::csrs::{
-    hcounteren, hedeleg, hedeleg::ExceptionKind, henvcfg, hgatp, hideleg, hie, hstateen0, hstatus,
-    hvip, vsatp, VsInterruptKind,
+    hcounteren, hedeleg, hedeleg::ExceptionKind, henvcfg, hideleg, hie, hstateen0, hstatus, hvip,
+    vsatp, VsInterruptKind,
 };
 use crate::h_extension::instruction::hfence_gvma_all;
 use crate::memmap::{
-    constant::guest_memory, page_table::sv39x4::ROOT_PAGE_TABLE, GuestPhysicalAddress,
-    HostPhysicalAddress,
+    constant::MAX_HART_NUM, page_table, GuestPhysicalAddress, HostPhysicalAddress,
 };
-use crate::trap::hypervisor_supervisor::hstrap_vector;
-use crate::{HypervisorData, GUEST_DTB, HYPERVISOR_DATA};
+use crate::trap::hypervisor_supervisor::{hstrap_exit, hstrap_vector};
+use crate::{hikami_regions, HypervisorData, FRAME_ALLOCATOR, HYPERVISOR_DATA};
 
+use alloc::vec::Vec;
 use core::arch::asm;
 
-use elf::{endian::AnyEndian, ElfBytes};
-use riscv::register::{sepc, sie, sscratch, sstatus, sstatus::FS, stvec};
+use fdt::Fdt;
+use riscv::register::{sie, sscratch, sstatus::FS, stvec};
 
 /// Entry point to HS-mode.
 #[inline(never)]
 pub extern "C" fn hstart(hart_id: usize, dtb_addr: usize) -> ! {
-    // hart_id must be zero.
-    assert_eq!(hart_id, 0);
+    // hart_id must be less than `MAX_HART_NUM`.
+    assert!(hart_id < MAX_HART_NUM);
 
     // dtb_addr test and hint for register usage.
     assert_ne!(dtb_addr, 0);
@@ -82,25 +88,18 @@ pub extern "C" fn hstart(hart_id: usize, dtb_addr: usize) -> ! {
             | VsInterruptKind::Software as usize,
     );
 
-    vsmode_setup(hart_id, HostPhysicalAddress(dtb_addr));
+    if hart_id == 0 {
+        vsmode_setup(hart_id, HostPhysicalAddress(dtb_addr));
+    } else {
+        vsmode_setup_secondary(hart_id);
+    }
 }
 
 /// Setup for VS-mode
 ///
 /// * Parse DTB
-/// * Setup page table
+/// * Create all guests (setup page table)
 fn vsmode_setup(hart_id: usize, dtb_addr: HostPhysicalAddress) -> ! {
-    // create new guest data
-    let guest_id = hart_id + 1;
-    let guest_memory_begin = guest_memory::DRAM_BASE + guest_id * guest_memory::DRAM_SIZE_PER_GUEST;
-    let root_page_table_addr = HostPhysicalAddress(ROOT_PAGE_TABLE.as_ptr() as usize);
-    let new_guest = Guest::new(
-        hart_id,
-        &ROOT_PAGE_TABLE,
-        &GUEST_DTB,
-        guest_memory_begin..guest_memory_begin + guest_memory::DRAM_SIZE_PER_GUEST,
-    );
-
     // parse device tree
     let device_tree = unsafe {
         match fdt::Fdt::from_ptr(dtb_addr.raw() as *const u8) {
@@ -109,91 +108,384 @@ fn vsmode_setup(hart_id: usize, dtb_addr: HostPhysicalAddress) -> ! {
         }
     };
 
+    // parse guest configurations
+    let guest_configs = config::parse_guest_configs(&device_tree);
+
+    // VMID is limited by VMIDLEN.
+    init_vmid(
+        guest_configs
+            .iter()
+            .map(|guest_config| guest_config.guest_id)
+            .max()
+            .unwrap_or(0),
+    );
+
+    // select G-stage translation mode for all guests.
+    page_table::g_stage::init_mode();
+
     // initialize hypervisor data
     let mut hypervisor_data = unsafe { HYPERVISOR_DATA.lock() };
     hypervisor_data.get_or_init(|| HypervisorData::new(device_tree));
 
-    // load guest elf from address
+    // physical frames are allocated from DRAM except for the regions in use.
     let initrd = &hypervisor_data.get_mut().unwrap().devices().initrd;
-    let guest_elf = unsafe {
-        ElfBytes::<AnyEndian>::minimal_parse(core::slice::from_raw_parts(
-            initrd.paddr().raw() as *mut u8,
-            initrd.size(),
-        ))
+    let mut reserved_regions = Vec::from(hikami_regions());
+    reserved_regions.extend([
+        dtb_addr..dtb_addr + device_tree.total_size(),
+        initrd.paddr()..initrd.paddr() + initrd.size(),
+    ]);
+    if let Some(blob_addr) = config::boot_config_blob_addr(&device_tree) {
+        let blob_size = unsafe { fdt::Fdt::from_ptr(blob_addr.raw() as *const u8) }
+            .unwrap()
+            .total_size();
+        reserved_regions.push(blob_addr..blob_addr + blob_size);
+    }
+    for guest_config in &guest_configs {
+        if let GuestImage::Region(region) = &guest_config.image {
+            reserved_regions.push(region.clone());
+        }
+        reserved_regions.extend(guest_config.dtb.clone());
+        reserved_regions.extend(guest_config.initrd.clone());
+    }
+    FRAME_ALLOCATOR.lock().init(&device_tree, &reserved_regions);
+
+    // hikami owns the physical UART.
+    hypervisor_data
+        .get_mut()
         .unwrap()
-    };
+        .devices()
+        .init_console(hart_id);
 
-    // load guest image
-    let (guest_entry_point, elf_end_addr) =
-        new_guest.load_guest_elf(&guest_elf, initrd.paddr().raw() as *mut u8);
+    for guest_config in &guest_configs {
+        create_guest(
+            hypervisor_data.get_mut().unwrap(),
+            &device_tree,
+            guest_config,
+        );
+    }
+
+    // enable two-level address translation
+    hypervisor_data
+        .get()
+        .unwrap()
+        .guest_by_hart_id(hart_id)
+        .expect("guest data not found")
+        .activate_g_stage();
+    hfence_gvma_all();
+
+    // initialize IOMMU for the guest that owns PCI devices.
+    let guest_id = hypervisor_data
+        .get()
+        .unwrap()
+        .guest_by_hart_id(hart_id)
+        .unwrap()
+        .guest_id();
+    hypervisor_data
+        .get_mut()
+        .unwrap()
+        .devices()
+        .init_iommu(guest_id);
+
+    // initialize APLIC
+    hypervisor_data.get_mut().unwrap().devices().init_aplic();
+
+    // release HYPERVISOR_DATA lock
+    drop(hypervisor_data);
+
+    wait_for_hart_start(hart_id);
+}
+
+/// Load guest image and initramfs, and back the remaining guest memory.
+///
+/// Return entry point of the guest.
+fn load_guest_memory(
+    hypervisor_data: &mut HypervisorData,
+    guest: &mut Guest,
+    guest_config: &GuestConfig,
+) -> GuestPhysicalAddress {
+    // load guest image (ELF, Linux Image or flat binary) from address
+    let image_region = match &guest_config.image {
+        GuestImage::Initrd => {
+            let initrd = &hypervisor_data.devices().initrd;
+            initrd.paddr()..initrd.paddr() + initrd.size()
+        }
+        GuestImage::Region(region) => region.clone(),
+    };
+    let guest_image = unsafe {
+        core::slice::from_raw_parts(
+            image_region.start.raw() as *const u8,
+            image_region.end.raw() - image_region.start.raw(),
+        )
+    };
+    let (guest_entry_point, image_loaded_region) = guest.load_guest_image(guest_image);
+    let mut loaded_regions = Vec::from([image_loaded_region]);
+
+    // copy guest initramfs to the end of guest memory
+    if let (Some(initrd), Some(initrd_region)) =
+        (&guest_config.initrd, guest_config.initrd_region())
+    {
+        assert!(
+            loaded_regions[0].end <= initrd_region.start,
+            "initrd of guest {} overlaps with guest image",
+            guest_config.guest_id
+        );
+        let guest_initrd = unsafe {
+            core::slice::from_raw_parts(
+                initrd.start.raw() as *const u8,
+                initrd.end.raw() - initrd.start.raw(),
+            )
+        };
+        loaded_regions.push(guest.load_guest_initrd(guest_initrd, initrd_region.start));
+    }
 
     // filling remain memory region
-    new_guest.filling_memory_region(
-        elf_end_addr..guest_memory_begin + guest_memory::DRAM_SIZE_PER_GUEST,
+    if guest_config.demand_paging {
+        guest.enable_demand_paging();
+    } else {
+        let mut fill_start = guest_config.memory_region.start;
+        for loaded_region in &loaded_regions {
+            guest.filling_memory_region(fill_start..loaded_region.start);
+            fill_start = loaded_region.end;
+        }
+        guest.filling_memory_region(fill_start..guest_config.memory_region.end);
+    }
+
+    guest_entry_point
+}
+
+/// Create a guest from the configuration and register it to the assigned harts.
+///
+/// The boot hart of the guest starts immediately and the other harts wait for `sbi_hart_start`.
+fn create_guest(
+    hypervisor_data: &mut HypervisorData,
+    host_device_tree: &Fdt,
+    guest_config: &GuestConfig,
+) {
+    let guest_dtb = match &guest_config.dtb {
+        Some(dtb_region) => {
+            let prebuilt = unsafe { Fdt::from_ptr(dtb_region.start.raw() as *const u8) }
+                .expect("failed to parse prebuilt guest device tree");
+            device_tree::patch(&prebuilt, guest_config)
+        }
+        None => device_tree::generate(host_device_tree, guest_config, hypervisor_data.devices()),
+    };
+    let mut new_guest = Guest::new(
+        guest_config.boot_hart_id(),
+        guest_config.guest_id,
+        &guest_dtb,
+        guest_config.memory_region.clone(),
     );
 
+    let guest_entry_point = load_guest_memory(hypervisor_data, &mut new_guest, guest_config);
+
     // set device memory map
     hypervisor_data
-        .get_mut()
-        .unwrap()
         .devices()
-        .device_mapping_g_stage(root_page_table_addr);
+        .device_mapping_g_stage(new_guest.page_table_addr(), &guest_config.devices);
 
-    // enable two-level address translation
-    hgatp::set(hgatp::Mode::Sv39x4, 0, root_page_table_addr.raw() >> 12);
-    hfence_gvma_all();
+    // create virtual PLIC
+    if let Some(plic) = &mut hypervisor_data.devices().plic {
+        if guest_config.devices.contains(&DeviceKind::Plic) {
+            plic.register_guest(guest_config.guest_id, &guest_config.irqs);
+        }
+    }
+
+    // create emulated UART
+    if guest_config.devices.contains(&DeviceKind::Uart) {
+        hypervisor_data
+            .devices()
+            .register_console(guest_config.guest_id);
+    }
 
-    // initialize IOMMU
-    hypervisor_data.get_mut().unwrap().devices().init_iommu();
+    // emulate test finisher
+    if guest_config.devices.contains(&DeviceKind::TestFinisher) {
+        hypervisor_data
+            .devices()
+            .register_test_finisher(guest_config.guest_id);
+    }
 
-    // set new guest data
-    hypervisor_data.get_mut().unwrap().register_guest(new_guest);
+    // assign guest interrupt files of IMSIC to each vCPU.
+    let mut guest_files = Vec::new();
+    if let Some(imsic) = &mut hypervisor_data.devices().imsic {
+        if guest_config.devices.contains(&DeviceKind::Imsic) {
+            for hart_id in &guest_config.harts {
+                let guest_file = imsic.assign_guest_file(guest_config.guest_id, *hart_id);
+                page_table::g_stage::generate_page_table(
+                    new_guest.page_table_addr(),
+                    &[imsic.guest_file_memmap(&guest_file)],
+                );
+                guest_files.push(guest_file);
+            }
+        }
+    }
 
-    // initialize emulate_extension data
-    emulate_extension::initialize();
+    // create virtual APLIC
+    if let Some(aplic) = &mut hypervisor_data.devices().aplic {
+        if guest_config.devices.contains(&DeviceKind::Aplic) {
+            aplic.register_guest(guest_config.guest_id, &guest_config.irqs, &guest_files);
+        }
+    }
 
-    unsafe {
-        // sstatus.SUM = 1, sstatus.SPP = 0
-        sstatus::set_sum();
-        sstatus::set_spp(sstatus::SPP::Supervisor);
-        // sstatus.sie = 1
-        sstatus::set_sie();
-        // sstatus.fs = 1
-        sstatus::set_fs(FS::Initial);
+    new_guest.set_priority(guest_config.priority);
+
+    // boot hart enters the guest with device tree address.
+    new_guest.set_hart_state(HartState::StartPending {
+        start_addr: guest_entry_point,
+        opaque: new_guest.guest_dtb_addr().raw(),
+    });
+
+    let vgein_of = |hart_id: usize| {
+        guest_files
+            .iter()
+            .find(|file| file.hart_id == hart_id)
+            .map_or(0, |file| file.vgein)
+    };
+    for hart_id in &guest_config.harts[1..] {
+        let mut secondary_guest = Guest::new_secondary(*hart_id, &new_guest);
+        secondary_guest.set_vgein(vgein_of(*hart_id));
+        hypervisor_data.register_guest(secondary_guest);
+    }
+    new_guest.set_vgein(vgein_of(new_guest.hart_id()));
+    hypervisor_data.register_guest(new_guest);
+}
+
+/// Setup for VS-mode of secondary harts.
+///
+/// * Wait for the boot hart to create guest.
+/// * Wait for `sbi_hart_start` from guest.
+fn vsmode_setup_secondary(hart_id: usize) -> ! {
+    // wait for the boot hart to register guest.
+    loop {
+        let hypervisor_data = unsafe { HYPERVISOR_DATA.lock() };
+        if hypervisor_data
+            .get()
+            .is_some_and(|data| data.guest_by_hart_id(hart_id).is_some())
+        {
+            break;
+        }
+        drop(hypervisor_data);
+        core::hint::spin_loop();
+    }
+
+    wait_for_hart_start(hart_id);
+}
+
+/// Wait for `sbi_hart_start` from guest and enter VS-mode.
+///
+/// It is also used to park the hart that is stopped by `sbi_hart_stop`.
+/// If other vCPUs are assigned to the hart, the hart runs them while waiting.
+pub fn wait_for_hart_start(hart_id: usize) -> ! {
+    let (start_addr, opaque) = loop {
+        let mut hypervisor_data = unsafe { HYPERVISOR_DATA.lock() };
+        let guest = hypervisor_data
+            .get_mut()
+            .unwrap()
+            .guest_by_hart_id_mut(hart_id)
+            .expect("guest data not found");
+        if let HartState::StartPending { start_addr, opaque } = guest.hart_state() {
+            guest.set_hart_state(HartState::Started);
+
+            // switch G-stage address translation and guest interrupt file to the guest.
+            guest.activate_g_stage();
+            guest.activate_interrupt_file();
+            hfence_gvma_all();
+
+            break (start_addr, opaque);
+        }
 
+        // run other vCPU on the hart.
+        if scheduler::switch_vcpu(hypervisor_data.get_mut().unwrap(), hart_id) {
+            hypervisor_data.get_mut().unwrap().schedulers[hart_id].start_slice();
+            scheduler::arm_timer(hypervisor_data.get().unwrap(), hart_id);
+            drop(hypervisor_data);
+            unsafe { hstrap_exit() };
+        }
+
+        drop(hypervisor_data);
+        core::hint::spin_loop();
+    };
+
+    // The hart starts with address translation disabled. (satp = 0)
+    vsatp::write(0);
+
+    let mut hypervisor_data = unsafe { HYPERVISOR_DATA.lock() };
+    let guest = hypervisor_data
+        .get_mut()
+        .unwrap()
+        .guest_by_hart_id_mut(hart_id)
+        .unwrap();
+    // the hart starts with clean context. (e.g. no shadow stack)
+    guest.context.load(&ContextData::default());
+    guest.zicfiss = Zicfiss::new();
+    guest.set_timer(usize::MAX);
+    prepare_vsmode_entry(guest.context, start_addr);
+    // floating-point and vector registers are restored (zero cleared) on first use.
+    guest.lazy_context = LazyContext::new();
+    guest.lazy_context.defer_restore(&mut guest.context);
+
+    // start time-slicing if the hart is shared.
+    hypervisor_data.get_mut().unwrap().schedulers[hart_id].start_slice();
+    scheduler::arm_timer(hypervisor_data.get().unwrap(), hart_id);
+    drop(hypervisor_data);
+
+    hart_entry(hart_id, opaque);
+}
+
+/// Set CSRs and guest context to enter VS-mode from `entry_point`.
+///
+/// It does not change `sstatus` and `sepc` of HS-mode, so it can be called in trap handler.
+/// These values are restored from the context on entering VS-mode.
+pub fn prepare_vsmode_entry(mut context: Context, entry_point: GuestPhysicalAddress) {
+    /// sstatus.SIE
+    const SSTATUS_SIE: usize = 1 << 1;
+    /// sstatus.SPP
+    const SSTATUS_SPP: usize = 1 << 8;
+    /// sstatus.VS
+    const SSTATUS_VS: usize = 0b11 << 9;
+    /// sstatus.FS
+    const SSTATUS_FS: usize = 0b11 << 13;
+    /// sstatus.SUM
+    const SSTATUS_SUM: usize = 1 << 18;
+
+    unsafe {
         // hstatus.spv = 1 (enable V bit when sret executed)
         hstatus::set_spv();
 
-        // set entry point
-        sepc::write(guest_entry_point.raw());
-
         // set trap vector
         assert!(hstrap_vector as *const fn() as usize % 4 == 0);
         stvec::write(
             hstrap_vector as *const fn() as usize,
             stvec::TrapMode::Direct,
         );
+    }
 
-        let mut context = hypervisor_data.get().unwrap().guest().context;
-        context.set_sepc(sepc::read());
+    // set entry point
+    context.set_sepc(entry_point.raw());
 
-        // set sstatus value to context
-        let mut sstatus_val;
+    // sstatus.SUM = 1, sstatus.SPP = 1 (Supervisor), sstatus.SIE = 1,
+    // sstatus.FS = 1 (Initial), sstatus.VS = 1 (Initial) (VS is encoded as same as FS)
+    // sstatus.VS is kept 0 (Off) if V extension is not implemented.
+    let mut sstatus_val: usize;
+    unsafe {
         asm!("csrr {}, sstatus", out(reg) sstatus_val);
-        context.set_sstatus(sstatus_val);
     }
-
-    let guest_dtb_addr = hypervisor_data.get().unwrap().guest().guest_dtb_addr();
-
-    // release HYPERVISOR_DATA lock
-    drop(hypervisor_data);
-
-    hart_entry(hart_id, guest_dtb_addr);
+    sstatus_val = (sstatus_val & !SSTATUS_FS & !SSTATUS_VS)
+        | SSTATUS_SUM
+        | SSTATUS_SPP
+        | SSTATUS_SIE
+        | (FS::Initial as usize) << 13;
+    if has_vector_extension() {
+        sstatus_val |= (FS::Initial as usize) << 9;
+    }
+    context.set_sstatus(sstatus_val);
 }
 
 /// Entry for guest (VS-mode).
+///
+/// * `hart_id` - Value passed to `a0`.
+/// * `opaque` - Value passed to `a1`. (device tree address for the boot hart)
 #[inline(never)]
-fn hart_entry(hart_id: usize, dtb_addr: GuestPhysicalAddress) -> ! {
+fn hart_entry(hart_id: usize, opaque: usize) -> ! {
     // aquire hypervisor data
     let hypervisor_data = unsafe { HYPERVISOR_DATA.lock() };
     let stack_top = hypervisor_data.get().unwrap().guest().stack_top();
@@ -211,7 +503,7 @@ fn hart_entry(hart_id: usize, dtb_addr: GuestPhysicalAddress) -> ! {
 
             // set sp to scratch stack top
             mv sp, {stack_top}  
-            addi sp, sp, -272 // Size of ContextData = 8 * 34
+            addi sp, sp, -368 // Size of ContextData = 8 * 46
 
             // restore sstatus 
             ld t0, 32*8(sp)
@@ -231,7 +523,7 @@ fn hart_entry(hart_id: usize, dtb_addr: GuestPhysicalAddress) -> ! {
             ld s0, 8*8(sp)
             ld s1, 9*8(sp)
             // a0 -> hart_id
-            // a1 -> dtb_addr
+            // a1 -> opaque
             ld a2, 12*8(sp)
             ld a3, 13*8(sp)
             ld a4, 14*8(sp)
@@ -254,13 +546,13 @@ fn hart_entry(hart_id: usize, dtb_addr: GuestPhysicalAddress) -> ! {
             ld t6, 31*8(sp)
 
             // swap HS-mode sp for original mode sp.
-            addi sp, sp, 272
+            addi sp, sp, 368
             csrrw sp, sscratch, sp
 
             sret
             ",
             in("a0") hart_id,
-            in("a1") dtb_addr.raw(),
+            in("a1") opaque,
             stack_top = in(reg) stack_top.raw(),
             options(noreturn)
         );
@@ -1,9 +1,10 @@
 //! M-mode level initialization.
 
+use crate::guest::context::ContextData;
 use crate::hypervisor_init;
 use crate::memmap::constant::STACK_SIZE_PER_HART;
 use crate::trap::machine::mtrap_vector;
-use crate::{sbi::Sbi, SBI};
+use crate::{hs_stack_top, sbi::Sbi, SBI};
 use core::arch::asm;
 use riscv::asm::sfence_vma_all;
 use riscv::register::{
@@ -32,6 +33,7 @@ pub fn mstart(hart_id: usize, dtb_addr: usize) -> ! {
         medeleg::set_load_page_fault();
         medeleg::set_store_page_fault();
         asm!("csrs medeleg, {vsmode_ecall}", vsmode_ecall = in(reg) 1 << 10, options(nomem)); // deleg env call from VS-mode
+        asm!("csrs medeleg, {instruction_guest_page_fault}", instruction_guest_page_fault = in(reg) 1 << 20, options(nomem)); // deleg instruction guest page fault
         asm!("csrs medeleg, {load_guest_page_fault}", load_guest_page_fault = in(reg) 1 << 21, options(nomem)); // deleg load guest page fault
         asm!("csrs medeleg, {virtual_instruction}", virtual_instruction = in(reg) 1 << 22, options(nomem)); // deleg virtual instruction
         asm!("csrs medeleg, {store_amo_guest_page_fault}", store_amo_guest_page_fault = in(reg) 1 << 23, options(nomem)); // deleg store/amo guest page fault
@@ -141,11 +143,12 @@ extern "C" fn enter_hypervisor_mode(hart_id: usize, dtb_addr: usize) -> ! {
             "
             mv t0, {hart_id}
             mv t1, {dtb_addr}
-            mv sp, {machine_sp}
+            mv sp, {hs_sp}
             ",
             hart_id = in(reg) hart_id,
             dtb_addr = in(reg) dtb_addr,
-            machine_sp = in(reg) core::ptr::addr_of!(crate::_top_m_stack) as usize + STACK_SIZE_PER_HART * hart_id
+            // HS-mode stack is placed under the guest context.
+            hs_sp = in(reg) hs_stack_top(hart_id).raw() - core::mem::size_of::<ContextData>()
         );
         // enter HS-mode.
         asm!(
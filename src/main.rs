@@ -13,22 +13,22 @@ mod memmap;
 mod sbi;
 mod trap;
 
-use alloc::boxed::Box;
-use alloc::vec::Vec;
 use core::arch::asm;
 use core::cell::OnceCell;
+use core::ops::Range;
 use core::panic::PanicInfo;
 
 use fdt::Fdt;
 use linked_list_allocator::LockedHeap;
+use riscv::register::{mie, mip};
 use riscv_rt::entry;
 use spin::Mutex;
 
 use crate::device::Devices;
-use crate::guest::Guest;
+use crate::guest::{scheduler::Scheduler, vcpu::Vcpu, Guest};
 use crate::machine_init::mstart;
-use crate::memmap::constant::{DRAM_BASE, MAX_HART_NUM, STACK_SIZE_PER_HART};
-use crate::memmap::HostPhysicalAddress;
+use crate::memmap::constant::{device::CLINT_ADDR, DRAM_BASE, MAX_HART_NUM, STACK_SIZE_PER_HART};
+use crate::memmap::{frame_allocator::FrameAllocator, HostPhysicalAddress};
 use crate::sbi::Sbi;
 
 #[global_allocator]
@@ -42,22 +42,33 @@ static mut HYPERVISOR_DATA: Mutex<OnceCell<HypervisorData>> = Mutex::new(OnceCel
 /// Singleton for SBI handler.
 static SBI: Mutex<OnceCell<Sbi>> = Mutex::new(OnceCell::new());
 
+/// Physical frame allocator for guest memory, page tables and device queues.
+///
+/// It is initialized by the boot hart in `vsmode_setup`.
+static FRAME_ALLOCATOR: Mutex<FrameAllocator> = Mutex::new(FrameAllocator::new());
+
 /// Device tree blob that is passed to hypervisor
 #[cfg(feature = "embedded_host_dtb")]
 #[link_section = ".host_dtb"]
 static HOST_DTB: [u8; include_bytes!("../host.dtb").len()] = *include_bytes!("../host.dtb");
 
-/// Device tree blob that is passed to guest
-#[link_section = ".guest_dtb"]
-static GUEST_DTB: [u8; include_bytes!("../guest.dtb").len()] = *include_bytes!("../guest.dtb");
-
 extern "C" {
     /// stack top (defined in `memory.x`)
     static _stack_start: u8;
+    /// start of hikami image (defined in `memory.x`)
+    static _start_image: u8;
+    /// end of hikami image (defined in `memory.x`)
+    static _end_image: u8;
+    /// start of data (defined in `memory.x`)
+    static _start_data: u8;
     /// start of heap (defined in `memory.x`)
     static mut _start_heap: u8;
+    /// end of heap (defined in `memory.x`)
+    static _end_heap: u8;
     /// heap size (defined in `memory.x`)
     static _hv_heap_size: u8;
+    /// machine stack bottom (defined in `memory.x`)
+    static _bottom_m_stack: u8;
     /// machine stack top (defined in `memory.x`)
     static _top_m_stack: u8;
 }
@@ -71,21 +82,67 @@ pub fn panic(info: &PanicInfo) -> ! {
     }
 }
 
+/// Return hart id of the current hart.
+///
+/// hikami keeps its own hart id in `tp` register while it runs in M-mode or HS-mode.
+/// (`tp` is set in `_start`, `mtrap_vector` and `hstrap_vector`)
+#[inline(always)]
+#[allow(clippy::inline_always)]
+#[must_use]
+pub fn current_hart_id() -> usize {
+    let hart_id;
+    unsafe {
+        asm!("mv {}, tp", out(reg) hart_id);
+    }
+    hart_id
+}
+
+/// Return top of HS-mode stack of the hart.
+///
+/// Stack of each hart is `STACK_SIZE_PER_HART` and they are placed from `_stack_start` downward.
+/// It is same as stack that is allocated for each hart by `riscv-rt`. (See `_hart_stack_size` in `memory.x`)
+#[must_use]
+pub fn hs_stack_top(hart_id: usize) -> HostPhysicalAddress {
+    let stack_start = unsafe { core::ptr::addr_of!(_stack_start) as usize };
+    HostPhysicalAddress(stack_start - hart_id * STACK_SIZE_PER_HART)
+}
+
+/// Return memory regions that hikami itself occupies.
+///
+/// They are image (text and rodata), data (data, bss and heap), machine stack and HS-mode stacks of all harts.
+#[must_use]
+pub fn hikami_regions() -> [Range<HostPhysicalAddress>; 4] {
+    /// Convert address of the linker symbol.
+    macro_rules! symbol_addr {
+        ($symbol:ident) => {
+            HostPhysicalAddress(unsafe { core::ptr::addr_of!($symbol) as usize })
+        };
+    }
+
+    [
+        symbol_addr!(_start_image)..symbol_addr!(_end_image),
+        symbol_addr!(_start_data)..symbol_addr!(_end_heap),
+        symbol_addr!(_bottom_m_stack)..symbol_addr!(_top_m_stack),
+        hs_stack_top(MAX_HART_NUM - 1) - STACK_SIZE_PER_HART..hs_stack_top(0),
+    ]
+}
+
 /// Aligned page size memory block
 #[repr(C, align(0x1000))]
 struct PageBlock([u8; 0x1000]);
 
 impl PageBlock {
     /// Return aligned address of page size memory block.
+    ///
+    /// The block is allocated from `FRAME_ALLOCATOR` and not initialized.
+    ///
+    /// # Panics
+    /// It will be panic if physical frames are exhausted.
     fn alloc() -> HostPhysicalAddress {
-        let mut host_physical_block_as_vec: Vec<core::mem::MaybeUninit<PageBlock>> =
-            Vec::with_capacity(1);
-        unsafe {
-            host_physical_block_as_vec.set_len(1);
-        }
-
-        let host_physical_block_slice = host_physical_block_as_vec.into_boxed_slice();
-        HostPhysicalAddress(Box::into_raw(host_physical_block_slice) as *const u8 as usize)
+        FRAME_ALLOCATOR
+            .lock()
+            .alloc(1, core::mem::align_of::<PageBlock>())
+            .expect("physical frames are exhausted")
     }
 }
 
@@ -94,10 +151,10 @@ impl PageBlock {
 /// FIXME: Rename me!
 #[derive(Debug)]
 pub struct HypervisorData {
-    /// Current hart id (zero indexed).
-    current_hart: usize,
-    /// Guests data
+    /// Guests data (running vCPU of each hart)
     guests: [Option<guest::Guest>; MAX_HART_NUM],
+    /// vCPU schedulers of each hart.
+    schedulers: [Scheduler; MAX_HART_NUM],
     /// Devices data.
     devices: device::Devices,
 }
@@ -110,8 +167,8 @@ impl HypervisorData {
     #[must_use]
     pub fn new(device_tree: Fdt) -> Self {
         HypervisorData {
-            current_hart: 0,
             guests: [const { None }; MAX_HART_NUM],
+            schedulers: [const { Scheduler::new() }; MAX_HART_NUM],
             devices: Devices::new(device_tree),
         }
     }
@@ -131,19 +188,98 @@ impl HypervisorData {
     /// It will be panic if current HART's guest data is empty.
     #[must_use]
     pub fn guest(&self) -> &Guest {
-        self.guests[self.current_hart]
+        self.guests[current_hart_id()]
             .as_ref()
             .expect("guest data not found")
     }
 
+    /// Return guest that is running on the hart.
+    #[must_use]
+    pub fn guest_by_hart_id(&self, hart_id: usize) -> Option<&Guest> {
+        self.guests.get(hart_id)?.as_ref()
+    }
+
+    /// Return mutable guest that is running on the hart.
+    pub fn guest_by_hart_id_mut(&mut self, hart_id: usize) -> Option<&mut Guest> {
+        self.guests.get_mut(hart_id)?.as_mut()
+    }
+
+    /// Return vCPU of the guest on the hart regardless of whether it is running.
+    #[must_use]
+    pub fn vcpu_guest(&self, guest_id: usize, hart_id: usize) -> Option<&Guest> {
+        self.guest_by_hart_id(hart_id)
+            .into_iter()
+            .chain(self.schedulers.get(hart_id)?.iter().map(Vcpu::guest))
+            .find(|guest| guest.guest_id() == guest_id)
+    }
+
+    /// Return mutable vCPU of the guest on the hart regardless of whether it is running.
+    pub fn vcpu_guest_mut(&mut self, guest_id: usize, hart_id: usize) -> Option<&mut Guest> {
+        let running_guest = self.guests.get_mut(hart_id)?.as_mut();
+        let waiting_guests = self
+            .schedulers
+            .get_mut(hart_id)?
+            .iter_mut()
+            .map(Vcpu::guest_mut);
+        running_guest
+            .into_iter()
+            .chain(waiting_guests)
+            .find(|guest| guest.guest_id() == guest_id)
+    }
+
     /// Add new guest data.
     ///
+    /// If another vCPU is already registered to the hart, the new one waits in the scheduler.
+    ///
     /// # Panics
     /// It will be panic if `hart_id` is greater than `MAX_HART_NUM`.
     pub fn register_guest(&mut self, new_guest: Guest) {
         let hart_id = new_guest.hart_id();
         assert!(hart_id < MAX_HART_NUM);
-        self.guests[hart_id] = Some(new_guest);
+        if self.guests[hart_id].is_none() {
+            self.guests[hart_id] = Some(new_guest);
+        } else {
+            self.schedulers[hart_id].push(Vcpu::new(new_guest));
+        }
+    }
+}
+
+/// Multi processor hook for `riscv-rt`.
+///
+/// Only boot hart (hart 0) initializes `.bss` and `.data` (returns `true`).
+/// The other harts are parked until the boot hart finishes initialization of the global data and sends IPI.
+/// Global data must not be used for the wait because it is not initialized yet.
+#[must_use]
+#[export_name = "_mp_hook"]
+pub extern "Rust" fn mp_hook(hart_id: usize) -> bool {
+    if hart_id == 0 {
+        true
+    } else {
+        // wfi wakes up by pending interrupt that is enabled in mie even if mstatus.MIE is cleared.
+        unsafe {
+            mie::set_msoft();
+        }
+        while !mip::read().msoft() {
+            riscv::asm::wfi();
+        }
+
+        let msip_ptr = (CLINT_ADDR.raw() + hart_id * 4) as *mut u32;
+        unsafe {
+            msip_ptr.write_volatile(0);
+        }
+        false
+    }
+}
+
+/// Wake up secondary harts that are parked in `_mp_hook` by machine software interrupt.
+///
+/// MSIP of harts that do not exist is placed in the CLINT region, so it writes to all `MAX_HART_NUM` harts.
+fn release_secondary_harts() {
+    for hart_id in 1..MAX_HART_NUM {
+        let msip_ptr = (CLINT_ADDR.raw() + hart_id * 4) as *mut u32;
+        unsafe {
+            msip_ptr.write_volatile(1);
+        }
     }
 }
 
@@ -153,12 +289,17 @@ impl HypervisorData {
 /// * jump to mstart
 #[entry]
 fn _start(hart_id: usize, dtb_addr: usize) -> ! {
-    unsafe {
-        // Initialize global allocator
-        ALLOCATOR.lock().init(
-            core::ptr::addr_of_mut!(_start_heap),
-            core::ptr::addr_of!(_hv_heap_size) as usize,
-        );
+    if hart_id == 0 {
+        unsafe {
+            // Initialize global allocator
+            ALLOCATOR.lock().init(
+                core::ptr::addr_of_mut!(_start_heap),
+                core::ptr::addr_of!(_hv_heap_size) as usize,
+            );
+        }
+
+        // wake up secondary harts
+        release_secondary_harts();
     }
 
     unsafe {
@@ -167,6 +308,7 @@ fn _start(hart_id: usize, dtb_addr: usize) -> ! {
             "
             mv a0, {hart_id}
             mv a1, {dtb_addr}
+            mv tp, {hart_id}
             mv t1, {stack_size_per_hart}
             mul t0, a0, t1
             mv sp, {stack_base}
@@ -1,6 +1,13 @@
 //! See `memmap/constant` module for specefic memmory map.
 
 pub mod constant;
+pub mod frame_allocator;
+// It uses hypervisor virtual-machine load and store instructions.
+#[cfg(target_arch = "riscv64")]
+pub mod guest_access;
+// Host tests replace it with the mock that translates by software.
+#[cfg(not(target_arch = "riscv64"))]
+pub use crate::guest_access;
 pub mod page_table;
 
 use crate::memmap::page_table::PteFlag;
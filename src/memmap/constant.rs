@@ -7,12 +7,16 @@
 //! | `0x8000_0000` | `0x8000_XXXX` | text data of hikami |
 //!
 //! # Guest physical address
-//! | start         | end           | region                 |
-//! |---------------|---------------|------------------------|
-//! | `0xXXXX_XXXX` | `0xXXXX_XXXX` | device identity map    |
-//! |               |               |                        |
-//! | `0x8000_0000` | `0x8000_2000` | device tree of guest 1 |
-//! | `0x9000_0000` | `0xa000_0000` | text data of guest 1   |
+//! | start         | end           | region                          |
+//! |---------------|---------------|---------------------------------|
+//! | `0xXXXX_XXXX` | `0xXXXX_XXXX` | device identity map             |
+//! |               |               |                                 |
+//! | `0x8000_0000` | `0x8008_0000` | device tree (for each hart)     |
+//! | `0x9000_0000` | `0xa000_0000` | text data of guest 1 (default)  |
+//! | `0xa000_0000` | `0xb000_0000` | text data of guest 2 (default)  |
+//!
+//! Base and size of guest memory can be configured for each guest. (See `guest::config`)
+//! Each guest has its own G-stage page table, so guests can not access the memory of other guests.
 
 /// Max number of HART
 pub const MAX_HART_NUM: usize = 8;
@@ -42,8 +46,10 @@ pub mod guest_memory {
 
     /// Dram base address
     pub const DRAM_BASE: GuestPhysicalAddress = GuestPhysicalAddress(0x8000_0000);
-    /// Dram memory space per HART.
-    pub const DRAM_SIZE_PER_GUEST: usize = 256 * 1024 * 1024; // 256 MB = 0x1000_0000
+    /// Default dram memory space per guest.
+    ///
+    /// Default base address of guest memory is `DRAM_BASE + guest_id * DEFAULT_DRAM_SIZE`.
+    pub const DEFAULT_DRAM_SIZE: usize = 256 * 1024 * 1024; // 256 MB = 0x1000_0000
     /// Guest DTB space size
-    pub const GUEST_DTB_SIZE_PER_HART: usize = 0x2000;
+    pub const GUEST_DTB_SIZE_PER_HART: usize = 0x1_0000;
 }
@@ -0,0 +1,362 @@
+//! Physical frame allocator.
+//!
+//! Buddy allocator of host physical page frames that is separated from the global heap.
+//! It manages the host DRAM described by `/memory` nodes of the host device tree,
+//! except for hikami itself and the regions that are still in use. (device tree, initrd, guest images, ...)
+//!
+//! Free blocks of each order are kept in `BTreeSet` on the global heap, so that the frames themselves are never touched.
+
+use super::page_table::constants::PAGE_SIZE;
+use super::HostPhysicalAddress;
+
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+use core::ops::Range;
+use fdt::Fdt;
+
+/// Max order of block. (2^18 pages = 1 GiB)
+const MAX_ORDER: usize = 18;
+
+/// Usage statistics of physical frames.
+#[derive(Debug, Copy, Clone)]
+pub struct FrameStats {
+    /// Number of managed frames.
+    pub total_frames: usize,
+    /// Number of free frames.
+    pub free_frames: usize,
+}
+
+/// Buddy allocator of physical frames.
+#[derive(Debug)]
+#[allow(clippy::module_name_repetitions)]
+pub struct FrameAllocator {
+    /// Start addresses of free blocks for each order.
+    free_lists: [BTreeSet<usize>; MAX_ORDER + 1],
+    /// Number of managed frames.
+    total_frames: usize,
+    /// Number of free frames.
+    free_frames: usize,
+}
+
+impl FrameAllocator {
+    /// Constructor for `FrameAllocator`.
+    ///
+    /// It manages no memory until `init` or `add_region` is called.
+    pub const fn new() -> Self {
+        FrameAllocator {
+            free_lists: [const { BTreeSet::new() }; MAX_ORDER + 1],
+            total_frames: 0,
+            free_frames: 0,
+        }
+    }
+
+    /// Add DRAM regions of the host device tree.
+    ///
+    /// Memory reservations of the device tree, `/reserved-memory` and `reserved` are excluded.
+    pub fn init(&mut self, device_tree: &Fdt, reserved: &[Range<HostPhysicalAddress>]) {
+        let mut reserved = reserved.to_vec();
+        reserved.extend(device_tree.memory_reservations().map(|reservation| {
+            let start = HostPhysicalAddress(reservation.address() as usize);
+            start..start + reservation.size()
+        }));
+        if let Some(reserved_memory) = device_tree.find_node("/reserved-memory") {
+            for child in reserved_memory.children() {
+                for region in child.reg().into_iter().flatten() {
+                    let start = HostPhysicalAddress(region.starting_address as usize);
+                    reserved.push(start..start + region.size.unwrap_or(0));
+                }
+            }
+        }
+        reserved.sort_by_key(|range| range.start.raw());
+
+        for region in device_tree.memory().regions() {
+            let start = HostPhysicalAddress(region.starting_address as usize);
+            let end = start + region.size.unwrap_or(0);
+            for free in subtract_ranges(start..end, &reserved) {
+                self.add_region(free);
+            }
+        }
+    }
+
+    /// Add free physical memory region.
+    ///
+    /// Partial pages at both ends of the region are ignored.
+    pub fn add_region(&mut self, region: Range<HostPhysicalAddress>) {
+        let start = region.start.raw().next_multiple_of(PAGE_SIZE);
+        let end = region.end.raw() / PAGE_SIZE * PAGE_SIZE;
+        if start >= end {
+            return;
+        }
+
+        self.total_frames += (end - start) / PAGE_SIZE;
+        self.free_range(start..end);
+    }
+
+    /// Allocate `pages` contiguous frames that are aligned to `align` bytes.
+    ///
+    /// Return `None` if there is no free block that satisfies the request.
+    /// The frames are not initialized.
+    pub fn alloc(&mut self, pages: usize, align: usize) -> Option<HostPhysicalAddress> {
+        assert!(pages > 0, "allocating zero frames");
+        assert!(align.is_power_of_two());
+
+        let size_order = pages.next_power_of_two().trailing_zeros() as usize;
+        let align_order = (align / PAGE_SIZE).max(1).trailing_zeros() as usize;
+        let order = size_order.max(align_order);
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        // find the smallest free block and split it.
+        let mut block_order = (order..=MAX_ORDER).find(|o| !self.free_lists[*o].is_empty())?;
+        let block = self.free_lists[block_order].pop_first().unwrap();
+        while block_order > order {
+            block_order -= 1;
+            self.free_lists[block_order].insert(block + (PAGE_SIZE << block_order));
+        }
+        self.free_frames -= 1 << order;
+
+        // return the unused tail of the block.
+        self.free_range(block + pages * PAGE_SIZE..block + (PAGE_SIZE << order));
+
+        Some(HostPhysicalAddress(block))
+    }
+
+    /// Free `pages` frames from `addr` that are allocated by `alloc`.
+    ///
+    /// A part of an allocated region can also be freed.
+    pub fn free(&mut self, addr: HostPhysicalAddress, pages: usize) {
+        assert!(addr % PAGE_SIZE == 0);
+        self.free_range(addr.raw()..addr.raw() + pages * PAGE_SIZE);
+    }
+
+    /// Return usage statistics.
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total_frames: self.total_frames,
+            free_frames: self.free_frames,
+        }
+    }
+
+    /// Split the page aligned range into the largest aligned blocks and free them.
+    fn free_range(&mut self, range: Range<usize>) {
+        let mut addr = range.start;
+        while addr < range.end {
+            let order = (0..=MAX_ORDER)
+                .rev()
+                .find(|o| addr % (PAGE_SIZE << o) == 0 && addr + (PAGE_SIZE << o) <= range.end)
+                .unwrap();
+            self.free_block(addr, order);
+            addr += PAGE_SIZE << order;
+        }
+    }
+
+    /// Free the block and merge it with its buddy as far as possible.
+    fn free_block(&mut self, mut addr: usize, mut order: usize) {
+        self.free_frames += 1 << order;
+        while order < MAX_ORDER {
+            let buddy = addr ^ (PAGE_SIZE << order);
+            if !self.free_lists[order].remove(&buddy) {
+                break;
+            }
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        self.free_lists[order].insert(addr);
+    }
+}
+
+/// Return the parts of `range` that do not overlap with `reserved`.
+///
+/// `reserved` must be sorted by start address.
+fn subtract_ranges(
+    range: Range<HostPhysicalAddress>,
+    reserved: &[Range<HostPhysicalAddress>],
+) -> Vec<Range<HostPhysicalAddress>> {
+    let mut free = Vec::new();
+    let mut start = range.start;
+    for reserved in reserved {
+        if reserved.end <= start || reserved.start >= range.end {
+            continue;
+        }
+        if reserved.start > start {
+            free.push(start..reserved.start);
+        }
+        if reserved.end > start {
+            start = reserved.end;
+        }
+    }
+    if start < range.end {
+        free.push(start..range.end);
+    }
+
+    free
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Base address of managed region for tests. (aligned to 1 GiB)
+    const BASE: usize = 0x1_0000_0000;
+    /// Size of 2 MiB block.
+    const SIZE_2MB: usize = 0x20_0000;
+
+    /// Create allocator that manages `pages` frames from `BASE`.
+    ///
+    /// The frames are never touched, so the region need not exist.
+    fn allocator(pages: usize) -> FrameAllocator {
+        let mut allocator = FrameAllocator::new();
+        allocator
+            .add_region(HostPhysicalAddress(BASE)..HostPhysicalAddress(BASE + pages * PAGE_SIZE));
+        allocator
+    }
+
+    /// Return the number of free blocks of each order.
+    fn free_blocks(allocator: &FrameAllocator) -> Vec<usize> {
+        allocator.free_lists.iter().map(BTreeSet::len).collect()
+    }
+
+    /// Region is split into the largest aligned blocks and partial pages are ignored.
+    #[test]
+    fn add_region() {
+        let mut allocator = FrameAllocator::new();
+        allocator.add_region(
+            HostPhysicalAddress(BASE - PAGE_SIZE + 1)
+                ..HostPhysicalAddress(BASE + 3 * PAGE_SIZE + 1),
+        );
+
+        let stats = allocator.stats();
+        assert_eq!(stats.total_frames, 3);
+        assert_eq!(stats.free_frames, 3);
+        assert_eq!(free_blocks(&allocator)[..3], [1, 1, 0]);
+        assert!(allocator.free_lists[1].contains(&BASE));
+        assert!(allocator.free_lists[0].contains(&(BASE + 2 * PAGE_SIZE)));
+    }
+
+    /// Allocated frames are disjoint and they are counted in statistics.
+    #[test]
+    fn alloc_and_stats() {
+        let mut allocator = allocator(8);
+
+        let first = allocator.alloc(1, PAGE_SIZE).unwrap();
+        let second = allocator.alloc(1, PAGE_SIZE).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(allocator.stats().free_frames, 6);
+
+        // the unused tail of the block is freed.
+        let block = allocator.alloc(3, PAGE_SIZE).unwrap();
+        assert_eq!(block, HostPhysicalAddress(BASE + 4 * PAGE_SIZE));
+        assert_eq!(allocator.stats().free_frames, 3);
+        assert_eq!(allocator.stats().total_frames, 8);
+
+        assert!(allocator.alloc(4, PAGE_SIZE).is_none());
+        for _ in 0..3 {
+            assert!(allocator.alloc(1, PAGE_SIZE).is_some());
+        }
+        assert!(allocator.alloc(1, PAGE_SIZE).is_none());
+        assert_eq!(allocator.stats().free_frames, 0);
+    }
+
+    /// Allocated block is aligned to the requested alignment.
+    #[test]
+    fn alloc_aligned() {
+        let mut allocator = allocator(2 * SIZE_2MB / PAGE_SIZE);
+
+        // make the region fragmented
+        let page = allocator.alloc(1, PAGE_SIZE).unwrap();
+        assert_eq!(page, HostPhysicalAddress(BASE));
+
+        let block = allocator.alloc(1, SIZE_2MB).unwrap();
+        assert_eq!(block % SIZE_2MB, 0);
+        assert_eq!(block, HostPhysicalAddress(BASE + SIZE_2MB));
+        assert!(allocator.alloc(SIZE_2MB / PAGE_SIZE, SIZE_2MB).is_none());
+
+        let pages = allocator.alloc(4, 4 * PAGE_SIZE).unwrap();
+        assert_eq!(pages % (4 * PAGE_SIZE), 0);
+    }
+
+    /// Block that is larger than max order can not be allocated.
+    #[test]
+    fn alloc_too_large() {
+        let mut allocator = allocator(1 << MAX_ORDER);
+        assert!(allocator.alloc((1 << MAX_ORDER) + 1, PAGE_SIZE).is_none());
+        assert!(allocator.alloc(1, PAGE_SIZE << (MAX_ORDER + 1)).is_none());
+        assert_eq!(
+            allocator.alloc(1 << MAX_ORDER, PAGE_SIZE << MAX_ORDER),
+            Some(HostPhysicalAddress(BASE))
+        );
+    }
+
+    /// Freed blocks are merged with their buddies.
+    #[test]
+    fn free_and_merge() {
+        let mut allocator = allocator(4);
+        let blocks: Vec<usize> = (0..4)
+            .map(|_| allocator.alloc(1, PAGE_SIZE).unwrap().raw())
+            .collect();
+        assert_eq!(free_blocks(&allocator).iter().sum::<usize>(), 0);
+
+        // the buddy is still allocated.
+        allocator.free_range(blocks[0]..blocks[0] + PAGE_SIZE);
+        allocator.free_range(blocks[2]..blocks[2] + PAGE_SIZE);
+        assert_eq!(free_blocks(&allocator)[..3], [2, 0, 0]);
+
+        allocator.free_range(blocks[1]..blocks[1] + PAGE_SIZE);
+        assert_eq!(free_blocks(&allocator)[..3], [1, 1, 0]);
+
+        allocator.free_range(blocks[3]..blocks[3] + PAGE_SIZE);
+        assert_eq!(free_blocks(&allocator)[..3], [0, 0, 1]);
+        assert!(allocator.free_lists[2].contains(&BASE));
+        assert_eq!(allocator.stats().free_frames, 4);
+    }
+
+    /// Freed frames are merged back to the block of max order.
+    #[test]
+    fn free_to_max_order() {
+        let mut allocator = allocator(1 << MAX_ORDER);
+        let page = allocator.alloc(1, PAGE_SIZE).unwrap();
+        let superpage = allocator.alloc(SIZE_2MB / PAGE_SIZE, SIZE_2MB).unwrap();
+        let pages = allocator.alloc(3, PAGE_SIZE).unwrap();
+        assert_eq!(
+            allocator.stats().free_frames,
+            (1 << MAX_ORDER) - 4 - SIZE_2MB / PAGE_SIZE
+        );
+        assert!(allocator.free_lists[MAX_ORDER].is_empty());
+
+        // a part of the allocated region can be freed.
+        allocator.free(pages + PAGE_SIZE, 2);
+        allocator.free(pages, 1);
+        allocator.free(superpage, SIZE_2MB / PAGE_SIZE);
+        allocator.free(page, 1);
+
+        assert_eq!(allocator.stats().free_frames, 1 << MAX_ORDER);
+        assert_eq!(free_blocks(&allocator)[..MAX_ORDER], [0; MAX_ORDER]);
+        assert!(allocator.free_lists[MAX_ORDER].contains(&BASE));
+        assert_eq!(
+            allocator.alloc(1 << MAX_ORDER, PAGE_SIZE << MAX_ORDER),
+            Some(HostPhysicalAddress(BASE))
+        );
+    }
+
+    /// Reserved ranges are removed from the range.
+    #[test]
+    fn subtract_reserved_ranges() {
+        let addr = |addr: usize| HostPhysicalAddress(addr);
+        let reserved = [
+            addr(0x0)..addr(0x1000),
+            addr(0x3000)..addr(0x5000),
+            addr(0x4000)..addr(0x6000),
+            addr(0x9000)..addr(0xb000),
+        ];
+        assert_eq!(
+            subtract_ranges(addr(0x800)..addr(0xa000), &reserved),
+            [addr(0x1000)..addr(0x3000), addr(0x6000)..addr(0x9000)]
+        );
+        assert_eq!(
+            subtract_ranges(addr(0xb000)..addr(0xc000), &reserved),
+            [addr(0xb000)..addr(0xc000)]
+        );
+        assert!(subtract_ranges(addr(0x3000)..addr(0x6000), &reserved).is_empty());
+    }
+}
@@ -0,0 +1,288 @@
+//! Access guest memory by hypervisor virtual-machine load/store instructions.
+//!
+//! `hlv`, `hlvx` and `hsv` translate the guest virtual address by VS-stage and G-stage page tables
+//! with the privilege of `hstatus.SPVP`, as if the guest accessed it.
+//! Guest physical address is accessed with VS-stage translation disabled temporarily.
+//!
+//! A fault of the instructions is taken in HS-mode.
+//! It is caught by a temporary trap vector and returned as error instead of being handled by `hstrap_vector`.
+//! CSRs that are overwritten by the trap are restored, so the caller can still refer to the original trap.
+
+use super::{GuestPhysicalAddress, GuestVirtualAddress};
+use crate::h_extension::csrs::vsatp;
+use crate::h_extension::HvException;
+
+use core::arch::asm;
+
+/// Sentinel of trap cause that means no fault occurred.
+const NO_FAULT: usize = usize::MAX;
+
+/// Fault of guest memory access.
+#[derive(Debug, Copy, Clone)]
+#[allow(clippy::module_name_repetitions)]
+pub struct GuestAccessFault {
+    /// Exception code of the fault. (e.g. load page fault, load guest-page fault)
+    pub cause: usize,
+    /// Faulting address. (guest physical address for `*_guest_physical`)
+    pub addr: GuestVirtualAddress,
+}
+
+impl GuestAccessFault {
+    /// Return whether the fault is caused by G-stage translation.
+    ///
+    /// Otherwise, it is caused by VS-stage translation or PMP and can be delivered to the guest.
+    pub fn is_guest_page_fault(&self) -> bool {
+        self.cause == HvException::InstructionGuestPageFault as usize
+            || self.cause == HvException::LoadGuestPageFault as usize
+            || self.cause == HvException::StoreAmoGuestPageFault as usize
+    }
+}
+
+/// CSRs that are overwritten by a trap taken in HS-mode.
+struct TrapCsrs {
+    /// sepc
+    sepc: usize,
+    /// scause
+    scause: usize,
+    /// stval
+    stval: usize,
+    /// sstatus
+    sstatus: usize,
+    /// hstatus (`SPV` and `GVA`)
+    hstatus: usize,
+    /// htval
+    htval: usize,
+    /// htinst
+    htinst: usize,
+}
+
+impl TrapCsrs {
+    /// Save current values.
+    fn save() -> Self {
+        let (sepc, scause, stval, sstatus, hstatus, htval, htinst);
+        unsafe {
+            asm!(
+                "csrr {sepc}, sepc",
+                "csrr {scause}, scause",
+                "csrr {stval}, stval",
+                "csrr {sstatus}, sstatus",
+                "csrr {hstatus}, hstatus",
+                "csrr {htval}, htval",
+                "csrr {htinst}, htinst",
+                sepc = out(reg) sepc,
+                scause = out(reg) scause,
+                stval = out(reg) stval,
+                sstatus = out(reg) sstatus,
+                hstatus = out(reg) hstatus,
+                htval = out(reg) htval,
+                htinst = out(reg) htinst,
+            );
+        }
+
+        TrapCsrs {
+            sepc,
+            scause,
+            stval,
+            sstatus,
+            hstatus,
+            htval,
+            htinst,
+        }
+    }
+
+    /// Restore saved values.
+    fn restore(&self) {
+        unsafe {
+            asm!(
+                "csrw sepc, {sepc}",
+                "csrw scause, {scause}",
+                "csrw stval, {stval}",
+                "csrw sstatus, {sstatus}",
+                "csrw hstatus, {hstatus}",
+                "csrw htval, {htval}",
+                "csrw htinst, {htinst}",
+                sepc = in(reg) self.sepc,
+                scause = in(reg) self.scause,
+                stval = in(reg) self.stval,
+                sstatus = in(reg) self.sstatus,
+                hstatus = in(reg) self.hstatus,
+                htval = in(reg) self.htval,
+                htinst = in(reg) self.htinst,
+            );
+        }
+    }
+}
+
+/// Execute a hypervisor virtual-machine load instruction with fault recovery.
+///
+/// `stvec` is replaced with the local handler during the instruction.
+/// The handler stores `scause` to `cause` and resumes after the instruction.
+/// `cause` must be given as `inout` operand that is initialized with `NO_FAULT`.
+macro_rules! guarded_access {
+    ($inst:expr, $($operands:tt)*) => {
+        asm!(
+            "la {tmp}, 3f",
+            "csrrw {tmp}, stvec, {tmp}",
+            $inst,
+            "j 4f",
+            ".align 2",
+            "3:",
+            "csrr {cause}, scause",
+            "la {tmp2}, 4f",
+            "csrw sepc, {tmp2}",
+            "sret",
+            "4:",
+            "csrw stvec, {tmp}",
+            $($operands)*
+            tmp = out(reg) _,
+            tmp2 = out(reg) _,
+            options(nostack),
+        )
+    };
+}
+
+/// Value that can be accessed by a single hypervisor virtual-machine load/store instruction.
+pub trait GuestAccess: Copy {
+    /// Load the value by `hlv`.
+    ///
+    /// # Safety
+    /// `TrapCsrs` must be restored if it faults.
+    unsafe fn load(gva: GuestVirtualAddress) -> (Self, usize);
+
+    /// Store the value by `hsv`.
+    ///
+    /// # Safety
+    /// `TrapCsrs` must be restored if it faults.
+    unsafe fn store(gva: GuestVirtualAddress, value: Self) -> usize;
+}
+
+/// Implement `GuestAccess` with load and store instructions.
+macro_rules! impl_guest_access {
+    ($ty:ty, $load:literal, $store:literal) => {
+        impl GuestAccess for $ty {
+            #[allow(clippy::cast_possible_truncation)]
+            unsafe fn load(gva: GuestVirtualAddress) -> (Self, usize) {
+                let value: usize;
+                let mut cause = NO_FAULT;
+                guarded_access!(
+                    concat!($load, " {value}, ({addr})"),
+                    value = out(reg) value,
+                    addr = in(reg) gva.0,
+                    cause = inout(reg) cause,
+                );
+                (value as $ty, cause)
+            }
+
+            unsafe fn store(gva: GuestVirtualAddress, value: Self) -> usize {
+                let mut cause = NO_FAULT;
+                guarded_access!(
+                    concat!($store, " {value}, ({addr})"),
+                    value = in(reg) value,
+                    addr = in(reg) gva.0,
+                    cause = inout(reg) cause,
+                );
+                cause
+            }
+        }
+    };
+}
+
+impl_guest_access!(u8, "hlv.bu", "hsv.b");
+impl_guest_access!(u16, "hlv.hu", "hsv.h");
+impl_guest_access!(u32, "hlv.wu", "hsv.w");
+impl_guest_access!(u64, "hlv.d", "hsv.d");
+impl_guest_access!(usize, "hlv.d", "hsv.d");
+
+/// Convert the trap cause to the result.
+///
+/// CSRs are restored if the access faults.
+fn check_fault(
+    cause: usize,
+    gva: GuestVirtualAddress,
+    saved: &TrapCsrs,
+) -> Result<(), GuestAccessFault> {
+    if cause == NO_FAULT {
+        Ok(())
+    } else {
+        saved.restore();
+        Err(GuestAccessFault { cause, addr: gva })
+    }
+}
+
+/// Read a value from guest memory.
+pub fn read_guest<T: GuestAccess>(gva: GuestVirtualAddress) -> Result<T, GuestAccessFault> {
+    let saved = TrapCsrs::save();
+    let (value, cause) = unsafe { T::load(gva) };
+    check_fault(cause, gva, &saved).map(|()| value)
+}
+
+/// Write a value to guest memory.
+pub fn write_guest<T: GuestAccess>(
+    gva: GuestVirtualAddress,
+    value: T,
+) -> Result<(), GuestAccessFault> {
+    let saved = TrapCsrs::save();
+    let cause = unsafe { T::store(gva, value) };
+    check_fault(cause, gva, &saved)
+}
+
+/// Run `access` with `vsatp` set to Bare, so that guest virtual address is the same as guest physical one.
+fn without_vs_stage<R>(
+    gpa: GuestPhysicalAddress,
+    access: impl FnOnce(GuestVirtualAddress) -> R,
+) -> R {
+    let saved_vsatp = vsatp::read().bits();
+    vsatp::write(0);
+    let result = access(GuestVirtualAddress(gpa.raw()));
+    vsatp::write(saved_vsatp);
+    result
+}
+
+/// Read a value from guest physical address.
+///
+/// It is translated by G-stage page table only. (e.g. buffers of SBI calls)
+pub fn read_guest_physical<T: GuestAccess>(
+    gpa: GuestPhysicalAddress,
+) -> Result<T, GuestAccessFault> {
+    without_vs_stage(gpa, read_guest)
+}
+
+/// Write a value to guest physical address.
+///
+/// It is translated by G-stage page table only. (e.g. buffers of SBI calls)
+pub fn write_guest_physical<T: GuestAccess>(
+    gpa: GuestPhysicalAddress,
+    value: T,
+) -> Result<(), GuestAccessFault> {
+    without_vs_stage(gpa, |gva| write_guest(gva, value))
+}
+
+/// Read an instruction from guest memory by `hlvx.hu`.
+///
+/// It requires execute permission instead of read permission as instruction fetch.
+/// Return the instruction value. (16 bit for compressed instruction)
+pub fn read_guest_instruction(gva: GuestVirtualAddress) -> Result<usize, GuestAccessFault> {
+    let read_half = |gva: GuestVirtualAddress| {
+        let saved = TrapCsrs::save();
+        let value: usize;
+        let mut cause = NO_FAULT;
+        unsafe {
+            guarded_access!(
+                "hlvx.hu {value}, ({addr})",
+                value = out(reg) value,
+                addr = in(reg) gva.0,
+                cause = inout(reg) cause,
+            );
+        }
+        check_fault(cause, gva, &saved).map(|()| value)
+    };
+
+    let lower = read_half(gva)?;
+    if lower & 0b11 != 0b11 {
+        // compressed instruction
+        return Ok(lower);
+    }
+    // the upper half may be on the next page.
+    let upper = read_half(GuestVirtualAddress(gva.0 + 2))?;
+    Ok(upper << 16 | lower)
+}
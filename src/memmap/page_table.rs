@@ -1,8 +1,7 @@
 //! Page table for address translation.
 
-pub mod sv39;
-pub mod sv39x4;
-pub mod sv57;
+pub mod g_stage;
+pub mod vs_stage;
 
 use crate::memmap::{GuestPhysicalAddress, GuestVirtualAddress, HostPhysicalAddress};
 
@@ -21,7 +20,7 @@ pub mod constants {
 ///
 /// ref: The RISC-V Instruction Set Manual: Volume II p151.
 #[derive(Copy, Clone, PartialEq)]
-#[allow(clippy::module_name_repetitions)]
+#[allow(clippy::module_name_repetitions, dead_code)]
 enum PageTableLevel {
     /// 256TB = 48 bit = vpn\[3\] (9 bit) + vpn\[2\] (9 bit) + vpn\[1\] (9 bit) + vpn\[0\] (9 bit) + offset (12 bit)
     Lv256TB = 4,
@@ -91,6 +90,12 @@ impl PageTableEntry {
         pte_r == 1 || pte_x == 1 || (pte_r == 0 && pte_w == 1 && pte_x == 0)
     }
 
+    /// Return flags field (bit 7:0)
+    #[allow(clippy::cast_possible_truncation)]
+    fn flags(self) -> u8 {
+        self.0 as u8
+    }
+
     /// Is it has already been created
     fn already_created(self) -> bool {
         self.0 & PteFlag::Valid as u64 == 1
@@ -106,12 +111,6 @@ impl PageTableEntry {
 #[derive(Copy, Clone)]
 struct PageTableAddress(usize);
 
-impl From<*mut [PageTableEntry; constants::PAGE_TABLE_LEN]> for PageTableAddress {
-    fn from(f: *mut [PageTableEntry; constants::PAGE_TABLE_LEN]) -> Self {
-        PageTableAddress(f as *const u64 as usize)
-    }
-}
-
 impl PageTableAddress {
     /// Return page number
     fn page_number(self) -> u64 {
@@ -122,12 +121,6 @@ impl PageTableAddress {
     fn to_pte_ptr(self) -> *mut PageTableEntry {
         self.0 as *mut PageTableEntry
     }
-
-    /// Convert guest physical page table address to host physical one.
-    fn to_host_physical_ptr(self) -> *mut PageTableEntry {
-        let hpa = g_stage_trans_addr(GuestPhysicalAddress(self.0));
-        hpa.0 as *mut PageTableEntry
-    }
 }
 
 impl GuestVirtualAddress {
@@ -137,13 +130,6 @@ impl GuestVirtualAddress {
     }
 }
 
-impl GuestPhysicalAddress {
-    /// Return page offset.
-    fn page_offset(self) -> usize {
-        self.0 & 0xfff
-    }
-}
-
 impl HostPhysicalAddress {
     /// Return page number
     fn page_number(self) -> u64 {
@@ -151,27 +137,96 @@ impl HostPhysicalAddress {
     }
 }
 
-/// VS-stage address translation.
-pub fn vs_stage_trans_addr(gva: GuestVirtualAddress) -> Result<GuestPhysicalAddress, ()> {
+/// VS-stage address translation of the guest access.
+///
+/// Return the reason if the guest access causes a page fault.
+pub fn vs_stage_trans_addr(
+    gva: GuestVirtualAddress,
+    privilege: vs_stage::Privilege,
+    access: vs_stage::AccessType,
+) -> Result<GuestPhysicalAddress, vs_stage::TranslationError> {
     use crate::h_extension::csrs::vsatp;
 
     let vsatp = vsatp::read();
+    let root = GuestPhysicalAddress(vsatp.ppn() * constants::PAGE_SIZE);
     match vsatp.mode() {
-        vsatp::Mode::Bare => unreachable!("no trans addr"),
-        vsatp::Mode::Sv39 => Ok(sv39::trans_addr(gva)),
-        vsatp::Mode::Sv57 => sv57::trans_addr(gva),
-        vsatp::Mode::Sv48 | vsatp::Mode::Sv64 => unimplemented!(),
+        vsatp::Mode::Bare => Ok(GuestPhysicalAddress(gva.0)),
+        vsatp::Mode::Sv39 => vs_stage::trans_addr(gva, root, 3, privilege, access),
+        vsatp::Mode::Sv48 => vs_stage::trans_addr(gva, root, 4, privilege, access),
+        vsatp::Mode::Sv57 => vs_stage::trans_addr(gva, root, 5, privilege, access),
+        vsatp::Mode::Sv64 => Err(vs_stage::TranslationError::UnsupportedMode),
     }
 }
 
-/// G-stage address translation.
-pub fn g_stage_trans_addr(gpa: GuestPhysicalAddress) -> HostPhysicalAddress {
-    use crate::h_extension::csrs::hgatp;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Flags are placed at bit 7:0 and PPN at bit 53:10.
+    #[test]
+    fn pte_encoding() {
+        let flags = PteFlag::Valid as u8 | PteFlag::Read as u8 | PteFlag::Write as u8;
+        let pte = PageTableEntry::new(0x8_0200, flags);
+        assert_eq!(pte.0, 0x8_0200 << 10 | 0b111);
+        assert_eq!(pte.entire_ppn(), 0x8_0200);
+        assert!(pte.already_created());
+        assert!(pte.is_leaf());
+
+        // PPN is 44 bits.
+        let pte = PageTableEntry::new(0xfff_ffff_ffff, PteFlag::Valid as u8);
+        assert_eq!(pte.entire_ppn(), 0xfff_ffff_ffff);
+    }
+
+    /// PTE without R, W and X is a pointer to the next level page table.
+    #[test]
+    fn pte_leaf_and_pointer() {
+        let pointer = PageTableEntry::new(0x8_0200, PteFlag::Valid as u8);
+        assert!(pointer.already_created());
+        assert!(!pointer.is_leaf());
+
+        let exec_only = PageTableEntry::new(0x8_0200, PteFlag::Valid as u8 | PteFlag::Exec as u8);
+        assert!(exec_only.is_leaf());
+
+        // shadow stack page (W only)
+        let shadow_stack =
+            PageTableEntry::new(0x8_0200, PteFlag::Valid as u8 | PteFlag::Write as u8);
+        assert!(shadow_stack.is_leaf());
+
+        assert!(!PageTableEntry::default().already_created());
+        assert!(!PageTableEntry::new(0x8_0200, PteFlag::Read as u8).already_created());
+    }
+
+    /// Size of the region that a leaf of each level maps.
+    #[test]
+    fn page_table_level_size() {
+        assert_eq!(PageTableLevel::Lv4KB.size(), constants::PAGE_SIZE);
+        assert_eq!(
+            PageTableLevel::Lv2MB.size(),
+            PageTableLevel::Lv4KB.size() * constants::PAGE_TABLE_LEN
+        );
+        assert_eq!(
+            PageTableLevel::Lv1GB.size(),
+            PageTableLevel::Lv2MB.size() * constants::PAGE_TABLE_LEN
+        );
+        assert_eq!(
+            PageTableLevel::Lv512GB.size(),
+            PageTableLevel::Lv1GB.size() * constants::PAGE_TABLE_LEN
+        );
+        assert_eq!(
+            PageTableLevel::Lv256TB.size(),
+            PageTableLevel::Lv512GB.size() * constants::PAGE_TABLE_LEN
+        );
+        assert_eq!(PageTableLevel::Lv1GB as usize, 2);
+    }
 
-    let hgatp = hgatp::read();
-    match hgatp.mode() {
-        hgatp::Mode::Bare => unreachable!("no trans addr"),
-        hgatp::Mode::Sv39x4 => sv39x4::trans_addr(gpa),
-        hgatp::Mode::Sv48x4 | hgatp::Mode::Sv57x4 => unimplemented!(),
+    /// Page number of addresses.
+    #[test]
+    fn page_number() {
+        assert_eq!(HostPhysicalAddress(0x8020_3fff).page_number(), 0x8_0203);
+        assert_eq!(PageTableAddress(0x8020_4000).page_number(), 0x8_0204);
+        assert_eq!(
+            GuestVirtualAddress(0xffff_ffff_8000_1234).page_offset(),
+            0x234
+        );
     }
 }
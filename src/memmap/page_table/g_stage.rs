@@ -0,0 +1,596 @@
+//! G-stage page table: Sv39x4, Sv48x4 and Sv57x4.  
+//! For guest physical address translation.
+//!
+//! The mode is selected at boot by probing `hgatp.MODE` and shared by all guests.
+//! Each mode widens the root page table of the corresponding VS-stage mode by 2 bits (x4),
+//! so that the root page table has 2048 entries and the other levels are the same as Sv39/Sv48/Sv57.
+//!
+//! [The RISC-V Instruction Set Manual: Volume II Version 20240411](https://github.com/riscv/riscv-isa-manual/releases/download/20240411/priv-isa-asciidoc.pdf) p.151
+
+use super::{
+    constants::{PAGE_SIZE, PAGE_TABLE_LEN},
+    PageTableAddress, PageTableEntry, PageTableLevel, PteFlag,
+};
+use crate::h_extension::csrs::hgatp;
+use crate::memmap::{GuestPhysicalAddress, HostPhysicalAddress, MemoryMap};
+
+use crate::{PageBlock, FRAME_ALLOCATOR};
+
+use core::cell::OnceCell;
+use core::slice::from_raw_parts_mut;
+use spin::Mutex;
+
+/// First page table size
+pub const FIRST_LV_PAGE_TABLE_LEN: usize = 2048;
+
+/// Root page table size in bytes. (16 KiB)
+const ROOT_PAGE_TABLE_SIZE: usize =
+    FIRST_LV_PAGE_TABLE_LEN * core::mem::size_of::<PageTableEntry>();
+
+/// Number of bits of VPN in each level except root.
+const VPN_BITS: usize = 9;
+
+/// G-stage translation mode that is selected by `init_mode`.
+static G_STAGE_MODE: Mutex<OnceCell<hgatp::Mode>> = Mutex::new(OnceCell::new());
+
+/// Select the widest G-stage translation mode that is supported by the hart.
+///
+/// It overwrites hgatp, so it must be called before enabling G-stage translation.
+///
+/// # Panics
+/// It will be panic if no G-stage translation mode is supported.
+pub fn init_mode() {
+    let mode = [
+        hgatp::Mode::Sv57x4,
+        hgatp::Mode::Sv48x4,
+        hgatp::Mode::Sv39x4,
+    ]
+    .into_iter()
+    .find(|mode| hgatp::is_mode_supported(*mode))
+    .expect("G-stage address translation is not supported");
+
+    G_STAGE_MODE.lock().get_or_init(|| mode);
+}
+
+/// Return G-stage translation mode.
+///
+/// # Panics
+/// It will be panic if `init_mode` has not been called.
+pub fn mode() -> hgatp::Mode {
+    *G_STAGE_MODE
+        .lock()
+        .get()
+        .expect("G-stage translation mode is not initialized")
+}
+
+/// Return the number of page table levels of the mode.
+fn levels(mode: hgatp::Mode) -> usize {
+    match mode {
+        hgatp::Mode::Bare => unreachable!("no page table"),
+        hgatp::Mode::Sv39x4 => 3,
+        hgatp::Mode::Sv48x4 => 4,
+        hgatp::Mode::Sv57x4 => 5,
+    }
+}
+
+/// Return virtual page number of `level` in the page table of `levels` levels.
+///
+/// VPN of the root page table has 2 extra bits.
+fn vpn(gpa: GuestPhysicalAddress, level: usize, levels: usize) -> usize {
+    let mask = if level == levels - 1 {
+        FIRST_LV_PAGE_TABLE_LEN - 1
+    } else {
+        PAGE_TABLE_LEN - 1
+    };
+    (gpa.0 >> (12 + VPN_BITS * level)) & mask
+}
+
+/// Return page table of `level` at `addr`.
+///
+/// # Safety
+/// `addr` must point to the page table of the level.
+unsafe fn page_table<'a>(
+    addr: PageTableAddress,
+    level: usize,
+    levels: usize,
+) -> &'a mut [PageTableEntry] {
+    let len = if level == levels - 1 {
+        FIRST_LV_PAGE_TABLE_LEN
+    } else {
+        PAGE_TABLE_LEN
+    };
+    from_raw_parts_mut(addr.to_pte_ptr(), len)
+}
+
+/// Allocate root page table from physical frames.
+///
+/// The root page table of G-stage must be aligned to 16 KiB.
+pub fn allocate_root_page_table() -> HostPhysicalAddress {
+    FRAME_ALLOCATOR
+        .lock()
+        .alloc(ROOT_PAGE_TABLE_SIZE / PAGE_SIZE, ROOT_PAGE_TABLE_SIZE)
+        .expect("failed to allocate root page table")
+}
+
+/// Zero filling root page table
+pub fn initialize_page_table(root_table_start_addr: HostPhysicalAddress) {
+    let first_lv_page_table: &mut [PageTableEntry] = unsafe {
+        from_raw_parts_mut(
+            root_table_start_addr.raw() as *mut PageTableEntry,
+            FIRST_LV_PAGE_TABLE_LEN,
+        )
+    };
+
+    // zero filling page table
+    first_lv_page_table.fill(PageTableEntry(0));
+}
+
+/// Generate G-stage page table of the selected mode.
+///
+/// Each range is mapped by the largest leaf (1 GiB, 2 MiB or 4 KiB) whose size is aligned
+/// in both address spaces and fits in the rest of the range.
+/// Thus unaligned edges of the range fall back to smaller pages.
+#[allow(clippy::module_name_repetitions)]
+pub fn generate_page_table(root_table_start_addr: HostPhysicalAddress, memmaps: &[MemoryMap]) {
+    use crate::memmap::AddressRangeUtil;
+
+    assert!(root_table_start_addr % (16 * 1024) == 0); // root_table_start_addr must be aligned 16 KiB
+
+    let levels = levels(mode());
+
+    for memmap in memmaps {
+        assert!(memmap.virt.len() == memmap.phys.len());
+        assert!(memmap.virt.start % PAGE_SIZE == 0);
+        assert!(memmap.phys.start % PAGE_SIZE == 0);
+
+        let mut offset = 0;
+        while offset < memmap.virt.len() {
+            let v_start = memmap.virt.start + offset;
+            let p_start = memmap.phys.start + offset;
+
+            // decide page level from alignment and remaining size
+            let trans_page_level = [PageTableLevel::Lv1GB, PageTableLevel::Lv2MB]
+                .into_iter()
+                .find(|level| {
+                    v_start % level.size() == 0
+                        && p_start % level.size() == 0
+                        && memmap.virt.len() - offset >= level.size()
+                })
+                .unwrap_or(PageTableLevel::Lv4KB);
+
+            map_page(
+                root_table_start_addr,
+                levels,
+                v_start,
+                p_start,
+                trans_page_level,
+                memmap.flags,
+            );
+            offset += trans_page_level.size();
+        }
+    }
+}
+
+/// Create a leaf PTE of `trans_page_level` that maps `v_start` to `p_start`.
+///
+/// Intermediate page tables are allocated if they have not been created.
+/// Superpage that covers `v_start` is split into smaller pages with the same flags,
+/// and page tables under the new leaf are freed.
+fn map_page(
+    root_table_start_addr: HostPhysicalAddress,
+    levels: usize,
+    v_start: GuestPhysicalAddress,
+    p_start: HostPhysicalAddress,
+    trans_page_level: PageTableLevel,
+    flags: u8,
+) {
+    let mut current_table_addr = PageTableAddress(root_table_start_addr.raw());
+    for current_level in (trans_page_level as usize..levels).rev() {
+        let vpn = vpn(v_start, current_level, levels);
+        let current_page_table = unsafe { page_table(current_table_addr, current_level, levels) };
+
+        // End of translation
+        if current_level == trans_page_level as usize {
+            let replaced = current_page_table[vpn];
+            current_page_table[vpn] = PageTableEntry::new(p_start.page_number(), flags);
+
+            // the lower level page tables are no longer referred.
+            if replaced.already_created() && !replaced.is_leaf() {
+                free_page_table(
+                    PageTableAddress(usize::try_from(replaced.entire_ppn()).unwrap() * PAGE_SIZE),
+                    current_level - 1,
+                    levels,
+                );
+            }
+            return;
+        }
+
+        // Create next level page table
+        let pte = current_page_table[vpn];
+        current_table_addr = if pte.already_created() && !pte.is_leaf() {
+            PageTableAddress(usize::try_from(pte.entire_ppn()).unwrap() * PAGE_SIZE)
+        } else {
+            let next_page_table_addr = PageTableAddress(PageBlock::alloc().raw());
+            let next_page_table =
+                unsafe { page_table(next_page_table_addr, current_level - 1, levels) };
+            if pte.already_created() {
+                // split the superpage into the next level leaves, so that the rest of it is still mapped.
+                let pages_per_entry = 1 << (VPN_BITS * (current_level - 1));
+                for (index, entry) in next_page_table.iter_mut().enumerate() {
+                    *entry = PageTableEntry::new(
+                        pte.entire_ppn() + (index * pages_per_entry) as u64,
+                        pte.flags(),
+                    );
+                }
+            } else {
+                next_page_table.fill(PageTableEntry(0));
+            }
+
+            current_page_table[vpn] =
+                PageTableEntry::new(next_page_table_addr.page_number(), PteFlag::Valid as u8);
+
+            next_page_table_addr
+        };
+    }
+}
+
+/// Free the page table and its lower level page tables to `FRAME_ALLOCATOR`.
+///
+/// The frames mapped by leaves are not freed, because they may not be allocated from `FRAME_ALLOCATOR`. (e.g. MMIO)
+/// Return the number of freed page tables.
+fn free_page_table(table_addr: PageTableAddress, level: usize, levels: usize) -> usize {
+    let page_table = unsafe { page_table(table_addr, level, levels) };
+    let freed_lower_tables: usize = if level == 0 {
+        0
+    } else {
+        page_table
+            .iter()
+            .filter(|pte| pte.already_created() && !pte.is_leaf())
+            .map(|pte| {
+                free_page_table(
+                    PageTableAddress(usize::try_from(pte.entire_ppn()).unwrap() * PAGE_SIZE),
+                    level - 1,
+                    levels,
+                )
+            })
+            .sum()
+    };
+
+    FRAME_ALLOCATOR
+        .lock()
+        .free(HostPhysicalAddress(table_addr.0), 1);
+    freed_lower_tables + 1
+}
+
+/// Return whether the page of gpa is mapped in the page table of the selected mode.
+pub fn is_mapped(root_table_start_addr: HostPhysicalAddress, gpa: GuestPhysicalAddress) -> bool {
+    walk(
+        PageTableAddress(root_table_start_addr.raw()),
+        levels(mode()),
+        gpa,
+    )
+    .is_some()
+}
+
+/// Walk the page table of `levels` levels.
+///
+/// Return `None` if gpa is not mapped.
+#[allow(clippy::cast_possible_truncation)]
+fn walk(
+    root: PageTableAddress,
+    levels: usize,
+    gpa: GuestPhysicalAddress,
+) -> Option<HostPhysicalAddress> {
+    let mut page_table_addr = root;
+    for level in (0..levels).rev() {
+        let page_table = unsafe { page_table(page_table_addr, level, levels) };
+        let pte = page_table[vpn(gpa, level, levels)];
+        if !pte.already_created() {
+            return None;
+        }
+
+        if pte.is_leaf() {
+            // lower bits of superpage come from the guest physical address.
+            let page_mask = (1 << (12 + VPN_BITS * level)) - 1;
+            let leaf_addr = pte.entire_ppn() as usize * PAGE_SIZE;
+            assert!(
+                leaf_addr & page_mask == 0,
+                "Address translation failed: misaligned superpage"
+            );
+            return Some(HostPhysicalAddress(leaf_addr | gpa.0 & page_mask));
+        }
+
+        page_table_addr = PageTableAddress(pte.entire_ppn() as usize * PAGE_SIZE);
+    }
+
+    unreachable!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Flags of leaf PTE for tests.
+    const LEAF_FLAGS: u8 = PteFlag::Valid as u8 | PteFlag::Read as u8 | PteFlag::Write as u8;
+
+    /// Allocate zero filled root page table from mock physical memory.
+    fn new_root() -> HostPhysicalAddress {
+        let root = allocate_root_page_table();
+        initialize_page_table(root);
+        root
+    }
+
+    /// Translate gpa to hpa by the page table of current `hgatp`.
+    fn trans_addr(gpa: GuestPhysicalAddress) -> Option<HostPhysicalAddress> {
+        let hgatp = hgatp::read();
+        walk(
+            PageTableAddress(hgatp.ppn() << 12),
+            levels(hgatp.mode()),
+            gpa,
+        )
+    }
+
+    /// Return the level of leaf PTE that maps gpa.
+    fn leaf_level(root: HostPhysicalAddress, levels: usize, gpa: GuestPhysicalAddress) -> usize {
+        let mut page_table_addr = PageTableAddress(root.raw());
+        for level in (0..levels).rev() {
+            let pte =
+                unsafe { page_table(page_table_addr, level, levels) }[vpn(gpa, level, levels)];
+            assert!(pte.already_created());
+            if pte.is_leaf() {
+                return level;
+            }
+            page_table_addr =
+                PageTableAddress(usize::try_from(pte.entire_ppn()).unwrap() * PAGE_SIZE);
+        }
+        unreachable!();
+    }
+
+    /// Root page table has 2048 entries, and the other levels have 512 entries.
+    #[test]
+    fn vpn_of_root_has_two_extra_bits() {
+        let gpa = GuestPhysicalAddress(0x1ff_ffff_f000);
+        assert_eq!(vpn(gpa, 2, 3), 0x7ff);
+        assert_eq!(vpn(gpa, 1, 3), 0x1ff);
+        assert_eq!(vpn(gpa, 0, 3), 0x1ff);
+        assert_eq!(vpn(GuestPhysicalAddress(0x3_ffff_ffff_f000), 3, 4), 0x7ff);
+        assert_eq!(vpn(GuestPhysicalAddress(0x7ff_ffff_ffff_f000), 4, 5), 0x7ff);
+    }
+
+    /// Translate 4 KiB page, 2 MiB and 1 GiB superpages by the page table of `hgatp`.
+    #[test]
+    fn trans_addr_sv39x4() {
+        let root = new_root();
+        let levels = levels(hgatp::Mode::Sv39x4);
+        map_page(
+            root,
+            levels,
+            GuestPhysicalAddress(0x8000_1000),
+            HostPhysicalAddress(0x9000_5000),
+            PageTableLevel::Lv4KB,
+            LEAF_FLAGS,
+        );
+        map_page(
+            root,
+            levels,
+            GuestPhysicalAddress(0x8020_0000),
+            HostPhysicalAddress(0x9040_0000),
+            PageTableLevel::Lv2MB,
+            LEAF_FLAGS,
+        );
+        // beyond 39 bits. (root VPN > 511)
+        map_page(
+            root,
+            levels,
+            GuestPhysicalAddress(0x1c0_0000_0000),
+            HostPhysicalAddress(0x1_4000_0000),
+            PageTableLevel::Lv1GB,
+            LEAF_FLAGS,
+        );
+
+        hgatp::set(hgatp::Mode::Sv39x4, 1, root.raw() >> 12);
+        assert_eq!(
+            trans_addr(GuestPhysicalAddress(0x8000_1234)),
+            Some(HostPhysicalAddress(0x9000_5234))
+        );
+        assert_eq!(
+            trans_addr(GuestPhysicalAddress(0x8031_2345)),
+            Some(HostPhysicalAddress(0x9051_2345))
+        );
+        assert_eq!(
+            trans_addr(GuestPhysicalAddress(0x1c0_3456_7890)),
+            Some(HostPhysicalAddress(0x1_7456_7890))
+        );
+    }
+
+    /// Mapping a page inside a superpage splits the superpage instead of writing PTEs into its page.
+    #[test]
+    fn map_page_in_superpage() {
+        init_mode();
+        let root = new_root();
+        let levels = levels(mode());
+        map_page(
+            root,
+            levels,
+            GuestPhysicalAddress(0x4000_0000),
+            HostPhysicalAddress(0x8000_0000),
+            PageTableLevel::Lv1GB,
+            LEAF_FLAGS,
+        );
+        map_page(
+            root,
+            levels,
+            GuestPhysicalAddress(0x4020_1000),
+            HostPhysicalAddress(0x9000_0000),
+            PageTableLevel::Lv4KB,
+            LEAF_FLAGS,
+        );
+
+        assert_eq!(
+            leaf_level(root, levels, GuestPhysicalAddress(0x4020_1000)),
+            0
+        );
+        assert_eq!(
+            leaf_level(root, levels, GuestPhysicalAddress(0x4020_2000)),
+            0
+        );
+        assert_eq!(
+            leaf_level(root, levels, GuestPhysicalAddress(0x4000_0000)),
+            1
+        );
+        hgatp::set(mode(), 1, root.raw() >> 12);
+        assert_eq!(
+            trans_addr(GuestPhysicalAddress(0x4020_1234)),
+            Some(HostPhysicalAddress(0x9000_0234))
+        );
+        // the rest of the superpage keeps its mapping.
+        assert_eq!(
+            trans_addr(GuestPhysicalAddress(0x4020_0fff)),
+            Some(HostPhysicalAddress(0x8020_0fff))
+        );
+        assert_eq!(
+            trans_addr(GuestPhysicalAddress(0x4020_2000)),
+            Some(HostPhysicalAddress(0x8020_2000))
+        );
+        assert_eq!(
+            trans_addr(GuestPhysicalAddress(0x7fff_ffff)),
+            Some(HostPhysicalAddress(0xbfff_ffff))
+        );
+    }
+
+    /// Map two 4 KiB pages in the different 2 MiB regions of the 1 GiB region from `gpa`.
+    fn map_two_pages(root: HostPhysicalAddress, levels: usize, gpa: GuestPhysicalAddress) {
+        for (offset, hpa) in [
+            (0, 0x9000_0000),
+            (PageTableLevel::Lv2MB.size(), 0x9020_0000),
+        ] {
+            map_page(
+                root,
+                levels,
+                gpa + offset,
+                HostPhysicalAddress(hpa),
+                PageTableLevel::Lv4KB,
+                LEAF_FLAGS,
+            );
+        }
+    }
+
+    /// Page table is freed with its lower level page tables.
+    #[test]
+    fn free_page_tables() {
+        init_mode();
+        let root = new_root();
+        let levels = levels(mode());
+        let gpa = GuestPhysicalAddress(0x8000_0000);
+        map_two_pages(root, levels, gpa);
+
+        // 2 MiB level page table and two 4 KiB level page tables
+        let mut table = PageTableAddress(root.raw());
+        for level in (PageTableLevel::Lv1GB as usize..levels).rev() {
+            let pte = unsafe { page_table(table, level, levels) }[vpn(gpa, level, levels)];
+            table = PageTableAddress(usize::try_from(pte.entire_ppn()).unwrap() * PAGE_SIZE);
+        }
+        assert_eq!(
+            free_page_table(table, PageTableLevel::Lv2MB as usize, levels),
+            3
+        );
+    }
+
+    /// Superpage replaces the lower level page tables.
+    #[test]
+    fn superpage_replaces_page_tables() {
+        init_mode();
+        let root = new_root();
+        let levels = levels(mode());
+        let gpa = GuestPhysicalAddress(0x8000_0000);
+        map_two_pages(root, levels, gpa);
+        assert_eq!(leaf_level(root, levels, gpa), 0);
+
+        map_page(
+            root,
+            levels,
+            gpa,
+            HostPhysicalAddress(0x1_0000_0000),
+            PageTableLevel::Lv1GB,
+            LEAF_FLAGS,
+        );
+        assert_eq!(
+            leaf_level(root, levels, gpa + PageTableLevel::Lv2MB.size()),
+            PageTableLevel::Lv1GB as usize
+        );
+        hgatp::set(mode(), 1, root.raw() >> 12);
+        assert_eq!(
+            trans_addr(GuestPhysicalAddress(0x8020_1234)),
+            Some(HostPhysicalAddress(0x1_0020_1234))
+        );
+    }
+
+    /// Unmapped address is not translated in each mode.
+    #[test]
+    fn walk_each_mode() {
+        for mode in [
+            hgatp::Mode::Sv39x4,
+            hgatp::Mode::Sv48x4,
+            hgatp::Mode::Sv57x4,
+        ] {
+            let root = new_root();
+            let levels = levels(mode);
+            map_page(
+                root,
+                levels,
+                GuestPhysicalAddress(0x8000_0000),
+                HostPhysicalAddress(0x9000_0000),
+                PageTableLevel::Lv4KB,
+                LEAF_FLAGS,
+            );
+
+            let root = PageTableAddress(root.raw());
+            assert_eq!(
+                walk(root, levels, GuestPhysicalAddress(0x8000_0fff)),
+                Some(HostPhysicalAddress(0x9000_0fff))
+            );
+            assert_eq!(walk(root, levels, GuestPhysicalAddress(0x8000_1000)), None);
+            assert_eq!(
+                walk(root, levels, GuestPhysicalAddress(0x1_8000_0000)),
+                None
+            );
+        }
+    }
+
+    /// `generate_page_table` uses the largest leaf that is aligned in both address spaces.
+    #[test]
+    fn generate_page_table_with_superpages() {
+        init_mode();
+        let root = new_root();
+        let levels = levels(mode());
+
+        // 4 KiB + 2 MiB + 1 GiB + 4 KiB
+        let virt = GuestPhysicalAddress(0x3fdf_f000)..GuestPhysicalAddress(0x8000_1000);
+        let phys = HostPhysicalAddress(0x7fdf_f000)..HostPhysicalAddress(0xc000_1000);
+        generate_page_table(
+            root,
+            &[MemoryMap::new(virt, phys, &[PteFlag::Read, PteFlag::Valid])],
+        );
+
+        for (gpa, level) in [
+            (0x3fdf_f000, 0),
+            (0x3fe0_0000, 1),
+            (0x4000_0000, 2),
+            (0x7fff_ffff, 2),
+            (0x8000_0000, 0),
+        ] {
+            let gpa = GuestPhysicalAddress(gpa);
+            assert_eq!(leaf_level(root, levels, gpa), level);
+            assert!(is_mapped(root, gpa));
+        }
+        assert!(!is_mapped(root, GuestPhysicalAddress(0x3fdf_e000)));
+        assert!(!is_mapped(root, GuestPhysicalAddress(0x8000_1000)));
+
+        hgatp::set(mode(), 1, root.raw() >> 12);
+        assert_eq!(
+            trans_addr(GuestPhysicalAddress(0x5555_5555)),
+            Some(HostPhysicalAddress(0x9555_5555))
+        );
+        assert_eq!(trans_addr(GuestPhysicalAddress(0x8000_1000)), None);
+    }
+}
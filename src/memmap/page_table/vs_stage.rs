@@ -0,0 +1,693 @@
+//! VS-stage page table walker for Sv39, Sv48 and Sv57.
+//!
+//! Shadow stack pages (`xwr = 010`) are reserved encoding for hardware without Zicfiss,
+//! so the emulated shadow stack accesses can not be translated by `hlv`/`hsv` and are translated by software.
+//! The other guest memory accesses are translated by hardware. (See `memmap::guest_access`)
+//!
+//! It follows "Virtual Address Translation Process" in the privileged spec
+//! and reports the reason of failure, so that emulators can raise the correct fault to the guest.
+//! PTEs are read by `memmap::guest_access` and never updated. (A and D bits must be set by the guest as Svade)
+//! Svpbmt and Svnapot are not implemented, so PTEs with `PBMT` or `N` bit are invalid.
+
+use super::{constants::PAGE_SIZE, PageTableEntry, PteFlag};
+use crate::h_extension::csrs::vsstatus;
+use crate::memmap::{guest_access::read_guest_physical, GuestPhysicalAddress, GuestVirtualAddress};
+
+/// Number of bits of VPN in each level.
+const VPN_BITS: usize = 9;
+/// Reserved bits (60:54), `PBMT` (62:61) and `N` (63) of PTE.
+const PTE_RESERVED_MASK: u64 = 0x3ff << 54;
+/// sstatus.SUM: permit Supervisor User Memory access
+const SSTATUS_SUM: usize = 1 << 18;
+/// sstatus.MXR: Make eXecutable Readable
+const SSTATUS_MXR: usize = 1 << 19;
+
+/// Privilege mode of the access.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Privilege {
+    /// VU-mode
+    User,
+    /// VS-mode
+    Supervisor,
+}
+
+/// Type of the access.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AccessType {
+    /// Load
+    Load,
+    /// Store or AMO
+    Store,
+    /// Instruction fetch
+    Execute,
+    /// Shadow stack load or store of Zicfiss
+    ShadowStack,
+}
+
+impl AccessType {
+    /// Return the access type of guest-page fault. (`None` for the other exceptions)
+    pub fn from_guest_page_fault(cause: usize) -> Option<Self> {
+        /// Instruction guest-page fault
+        const INSTRUCTION_GUEST_PAGE_FAULT: usize = 20;
+        /// Load guest-page fault
+        const LOAD_GUEST_PAGE_FAULT: usize = 21;
+        /// Store/AMO guest-page fault
+        const STORE_AMO_GUEST_PAGE_FAULT: usize = 23;
+
+        match cause {
+            INSTRUCTION_GUEST_PAGE_FAULT => Some(AccessType::Execute),
+            LOAD_GUEST_PAGE_FAULT => Some(AccessType::Load),
+            STORE_AMO_GUEST_PAGE_FAULT => Some(AccessType::Store),
+            _ => None,
+        }
+    }
+
+    /// Return exception code of access fault.
+    ///
+    /// Faults of shadow stack accesses are reported as store/AMO faults even for loads.
+    pub fn access_fault_code(self) -> usize {
+        match self {
+            AccessType::Execute => 1,
+            AccessType::Load => 5,
+            AccessType::Store | AccessType::ShadowStack => 7,
+        }
+    }
+
+    /// Return exception code of page fault.
+    pub fn page_fault_code(self) -> usize {
+        match self {
+            AccessType::Execute => 12,
+            AccessType::Load => 13,
+            AccessType::Store | AccessType::ShadowStack => 15,
+        }
+    }
+}
+
+/// Error of VS-stage address translation.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TranslationError {
+    /// Translation mode of `vsatp` is not supported. (Sv64)
+    UnsupportedMode,
+    /// Upper bits of the address are not the same as the most significant bit of virtual address.
+    NonCanonicalAddress,
+    /// PTE is not valid or uses reserved encoding.
+    InvalidPte,
+    /// Access is not permitted by the PTE.
+    PermissionDenied,
+    /// PPN of superpage is not aligned to its size.
+    MisalignedSuperpage,
+    /// A bit is clear, or D bit is clear for store.
+    AccessedDirtyClear,
+    /// Shadow stack access to the page that is not shadow stack page.
+    NotShadowStackPage,
+    /// Store to shadow stack page by regular store instruction.
+    StoreToShadowStackPage,
+    /// Page table or the translated page is not mapped by G-stage page table.
+    GuestPageFault(GuestPhysicalAddress),
+}
+
+impl TranslationError {
+    /// Return exception code that is raised to the guest by the access.
+    ///
+    /// Guest-page fault is reported as access fault, because the guest can not handle it.
+    pub fn exception_code(self, access: AccessType) -> usize {
+        match self {
+            TranslationError::NotShadowStackPage
+            | TranslationError::StoreToShadowStackPage
+            | TranslationError::GuestPageFault(_) => access.access_fault_code(),
+            _ => access.page_fault_code(),
+        }
+    }
+}
+
+impl PageTableEntry {
+    /// Return whether the flag is set.
+    fn is_set(self, flag: PteFlag) -> bool {
+        self.0 & flag as u64 != 0
+    }
+}
+
+/// Check permission of the leaf PTE for the access.
+///
+/// `sstatus.SUM` permits VS-mode to load and store user pages, but not to execute them.
+/// `sstatus.MXR` makes executable pages readable by loads, but it does not affect shadow stack accesses.
+/// Shadow stack pages are readable by loads, but regular stores to them raise access fault.
+fn check_permission(
+    pte: PageTableEntry,
+    privilege: Privilege,
+    access: AccessType,
+    vsstatus: usize,
+) -> Result<(), TranslationError> {
+    let privilege_ok = match privilege {
+        Privilege::User => pte.is_set(PteFlag::User),
+        Privilege::Supervisor => {
+            !pte.is_set(PteFlag::User)
+                || (access != AccessType::Execute && vsstatus & SSTATUS_SUM != 0)
+        }
+    };
+    if !privilege_ok {
+        return Err(TranslationError::PermissionDenied);
+    }
+
+    let readable = pte.is_set(PteFlag::Read);
+    let writable = pte.is_set(PteFlag::Write);
+    let executable = pte.is_set(PteFlag::Exec);
+    let is_shadow_stack_page = !readable && writable && !executable;
+    let permitted = match access {
+        AccessType::Load => {
+            readable || is_shadow_stack_page || (executable && vsstatus & SSTATUS_MXR != 0)
+        }
+        AccessType::Store if is_shadow_stack_page => {
+            return Err(TranslationError::StoreToShadowStackPage)
+        }
+        AccessType::Store => readable && writable,
+        AccessType::Execute => executable,
+        AccessType::ShadowStack if !is_shadow_stack_page => {
+            return Err(TranslationError::NotShadowStackPage)
+        }
+        AccessType::ShadowStack => true,
+    };
+    if permitted {
+        Ok(())
+    } else {
+        Err(TranslationError::PermissionDenied)
+    }
+}
+
+/// Translate gva of the access to gpa by walking the page table of `levels` levels.
+/// * `root` - guest physical address of root page table (`vsatp.PPN`)
+/// * `levels` - 3 (Sv39), 4 (Sv48) or 5 (Sv57)
+#[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+pub fn trans_addr(
+    gva: GuestVirtualAddress,
+    root: GuestPhysicalAddress,
+    levels: usize,
+    privilege: Privilege,
+    access: AccessType,
+) -> Result<GuestPhysicalAddress, TranslationError> {
+    // bits above the virtual address width must be sign extension.
+    let va_bits = 12 + VPN_BITS * levels;
+    let upper_bits = (gva.0 as isize) >> (va_bits - 1);
+    if upper_bits != 0 && upper_bits != -1 {
+        return Err(TranslationError::NonCanonicalAddress);
+    }
+
+    let vsstatus = vsstatus::read().bits();
+    let mut page_table_addr = root.raw();
+    for level in (0..levels).rev() {
+        let vpn = (gva.0 >> (12 + VPN_BITS * level)) & ((1 << VPN_BITS) - 1);
+        let pte_addr =
+            GuestPhysicalAddress(page_table_addr + vpn * core::mem::size_of::<PageTableEntry>());
+        let pte = read_guest_physical::<u64>(pte_addr)
+            .map(PageTableEntry)
+            .map_err(|_| TranslationError::GuestPageFault(GuestPhysicalAddress(page_table_addr)))?;
+
+        if !pte.is_set(PteFlag::Valid) || pte.0 & PTE_RESERVED_MASK != 0 {
+            return Err(TranslationError::InvalidPte);
+        }
+
+        let is_pointer =
+            !pte.is_set(PteFlag::Read) && !pte.is_set(PteFlag::Write) && !pte.is_set(PteFlag::Exec);
+        if is_pointer {
+            // D, A and U bits of non-leaf PTE are reserved.
+            if level == 0
+                || pte.is_set(PteFlag::Dirty)
+                || pte.is_set(PteFlag::Accessed)
+                || pte.is_set(PteFlag::User)
+            {
+                return Err(TranslationError::InvalidPte);
+            }
+            page_table_addr = pte.entire_ppn() as usize * PAGE_SIZE;
+            continue;
+        }
+
+        check_permission(pte, privilege, access, vsstatus)?;
+
+        let ppn = pte.entire_ppn() as usize;
+        let superpage_mask = (1 << (VPN_BITS * level)) - 1;
+        if ppn & superpage_mask != 0 {
+            return Err(TranslationError::MisalignedSuperpage);
+        }
+
+        let needs_dirty = matches!(access, AccessType::Store | AccessType::ShadowStack);
+        if !pte.is_set(PteFlag::Accessed) || (needs_dirty && !pte.is_set(PteFlag::Dirty)) {
+            return Err(TranslationError::AccessedDirtyClear);
+        }
+
+        // lower VPNs of superpage come from the virtual address.
+        let gpn = ppn | ((gva.0 >> 12) & superpage_mask);
+        return Ok(GuestPhysicalAddress((gpn * PAGE_SIZE) | gva.page_offset()));
+    }
+
+    unreachable!("pointer PTE at level 0 is rejected");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::g_stage;
+    use super::*;
+    use crate::h_extension::csrs::{hgatp, vsatp};
+    use crate::memmap::{HostPhysicalAddress, MemoryMap};
+    use crate::PageBlock;
+
+    /// Virtual address of shadow stack for tests. (vpn\[2\] = 1, vpn\[1\] = 2, vpn\[0\] = 3)
+    const SSP: GuestVirtualAddress = GuestVirtualAddress(0x4040_3ff8);
+
+    /// Guest memory whose guest physical address is the same as host physical one.
+    struct GuestMemory {
+        /// Root of G-stage page table.
+        g_stage_root: HostPhysicalAddress,
+    }
+
+    impl GuestMemory {
+        /// Enable G-stage translation with empty page table.
+        fn new() -> Self {
+            g_stage::init_mode();
+            let g_stage_root = g_stage::allocate_root_page_table();
+            g_stage::initialize_page_table(g_stage_root);
+            hgatp::set(g_stage::mode(), 0, g_stage_root.raw() >> 12);
+            GuestMemory { g_stage_root }
+        }
+
+        /// Allocate zero filled page that is mapped to the same guest physical address.
+        fn alloc_page(&self) -> GuestPhysicalAddress {
+            let hpa = PageBlock::alloc();
+            unsafe {
+                core::ptr::write_bytes(hpa.raw() as *mut u8, 0, PAGE_SIZE);
+            }
+            let gpa = GuestPhysicalAddress(hpa.raw());
+            g_stage::generate_page_table(
+                self.g_stage_root,
+                &[MemoryMap::new(
+                    gpa..gpa + PAGE_SIZE,
+                    hpa..hpa + PAGE_SIZE,
+                    &[PteFlag::Valid, PteFlag::Read, PteFlag::Write, PteFlag::User],
+                )],
+            );
+            gpa
+        }
+
+        /// Create Sv39 page table whose leaf PTE of `gva` at `leaf_level` is `leaf`.
+        ///
+        /// Return the root of the page table.
+        fn sv39_page_table(
+            &self,
+            gva: GuestVirtualAddress,
+            leaf_level: usize,
+            leaf: PageTableEntry,
+        ) -> GuestPhysicalAddress {
+            let root = self.alloc_page();
+            let mut table = root;
+            for level in (0..3).rev() {
+                let vpn = (gva.0 >> (12 + VPN_BITS * level)) & ((1 << VPN_BITS) - 1);
+                let pte_ptr = (table.raw() + vpn * core::mem::size_of::<PageTableEntry>())
+                    as *mut PageTableEntry;
+                if level == leaf_level {
+                    unsafe { pte_ptr.write(leaf) };
+                    break;
+                }
+                let next = self.alloc_page();
+                let pointer =
+                    PageTableEntry::new((next.raw() / PAGE_SIZE) as u64, PteFlag::Valid as u8);
+                unsafe { pte_ptr.write(pointer) };
+                table = next;
+            }
+            root
+        }
+    }
+
+    /// Return leaf PTE that maps to `ppn` with `flags`.
+    fn leaf(ppn: usize, flags: &[PteFlag]) -> PageTableEntry {
+        PageTableEntry::new(
+            ppn as u64,
+            flags.iter().fold(0, |acc, flag| acc | *flag as u8),
+        )
+    }
+
+    /// Flags of shadow stack page. (`xwr = 010`)
+    const SHADOW_STACK: [PteFlag; 4] = [
+        PteFlag::Valid,
+        PteFlag::Write,
+        PteFlag::Accessed,
+        PteFlag::Dirty,
+    ];
+
+    /// Translate by the page table with vsstatus.
+    fn translate(
+        root: GuestPhysicalAddress,
+        privilege: Privilege,
+        access: AccessType,
+        vsstatus_bits: usize,
+    ) -> Result<GuestPhysicalAddress, TranslationError> {
+        vsstatus::write(vsstatus_bits);
+        trans_addr(SSP, root, 3, privilege, access)
+    }
+
+    /// Shadow stack page is translated by 4 KiB page and 2 MiB superpage.
+    #[test]
+    fn shadow_stack_page() {
+        let memory = GuestMemory::new();
+        let page = memory.sv39_page_table(SSP, 0, leaf(0x8_0123, &SHADOW_STACK));
+        assert_eq!(
+            translate(page, Privilege::Supervisor, AccessType::ShadowStack, 0),
+            Ok(GuestPhysicalAddress(0x8012_3ff8))
+        );
+
+        // lower VPN of superpage comes from the virtual address.
+        let superpage = memory.sv39_page_table(SSP, 1, leaf(0x8_0200, &SHADOW_STACK));
+        assert_eq!(
+            translate(superpage, Privilege::Supervisor, AccessType::ShadowStack, 0),
+            Ok(GuestPhysicalAddress(0x8020_3ff8))
+        );
+    }
+
+    /// Shadow stack access to the other pages raises store/AMO access fault.
+    #[test]
+    fn not_shadow_stack_page() {
+        let memory = GuestMemory::new();
+        let root = memory.sv39_page_table(
+            SSP,
+            0,
+            leaf(
+                0x8_0123,
+                &[
+                    PteFlag::Valid,
+                    PteFlag::Read,
+                    PteFlag::Write,
+                    PteFlag::Accessed,
+                    PteFlag::Dirty,
+                ],
+            ),
+        );
+        let result = translate(root, Privilege::Supervisor, AccessType::ShadowStack, 0);
+        assert_eq!(result, Err(TranslationError::NotShadowStackPage));
+        assert_eq!(
+            result.unwrap_err().exception_code(AccessType::ShadowStack),
+            7
+        );
+    }
+
+    /// sstatus.MXR does not make executable page accessible as shadow stack.
+    #[test]
+    fn mxr_does_not_affect_shadow_stack() {
+        let memory = GuestMemory::new();
+        let root = memory.sv39_page_table(
+            SSP,
+            0,
+            leaf(
+                0x8_0123,
+                &[
+                    PteFlag::Valid,
+                    PteFlag::Exec,
+                    PteFlag::Accessed,
+                    PteFlag::Dirty,
+                ],
+            ),
+        );
+        assert_eq!(
+            translate(
+                root,
+                Privilege::Supervisor,
+                AccessType::ShadowStack,
+                SSTATUS_MXR
+            ),
+            Err(TranslationError::NotShadowStackPage)
+        );
+    }
+
+    /// Loads, stores and instruction fetches are checked by R, W and X bits with sstatus.MXR.
+    #[test]
+    fn regular_access_permission() {
+        use AccessType::{Execute, Load, Store};
+        use PteFlag::{Accessed, Dirty, Exec, Read, Valid, Write};
+
+        let memory = GuestMemory::new();
+        let gpa = Ok(GuestPhysicalAddress(0x8012_3ff8));
+        let read_only = memory.sv39_page_table(SSP, 0, leaf(0x8_0123, &[Valid, Read, Accessed]));
+        assert_eq!(translate(read_only, Privilege::Supervisor, Load, 0), gpa);
+        let result = translate(read_only, Privilege::Supervisor, Store, 0);
+        assert_eq!(result, Err(TranslationError::PermissionDenied));
+        assert_eq!(result.unwrap_err().exception_code(Store), 15);
+        let result = translate(read_only, Privilege::Supervisor, Execute, 0);
+        assert_eq!(result, Err(TranslationError::PermissionDenied));
+        assert_eq!(result.unwrap_err().exception_code(Execute), 12);
+
+        // MXR makes execute-only page readable.
+        let exec_only = memory.sv39_page_table(SSP, 0, leaf(0x8_0123, &[Valid, Exec, Accessed]));
+        assert_eq!(translate(exec_only, Privilege::Supervisor, Execute, 0), gpa);
+        let result = translate(exec_only, Privilege::Supervisor, Load, 0);
+        assert_eq!(result, Err(TranslationError::PermissionDenied));
+        assert_eq!(result.unwrap_err().exception_code(Load), 13);
+        assert_eq!(
+            translate(exec_only, Privilege::Supervisor, Load, SSTATUS_MXR),
+            gpa
+        );
+
+        // store requires D bit.
+        let clean = memory.sv39_page_table(SSP, 0, leaf(0x8_0123, &[Valid, Read, Write, Accessed]));
+        assert_eq!(translate(clean, Privilege::Supervisor, Load, 0), gpa);
+        assert_eq!(
+            translate(clean, Privilege::Supervisor, Store, 0),
+            Err(TranslationError::AccessedDirtyClear)
+        );
+        let dirty = memory.sv39_page_table(
+            SSP,
+            0,
+            leaf(0x8_0123, &[Valid, Read, Write, Accessed, Dirty]),
+        );
+        assert_eq!(translate(dirty, Privilege::Supervisor, Store, 0), gpa);
+    }
+
+    /// Shadow stack page is readable by loads, and regular stores to it raise store/AMO access fault.
+    #[test]
+    fn regular_access_to_shadow_stack_page() {
+        let memory = GuestMemory::new();
+        let root = memory.sv39_page_table(SSP, 0, leaf(0x8_0123, &SHADOW_STACK));
+        assert_eq!(
+            translate(root, Privilege::Supervisor, AccessType::Load, 0),
+            Ok(GuestPhysicalAddress(0x8012_3ff8))
+        );
+        let result = translate(root, Privilege::Supervisor, AccessType::Store, 0);
+        assert_eq!(result, Err(TranslationError::StoreToShadowStackPage));
+        assert_eq!(result.unwrap_err().exception_code(AccessType::Store), 7);
+        assert_eq!(
+            translate(root, Privilege::Supervisor, AccessType::Execute, 0),
+            Err(TranslationError::PermissionDenied)
+        );
+    }
+
+    /// sstatus.SUM does not permit VS-mode to execute user page.
+    #[test]
+    fn sum_does_not_permit_execute() {
+        use PteFlag::{Accessed, Exec, Read, User, Valid};
+
+        let memory = GuestMemory::new();
+        let root =
+            memory.sv39_page_table(SSP, 0, leaf(0x8_0123, &[Valid, Read, Exec, User, Accessed]));
+        assert_eq!(
+            translate(root, Privilege::Supervisor, AccessType::Load, SSTATUS_SUM),
+            Ok(GuestPhysicalAddress(0x8012_3ff8))
+        );
+        assert_eq!(
+            translate(
+                root,
+                Privilege::Supervisor,
+                AccessType::Execute,
+                SSTATUS_SUM
+            ),
+            Err(TranslationError::PermissionDenied)
+        );
+        assert_eq!(
+            translate(root, Privilege::User, AccessType::Execute, 0),
+            Ok(GuestPhysicalAddress(0x8012_3ff8))
+        );
+    }
+
+    /// Guest-page faults are forwarded as access faults of the same access type.
+    #[test]
+    fn fault_codes_of_access_type() {
+        for (guest_page_fault, access, access_fault, page_fault) in [
+            (20, AccessType::Execute, 1, 12),
+            (21, AccessType::Load, 5, 13),
+            (23, AccessType::Store, 7, 15),
+        ] {
+            assert_eq!(
+                AccessType::from_guest_page_fault(guest_page_fault),
+                Some(access)
+            );
+            assert_eq!(access.access_fault_code(), access_fault);
+            assert_eq!(access.page_fault_code(), page_fault);
+        }
+        assert_eq!(AccessType::from_guest_page_fault(13), None);
+        assert_eq!(AccessType::ShadowStack.access_fault_code(), 7);
+        assert_eq!(AccessType::ShadowStack.page_fault_code(), 15);
+    }
+
+    /// VS-mode can access user page only if sstatus.SUM is set, and VU-mode can access only user page.
+    #[test]
+    fn sum_and_user_page() {
+        let memory = GuestMemory::new();
+        let mut user_flags = SHADOW_STACK.to_vec();
+        user_flags.push(PteFlag::User);
+        let user_page = memory.sv39_page_table(SSP, 0, leaf(0x8_0123, &user_flags));
+        let supervisor_page = memory.sv39_page_table(SSP, 0, leaf(0x8_0123, &SHADOW_STACK));
+
+        let result = translate(user_page, Privilege::Supervisor, AccessType::ShadowStack, 0);
+        assert_eq!(result, Err(TranslationError::PermissionDenied));
+        assert_eq!(
+            result.unwrap_err().exception_code(AccessType::ShadowStack),
+            15
+        );
+        assert_eq!(
+            translate(
+                user_page,
+                Privilege::Supervisor,
+                AccessType::ShadowStack,
+                SSTATUS_SUM
+            ),
+            Ok(GuestPhysicalAddress(0x8012_3ff8))
+        );
+        assert_eq!(
+            translate(user_page, Privilege::User, AccessType::ShadowStack, 0),
+            Ok(GuestPhysicalAddress(0x8012_3ff8))
+        );
+        assert_eq!(
+            translate(
+                supervisor_page,
+                Privilege::User,
+                AccessType::ShadowStack,
+                SSTATUS_SUM
+            ),
+            Err(TranslationError::PermissionDenied)
+        );
+    }
+
+    /// PPN of superpage must be aligned to its size.
+    #[test]
+    fn misaligned_superpage() {
+        let memory = GuestMemory::new();
+        let megapage = memory.sv39_page_table(SSP, 1, leaf(0x8_0201, &SHADOW_STACK));
+        assert_eq!(
+            translate(megapage, Privilege::Supervisor, AccessType::ShadowStack, 0),
+            Err(TranslationError::MisalignedSuperpage)
+        );
+
+        let gigapage = memory.sv39_page_table(SSP, 2, leaf(0x8_0200, &SHADOW_STACK));
+        assert_eq!(
+            translate(gigapage, Privilege::Supervisor, AccessType::ShadowStack, 0),
+            Err(TranslationError::MisalignedSuperpage)
+        );
+    }
+
+    /// A and D bits are not updated by the walker. (Svade)
+    #[test]
+    fn accessed_and_dirty() {
+        let memory = GuestMemory::new();
+        for cleared in [PteFlag::Accessed, PteFlag::Dirty] {
+            let flags: Vec<PteFlag> = SHADOW_STACK
+                .into_iter()
+                .filter(|flag| *flag as u8 != cleared as u8)
+                .collect();
+            let root = memory.sv39_page_table(SSP, 0, leaf(0x8_0123, &flags));
+            assert_eq!(
+                translate(root, Privilege::Supervisor, AccessType::ShadowStack, 0),
+                Err(TranslationError::AccessedDirtyClear)
+            );
+        }
+    }
+
+    /// Invalid PTE, reserved bits, pointer PTE at level 0 and non-canonical address.
+    #[test]
+    fn invalid_translation() {
+        let memory = GuestMemory::new();
+        let invalid = memory.sv39_page_table(SSP, 0, PageTableEntry(0));
+        assert_eq!(
+            translate(invalid, Privilege::Supervisor, AccessType::ShadowStack, 0),
+            Err(TranslationError::InvalidPte)
+        );
+
+        // reserved bits, PBMT (Svpbmt) and N (Svnapot)
+        for bit in [54, 60, 61, 62, 63] {
+            let mut reserved = leaf(0x8_0123, &SHADOW_STACK);
+            reserved.0 |= 1 << bit;
+            let reserved = memory.sv39_page_table(SSP, 0, reserved);
+            assert_eq!(
+                translate(reserved, Privilege::Supervisor, AccessType::ShadowStack, 0),
+                Err(TranslationError::InvalidPte)
+            );
+        }
+
+        let pointer = memory.sv39_page_table(SSP, 0, leaf(0x8_0123, &[PteFlag::Valid]));
+        assert_eq!(
+            translate(pointer, Privilege::Supervisor, AccessType::ShadowStack, 0),
+            Err(TranslationError::InvalidPte)
+        );
+
+        let root = memory.alloc_page();
+        assert_eq!(
+            trans_addr(
+                GuestVirtualAddress(1 << 38),
+                root,
+                3,
+                Privilege::Supervisor,
+                AccessType::Load
+            ),
+            Err(TranslationError::NonCanonicalAddress)
+        );
+    }
+
+    /// Page table that is not mapped by G-stage page table is reported with its guest physical address.
+    #[test]
+    fn unmapped_page_table() {
+        let memory = GuestMemory::new();
+
+        // root page table
+        let unmapped = GuestPhysicalAddress(0x1_0000_0000);
+        let result = translate(unmapped, Privilege::Supervisor, AccessType::ShadowStack, 0);
+        assert_eq!(result, Err(TranslationError::GuestPageFault(unmapped)));
+        assert_eq!(
+            result.unwrap_err().exception_code(AccessType::ShadowStack),
+            7
+        );
+
+        // next level page table
+        let pointer =
+            PageTableEntry::new((unmapped.raw() / PAGE_SIZE) as u64, PteFlag::Valid as u8);
+        let root = memory.sv39_page_table(SSP, 2, pointer);
+        assert_eq!(
+            translate(root, Privilege::Supervisor, AccessType::ShadowStack, 0),
+            Err(TranslationError::GuestPageFault(unmapped))
+        );
+    }
+
+    /// Translation mode is selected by vsatp.
+    #[test]
+    fn vsatp_mode() {
+        use super::super::vs_stage_trans_addr;
+
+        /// Bit position of vsatp.MODE
+        const MODE_SHIFT: usize = 60;
+
+        let memory = GuestMemory::new();
+        let root = memory.sv39_page_table(SSP, 0, leaf(0x8_0123, &SHADOW_STACK));
+        vsstatus::write(0);
+
+        vsatp::write((vsatp::Mode::Bare as usize) << MODE_SHIFT);
+        assert_eq!(
+            vs_stage_trans_addr(SSP, Privilege::Supervisor, AccessType::ShadowStack),
+            Ok(GuestPhysicalAddress(SSP.0))
+        );
+
+        vsatp::write((vsatp::Mode::Sv39 as usize) << MODE_SHIFT | (root.raw() / PAGE_SIZE));
+        assert_eq!(
+            vs_stage_trans_addr(SSP, Privilege::Supervisor, AccessType::ShadowStack),
+            Ok(GuestPhysicalAddress(0x8012_3ff8))
+        );
+
+        vsatp::write((vsatp::Mode::Sv64 as usize) << MODE_SHIFT);
+        assert_eq!(
+            vs_stage_trans_addr(SSP, Privilege::Supervisor, AccessType::ShadowStack),
+            Err(TranslationError::UnsupportedMode)
+        );
+    }
+}
@@ -2,7 +2,7 @@
 //! Ref: [https://github.com/rustsbi/rustsbi-qemu](https://github.com/rustsbi/rustsbi-qemu)  
 //! Document: [https://docs.rs/rustsbi/0.4.0-alpha.1/rustsbi/derive.RustSBI.html](https://docs.rs/rustsbi/0.4.0-alpha.1/rustsbi/derive.RustSBI.html)  
 
-mod rfence;
+pub mod rfence;
 
 use crate::device::{clint, uart, MmioDevice};
 use fdt::Fdt;
@@ -1,11 +1,76 @@
 //! Remote fence implementation for `RustSBI`.
 
 use core::arch::asm;
+use core::sync::atomic::{AtomicU8, Ordering};
 use rustsbi::{HartMask, SbiRet};
 
-use crate::memmap::constant::MAX_HART_NUM;
+use crate::memmap::constant::{device::CLINT_ADDR, MAX_HART_NUM};
 use crate::memmap::page_table::constants::PAGE_SIZE;
 
+/// Request bit of `fence.i`.
+const FENCE_I_REQUEST: u8 = 1 << 0;
+/// Request bit of `sfence.vma`.
+const SFENCE_VMA_REQUEST: u8 = 1 << 1;
+
+/// Pending remote fence requests of each hart.
+///
+/// The requests are handled in machine software interrupt handler of the target hart.
+static REMOTE_FENCE_REQUESTS: [AtomicU8; MAX_HART_NUM] = [const { AtomicU8::new(0) }; MAX_HART_NUM];
+
+/// Send remote fence request to the other hart and raise machine software interrupt.
+fn send_request(hart_id: usize, request: u8) {
+    REMOTE_FENCE_REQUESTS[hart_id].fetch_or(request, Ordering::Release);
+
+    let msip_ptr = (CLINT_ADDR.raw() + hart_id * 4) as *mut u32;
+    unsafe {
+        msip_ptr.write_volatile(1);
+    }
+}
+
+/// Wait until the other harts in `hart_mask` complete the `request`.
+///
+/// The target hart clears the request bits after executing the fence.
+/// Requests to current hart are handled while waiting, because the target hart may wait for current hart in M-mode
+/// where machine software interrupt is disabled.
+fn wait_for_completion(hart_mask: &HartMask, request: u8) {
+    let current_hart_id = riscv::register::mhartid::read();
+    for hart_id in (0..MAX_HART_NUM).filter(|id| hart_mask.has_bit(*id) && *id != current_hart_id) {
+        while REMOTE_FENCE_REQUESTS[hart_id].load(Ordering::Acquire) & request != 0 {
+            handle_remote_fence_request();
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Handle pending remote fence requests of current hart.
+///
+/// It is called from machine software interrupt handler.
+/// Clearing the request bits notifies the completion to the requesting hart.
+pub fn handle_remote_fence_request() {
+    let hart_id = riscv::register::mhartid::read();
+    let mut requests = REMOTE_FENCE_REQUESTS[hart_id].load(Ordering::Acquire);
+    while requests != 0 {
+        if requests & FENCE_I_REQUEST != 0 {
+            unsafe { asm!("fence.i") }
+        }
+        if requests & SFENCE_VMA_REQUEST != 0 {
+            // Address range is not passed to the remote hart, so flush all.
+            unsafe { asm!("sfence.vma x0, x0") }
+        }
+
+        // retry if new requests arrived during the fence.
+        match REMOTE_FENCE_REQUESTS[hart_id].compare_exchange(
+            requests,
+            0,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => break,
+            Err(current) => requests = current,
+        }
+    }
+}
+
 /// Remote fence implementation.
 /// ref: [https://docs.rs/rustsbi/0.4.0-alpha.3/rustsbi/trait.Fence.html](https://docs.rs/rustsbi/0.4.0-alpha.3/rustsbi/trait.Fence.html)
 pub struct RemoteFence;
@@ -13,27 +78,31 @@ pub struct RemoteFence;
 impl rustsbi::Fence for RemoteFence {
     // Required methods
     fn remote_fence_i(&self, hart_mask: HartMask) -> SbiRet {
-        // current hart must be 0.
-        for hart_id in 1..MAX_HART_NUM {
-            debug_assert!(!hart_mask.has_bit(hart_id));
-        }
-
-        if hart_mask.has_bit(0) {
-            unsafe { asm!("fence.i") }
+        let current_hart_id = riscv::register::mhartid::read();
+        for hart_id in (0..MAX_HART_NUM).filter(|id| hart_mask.has_bit(*id)) {
+            if hart_id == current_hart_id {
+                unsafe { asm!("fence.i") }
+            } else {
+                send_request(hart_id, FENCE_I_REQUEST);
+            }
         }
+        wait_for_completion(&hart_mask, FENCE_I_REQUEST);
 
         SbiRet::success(0)
     }
 
     fn remote_sfence_vma(&self, hart_mask: HartMask, start_addr: usize, size: usize) -> SbiRet {
-        // current hart must be 0.
-        for hart_id in 1..MAX_HART_NUM {
-            debug_assert!(!hart_mask.has_bit(hart_id));
-        }
-
-        for addr in (start_addr..start_addr + size).step_by(PAGE_SIZE) {
-            unsafe { asm!("sfence.vma {vaddr}, x0", vaddr = in(reg) addr) }
+        let current_hart_id = riscv::register::mhartid::read();
+        for hart_id in (0..MAX_HART_NUM).filter(|id| hart_mask.has_bit(*id)) {
+            if hart_id == current_hart_id {
+                for addr in (start_addr..start_addr + size).step_by(PAGE_SIZE) {
+                    unsafe { asm!("sfence.vma {vaddr}, x0", vaddr = in(reg) addr) }
+                }
+            } else {
+                send_request(hart_id, SFENCE_VMA_REQUEST);
+            }
         }
+        wait_for_completion(&hart_mask, SFENCE_VMA_REQUEST);
 
         SbiRet::success(0)
     }
@@ -45,14 +114,19 @@ impl rustsbi::Fence for RemoteFence {
         size: usize,
         asid: usize,
     ) -> SbiRet {
-        // current hart must be 0.
-        for hart_id in 1..MAX_HART_NUM {
-            debug_assert!(!hart_mask.has_bit(hart_id));
-        }
-
-        for addr in (start_addr..start_addr + size).step_by(PAGE_SIZE) {
-            unsafe { asm!("sfence.vma {vaddr}, {asid}", vaddr = in(reg) addr, asid = in(reg) asid) }
+        let current_hart_id = riscv::register::mhartid::read();
+        for hart_id in (0..MAX_HART_NUM).filter(|id| hart_mask.has_bit(*id)) {
+            if hart_id == current_hart_id {
+                for addr in (start_addr..start_addr + size).step_by(PAGE_SIZE) {
+                    unsafe {
+                        asm!("sfence.vma {vaddr}, {asid}", vaddr = in(reg) addr, asid = in(reg) asid);
+                    }
+                }
+            } else {
+                send_request(hart_id, SFENCE_VMA_REQUEST);
+            }
         }
+        wait_for_completion(&hart_mask, SFENCE_VMA_REQUEST);
 
         SbiRet::success(0)
     }
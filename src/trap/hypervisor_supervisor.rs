@@ -12,6 +12,8 @@ use riscv::register::scause::{self, Trap};
 
 /// Switch to original mode stack and save contexts.
 ///
+/// See `guest::context` for the layout of the context.
+///
 /// # TODO
 /// replace stringify macro to const when `asm_const` is stabled.
 #[inline(always)]
@@ -29,7 +31,7 @@ pub unsafe fn hstrap_exit() -> ! {
 
         // set to stack top
         mv sp, {stack_top}  
-        addi sp, sp, -272 // Size of ContextData = 8 * 34
+        addi sp, sp, -368 // Size of ContextData = 8 * 46
 
         // restore sstatus 
         ld t0, 32*8(sp)
@@ -72,7 +74,7 @@ pub unsafe fn hstrap_exit() -> ! {
         ld t6, 31*8(sp)
 
         // swap HS-mode sp for original mode sp.
-        addi sp, sp, 272
+        addi sp, sp, 368
         csrrw sp, sscratch, sp
 
         sret
@@ -85,6 +87,8 @@ pub unsafe fn hstrap_exit() -> ! {
 /// Trap vector for HS-mode.
 /// Switch to hypervisor stack and save contexts.
 ///
+/// See `guest::context` for the layout of the context.
+///
 /// # TODO
 /// ## `asm_const`
 /// replace stringify macro to const when `asm_const` is stabled.
@@ -106,7 +110,7 @@ pub unsafe extern "C" fn hstrap_vector() -> ! {
 
             // swap original mode sp for HS-mode sp 
             csrrw sp, sscratch, sp
-            addi sp, sp, -272 // Size of ContextData = 8 * 34
+            addi sp, sp, -368 // Size of ContextData = 8 * 46
 
             // save registers
             sd ra, 1*8(sp)
@@ -147,6 +151,14 @@ pub unsafe extern "C" fn hstrap_vector() -> ! {
             // save pc
             csrr t1, sepc
             sd t1, 33*8(sp)
+
+            // set hart id to tp (= (_stack_start - sp) / STACK_SIZE_PER_HART)
+            la t0, _stack_start
+            sub t0, t0, sp
+            // t1 = _hart_stack_size (= STACK_SIZE_PER_HART, defined in `memory.x`)
+            lui t1, %hi(_hart_stack_size)
+            addi t1, t1, %lo(_hart_stack_size)
+            divu tp, t0, t1
             ",
         );
     }
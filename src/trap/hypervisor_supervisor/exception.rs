@@ -7,14 +7,26 @@ mod sbi_handler;
 use super::hstrap_exit;
 use crate::guest;
 use crate::h_extension::{csrs::vstvec, HvException};
-use crate::HYPERVISOR_DATA;
+use crate::memmap::page_table::vs_stage::AccessType;
+use crate::{current_hart_id, HYPERVISOR_DATA};
 
 use core::arch::asm;
 use riscv::register::{
     scause::{self, Exception},
     stval,
 };
-use sbi_handler::{sbi_base_handler, sbi_fwft_handler, sbi_rfnc_handler};
+use sbi_handler::{
+    sbi_base_handler, sbi_dbcn_handler, sbi_fwft_handler, sbi_hsm_handler, sbi_rfnc_handler,
+    sbi_spi_handler, sbi_time_handler,
+};
+
+/// Return the exception cause that is seen by the guest.
+///
+/// Guest-page faults are converted to the corresponding access faults,
+/// because they are not defined for VS-mode and the guest can not resolve them.
+fn forwarded_cause(cause: usize) -> usize {
+    AccessType::from_guest_page_fault(cause).map_or(cause, AccessType::access_fault_code)
+}
 
 /// Delegate exception to supervisor mode from VS-mode.
 #[no_mangle]
@@ -28,7 +40,7 @@ pub extern "C" fn hs_forward_exception() {
             "csrw vscause, {scause}",
             "csrw vstval, {stval}",
             sepc = in(reg) context.sepc(),
-            scause = in(reg) scause::read().bits(),
+            scause = in(reg) forwarded_cause(scause::read().bits()),
             stval = in(reg) stval::read(),
         );
 
@@ -55,6 +67,10 @@ fn sbi_vs_mode_handler(context: &mut guest::context::Context) {
     let sbiret = match ext_id {
         sbi_spec::base::EID_BASE => sbi_base_handler(func_id),
         sbi_spec::rfnc::EID_RFNC => sbi_rfnc_handler(func_id, arguments),
+        sbi_spec::hsm::EID_HSM => sbi_hsm_handler(func_id, arguments),
+        sbi_spec::spi::EID_SPI => sbi_spi_handler(func_id, arguments),
+        sbi_spec::time::EID_TIME => sbi_time_handler(func_id, arguments),
+        sbi_spec::dbcn::EID_DBCN => sbi_dbcn_handler(func_id, arguments),
         EID_FWFT => sbi_fwft_handler(func_id, arguments),
         _ => panic!(
             "Unsupported SBI call, eid: {:#x}, fid: {:#x}",
@@ -66,22 +82,26 @@ fn sbi_vs_mode_handler(context: &mut guest::context::Context) {
     context.set_xreg(11, sbiret.value as u64);
 }
 
-/// Update sepc by htinst value.
-fn update_sepc_by_htinst_value(htinst_inst_value: usize, context: &mut guest::context::Context) {
-    if (htinst_inst_value & 0b10) >> 1 == 0 {
-        // compressed instruction
-        context.set_sepc(context.sepc() + 2);
-    } else {
-        // normal size instruction
-        context.set_sepc(context.sepc() + 4);
-    }
-}
-
 /// Trap handler for exception
 #[allow(clippy::cast_possible_truncation, clippy::module_name_repetitions)]
 pub unsafe fn trap_exception(exception_cause: Exception) -> ! {
     match exception_cause {
-        Exception::IllegalInstruction => instruction_handler::illegal_instruction(),
+        Exception::IllegalInstruction => {
+            let mut hypervisor_data = unsafe { HYPERVISOR_DATA.lock() };
+            let guest = hypervisor_data
+                .get_mut()
+                .unwrap()
+                .guest_by_hart_id_mut(current_hart_id())
+                .expect("guest data not found");
+            // first floating-point or vector instruction after vCPU switching.
+            // retry the instruction after restoring registers.
+            let restored = guest.lazy_context.restore_pending(&mut guest.context);
+            drop(hypervisor_data);
+
+            if !restored {
+                instruction_handler::illegal_instruction();
+            }
+        }
         Exception::SupervisorEnvCall => panic!("SupervisorEnvCall should be handled by M-mode"),
         // Enum not found in `riscv` crate.
         Exception::Unknown => match HvException::from(scause::read().code()) {
@@ -91,7 +111,7 @@ pub unsafe fn trap_exception(exception_cause: Exception) -> ! {
                 context.set_sepc(context.sepc() + 4);
             }
             HvException::InstructionGuestPageFault => {
-                panic!("Instruction guest-page fault");
+                page_fault_handler::instruction_guest_page_fault();
             }
             HvException::LoadGuestPageFault => page_fault_handler::load_guest_page_fault(),
             HvException::StoreAmoGuestPageFault => page_fault_handler::store_guest_page_fault(),
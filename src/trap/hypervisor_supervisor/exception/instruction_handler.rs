@@ -3,9 +3,8 @@
 //! - Illegal Instruction
 //! - Virtual Instruction
 
-use crate::emulate_extension::zicfiss::ZICFISS_DATA;
 use crate::emulate_extension::EmulateExtension;
-use crate::HYPERVISOR_DATA;
+use crate::{current_hart_id, HYPERVISOR_DATA};
 
 use core::arch::asm;
 use raki::{Instruction, OpcodeKind};
@@ -18,18 +17,19 @@ pub fn illegal_instruction() {
     let fault_inst =
         Instruction::try_from(fault_inst_value).expect("decoding load fault instruction failed");
 
+    // shadow stack enables of the vCPU are not changed by these instructions.
+    let mut zicfiss = unsafe { HYPERVISOR_DATA.lock() }
+        .get()
+        .unwrap()
+        .guest()
+        .zicfiss;
+
     // emulate the instruction
     match fault_inst.opc {
-        OpcodeKind::Zicfiss(_) => unsafe { ZICFISS_DATA.lock() }
-            .get_mut()
-            .unwrap()
-            .instruction(&fault_inst),
+        OpcodeKind::Zicfiss(_) => zicfiss.instruction(&fault_inst),
         OpcodeKind::Zicsr(_) => match fault_inst.rs2.unwrap() {
             // ssp
-            0x11 => unsafe { ZICFISS_DATA.lock() }
-                .get_mut()
-                .unwrap()
-                .csr(&fault_inst),
+            0x11 => zicfiss.csr(&fault_inst),
             unsupported_csr_num => {
                 unimplemented!("unsupported CSRs: {unsupported_csr_num:#x}")
             }
@@ -70,12 +70,14 @@ pub fn virtual_instruction() {
 
                     let write_to_csr_value = context.xreg(fault_inst.rs1.unwrap());
 
-                    // update emulated CSR field.
-                    unsafe { ZICFISS_DATA.lock() }.get_mut().unwrap().csr_field(
-                        &fault_inst,
-                        write_to_csr_value,
-                        &mut read_from_csr_value,
-                    );
+                    // update emulated CSR field of the vCPU.
+                    unsafe { HYPERVISOR_DATA.lock() }
+                        .get_mut()
+                        .unwrap()
+                        .guest_by_hart_id_mut(current_hart_id())
+                        .expect("guest data not found")
+                        .zicfiss
+                        .csr_field(&fault_inst, write_to_csr_value, &mut read_from_csr_value);
 
                     // commit result
                     unsafe {
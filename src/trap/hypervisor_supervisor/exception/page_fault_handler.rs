@@ -1,74 +1,341 @@
 //! Handle page fault exceptions.
 //!
+//! - Instruction guest page fault
 //! - Load guest page fault
 //! - Store AMO guest page fault
+//!
+//! Guest page faults on demand-paged guest memory are resolved by backing the page.
+//! Guest page faults on the ranges of emulated devices are emulated by decoding the faulting instruction.
+//! Loads and stores of all widths (including compressed ones) and atomic memory operations are supported.
+//! If `htinst` is 0 or can not be decoded, the faulting instruction is read from guest memory.
+//! Faults of the implicit accesses of VS-stage address translation (pseudoinstructions in `htinst`) are not emulated.
+//! The other guest page faults are forwarded to the guest as access faults.
 
-use super::{hs_forward_exception, hstrap_exit, update_sepc_by_htinst_value};
-use crate::device::DeviceEmulateError;
+use super::hs_forward_exception;
+use crate::device::{AccessWidth, DeviceEmulateError};
 use crate::h_extension::csrs::{htinst, htval};
-use crate::memmap::HostPhysicalAddress;
-use crate::HYPERVISOR_DATA;
+use crate::memmap::guest_access::read_guest_instruction;
+use crate::memmap::{GuestPhysicalAddress, GuestVirtualAddress};
+use crate::{current_hart_id, HYPERVISOR_DATA};
 
-use raki::Instruction;
+use raki::{AOpcode, BaseIOpcode, COpcode, Instruction, OpcodeKind};
+use riscv::register::stval;
 
-/// Trap `Load guest page fault` exception.
-pub fn load_guest_page_fault() {
-    let fault_addr = HostPhysicalAddress(htval::read().bits() << 2);
+/// Operation of atomic memory operation.
+#[derive(Debug, Copy, Clone)]
+enum AmoOp {
+    /// `amoswap`
+    Swap,
+    /// `amoadd`
+    Add,
+    /// `amoxor`
+    Xor,
+    /// `amoand`
+    And,
+    /// `amoor`
+    Or,
+    /// `amomin`
+    Min,
+    /// `amomax`
+    Max,
+    /// `amominu`
+    MinU,
+    /// `amomaxu`
+    MaxU,
+}
+
+impl AmoOp {
+    /// Return the value to be stored.
+    ///
+    /// Both `loaded` and `src` must be sign-extended from the access width.
+    #[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+    fn apply(self, loaded: u64, src: u64) -> u64 {
+        match self {
+            AmoOp::Swap => src,
+            AmoOp::Add => loaded.wrapping_add(src),
+            AmoOp::Xor => loaded ^ src,
+            AmoOp::And => loaded & src,
+            AmoOp::Or => loaded | src,
+            AmoOp::Min => (loaded as i64).min(src as i64) as u64,
+            AmoOp::Max => (loaded as i64).max(src as i64) as u64,
+            // sign extension keeps the unsigned order of 32 bit values.
+            AmoOp::MinU => loaded.min(src),
+            AmoOp::MaxU => loaded.max(src),
+        }
+    }
+}
+
+/// MMIO access decoded from the faulting instruction.
+#[derive(Debug)]
+enum MmioAccess {
+    /// Load to `rd`.
+    Load {
+        /// Destination register.
+        rd: usize,
+        /// Access width.
+        width: AccessWidth,
+        /// Whether the loaded value is sign-extended.
+        signed: bool,
+    },
+    /// Store value of `rs2`.
+    Store {
+        /// Source register.
+        rs2: usize,
+        /// Access width.
+        width: AccessWidth,
+    },
+    /// Store conditional.
+    /// It always succeeds because emulated devices have no reservation.
+    StoreConditional {
+        /// Destination register of the result. (always 0)
+        rd: usize,
+        /// Source register.
+        rs2: usize,
+        /// Access width.
+        width: AccessWidth,
+    },
+    /// Atomic memory operation.
+    /// Load to `rd` and store the result of `op` with the loaded value and `rs2`.
+    Amo {
+        /// Operation.
+        op: AmoOp,
+        /// Destination register.
+        rd: usize,
+        /// Source register.
+        rs2: usize,
+        /// Access width.
+        width: AccessWidth,
+    },
+}
+
+impl MmioAccess {
+    /// Decode MMIO access from the instruction.
+    ///
+    /// Return `None` if the instruction does not access memory.
+    fn decode(inst: &Instruction) -> Option<Self> {
+        let load = |width, signed| inst.rd.map(|rd| MmioAccess::Load { rd, width, signed });
+        let store = |width| inst.rs2.map(|rs2| MmioAccess::Store { rs2, width });
+
+        match &inst.opc {
+            OpcodeKind::BaseI(BaseIOpcode::LB) => load(AccessWidth::Byte, true),
+            OpcodeKind::BaseI(BaseIOpcode::LH) => load(AccessWidth::Half, true),
+            OpcodeKind::BaseI(BaseIOpcode::LW) | OpcodeKind::C(COpcode::LW | COpcode::LWSP) => {
+                load(AccessWidth::Word, true)
+            }
+            OpcodeKind::BaseI(BaseIOpcode::LD) | OpcodeKind::C(COpcode::LD | COpcode::LDSP) => {
+                load(AccessWidth::Double, true)
+            }
+            OpcodeKind::BaseI(BaseIOpcode::LBU) => load(AccessWidth::Byte, false),
+            OpcodeKind::BaseI(BaseIOpcode::LHU) => load(AccessWidth::Half, false),
+            OpcodeKind::BaseI(BaseIOpcode::LWU) => load(AccessWidth::Word, false),
+            OpcodeKind::BaseI(BaseIOpcode::SB) => store(AccessWidth::Byte),
+            OpcodeKind::BaseI(BaseIOpcode::SH) => store(AccessWidth::Half),
+            OpcodeKind::BaseI(BaseIOpcode::SW) | OpcodeKind::C(COpcode::SW | COpcode::SWSP) => {
+                store(AccessWidth::Word)
+            }
+            OpcodeKind::BaseI(BaseIOpcode::SD) | OpcodeKind::C(COpcode::SD | COpcode::SDSP) => {
+                store(AccessWidth::Double)
+            }
+            OpcodeKind::A(opcode) => Self::decode_atomic(inst, opcode),
+            _ => None,
+        }
+    }
+
+    /// Decode MMIO access from the instruction of A extension.
+    fn decode_atomic(inst: &Instruction, opcode: &AOpcode) -> Option<Self> {
+        let (rd, rs2) = (inst.rd?, inst.rs2);
+        let amo = |op, width| rs2.map(|rs2| MmioAccess::Amo { op, rd, rs2, width });
+
+        match opcode {
+            AOpcode::LR_W => Some(MmioAccess::Load {
+                rd,
+                width: AccessWidth::Word,
+                signed: true,
+            }),
+            AOpcode::LR_D => Some(MmioAccess::Load {
+                rd,
+                width: AccessWidth::Double,
+                signed: true,
+            }),
+            AOpcode::SC_W => Some(MmioAccess::StoreConditional {
+                rd,
+                rs2: rs2?,
+                width: AccessWidth::Word,
+            }),
+            AOpcode::SC_D => Some(MmioAccess::StoreConditional {
+                rd,
+                rs2: rs2?,
+                width: AccessWidth::Double,
+            }),
+            AOpcode::AMOSWAP_W => amo(AmoOp::Swap, AccessWidth::Word),
+            AOpcode::AMOADD_W => amo(AmoOp::Add, AccessWidth::Word),
+            AOpcode::AMOXOR_W => amo(AmoOp::Xor, AccessWidth::Word),
+            AOpcode::AMOAND_W => amo(AmoOp::And, AccessWidth::Word),
+            AOpcode::AMOOR_W => amo(AmoOp::Or, AccessWidth::Word),
+            AOpcode::AMOMIN_W => amo(AmoOp::Min, AccessWidth::Word),
+            AOpcode::AMOMAX_W => amo(AmoOp::Max, AccessWidth::Word),
+            AOpcode::AMOMINU_W => amo(AmoOp::MinU, AccessWidth::Word),
+            AOpcode::AMOMAXU_W => amo(AmoOp::MaxU, AccessWidth::Word),
+            AOpcode::AMOSWAP_D => amo(AmoOp::Swap, AccessWidth::Double),
+            AOpcode::AMOADD_D => amo(AmoOp::Add, AccessWidth::Double),
+            AOpcode::AMOXOR_D => amo(AmoOp::Xor, AccessWidth::Double),
+            AOpcode::AMOAND_D => amo(AmoOp::And, AccessWidth::Double),
+            AOpcode::AMOOR_D => amo(AmoOp::Or, AccessWidth::Double),
+            AOpcode::AMOMIN_D => amo(AmoOp::Min, AccessWidth::Double),
+            AOpcode::AMOMAX_D => amo(AmoOp::Max, AccessWidth::Double),
+            AOpcode::AMOMINU_D => amo(AmoOp::MinU, AccessWidth::Double),
+            AOpcode::AMOMAXU_D => amo(AmoOp::MaxU, AccessWidth::Double),
+        }
+    }
+}
+
+/// Pseudoinstructions that are written to `htinst` if the fault occurred by the implicit memory access of
+/// VS-stage address translation. (32/64 bit read of PTE, and write to update A/D bits)
+/// ref: vol. II p.161
+const HTINST_PSEUDOINSTRUCTIONS: [usize; 4] = [0x2000, 0x2020, 0x3000, 0x3020];
+
+/// Read the faulting instruction from guest memory and return it with its length in bytes.
+fn read_fault_instruction(sepc: usize) -> Option<(Instruction, usize)> {
+    let inst_value = read_guest_instruction(GuestVirtualAddress(sepc)).ok()?;
+    let inst = Instruction::try_from(inst_value).ok()?;
+    let inst_len = if inst.is_compressed { 2 } else { 4 };
+    Some((inst, inst_len))
+}
+
+/// Return the faulting instruction and its length in bytes.
+///
+/// `htinst` holds the transformed instruction if it is not 0.
+/// Otherwise, the instruction is read from guest memory at `sepc` because implementations may always write 0 to `htinst`.
+/// It is also read from guest memory if `htinst` has a custom value that can not be decoded.
+///
+/// Return `None` if the fault is not caused by the instruction itself but by VS-stage address translation.
+fn fault_instruction(sepc: usize) -> Option<(Instruction, usize)> {
     let fault_inst_value = htinst::read().bits();
+    if fault_inst_value == 0 {
+        return read_fault_instruction(sepc);
+    }
+    if HTINST_PSEUDOINSTRUCTIONS.contains(&fault_inst_value) {
+        return None;
+    }
+
     // htinst bit 1 replaced with a 0.
     // thus it needed to flip bit 1.
     // ref: vol. II p.161
-    let fault_inst = Instruction::try_from(fault_inst_value | 0b10)
-        .expect("decoding load fault instruction failed");
+    let Ok(inst) = Instruction::try_from(fault_inst_value | 0b10) else {
+        return read_fault_instruction(sepc);
+    };
+    // bit 1 of transformed instruction is 0 if the original instruction is compressed.
+    let inst_len = if fault_inst_value & 0b10 == 0 { 2 } else { 4 };
+    Some((inst, inst_len))
+}
 
-    let mut hypervisor_data = unsafe { HYPERVISOR_DATA.lock() };
-    match hypervisor_data
-        .get_mut()
+/// Return the faulting guest physical address.
+fn fault_address() -> GuestPhysicalAddress {
+    // htval has the guest physical address shifted right by 2 bits.
+    // low 2 bits are the same as the guest virtual address in stval.
+    GuestPhysicalAddress(htval::read().bits() << 2 | stval::read() & 0b11)
+}
+
+/// Back the faulting page if it is demand-paged guest memory.
+///
+/// Return `true` if the faulting instruction should be retried.
+fn back_demand_page(fault_addr: GuestPhysicalAddress) -> bool {
+    let hypervisor_data = unsafe { HYPERVISOR_DATA.lock() };
+    hypervisor_data
+        .get()
         .unwrap()
-        .devices()
-        .plic
-        .emulate_read(fault_addr)
-    {
-        Ok(value) => {
-            let mut context = hypervisor_data.get().unwrap().guest().context;
-            context.set_xreg(fault_inst.rd.expect("rd is not found"), u64::from(value));
-            update_sepc_by_htinst_value(fault_inst_value, &mut context);
-        }
-        Err(
-            DeviceEmulateError::InvalidAddress
-            | DeviceEmulateError::InvalidContextId
-            | DeviceEmulateError::ReservedRegister,
-        ) => hs_forward_exception(),
+        .guest()
+        .back_demand_page(fault_addr)
+}
+
+/// Trap `Instruction guest page fault` exception.
+///
+/// The exception is forwarded to the guest if the page is not demand-paged guest memory.
+pub fn instruction_guest_page_fault() {
+    if !back_demand_page(fault_address()) {
+        hs_forward_exception();
+    }
+}
+
+/// Trap `Load guest page fault` exception.
+pub fn load_guest_page_fault() {
+    let fault_addr = fault_address();
+    if !back_demand_page(fault_addr) {
+        emulate_mmio_access(fault_addr);
     }
 }
 
 /// Trap `Store guest page fault` exception.
 pub fn store_guest_page_fault() {
-    let fault_addr = HostPhysicalAddress(htval::read().bits() << 2);
-    let fault_inst_value = htinst::read().bits();
-    // htinst bit 1 replaced with a 0.
-    // thus it needed to flip bit 1.
-    // ref: vol. II p.161
-    let fault_inst = Instruction::try_from(fault_inst_value | 0b10)
-        .expect("decoding load fault instruction failed");
+    let fault_addr = fault_address();
+    if !back_demand_page(fault_addr) {
+        emulate_mmio_access(fault_addr);
+    }
+}
 
-    let mut hypervisor_data = unsafe { HYPERVISOR_DATA.lock() };
-    let mut context = hypervisor_data.get().unwrap().guest().context;
-    let store_value = context.xreg(fault_inst.rs2.expect("rs2 is not found"));
+/// Emulate the faulting access to emulated devices.
+///
+/// The exception is forwarded to the guest if the access can not be emulated.
+fn emulate_mmio_access(fault_addr: GuestPhysicalAddress) {
+    let mut context = unsafe { HYPERVISOR_DATA.lock().get().unwrap().guest().context };
+    let Some((fault_inst, inst_len)) = fault_instruction(context.sepc()) else {
+        hs_forward_exception();
+        return;
+    };
+    let Some(access) = MmioAccess::decode(&fault_inst) else {
+        hs_forward_exception();
+        return;
+    };
 
-    if let Ok(()) = hypervisor_data
-        .get_mut()
-        .unwrap()
-        .devices()
-        .plic
-        .emulate_write(fault_addr, store_value.try_into().unwrap())
-    {
-        update_sepc_by_htinst_value(fault_inst_value, &mut context);
-        drop(hypervisor_data);
-        unsafe {
-            hstrap_exit(); // exit handler
+    let result = {
+        let mut hypervisor_data = unsafe { HYPERVISOR_DATA.lock() };
+        let hypervisor_data = hypervisor_data.get_mut().unwrap();
+        let guest_id = hypervisor_data.guest().guest_id();
+        let devices = hypervisor_data.devices();
+        let hart_id = current_hart_id();
+
+        match access {
+            MmioAccess::Load { rd, width, signed } => devices
+                .emulate_read(guest_id, hart_id, fault_addr, width)
+                .map(|value| {
+                    let value = if signed {
+                        width.sign_extend(value)
+                    } else {
+                        value
+                    };
+                    context.set_xreg(rd, value);
+                }),
+            MmioAccess::Store { rs2, width } => {
+                devices.emulate_write(guest_id, hart_id, fault_addr, width, context.xreg(rs2))
+            }
+            MmioAccess::StoreConditional { rd, rs2, width } => devices
+                .emulate_write(guest_id, hart_id, fault_addr, width, context.xreg(rs2))
+                .map(|()| context.set_xreg(rd, 0)),
+            MmioAccess::Amo { op, rd, rs2, width } => devices
+                .emulate_read(guest_id, hart_id, fault_addr, width)
+                .and_then(|value| {
+                    let loaded = width.sign_extend(value);
+                    let src = width.sign_extend(context.xreg(rs2));
+                    devices.emulate_write(
+                        guest_id,
+                        hart_id,
+                        fault_addr,
+                        width,
+                        op.apply(loaded, src),
+                    )?;
+                    context.set_xreg(rd, loaded);
+                    Ok(())
+                }),
         }
-    }
+    };
 
-    hs_forward_exception();
+    match result {
+        Ok(()) => context.set_sepc(context.sepc() + inst_len),
+        Err(
+            DeviceEmulateError::InvalidAddress
+            | DeviceEmulateError::InvalidContextId
+            | DeviceEmulateError::ReservedRegister
+            | DeviceEmulateError::InvalidAccessWidth,
+        ) => hs_forward_exception(),
+    }
 }
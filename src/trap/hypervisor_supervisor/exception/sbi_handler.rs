@@ -1,8 +1,71 @@
 //! Handle VS-mode Ecall exception  
 //! See [https://github.com/riscv-non-isa/riscv-sbi-doc/releases/download/v2.0/riscv-sbi.pdf](https://github.com/riscv-non-isa/riscv-sbi-doc/releases/download/v2.0/riscv-sbi.pdf)
 
+mod fwft;
+
+use crate::guest::{scheduler, Guest, HartState};
+use crate::h_extension::csrs::{hvip, VsInterruptKind};
+use crate::hypervisor_init::wait_for_hart_start;
+use crate::memmap::{
+    constant::MAX_HART_NUM,
+    guest_access::{read_guest_physical, write_guest_physical, GuestAccessFault},
+    GuestPhysicalAddress,
+};
+use crate::{current_hart_id, HYPERVISOR_DATA};
+
+use alloc::vec::Vec;
+use fwft::FwftFeature;
+use rustsbi::HartMask;
 use sbi_rt::SbiRet;
 
+/// Return hart mask of the calling guest from `hart_mask` and `hart_mask_base`.
+///
+/// Guest hart id is the same as physical hart id, and `hart_mask_base = -1` selects all harts of the guest.
+/// Return `None` if the mask has harts that are not assigned to the guest,
+/// so that the guest can not send IPIs or remote fences to harts of other guests.
+fn guest_hart_mask(hart_mask: usize, hart_mask_base: usize) -> Option<HartMask> {
+    let hypervisor_data = unsafe { HYPERVISOR_DATA.lock() };
+    let hypervisor_data = hypervisor_data.get().unwrap();
+    let guest_id = hypervisor_data.guest().guest_id();
+    let assigned_harts = (0..MAX_HART_NUM)
+        .filter(|hart_id| hypervisor_data.vcpu_guest(guest_id, *hart_id).is_some())
+        .fold(0, |mask, hart_id| mask | 1 << hart_id);
+
+    if hart_mask_base == usize::MAX {
+        return Some(HartMask::from_mask_base(assigned_harts, 0));
+    }
+
+    let requested_harts = (0..usize::BITS as usize)
+        .filter(|bit| hart_mask >> bit & 1 == 1)
+        .map(|bit| hart_mask_base.checked_add(bit))
+        .try_fold(0, |mask, hart_id| match hart_id {
+            Some(hart_id) if hart_id < MAX_HART_NUM && assigned_harts >> hart_id & 1 == 1 => {
+                Some(mask | 1 << hart_id)
+            }
+            _ => None,
+        })?;
+    Some(HartMask::from_mask_base(requested_harts, 0))
+}
+
+/// Access the buffer of SBI call in guest memory.
+///
+/// Demand-paged guest memory is backed on the first access.
+/// Return `None` if the buffer is not accessible by the guest.
+fn access_guest_buffer<T>(
+    guest: &Guest,
+    access: impl Fn() -> Result<T, GuestAccessFault>,
+) -> Option<T> {
+    loop {
+        match access() {
+            Ok(value) => return Some(value),
+            Err(fault)
+                if fault.is_guest_page_fault()
+                    && guest.back_demand_page(GuestPhysicalAddress(fault.addr.0)) => {}
+            Err(_) => return None,
+        }
+    }
+}
+
 /// SBI ecall handler for Base Extension (EID: #0x10)
 ///
 /// All functions in the base extension must be supported by all SBI implementations,
@@ -34,21 +97,22 @@ pub fn sbi_base_handler(func_id: usize) -> SbiRet {
 }
 
 /// SBI ecall handler for RFENCE Extension (EID: #0x52464E43)
+///
+/// Only the harts that are assigned to the guest can be fenced. (See `guest_hart_mask`)
 #[allow(clippy::module_name_repetitions, clippy::cast_possible_truncation)]
 pub fn sbi_rfnc_handler(func_id: usize, args: &[u64; 5]) -> SbiRet {
-    use rustsbi::HartMask;
     use sbi_spec::rfnc::{REMOTE_FENCE_I, REMOTE_SFENCE_VMA, REMOTE_SFENCE_VMA_ASID};
+
+    let Some(hart_mask) = guest_hart_mask(args[0] as usize, args[1] as usize) else {
+        return SbiRet::invalid_param();
+    };
     match func_id {
-        REMOTE_FENCE_I => {
-            sbi_rt::remote_fence_i(HartMask::from_mask_base(args[0] as usize, args[1] as usize))
+        REMOTE_FENCE_I => sbi_rt::remote_fence_i(hart_mask),
+        REMOTE_SFENCE_VMA => {
+            sbi_rt::remote_sfence_vma(hart_mask, args[2] as usize, args[3] as usize)
         }
-        REMOTE_SFENCE_VMA => sbi_rt::remote_sfence_vma(
-            HartMask::from_mask_base(args[0] as usize, args[1] as usize),
-            args[2] as usize,
-            args[3] as usize,
-        ),
         REMOTE_SFENCE_VMA_ASID => sbi_rt::remote_sfence_vma_asid(
-            HartMask::from_mask_base(args[0] as usize, args[1] as usize),
+            hart_mask,
             args[2] as usize,
             args[3] as usize,
             args[4] as usize,
@@ -57,42 +121,173 @@ pub fn sbi_rfnc_handler(func_id: usize, args: &[u64; 5]) -> SbiRet {
     }
 }
 
-/// FWFT Feature
-/// Ref: [https://github.com/riscv-non-isa/riscv-sbi-doc/releases/download/vv3.0-rc1/riscv-sbi.pdf](https://github.com/riscv-non-isa/riscv-sbi-doc/releases/download/vv3.0-rc1/riscv-sbi.pdf) p.78
-#[derive(Debug)]
-enum FwftFeature {
-    /// Control misaligned access exception delegation to supervisor-mode if medeleg is present.
-    MisalignedExcDeleg,
-    /// Control landing pad support for supervisor-mode.
-    LandingPad,
-    /// Control shadow stack support for supervisor-mode.
-    ShadowStack,
-    /// Control double trap support for supervisor-mode.
-    DoubleTrap,
-    /// Control hardware updating of PTE A/D bits for supervisor-mode.
-    PteAdHwUpdating,
-    /// Control the pointer masking tag length for supervisor-mode.
-    PointerMaskingPmlen,
+/// SBI ecall handler for Timer Extension (EID #0x54494D45)
+///
+/// The timer of each vCPU shares HS-level timer with the scheduler. (See `guest::scheduler::arm_timer`)
+/// The timer interrupt is injected to the vCPU by `hvip` when the time is reached.
+#[allow(clippy::module_name_repetitions, clippy::cast_possible_truncation)]
+pub fn sbi_time_handler(func_id: usize, args: &[u64; 5]) -> SbiRet {
+    use sbi_spec::time::SET_TIMER;
+    match func_id {
+        SET_TIMER => {
+            let mut hypervisor_data = unsafe { HYPERVISOR_DATA.lock() };
+            let hypervisor_data = hypervisor_data.get_mut().unwrap();
+            let hart_id = current_hart_id();
+            hypervisor_data
+                .guest_by_hart_id_mut(hart_id)
+                .expect("guest data not found")
+                .set_timer(args[0] as usize);
+
+            // it clears the pending timer interrupt.
+            hvip::clear(VsInterruptKind::Timer);
+            scheduler::arm_timer(hypervisor_data, hart_id);
+            SbiRet::success(0)
+        }
+        _ => SbiRet::not_supported(),
+    }
+}
+
+/// SBI ecall handler for Debug Console Extension (EID #0x4442434E)
+///
+/// Buffers are given by guest physical address and accessed by `memmap::guest_access`,
+/// so the guest can only pass its own memory.
+/// Input is taken from the guest's emulated UART. (See `device::uart`)
+#[allow(clippy::module_name_repetitions, clippy::cast_possible_truncation)]
+pub fn sbi_dbcn_handler(func_id: usize, args: &[u64; 5]) -> SbiRet {
+    use sbi_spec::dbcn::{CONSOLE_READ, CONSOLE_WRITE, CONSOLE_WRITE_BYTE};
+
+    let mut hypervisor_data = unsafe { HYPERVISOR_DATA.lock() };
+    let hypervisor_data = hypervisor_data.get_mut().unwrap();
+    let num_bytes = args[0] as usize;
+    let buffer = GuestPhysicalAddress(args[1] as usize);
+    // upper bits of the buffer address are always zero on RV64.
+    if func_id != CONSOLE_WRITE_BYTE && args[2] != 0 {
+        return SbiRet::invalid_param();
+    }
+
+    match func_id {
+        CONSOLE_WRITE => {
+            let guest = hypervisor_data.guest();
+            let Some(bytes) = (0..num_bytes)
+                .map(|offset| access_guest_buffer(guest, || read_guest_physical(buffer + offset)))
+                .collect::<Option<Vec<u8>>>()
+            else {
+                return SbiRet::invalid_param();
+            };
+
+            let uart = &hypervisor_data.devices().uart;
+            for byte in bytes {
+                uart.console_write(byte);
+            }
+            SbiRet::success(num_bytes)
+        }
+        CONSOLE_READ => {
+            let guest_id = hypervisor_data.guest().guest_id();
+            let uart = &mut hypervisor_data.devices().uart;
+            let input: Vec<u8> = core::iter::from_fn(|| uart.console_read(guest_id))
+                .take(num_bytes)
+                .collect();
+
+            let guest = hypervisor_data.guest();
+            for (offset, byte) in input.iter().enumerate() {
+                if access_guest_buffer(guest, || write_guest_physical(buffer + offset, *byte))
+                    .is_none()
+                {
+                    return SbiRet::invalid_param();
+                }
+            }
+            SbiRet::success(input.len())
+        }
+        CONSOLE_WRITE_BYTE => {
+            hypervisor_data.devices().uart.console_write(args[0] as u8);
+            SbiRet::success(0)
+        }
+        _ => SbiRet::not_supported(),
+    }
+}
+
+/// SBI ecall handler for IPI Extension (EID #0x735049)
+///
+/// Only the harts that are assigned to the guest can be interrupted. (See `guest_hart_mask`)
+#[allow(clippy::module_name_repetitions, clippy::cast_possible_truncation)]
+pub fn sbi_spi_handler(func_id: usize, args: &[u64; 5]) -> SbiRet {
+    use sbi_spec::spi::SEND_IPI;
+
+    let Some(hart_mask) = guest_hart_mask(args[0] as usize, args[1] as usize) else {
+        return SbiRet::invalid_param();
+    };
+    match func_id {
+        SEND_IPI => sbi_rt::send_ipi(hart_mask),
+        _ => panic!("unsupported fid: {}", func_id),
+    }
 }
 
-impl TryFrom<usize> for FwftFeature {
-    type Error = usize;
-    fn try_from(from: usize) -> Result<Self, Self::Error> {
-        match from {
-            0 => Ok(FwftFeature::MisalignedExcDeleg),
-            1 => Ok(FwftFeature::LandingPad),
-            2 => Ok(FwftFeature::ShadowStack),
-            3 => Ok(FwftFeature::DoubleTrap),
-            4 => Ok(FwftFeature::PteAdHwUpdating),
-            5 => Ok(FwftFeature::PointerMaskingPmlen),
-            _ => Err(from),
+/// SBI ecall handler for Hart State Management Extension (EID #0x48534D)
+///
+/// Each guest hart is mapped to the physical hart which has same hart id.
+/// The guest can only control harts that are assigned to itself.
+#[allow(clippy::module_name_repetitions, clippy::cast_possible_truncation)]
+pub fn sbi_hsm_handler(func_id: usize, args: &[u64; 5]) -> SbiRet {
+    use sbi_spec::hsm::{HART_GET_STATUS, HART_START, HART_STOP};
+
+    let mut hypervisor_data = unsafe { HYPERVISOR_DATA.lock() };
+    let guest_id = hypervisor_data.get().unwrap().guest().guest_id();
+    match func_id {
+        HART_START => {
+            // the hart does not exist or it is assigned to other guest.
+            let Some(guest) = hypervisor_data
+                .get_mut()
+                .unwrap()
+                .vcpu_guest_mut(guest_id, args[0] as usize)
+            else {
+                return SbiRet::invalid_param();
+            };
+
+            let start_addr = GuestPhysicalAddress(args[1] as usize);
+            if !guest.memory_region().contains(&start_addr) {
+                return SbiRet::invalid_address();
+            }
+
+            if guest.hart_state() == HartState::Stopped {
+                guest.set_hart_state(HartState::StartPending {
+                    start_addr,
+                    opaque: args[2] as usize,
+                });
+                SbiRet::success(0)
+            } else {
+                SbiRet::already_available()
+            }
+        }
+        HART_STOP => {
+            let hart_id = current_hart_id();
+            hypervisor_data
+                .get_mut()
+                .unwrap()
+                .guest_by_hart_id_mut(hart_id)
+                .expect("guest data not found")
+                .set_hart_state(HartState::Stopped);
+
+            // release HYPERVISOR_DATA lock
+            drop(hypervisor_data);
+
+            // never return to the caller.
+            wait_for_hart_start(hart_id);
         }
+        HART_GET_STATUS => hypervisor_data
+            .get()
+            .unwrap()
+            .vcpu_guest(guest_id, args[0] as usize)
+            .map_or(SbiRet::invalid_param(), |guest| {
+                SbiRet::success(guest.hart_state().state_id())
+            }),
+        _ => SbiRet::not_supported(),
     }
 }
 
 /// SBI ecall handler for Firmware Features Extension (EID #0x46574654)
 ///
 /// FWFT ecall will be emulated because `sbi_rt` is not supported.
+/// Features are set for the calling vCPU.
 #[allow(clippy::cast_possible_truncation)]
 pub fn sbi_fwft_handler(func_id: usize, args: &[u64; 5]) -> SbiRet {
     /// Firmware Features Set (FID #0)
@@ -101,20 +296,24 @@ pub fn sbi_fwft_handler(func_id: usize, args: &[u64; 5]) -> SbiRet {
     const FWFT_GET: usize = 1;
 
     let feature = args[0] as usize;
+    let mut hypervisor_data = unsafe { HYPERVISOR_DATA.lock() };
+    let guest = hypervisor_data
+        .get_mut()
+        .unwrap()
+        .guest_by_hart_id_mut(current_hart_id())
+        .expect("guest data not found");
 
     match func_id {
         FWFT_SET => match FwftFeature::try_from(feature).unwrap() {
             FwftFeature::ShadowStack => {
-                // hypervisor does not use shadow stack.
+                // shadow stack of VS-mode is emulated by hikami. (See `emulate_extension::zicfiss`)
+                guest.zicfiss.henv_sse = args[1] & 0x1 == 1;
                 SbiRet::success(0)
             }
             feat => unimplemented!("unimplemented feature {:?}", feat),
         },
         FWFT_GET => match FwftFeature::try_from(feature).unwrap() {
-            FwftFeature::ShadowStack => {
-                // hypervisor does not use shadow stack.
-                SbiRet::success(0)
-            }
+            FwftFeature::ShadowStack => SbiRet::success(usize::from(guest.zicfiss.henv_sse)),
             feat => unimplemented!("unimplemented feature {:?}", feat),
         },
         _ => unreachable!(),
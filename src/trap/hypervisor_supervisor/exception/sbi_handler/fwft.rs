@@ -0,0 +1,67 @@
+//! FWFT: Firmware Features extension
+//!
+//! It does not depend on hardware, so it can be tested on host.
+
+/// FWFT Feature
+/// Ref: [https://github.com/riscv-non-isa/riscv-sbi-doc/releases/download/vv3.0-rc1/riscv-sbi.pdf](https://github.com/riscv-non-isa/riscv-sbi-doc/releases/download/vv3.0-rc1/riscv-sbi.pdf) p.78
+#[derive(Debug, PartialEq, Eq)]
+#[allow(clippy::module_name_repetitions)]
+pub enum FwftFeature {
+    /// Control misaligned access exception delegation to supervisor-mode if medeleg is present.
+    MisalignedExcDeleg,
+    /// Control landing pad support for supervisor-mode.
+    LandingPad,
+    /// Control shadow stack support for supervisor-mode.
+    ShadowStack,
+    /// Control double trap support for supervisor-mode.
+    DoubleTrap,
+    /// Control hardware updating of PTE A/D bits for supervisor-mode.
+    PteAdHwUpdating,
+    /// Control the pointer masking tag length for supervisor-mode.
+    PointerMaskingPmlen,
+}
+
+impl TryFrom<usize> for FwftFeature {
+    type Error = usize;
+    fn try_from(from: usize) -> Result<Self, Self::Error> {
+        match from {
+            0 => Ok(FwftFeature::MisalignedExcDeleg),
+            1 => Ok(FwftFeature::LandingPad),
+            2 => Ok(FwftFeature::ShadowStack),
+            3 => Ok(FwftFeature::DoubleTrap),
+            4 => Ok(FwftFeature::PteAdHwUpdating),
+            5 => Ok(FwftFeature::PointerMaskingPmlen),
+            _ => Err(from),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feature IDs defined by the spec are converted to `FwftFeature`.
+    #[test]
+    fn try_from_feature_id() {
+        assert_eq!(
+            FwftFeature::try_from(0),
+            Ok(FwftFeature::MisalignedExcDeleg)
+        );
+        assert_eq!(FwftFeature::try_from(1), Ok(FwftFeature::LandingPad));
+        assert_eq!(FwftFeature::try_from(2), Ok(FwftFeature::ShadowStack));
+        assert_eq!(FwftFeature::try_from(3), Ok(FwftFeature::DoubleTrap));
+        assert_eq!(FwftFeature::try_from(4), Ok(FwftFeature::PteAdHwUpdating));
+        assert_eq!(
+            FwftFeature::try_from(5),
+            Ok(FwftFeature::PointerMaskingPmlen)
+        );
+    }
+
+    /// Reserved and platform-specific feature IDs are returned as error.
+    #[test]
+    fn try_from_unknown_feature_id() {
+        assert_eq!(FwftFeature::try_from(6), Err(6));
+        assert_eq!(FwftFeature::try_from(0x4000_0000), Err(0x4000_0000));
+        assert_eq!(FwftFeature::try_from(0x8000_0000), Err(0x8000_0000));
+    }
+}
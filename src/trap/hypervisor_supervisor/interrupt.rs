@@ -1,13 +1,13 @@
 //! Trap VS-mode interrupt.
 
 use super::hstrap_exit;
-use crate::device::plic::ContextId;
 use crate::device::MmioDevice;
+use crate::guest::scheduler;
 use crate::h_extension::csrs::{hvip, vsip, VsInterruptKind};
-use crate::HYPERVISOR_DATA;
+use crate::{current_hart_id, HYPERVISOR_DATA};
 
 use riscv::register::scause::Interrupt;
-use riscv::register::sie;
+use riscv::register::time;
 
 /// Trap handler for Interrupt
 #[allow(clippy::module_name_repetitions)]
@@ -20,27 +20,42 @@ pub unsafe fn trap_interrupt(interrupt_cause: Interrupt) -> ! {
             let clint_addr = hypervisor_data.get().unwrap().devices.clint.paddr();
 
             vsip::set_ssoft();
-            let interrupt_addr = (clint_addr.raw() + hart_id * 4) as *mut u64;
+            let interrupt_addr = (clint_addr.raw() + hart_id * 4) as *mut u32;
             interrupt_addr.write_volatile(0);
         }
         Interrupt::SupervisorTimer => {
-            hvip::set(VsInterruptKind::Timer);
-            sie::clear_stimer();
+            let mut hypervisor_data = HYPERVISOR_DATA.lock();
+            let hypervisor_data = hypervisor_data.get_mut().unwrap();
+            let hart_id = current_hart_id();
+
+            // timer of the running vCPU that is set by SBI `set_timer`.
+            let guest = hypervisor_data
+                .guest_by_hart_id_mut(hart_id)
+                .expect("guest data not found");
+            if guest.timer() <= time::read() {
+                hvip::set(VsInterruptKind::Timer);
+                guest.set_timer(usize::MAX);
+            }
+
+            // scheduler tick
+            if hypervisor_data.schedulers[hart_id].is_slice_expired() {
+                scheduler::switch_vcpu(hypervisor_data, hart_id);
+                hypervisor_data.schedulers[hart_id].start_slice();
+            }
+            scheduler::arm_timer(hypervisor_data, hart_id);
         }
         Interrupt::SupervisorExternal => {
             let mut hypervisor_data = HYPERVISOR_DATA.lock();
-            let hart_id = hypervisor_data.get().unwrap().guest().hart_id();
-            let context_id = ContextId::new(hart_id, true);
-
-            // read plic claim/update register and reflect to plic.claim_complete.
-            hypervisor_data
-                .get_mut()
-                .unwrap()
-                .devices()
-                .plic
-                .update_claim_complete(&context_id);
+            let hypervisor_data = hypervisor_data.get_mut().unwrap();
+            let hart_id = current_hart_id();
+            let guest_id = hypervisor_data.guest().guest_id();
 
-            hvip::set(VsInterruptKind::External);
+            // claim physical IRQ and make it pending on the owner's virtual PLIC.
+            // The IRQ of the guest that is not running is injected when the guest is switched in.
+            // (APLIC delivers interrupts to guest interrupt files, so they do not reach here.)
+            let devices = hypervisor_data.devices();
+            devices.handle_external_interrupt(hart_id);
+            devices.update_external_interrupt(guest_id, hart_id);
         }
         Interrupt::Unknown => panic!("unknown interrupt type"),
     }
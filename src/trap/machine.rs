@@ -15,10 +15,16 @@ use riscv::register::mcause::{self, Trap};
 unsafe fn mtrap_exit() -> ! {
     asm!(
         "
-        // set to stack top
+        // set to stack top (_top_m_stack + STACK_SIZE_PER_HART * mhartid)
     1:
         auipc sp, %pcrel_hi(_top_m_stack)
         addi sp, sp, %pcrel_lo(1b)
+        csrr t0, mhartid
+        // t1 = _hart_stack_size (= STACK_SIZE_PER_HART, defined in `memory.x`)
+        lui t1, %hi(_hart_stack_size)
+        addi t1, t1, %lo(_hart_stack_size)
+        mul t0, t0, t1
+        add sp, sp, t0
         addi sp, sp, -256
         
         ld ra, 1*8(sp)
@@ -72,10 +78,16 @@ unsafe fn mtrap_exit() -> ! {
 #[allow(clippy::inline_always)]
 unsafe fn mtrap_exit_sbi(error: usize, value: usize) -> ! {
     asm!("
-        // set to stack top
+        // set to stack top (_top_m_stack + STACK_SIZE_PER_HART * mhartid)
     1:
         auipc sp, %pcrel_hi(_top_m_stack)
         addi sp, sp, %pcrel_lo(1b)
+        csrr t0, mhartid
+        // t1 = _hart_stack_size (= STACK_SIZE_PER_HART, defined in `memory.x`)
+        lui t1, %hi(_hart_stack_size)
+        addi t1, t1, %lo(_hart_stack_size)
+        mul t0, t0, t1
+        add sp, sp, t0
         addi sp, sp, -256
 
         ld ra, 1*8(sp)
@@ -86,8 +98,8 @@ unsafe fn mtrap_exit_sbi(error: usize, value: usize) -> ! {
         ld t2, 7*8(sp)
         ld s0, 8*8(sp)
         ld s1, 9*8(sp)
-        mv a0, {error}
-        mv a1, {value}
+        // a0 -> error
+        // a1 -> value
         ld a2, 12*8(sp)
         ld a3, 13*8(sp)
         ld a4, 14*8(sp)
@@ -109,7 +121,7 @@ unsafe fn mtrap_exit_sbi(error: usize, value: usize) -> ! {
         ld t5, 30*8(sp)
         ld t6, 31*8(sp)
 
-        // revert stack pointer to top (0x80800000)
+        // revert stack pointer to top
         addi sp, sp, 256
 
         // swap current sp for stored original mode sp
@@ -117,8 +129,8 @@ unsafe fn mtrap_exit_sbi(error: usize, value: usize) -> ! {
 
         mret
         ",
-        error = in(reg) error,
-        value = in(reg) value,
+        in("a0") error,
+        in("a1") value,
         options(noreturn),
     );
 }
@@ -164,6 +176,9 @@ pub unsafe extern "C" fn mtrap_vector() -> ! {
         sd t4, 29*8(sp)
         sd t5, 30*8(sp)
         sd t6, 31*8(sp)
+
+        // set hart id to tp
+        csrr tp, mhartid
         ",
     );
 
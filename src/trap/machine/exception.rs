@@ -1,7 +1,6 @@
 //! Trap machine exception.
 
 use super::{mtrap_exit, mtrap_exit_sbi};
-use crate::device::MmioDevice;
 use crate::print;
 use crate::{HYPERVISOR_DATA, SBI};
 use riscv::register::{
@@ -41,13 +40,10 @@ pub unsafe fn trap_envcall(a0: usize, a1: usize, a2: usize, a6: usize, a7: usize
             }
             // Console Getchar (EID #0x02)
             legacy::LEGACY_CONSOLE_GETCHAR => {
-                let uart_addr = sbi_data.uart.paddr().raw() as *mut u32;
-                let uart_lsr_addr = sbi_data.uart.lsr_addr().raw() as *mut u32;
-
-                while uart_lsr_addr.read_volatile() & 0x1 == 0 {}
-                let c = uart_addr.read_volatile() as u8;
+                // return -1 if no character is received.
+                let c = sbi_data.uart.get_char().map_or(usize::MAX, usize::from);
                 drop(sbi_cell);
-                mtrap_exit_sbi(0, c.into())
+                mtrap_exit_sbi(0, c)
             }
             _ => panic!(
                 "SBI call failed: error:{}, eid:{a7}, fid:{a6}",
@@ -2,6 +2,7 @@
 
 use super::mtrap_exit;
 use crate::memmap::constant::device::{CLINT_ADDR, MTIMECMP_ADDR};
+use crate::sbi::rfence::handle_remote_fence_request;
 use riscv::register::mcause::Interrupt;
 use riscv::register::{mhartid, mip};
 
@@ -10,8 +11,9 @@ use riscv::register::{mhartid, mip};
 pub unsafe fn trap_interrupt(interrupt_cause: Interrupt) -> ! {
     match interrupt_cause {
         Interrupt::MachineSoft => {
+            handle_remote_fence_request();
             mip::set_ssoft();
-            let interrupt_addr = (CLINT_ADDR.raw() + mhartid::read() * 4) as *mut u64;
+            let interrupt_addr = (CLINT_ADDR.raw() + mhartid::read() * 4) as *mut u32;
             interrupt_addr.write_volatile(0);
         }
         Interrupt::MachineTimer => {
@@ -0,0 +1,48 @@
+/* Test guests are linked at the default base address of guest memory of the primary guest (guest 1).
+ * hikami loads PT_LOAD segments at `p_paddr` relative to guest memory base, so LMA starts from 0. */
+MEMORY
+{
+    GUEST_RAM : ORIGIN = 0x90000000, LENGTH = 16M
+    LOAD      : ORIGIN = 0x00000000, LENGTH = 16M
+}
+
+ENTRY(_start)
+
+SECTIONS
+{
+    .text : ALIGN(4096)
+    {
+        KEEP(*(.text.entry))
+        *(.text .text.*)
+    } > GUEST_RAM AT> LOAD
+
+    .rodata : ALIGN(4096)
+    {
+        *(.rodata .rodata.*)
+        *(.srodata .srodata.*)
+    } > GUEST_RAM AT> LOAD
+
+    .data : ALIGN(4096)
+    {
+        *(.data .data.*)
+        *(.sdata .sdata.*)
+    } > GUEST_RAM AT> LOAD
+
+    .bss (NOLOAD) : ALIGN(4096)
+    {
+        _start_bss = .;
+        *(.bss .bss.*)
+        *(.sbss .sbss.*)
+        . = ALIGN(8);
+        _end_bss = .;
+
+        . = ALIGN(4096);
+        . += 0x4000;
+        _stack_top = .;
+    } > GUEST_RAM AT> LOAD
+
+    /DISCARD/ :
+    {
+        *(.eh_frame .eh_frame_hdr)
+    }
+}
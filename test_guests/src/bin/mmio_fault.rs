@@ -0,0 +1,266 @@
+//! Accesses to emulated devices and addresses that are not mapped to the guest.
+//!
+//! The accesses to emulated devices are decoded and emulated by hikami,
+//! and the others are forwarded to the guest as access faults. (not as guest-page faults)
+//!
+//! - UART: 8 bit registers. Wider accesses use the lowest byte.
+//! - PLIC: 32 bit registers. The other widths are forwarded as access faults.
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use hikami_test_guests::{
+    ensure, entry, fail, set_trap_handler, Failure, Trap, TrapFrame, PLIC_BASE, UART_BASE, UART_IRQ,
+};
+
+/// Address that is not mapped to the guest. (flash of QEMU virt machine)
+const UNMAPPED_ADDR: usize = 0x2000_0000;
+/// SCR: Scratch Register of UART.
+const UART_SCR: usize = UART_BASE + 7;
+/// Priority register of the IRQ of UART.
+const PRIORITY: usize = PLIC_BASE + 4 * UART_IRQ;
+
+/// Instruction access fault.
+const INSTRUCTION_ACCESS_FAULT: usize = 1;
+/// Load access fault.
+const LOAD_ACCESS_FAULT: usize = 5;
+/// Store/AMO access fault.
+const STORE_ACCESS_FAULT: usize = 7;
+
+/// `scause` of the exception. (0 if no exception is taken)
+static EXCEPTION_CAUSE: AtomicUsize = AtomicUsize::new(0);
+/// `stval` of the exception.
+static EXCEPTION_VALUE: AtomicUsize = AtomicUsize::new(0);
+/// `sepc` of the exception.
+static EXCEPTION_PC: AtomicUsize = AtomicUsize::new(0);
+
+entry!(main);
+
+/// Record the exception and skip the instruction.
+fn exception_handler(frame: &mut TrapFrame, trap: Trap) {
+    let Some(cause) = trap.exception() else {
+        fail(Failure::UnexpectedTrap);
+    };
+    EXCEPTION_CAUSE.store(cause, Ordering::Relaxed);
+    EXCEPTION_VALUE.store(trap.stval, Ordering::Relaxed);
+    EXCEPTION_PC.store(frame.sepc, Ordering::Relaxed);
+
+    if cause == INSTRUCTION_ACCESS_FAULT {
+        // return to the caller. (see `unmapped`)
+        frame.sepc = frame.regs[0];
+        return;
+    }
+
+    // lowest 2 bits of uncompressed instructions are `11`.
+    let inst = unsafe { (frame.sepc as *const u16).read_volatile() };
+    frame.sepc += if inst & 0b11 == 0b11 { 4 } else { 2 };
+}
+
+/// Execute the memory access instruction and return the address of the instruction.
+///
+/// The instruction is not compressed unless it is prefixed with `compressed`.
+macro_rules! access {
+    (@option $option:literal, $inst:literal, $($operands:tt)*) => {{
+        let pc: usize;
+        unsafe {
+            asm!(
+                ".option push",
+                concat!(".option ", $option),
+                "1:",
+                $inst,
+                ".option pop",
+                "la {pc}, 1b",
+                pc = out(reg) pc,
+                $($operands)*
+            );
+        }
+        pc
+    }};
+    (compressed $inst:literal, $($operands:tt)*) => {
+        access!(@option "rvc", $inst, $($operands)*)
+    };
+    ($inst:literal, $($operands:tt)*) => {
+        access!(@option "norvc", $inst, $($operands)*)
+    };
+}
+
+/// Take the recorded exception.
+fn take_exception() -> Option<(usize, usize, usize)> {
+    let cause = EXCEPTION_CAUSE.swap(0, Ordering::Relaxed);
+    (cause != 0).then(|| {
+        (
+            cause,
+            EXCEPTION_VALUE.load(Ordering::Relaxed),
+            EXCEPTION_PC.load(Ordering::Relaxed),
+        )
+    })
+}
+
+/// Access UART by all widths.
+fn uart() -> Result<(), Failure> {
+    /// LSR: Line Status Register.
+    const UART_LSR: usize = UART_BASE + 5;
+    /// LSR: Transmitter Holding Register Empty.
+    const LSR_THRE: u8 = 1 << 5;
+
+    let lsr = unsafe { (UART_LSR as *const u8).read_volatile() };
+    ensure!(take_exception().is_none(), "exception on reading UART");
+    ensure!(
+        lsr & LSR_THRE != 0,
+        "THR of UART is not empty: LSR {lsr:#x}"
+    );
+
+    // halfword store writes the lowest byte.
+    access!("sh {value}, 0({addr})", addr = in(reg) UART_SCR, value = in(reg) 0x12a5);
+    let (byte, byte_unsigned, half, word, double): (usize, usize, usize, usize, usize);
+    access!("lb {value}, 0({addr})", addr = in(reg) UART_SCR, value = out(reg) byte);
+    access!("lbu {value}, 0({addr})", addr = in(reg) UART_SCR, value = out(reg) byte_unsigned);
+    access!("lh {value}, 0({addr})", addr = in(reg) UART_SCR, value = out(reg) half);
+    access!("lw {value}, 0({addr})", addr = in(reg) UART_SCR, value = out(reg) word);
+    access!("ld {value}, 0({addr})", addr = in(reg) UART_SCR, value = out(reg) double);
+    ensure!(take_exception().is_none(), "exception on accessing UART");
+    ensure!(
+        byte == 0xffff_ffff_ffff_ffa5,
+        "lb is not sign-extended: {byte:#x}"
+    );
+    ensure!(byte_unsigned == 0xa5, "lbu: {byte_unsigned:#x}");
+    ensure!(
+        half == 0xa5 && word == 0xa5 && double == 0xa5,
+        "wider loads: {half:#x}, {word:#x}, {double:#x}"
+    );
+
+    access!("sd {value}, 0({addr})", addr = in(reg) UART_SCR, value = in(reg) 0x5a);
+    let scr = unsafe { (UART_SCR as *const u8).read_volatile() };
+    ensure!(take_exception().is_none(), "exception on accessing UART");
+    ensure!(scr == 0x5a, "scratch register of UART is {scr:#x}");
+
+    Ok(())
+}
+
+/// Access PLIC by 32 bit loads/stores, compressed ones and AMOs.
+fn plic() -> Result<(), Failure> {
+    let (word, word_unsigned, compressed): (usize, usize, usize);
+    access!("sw {value}, 0({addr})", addr = in(reg) PRIORITY, value = in(reg) 0x8000_0001_usize);
+    access!("lw {value}, 0({addr})", addr = in(reg) PRIORITY, value = out(reg) word);
+    access!("lwu {value}, 0({addr})", addr = in(reg) PRIORITY, value = out(reg) word_unsigned);
+    access!(compressed "c.sw a1, 0(a0)", in("a0") PRIORITY, in("a1") 3);
+    access!(compressed "c.lw a1, 0(a0)", in("a0") PRIORITY, out("a1") compressed);
+    ensure!(take_exception().is_none(), "exception on accessing PLIC");
+    ensure!(
+        word == 0xffff_ffff_8000_0001,
+        "lw is not sign-extended: {word:#x}"
+    );
+    ensure!(word_unsigned == 0x8000_0001, "lwu: {word_unsigned:#x}");
+    ensure!(compressed == 3, "c.lw/c.sw: {compressed:#x}");
+
+    // AMOs return the old value and store the result.
+    let (add, or, swap, max, reserved, failed): (usize, usize, usize, usize, usize, usize);
+    access!(
+        "amoadd.w {old}, {src}, ({addr})",
+        addr = in(reg) PRIORITY, src = in(reg) 2, old = out(reg) add
+    );
+    access!(
+        "amoor.w {old}, {src}, ({addr})",
+        addr = in(reg) PRIORITY, src = in(reg) 8, old = out(reg) or
+    );
+    access!(
+        "amoswap.w {old}, {src}, ({addr})",
+        addr = in(reg) PRIORITY, src = in(reg) 0x8000_0000_usize, old = out(reg) swap
+    );
+    access!(
+        "amomax.w {old}, {src}, ({addr})",
+        addr = in(reg) PRIORITY, src = in(reg) 7, old = out(reg) max
+    );
+    access!("lr.w {old}, ({addr})", addr = in(reg) PRIORITY, old = out(reg) reserved);
+    access!(
+        "sc.w {result}, {src}, ({addr})",
+        addr = in(reg) PRIORITY, src = in(reg) 0, result = out(reg) failed
+    );
+    let priority = unsafe { (PRIORITY as *const u32).read_volatile() };
+    ensure!(take_exception().is_none(), "exception on AMO to PLIC");
+    ensure!(
+        add == 3 && or == 5 && swap == 13,
+        "amoadd/amoor/amoswap: {add:#x}, {or:#x}, {swap:#x}"
+    );
+    ensure!(
+        max == 0xffff_ffff_8000_0000,
+        "amomax is not sign-extended: {max:#x}"
+    );
+    ensure!(
+        reserved == 7 && failed == 0,
+        "lr/sc: {reserved:#x}, {failed}"
+    );
+    ensure!(priority == 0, "priority after sc: {priority:#x}");
+
+    // registers of PLIC are 32 bit wide.
+    let pc = access!("lh {value}, 0({addr})", addr = in(reg) PRIORITY, value = out(reg) _);
+    let exception = take_exception();
+    ensure!(
+        exception == Some((LOAD_ACCESS_FAULT, PRIORITY, pc)),
+        "halfword load from PLIC: {exception:x?}, pc: {pc:#x}"
+    );
+    let pc = access!("sd {value}, 0({addr})", addr = in(reg) PRIORITY, value = in(reg) 1);
+    let exception = take_exception();
+    ensure!(
+        exception == Some((STORE_ACCESS_FAULT, PRIORITY, pc)),
+        "doubleword store to PLIC: {exception:x?}, pc: {pc:#x}"
+    );
+
+    Ok(())
+}
+
+/// Access the address that is not mapped to the guest.
+fn unmapped() -> Result<(), Failure> {
+    let pc = access!("lw {value}, 0({addr})", addr = in(reg) UNMAPPED_ADDR, value = out(reg) _);
+    let exception = take_exception();
+    ensure!(
+        exception == Some((LOAD_ACCESS_FAULT, UNMAPPED_ADDR, pc)),
+        "load from unmapped address: {exception:x?}, pc: {pc:#x}"
+    );
+
+    let pc = access!(
+        "sw {value}, 0({addr})",
+        addr = in(reg) UNMAPPED_ADDR, value = in(reg) 0xdead_beef_usize
+    );
+    let exception = take_exception();
+    ensure!(
+        exception == Some((STORE_ACCESS_FAULT, UNMAPPED_ADDR, pc)),
+        "store to unmapped address: {exception:x?}, pc: {pc:#x}"
+    );
+
+    let pc = access!(compressed "c.lw a1, 0(a0)", in("a0") UNMAPPED_ADDR, out("a1") _);
+    let exception = take_exception();
+    ensure!(
+        exception == Some((LOAD_ACCESS_FAULT, UNMAPPED_ADDR, pc)),
+        "compressed load from unmapped address: {exception:x?}, pc: {pc:#x}"
+    );
+
+    let pc = access!(
+        "amoadd.w {old}, {src}, ({addr})",
+        addr = in(reg) UNMAPPED_ADDR, src = in(reg) 1, old = out(reg) _
+    );
+    let exception = take_exception();
+    ensure!(
+        exception == Some((STORE_ACCESS_FAULT, UNMAPPED_ADDR, pc)),
+        "AMO to unmapped address: {exception:x?}, pc: {pc:#x}"
+    );
+
+    access!("jalr {addr}", addr = in(reg) UNMAPPED_ADDR, out("ra") _);
+    let exception = take_exception();
+    ensure!(
+        exception == Some((INSTRUCTION_ACCESS_FAULT, UNMAPPED_ADDR, UNMAPPED_ADDR)),
+        "jump to unmapped address: {exception:x?}"
+    );
+
+    Ok(())
+}
+
+/// Access emulated UART, PLIC and unmapped address.
+fn main() -> Result<(), Failure> {
+    set_trap_handler(exception_handler);
+
+    uart()?;
+    plic()?;
+    unmapped()
+}
@@ -0,0 +1,107 @@
+//! Claim and complete the interrupt of emulated UART through the virtual PLIC.
+//!
+//! Enabling THR empty interrupt of emulated UART raises the IRQ on the virtual PLIC immediately.
+#![no_std]
+#![no_main]
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use hikami_test_guests::{
+    disable_interrupts, enable_interrupts, ensure, entry, fail, set_trap_handler, wait_until,
+    Failure, Trap, TrapFrame, PLIC_BASE, UART_BASE, UART_IRQ,
+};
+
+/// Supervisor external interrupt.
+const SUPERVISOR_EXTERNAL_INTERRUPT: usize = 9;
+/// SEIE bit of `sie`.
+const SIE_SEIE: usize = 1 << SUPERVISOR_EXTERNAL_INTERRUPT;
+
+/// Supervisor context of hart 0.
+const CONTEXT: usize = 1;
+/// Priority register of the IRQ.
+const PRIORITY: usize = PLIC_BASE + 4 * UART_IRQ;
+/// Enable register that contains the IRQ.
+const ENABLE: usize = PLIC_BASE + 0x2000 + 0x80 * CONTEXT + UART_IRQ / 32 * 4;
+/// Threshold register.
+const THRESHOLD: usize = PLIC_BASE + 0x20_0000 + 0x1000 * CONTEXT;
+/// Claim/complete register.
+const CLAIM: usize = THRESHOLD + 4;
+
+/// IER: Interrupt Enable Register.
+const UART_IER: usize = UART_BASE + 1;
+/// IIR: Interrupt Identification Register.
+const UART_IIR: usize = UART_BASE + 2;
+/// IER: Enable Transmitter Holding Register Empty Interrupt.
+const IER_ETBEI: u8 = 1 << 1;
+
+/// IRQ that is claimed in the handler. (0 if no interrupt is taken)
+static CLAIMED_IRQ: AtomicUsize = AtomicUsize::new(0);
+
+entry!(main);
+
+/// Read PLIC register.
+fn plic_read(addr: usize) -> u32 {
+    unsafe { (addr as *const u32).read_volatile() }
+}
+
+/// Write PLIC register.
+fn plic_write(addr: usize, value: u32) {
+    unsafe { (addr as *mut u32).write_volatile(value) }
+}
+
+/// Claim the IRQ, clear the interrupt of UART and complete it.
+fn external_interrupt_handler(_frame: &mut TrapFrame, trap: Trap) {
+    if trap.interrupt() != Some(SUPERVISOR_EXTERNAL_INTERRUPT) {
+        fail(Failure::UnexpectedTrap);
+    }
+
+    let irq = plic_read(CLAIM);
+    CLAIMED_IRQ.store(irq as usize, Ordering::Relaxed);
+    unsafe {
+        // reading IIR clears THR empty interrupt.
+        (UART_IIR as *const u8).read_volatile();
+        (UART_IER as *mut u8).write_volatile(0);
+    }
+    plic_write(CLAIM, irq);
+}
+
+/// Raise the IRQ of UART and handle it.
+fn main() -> Result<(), Failure> {
+    set_trap_handler(external_interrupt_handler);
+
+    plic_write(PRIORITY, 1);
+    ensure!(plic_read(PRIORITY) == 1, "priority is not written");
+    plic_write(ENABLE, plic_read(ENABLE) | 1 << (UART_IRQ % 32));
+    plic_write(THRESHOLD, 0);
+    enable_interrupts(SIE_SEIE);
+
+    unsafe {
+        (UART_IER as *mut u8).write_volatile(IER_ETBEI);
+    }
+    let interrupted = wait_until(1000, || CLAIMED_IRQ.load(Ordering::Relaxed) != 0);
+    disable_interrupts();
+
+    ensure!(interrupted, "external interrupt is not taken");
+    let irq = CLAIMED_IRQ.load(Ordering::Relaxed);
+    ensure!(irq == UART_IRQ, "claimed IRQ is {irq}");
+    ensure!(plic_read(CLAIM) == 0, "IRQ is pending after completion");
+
+    // threshold masks the IRQ whose priority is not greater than it.
+    plic_write(THRESHOLD, 1);
+    unsafe {
+        (UART_IER as *mut u8).write_volatile(IER_ETBEI);
+    }
+    ensure!(plic_read(CLAIM) == 0, "IRQ is not masked by threshold");
+
+    // claim by polling without interrupt.
+    plic_write(THRESHOLD, 0);
+    let irq = plic_read(CLAIM);
+    ensure!(irq as usize == UART_IRQ, "claimed IRQ by polling is {irq}");
+    unsafe {
+        (UART_IIR as *const u8).read_volatile();
+        (UART_IER as *mut u8).write_volatile(0);
+    }
+    plic_write(CLAIM, irq);
+    ensure!(plic_read(CLAIM) == 0, "IRQ is pending after completion");
+
+    Ok(())
+}
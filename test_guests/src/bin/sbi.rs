@@ -0,0 +1,91 @@
+//! SBI calls from VS-mode that are handled by hikami.
+#![no_std]
+#![no_main]
+
+use hikami_test_guests::{ensure, entry, sbi_call, Failure};
+
+/// Extension ID of Base extension.
+const EID_BASE: usize = 0x10;
+/// Extension ID of HSM extension.
+const EID_HSM: usize = 0x48_534d;
+/// Extension ID of FWFT extension.
+const EID_FWFT: usize = 0x4657_4654;
+
+/// SBI error: invalid parameter.
+const SBI_ERR_INVALID_PARAM: isize = -3;
+/// SBI error: invalid address.
+const SBI_ERR_INVALID_ADDRESS: isize = -5;
+
+entry!(main);
+
+/// Call the functions of Base, HSM and FWFT extensions.
+fn main() -> Result<(), Failure> {
+    /// `sbi_get_spec_version`
+    const GET_SBI_SPEC_VERSION: usize = 0;
+    /// `sbi_probe_extension`
+    const PROBE_EXTENSION: usize = 3;
+    /// `sbi_get_mvendorid`
+    const GET_MVENDORID: usize = 4;
+    /// `sbi_hart_start`
+    const HART_START: usize = 0;
+    /// `sbi_hart_get_status`
+    const HART_GET_STATUS: usize = 2;
+    /// `sbi_fwft_get`
+    const FWFT_GET: usize = 1;
+    /// Feature ID of shadow stack.
+    const FWFT_SHADOW_STACK: usize = 2;
+
+    let spec_version = sbi_call(EID_BASE, GET_SBI_SPEC_VERSION, [0; 3]);
+    ensure!(
+        spec_version.error == 0,
+        "get_spec_version: {spec_version:?}"
+    );
+    ensure!(
+        spec_version.value >= 2 << 24,
+        "SBI spec version is older than v2.0: {:#x}",
+        spec_version.value
+    );
+
+    let probe = sbi_call(EID_BASE, PROBE_EXTENSION, [EID_BASE, 0, 0]);
+    ensure!(
+        probe.error == 0 && probe.value != 0,
+        "probe_extension(base): {probe:?}"
+    );
+
+    let mvendorid = sbi_call(EID_BASE, GET_MVENDORID, [0; 3]);
+    ensure!(mvendorid.error == 0, "get_mvendorid: {mvendorid:?}");
+
+    // hart 0 is the boot hart of the primary guest.
+    let status = sbi_call(EID_HSM, HART_GET_STATUS, [0, 0, 0]);
+    ensure!(
+        status == hikami_test_guests::SbiRet { error: 0, value: 0 },
+        "hart_get_status(0): {status:?}"
+    );
+
+    let status = sbi_call(EID_HSM, HART_GET_STATUS, [usize::MAX, 0, 0]);
+    ensure!(
+        status.error == SBI_ERR_INVALID_PARAM,
+        "hart_get_status(invalid hart): {status:?}"
+    );
+
+    let start = sbi_call(EID_HSM, HART_START, [usize::MAX, 0x9000_0000, 0]);
+    ensure!(
+        start.error == SBI_ERR_INVALID_PARAM,
+        "hart_start(invalid hart): {start:?}"
+    );
+
+    // 0x1000 is outside of the guest memory.
+    let start = sbi_call(EID_HSM, HART_START, [0, 0x1000, 0]);
+    ensure!(
+        start.error == SBI_ERR_INVALID_ADDRESS,
+        "hart_start(invalid address): {start:?}"
+    );
+
+    let shadow_stack = sbi_call(EID_FWFT, FWFT_GET, [FWFT_SHADOW_STACK, 0, 0]);
+    ensure!(
+        shadow_stack.error == 0 && shadow_stack.value == 0,
+        "fwft_get(shadow stack) before it is enabled: {shadow_stack:?}"
+    );
+
+    Ok(())
+}
@@ -0,0 +1,86 @@
+//! Supervisor timer interrupt by `stimecmp`. (Sstc)
+//!
+//! `stimecmp` of VS-mode is `vstimecmp`, and the timer interrupt is delegated to the guest by `hideleg`.
+//! The timer that is set by SBI `set_timer` is emulated by hikami and injected through `hvip.VSTIP`.
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use hikami_test_guests::{
+    disable_interrupts, enable_interrupts, ensure, entry, fail, sbi_call, set_trap_handler, time,
+    wait_until, Failure, Trap, TrapFrame, TIMEBASE_FREQUENCY,
+};
+
+/// Supervisor timer interrupt.
+const SUPERVISOR_TIMER_INTERRUPT: usize = 5;
+/// STIE bit of `sie`.
+const SIE_STIE: usize = 1 << SUPERVISOR_TIMER_INTERRUPT;
+/// Extension ID of TIME extension.
+const EID_TIME: usize = 0x5449_4d45;
+/// `sbi_set_timer`
+const SET_TIMER: usize = 0;
+
+/// `time` when the timer interrupt is taken. (0 if it is not taken)
+static INTERRUPTED_AT: AtomicU64 = AtomicU64::new(0);
+
+entry!(main);
+
+/// Write `stimecmp`.
+fn set_timer(value: u64) {
+    unsafe {
+        // stimecmp (0x14d)
+        asm!("csrw 0x14d, {}", in(reg) value);
+    }
+}
+
+/// Set the timer by SBI `set_timer`.
+#[allow(clippy::cast_possible_truncation)]
+fn sbi_set_timer(value: u64) {
+    let ret = sbi_call(EID_TIME, SET_TIMER, [value as usize, 0, 0]);
+    if ret.error != 0 {
+        fail(Failure::Check);
+    }
+}
+
+/// Take the timer interrupt and stop both timers.
+fn timer_handler(_frame: &mut TrapFrame, trap: Trap) {
+    if trap.interrupt() != Some(SUPERVISOR_TIMER_INTERRUPT) {
+        fail(Failure::UnexpectedTrap);
+    }
+    INTERRUPTED_AT.store(time(), Ordering::Relaxed);
+    set_timer(u64::MAX);
+    sbi_set_timer(u64::MAX);
+}
+
+/// Set the timer 10 ms later by `arm` and wait for the interrupt.
+fn wait_timer_interrupt(arm: fn(u64)) -> Result<(), Failure> {
+    INTERRUPTED_AT.store(0, Ordering::Relaxed);
+    enable_interrupts(SIE_STIE);
+
+    let deadline = time() + TIMEBASE_FREQUENCY / 100;
+    arm(deadline);
+    let interrupted = wait_until(1000, || INTERRUPTED_AT.load(Ordering::Relaxed) != 0);
+    disable_interrupts();
+
+    ensure!(interrupted, "timer interrupt is not taken");
+    let interrupted_at = INTERRUPTED_AT.load(Ordering::Relaxed);
+    ensure!(
+        interrupted_at >= deadline,
+        "timer interrupt is taken before the deadline: {interrupted_at} < {deadline}"
+    );
+
+    Ok(())
+}
+
+/// Wait for the timer interrupt set by `stimecmp` and then by SBI `set_timer`.
+fn main() -> Result<(), Failure> {
+    set_trap_handler(timer_handler);
+    set_timer(u64::MAX);
+    sbi_set_timer(u64::MAX);
+
+    wait_timer_interrupt(set_timer)?;
+    wait_timer_interrupt(sbi_set_timer)?;
+
+    Ok(())
+}
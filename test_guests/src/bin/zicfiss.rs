@@ -0,0 +1,132 @@
+//! Shadow stack instructions of Zicfiss emulated by hikami.
+//!
+//! The instructions are encoded by `.4byte` because they are not supported by the assembler.
+//! Shadow stack of VS-mode is enabled by FWFT extension of SBI.
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use hikami_test_guests::{
+    ensure, entry, fail, sbi_call, set_trap_handler, Failure, Trap, TrapFrame,
+};
+
+/// Extension ID of FWFT extension.
+const EID_FWFT: usize = 0x4657_4654;
+/// `sbi_fwft_set`
+const FWFT_SET: usize = 0;
+/// `sbi_fwft_get`
+const FWFT_GET: usize = 1;
+/// Feature ID of shadow stack.
+const FWFT_SHADOW_STACK: usize = 2;
+
+/// Software check exception.
+const SOFTWARE_CHECK_EXCEPTION: usize = 18;
+/// `stval` of software check exception by shadow stack fault.
+const SHADOW_STACK_FAULT: usize = 3;
+
+/// Size of shadow stack. (in `usize`)
+const SHADOW_STACK_LEN: usize = 64;
+
+/// Shadow stack.
+static mut SHADOW_STACK: [usize; SHADOW_STACK_LEN] = [0; SHADOW_STACK_LEN];
+
+/// `scause` of the exception. (0 if no exception is taken)
+static EXCEPTION_CAUSE: AtomicUsize = AtomicUsize::new(0);
+/// `stval` of the exception.
+static EXCEPTION_VALUE: AtomicUsize = AtomicUsize::new(0);
+
+entry!(main);
+
+/// Record the exception and skip the instruction.
+fn exception_handler(frame: &mut TrapFrame, trap: Trap) {
+    let Some(cause) = trap.exception() else {
+        fail(Failure::UnexpectedTrap);
+    };
+    EXCEPTION_CAUSE.store(cause, Ordering::Relaxed);
+    EXCEPTION_VALUE.store(trap.stval, Ordering::Relaxed);
+    frame.sepc += 4;
+}
+
+/// Write `ssp`.
+fn write_ssp(value: usize) {
+    unsafe {
+        // ssp (0x11)
+        asm!("csrw 0x11, {}", in(reg) value);
+    }
+}
+
+/// `ssrdp a0`
+fn ssrdp() -> usize {
+    let ssp;
+    unsafe {
+        asm!(".4byte 0xcdc04573", out("a0") ssp);
+    }
+    ssp
+}
+
+/// `sspush t0`
+fn sspush(value: usize) {
+    unsafe {
+        asm!(".4byte 0xce504073", in("t0") value);
+    }
+}
+
+/// `sspopchk t0`
+fn sspopchk(expected: usize) {
+    unsafe {
+        asm!(".4byte 0xcdc2c073", in("t0") expected);
+    }
+}
+
+/// Push and pop the shadow stack.
+fn main() -> Result<(), Failure> {
+    set_trap_handler(exception_handler);
+
+    let ret = sbi_call(EID_FWFT, FWFT_SET, [FWFT_SHADOW_STACK, 1, 0]);
+    ensure!(ret.error == 0, "fwft_set(shadow stack): {ret:?}");
+    let ret = sbi_call(EID_FWFT, FWFT_GET, [FWFT_SHADOW_STACK, 0, 0]);
+    ensure!(
+        ret.error == 0 && ret.value == 1,
+        "fwft_get(shadow stack): {ret:?}"
+    );
+
+    let stack_top = unsafe { core::ptr::addr_of_mut!(SHADOW_STACK) as usize }
+        + SHADOW_STACK_LEN * core::mem::size_of::<usize>();
+    write_ssp(stack_top);
+    ensure!(ssrdp() == stack_top, "ssp is {:#x}", ssrdp());
+
+    sspush(0x1234);
+    ensure!(
+        ssrdp() == stack_top - core::mem::size_of::<usize>(),
+        "ssp after sspush is {:#x}",
+        ssrdp()
+    );
+    let pushed = unsafe { core::ptr::addr_of!(SHADOW_STACK[SHADOW_STACK_LEN - 1]).read_volatile() };
+    ensure!(pushed == 0x1234, "pushed value is {pushed:#x}");
+
+    sspopchk(0x1234);
+    ensure!(
+        EXCEPTION_CAUSE.load(Ordering::Relaxed) == 0,
+        "exception on sspopchk with the same value"
+    );
+    ensure!(ssrdp() == stack_top, "ssp after sspopchk is {:#x}", ssrdp());
+
+    sspush(0x1234);
+    sspopchk(0x5678);
+    let cause = EXCEPTION_CAUSE.load(Ordering::Relaxed);
+    let value = EXCEPTION_VALUE.load(Ordering::Relaxed);
+    ensure!(
+        cause == SOFTWARE_CHECK_EXCEPTION && value == SHADOW_STACK_FAULT,
+        "sspopchk with a different value: scause {cause}, stval {value}"
+    );
+
+    let ret = sbi_call(EID_FWFT, FWFT_SET, [FWFT_SHADOW_STACK, 0, 0]);
+    ensure!(ret.error == 0, "fwft_set(shadow stack): {ret:?}");
+    ensure!(
+        ssrdp() == 0,
+        "ssrdp returns ssp while shadow stack is disabled"
+    );
+
+    Ok(())
+}
@@ -0,0 +1,356 @@
+//! Runtime of bare-metal test guests for integration tests of hikami.
+//!
+//! Each binary in `src/bin` is a tiny guest that runs in VS-mode on QEMU virt machine.
+//! It is loaded as the primary guest through the ELF loader of hikami (`Guest::load_guest_elf`),
+//! and reports the result by the test finisher (`sifive,test0`) so that `xtask` can read it from the exit status of QEMU.
+//!
+//! | Exit status | Meaning                                    |
+//! |-------------|--------------------------------------------|
+//! | 0           | pass                                       |
+//! | 1           | a check failed (`ensure!`)                 |
+//! | 2           | unexpected trap                            |
+//! | 3           | panic in the test guest                    |
+//!
+//! Addresses of devices are fixed to the ones of QEMU virt machine.
+#![no_std]
+
+use core::arch::{asm, global_asm};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+
+/// Base address of test finisher.
+const TEST_FINISHER_BASE: usize = 0x10_0000;
+/// Value of test finisher: pass.
+const FINISHER_PASS: u32 = 0x5555;
+/// Value of test finisher: fail. (exit code is written in upper 16 bits)
+const FINISHER_FAIL: u32 = 0x3333;
+
+/// Base address of UART. (emulated by hikami)
+pub const UART_BASE: usize = 0x1000_0000;
+/// IRQ of UART.
+pub const UART_IRQ: usize = 10;
+/// Base address of PLIC. (emulated by hikami)
+pub const PLIC_BASE: usize = 0x0c00_0000;
+/// Frequency of `time` CSR.
+pub const TIMEBASE_FREQUENCY: u64 = 10_000_000;
+
+/// Cause of the failure that is reported to the test finisher.
+#[derive(Debug, Copy, Clone)]
+pub enum Failure {
+    /// A check failed.
+    Check = 1,
+    /// Unexpected trap.
+    UnexpectedTrap = 2,
+    /// Panic in the test guest.
+    Panic = 3,
+}
+
+/// Console on UART.
+pub struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        /// Transmitter holding register.
+        const THR: *mut u8 = UART_BASE as *mut u8;
+
+        for byte in s.bytes() {
+            // THR of emulated UART is always empty.
+            unsafe {
+                THR.write_volatile(byte);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Print to UART.
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {{
+        use core::fmt::Write;
+        let _ = write!($crate::Console, $($arg)*);
+    }};
+}
+
+/// Print to UART with a newline.
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+/// Return `Err(Failure::Check)` from the test with the message if the condition is false.
+#[macro_export]
+macro_rules! ensure {
+    ($cond:expr, $($arg:tt)*) => {
+        if !$cond {
+            $crate::println!("[{}:{}] check failed: {}", file!(), line!(), format_args!($($arg)*));
+            return Err($crate::Failure::Check);
+        }
+    };
+}
+
+/// Define the test function of the guest.
+///
+/// The function must be `fn() -> Result<(), Failure>`.
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        /// Entry of the test called by the runtime.
+        #[export_name = "test_main"]
+        fn __test_main() -> Result<(), $crate::Failure> {
+            $main()
+        }
+    };
+}
+
+extern "Rust" {
+    /// Test function defined by `entry!`.
+    fn test_main() -> Result<(), Failure>;
+}
+
+extern "C" {
+    /// Start of bss. (defined in `link.x`)
+    static mut _start_bss: u8;
+    /// End of bss. (defined in `link.x`)
+    static mut _end_bss: u8;
+}
+
+global_asm!(
+    r#"
+    .section .text.entry
+    .globl _start
+_start:
+    la sp, _stack_top
+    la t0, _trap_entry
+    csrw stvec, t0
+    call {start_rust}
+1:
+    j 1b
+
+    .text
+    .align 2
+_trap_entry:
+    addi sp, sp, -17 * 8
+    sd ra, 0 * 8(sp)
+    sd t0, 1 * 8(sp)
+    sd t1, 2 * 8(sp)
+    sd t2, 3 * 8(sp)
+    sd a0, 4 * 8(sp)
+    sd a1, 5 * 8(sp)
+    sd a2, 6 * 8(sp)
+    sd a3, 7 * 8(sp)
+    sd a4, 8 * 8(sp)
+    sd a5, 9 * 8(sp)
+    sd a6, 10 * 8(sp)
+    sd a7, 11 * 8(sp)
+    sd t3, 12 * 8(sp)
+    sd t4, 13 * 8(sp)
+    sd t5, 14 * 8(sp)
+    sd t6, 15 * 8(sp)
+    csrr t0, sepc
+    sd t0, 16 * 8(sp)
+
+    mv a0, sp
+    call {trap_handler}
+
+    ld t0, 16 * 8(sp)
+    csrw sepc, t0
+    ld ra, 0 * 8(sp)
+    ld t0, 1 * 8(sp)
+    ld t1, 2 * 8(sp)
+    ld t2, 3 * 8(sp)
+    ld a0, 4 * 8(sp)
+    ld a1, 5 * 8(sp)
+    ld a2, 6 * 8(sp)
+    ld a3, 7 * 8(sp)
+    ld a4, 8 * 8(sp)
+    ld a5, 9 * 8(sp)
+    ld a6, 10 * 8(sp)
+    ld a7, 11 * 8(sp)
+    ld t3, 12 * 8(sp)
+    ld t4, 13 * 8(sp)
+    ld t5, 14 * 8(sp)
+    ld t6, 15 * 8(sp)
+    addi sp, sp, 17 * 8
+    sret
+    "#,
+    start_rust = sym start_rust,
+    trap_handler = sym trap_handler,
+);
+
+/// Initialize bss, run the test and report the result.
+extern "C" fn start_rust() -> ! {
+    unsafe {
+        let start = core::ptr::addr_of_mut!(_start_bss);
+        let end = core::ptr::addr_of_mut!(_end_bss);
+        core::ptr::write_bytes(start, 0, end as usize - start as usize);
+    }
+
+    match unsafe { test_main() } {
+        Ok(()) => pass(),
+        Err(failure) => fail(failure),
+    }
+}
+
+/// Report pass to the test finisher.
+pub fn pass() -> ! {
+    println!("PASS");
+    finish(FINISHER_PASS)
+}
+
+/// Report failure to the test finisher.
+pub fn fail(failure: Failure) -> ! {
+    println!("FAIL: {failure:?}");
+    finish((failure as u32) << 16 | FINISHER_FAIL)
+}
+
+/// Write the value to the test finisher.
+fn finish(value: u32) -> ! {
+    unsafe {
+        (TEST_FINISHER_BASE as *mut u32).write_volatile(value);
+    }
+    // the test finisher is not assigned to the guest if it does not reach here.
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Registers saved on trap.
+///
+/// Only caller-saved registers are saved because the handler is a Rust function.
+#[repr(C)]
+#[derive(Debug)]
+pub struct TrapFrame {
+    /// `ra`, `t0`-`t2`, `a0`-`a7` and `t3`-`t6`
+    pub regs: [usize; 16],
+    /// Return address of the trap.
+    pub sepc: usize,
+}
+
+/// Trap that is passed to the handler.
+#[derive(Debug, Copy, Clone)]
+pub struct Trap {
+    /// `scause`
+    pub scause: usize,
+    /// `stval`
+    pub stval: usize,
+}
+
+impl Trap {
+    /// Interrupt bit of `scause`.
+    const INTERRUPT: usize = 1 << 63;
+
+    /// Return exception code if the trap is an exception.
+    #[must_use]
+    pub fn exception(&self) -> Option<usize> {
+        (self.scause & Self::INTERRUPT == 0).then_some(self.scause)
+    }
+
+    /// Return interrupt code if the trap is an interrupt.
+    #[must_use]
+    pub fn interrupt(&self) -> Option<usize> {
+        (self.scause & Self::INTERRUPT != 0).then_some(self.scause & !Self::INTERRUPT)
+    }
+}
+
+/// Handler of traps that is set by the test.
+static mut TRAP_HANDLER: Option<fn(&mut TrapFrame, Trap)> = None;
+
+/// Set the handler of traps.
+///
+/// Traps are reported as failure if the handler is not set.
+pub fn set_trap_handler(handler: fn(&mut TrapFrame, Trap)) {
+    unsafe {
+        TRAP_HANDLER = Some(handler);
+    }
+}
+
+/// Trap handler called by `_trap_entry`.
+extern "C" fn trap_handler(frame: &mut TrapFrame) {
+    let (scause, stval): (usize, usize);
+    unsafe {
+        asm!("csrr {}, scause", out(reg) scause);
+        asm!("csrr {}, stval", out(reg) stval);
+    }
+    let trap = Trap { scause, stval };
+
+    let Some(handler) = (unsafe { TRAP_HANDLER }) else {
+        println!("unexpected trap: {trap:x?}, sepc: {:#x}", frame.sepc);
+        fail(Failure::UnexpectedTrap);
+    };
+    handler(frame, trap);
+}
+
+/// Return value of SBI call.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SbiRet {
+    /// Error code.
+    pub error: isize,
+    /// Return value.
+    pub value: usize,
+}
+
+/// Call SBI function.
+#[must_use]
+pub fn sbi_call(eid: usize, fid: usize, args: [usize; 3]) -> SbiRet {
+    let (error, value);
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") args[0] => error,
+            inlateout("a1") args[1] => value,
+            in("a2") args[2],
+            in("a6") fid,
+            in("a7") eid,
+        );
+    }
+    SbiRet { error, value }
+}
+
+/// Read `time` CSR.
+#[must_use]
+pub fn time() -> u64 {
+    let time;
+    unsafe {
+        asm!("csrr {}, time", out(reg) time);
+    }
+    time
+}
+
+/// Wait until the condition becomes true.
+///
+/// Return `false` if it does not become true in `timeout_ms` milliseconds.
+pub fn wait_until(timeout_ms: u64, mut cond: impl FnMut() -> bool) -> bool {
+    let deadline = time() + TIMEBASE_FREQUENCY / 1000 * timeout_ms;
+    while !cond() {
+        if time() > deadline {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+/// Enable interrupts of the bits in `sie` and `sstatus.SIE`.
+pub fn enable_interrupts(sie_bits: usize) {
+    unsafe {
+        asm!("csrs sie, {}", in(reg) sie_bits);
+        asm!("csrsi sstatus, 0x2");
+    }
+}
+
+/// Disable all interrupts.
+pub fn disable_interrupts() {
+    unsafe {
+        asm!("csrci sstatus, 0x2");
+        asm!("csrw sie, zero");
+    }
+}
+
+/// Panic handler
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    println!("{info}");
+    fail(Failure::Panic)
+}
@@ -0,0 +1,235 @@
+//! Development tasks of hikami.
+//!
+//! ```sh
+//! $ cargo xtask integration-test [--qemu <path>] [--timeout <seconds>] [<test>...]
+//! ```
+//!
+//! `integration-test` builds hikami and the test guests in `test_guests/`,
+//! and runs each test guest as the primary guest of hikami on QEMU virt machine.
+//! The test guest is passed by `-initrd` and loaded by the ELF loader of hikami.
+//! It reports the result by the test finisher, so the result is the exit status of QEMU.
+//! Console output of each test is written to `target/xtask/<test>.log`.
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Target triple of hikami and test guests.
+const TARGET: &str = "riscv64imac-unknown-none-elf";
+
+/// All test guests. (binaries of `test_guests`)
+const TESTS: [&str; 5] = ["sbi", "plic", "timer", "zicfiss", "mmio_fault"];
+
+/// Default timeout of each test.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Options of `integration-test`.
+#[derive(Debug)]
+struct Options {
+    /// QEMU executable.
+    qemu: String,
+    /// Timeout of each test.
+    timeout: Duration,
+    /// Tests to run.
+    tests: Vec<String>,
+}
+
+impl Options {
+    /// Parse command line arguments after `integration-test`.
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options {
+            qemu: String::from("qemu-system-riscv64"),
+            timeout: DEFAULT_TIMEOUT,
+            tests: Vec::new(),
+        };
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--qemu" => options.qemu = args.next().ok_or("--qemu requires a path")?,
+                "--timeout" => {
+                    let seconds = args
+                        .next()
+                        .and_then(|seconds| seconds.parse().ok())
+                        .ok_or("--timeout requires seconds")?;
+                    options.timeout = Duration::from_secs(seconds);
+                }
+                test if TESTS.contains(&test) => options.tests.push(arg),
+                unknown => return Err(format!("unknown test or option: {unknown}")),
+            }
+        }
+
+        if options.tests.is_empty() {
+            options.tests = TESTS.iter().map(ToString::to_string).collect();
+        }
+        Ok(options)
+    }
+}
+
+/// Result of a test.
+#[derive(Debug)]
+enum TestResult {
+    /// The test guest reported pass.
+    Pass,
+    /// QEMU exited with the status. (the exit code is reported by the test guest)
+    Fail(ExitStatus),
+    /// QEMU did not exit in time. (e.g. hikami panicked)
+    Timeout,
+}
+
+/// Return root directory of the repository.
+fn root_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .unwrap()
+        .to_path_buf()
+}
+
+/// Return `cargo` command that is running this task.
+fn cargo() -> Command {
+    let mut command = Command::new(std::env::var("CARGO").unwrap_or_else(|_| "cargo".into()));
+    command.current_dir(root_dir());
+    command
+}
+
+/// Run the command and return error if it fails.
+fn run(command: &mut Command) -> Result<(), String> {
+    let status = command
+        .status()
+        .map_err(|err| format!("failed to run {command:?}: {err}"))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("{command:?} failed: {status}"))
+    }
+}
+
+/// Build hikami and return path of the binary.
+fn build_hikami() -> Result<PathBuf, String> {
+    run(cargo().args(["build", "--target", TARGET]))?;
+    Ok(root_dir().join("target").join(TARGET).join("debug/hikami"))
+}
+
+/// Build test guests and return the directory of the binaries.
+///
+/// Rustflags in `.cargo/config.toml` are for hikami, so they are replaced with the linker script of test guests.
+fn build_test_guests() -> Result<PathBuf, String> {
+    let test_guests = root_dir().join("test_guests");
+    let link_arg = format!("-Clink-arg=-T{}", test_guests.join("link.x").display());
+    run(cargo()
+        .args(["build", "--release", "--target", TARGET, "--manifest-path"])
+        .arg(test_guests.join("Cargo.toml"))
+        .env("CARGO_ENCODED_RUSTFLAGS", link_arg))?;
+    Ok(test_guests.join("target").join(TARGET).join("release"))
+}
+
+/// Run the test guest on hikami.
+fn run_test(
+    options: &Options,
+    hikami: &Path,
+    guest: &Path,
+    log_path: &Path,
+) -> Result<TestResult, String> {
+    let log = File::create(log_path)
+        .map_err(|err| format!("failed to create {}: {err}", log_path.display()))?;
+    let mut qemu = Command::new(&options.qemu)
+        .args(["-cpu", "rv64,smstateen=true"])
+        .args(["-machine", "virt"])
+        .args(["-smp", "1"])
+        .args(["-m", "2G"])
+        .args(["-bios", "none"])
+        .arg("-nographic")
+        .arg("-kernel")
+        .arg(hikami)
+        .arg("-initrd")
+        .arg(guest)
+        .stdin(Stdio::null())
+        .stdout(log.try_clone().map_err(|err| err.to_string())?)
+        .stderr(log)
+        .spawn()
+        .map_err(|err| format!("failed to run {}: {err}", options.qemu))?;
+
+    let start = Instant::now();
+    loop {
+        if let Some(status) = qemu.try_wait().map_err(|err| err.to_string())? {
+            return Ok(if status.success() {
+                TestResult::Pass
+            } else {
+                TestResult::Fail(status)
+            });
+        }
+        if start.elapsed() > options.timeout {
+            qemu.kill().map_err(|err| err.to_string())?;
+            qemu.wait().map_err(|err| err.to_string())?;
+            return Ok(TestResult::Timeout);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Run integration tests and return whether all tests passed.
+fn integration_test(options: &Options) -> Result<bool, String> {
+    let hikami = build_hikami()?;
+    let guests = build_test_guests()?;
+    let log_dir = root_dir().join("target/xtask");
+    fs::create_dir_all(&log_dir).map_err(|err| err.to_string())?;
+
+    println!("\nrunning {} tests", options.tests.len());
+    let mut failed = Vec::new();
+    for test in &options.tests {
+        let log_path = log_dir.join(format!("{test}.log"));
+        let result = run_test(options, &hikami, &guests.join(test), &log_path)?;
+        match result {
+            TestResult::Pass => println!("test {test} ... ok"),
+            TestResult::Fail(status) => println!("test {test} ... FAILED ({status})"),
+            TestResult::Timeout => println!("test {test} ... TIMEOUT"),
+        }
+        if !matches!(result, TestResult::Pass) {
+            failed.push((test, log_path));
+        }
+    }
+
+    for (test, log_path) in &failed {
+        println!("\n---- {test} console ({}) ----", log_path.display());
+        print!("{}", fs::read_to_string(log_path).unwrap_or_default());
+    }
+    println!(
+        "\ntest result: {}. {} passed; {} failed",
+        if failed.is_empty() { "ok" } else { "FAILED" },
+        options.tests.len() - failed.len(),
+        failed.len()
+    );
+
+    Ok(failed.is_empty())
+}
+
+/// Print usage.
+fn usage() {
+    eprintln!(
+        "Usage: cargo xtask integration-test [--qemu <path>] [--timeout <seconds>] [<test>...]"
+    );
+    eprintln!("Tests: {}", TESTS.join(", "));
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let options = match args.next().as_deref() {
+        Some("integration-test") => Options::parse(args),
+        _ => Err(String::from("unknown task")),
+    };
+    let options = options.unwrap_or_else(|err| {
+        eprintln!("error: {err}");
+        usage();
+        process::exit(2);
+    });
+
+    match integration_test(&options) {
+        Ok(true) => (),
+        Ok(false) => process::exit(1),
+        Err(err) => {
+            eprintln!("error: {err}");
+            process::exit(2);
+        }
+    }
+}